@@ -69,6 +69,15 @@ list(APPEND CMAKE_MODULE_PATH "$ENV{WASM_TC_CMAKE_MODULE_PATH}")
 
 list(APPEND CMAKE_FIND_ROOT_PATH "${EMSCRIPTEN_ROOT_PATH}/system")
 
+# The sysroot, and the config packages (`<sysroot>/lib/cmake/ZLIB` etc) the
+# drivers write for the libraries installed in it. Prefer those over the
+# Find*.cmake modules, which don't define the same imported targets.
+if (NOT "$ENV{WASM_SYSROOT}" STREQUAL "")
+	list(APPEND CMAKE_FIND_ROOT_PATH "$ENV{WASM_SYSROOT}")
+	list(APPEND CMAKE_PREFIX_PATH "$ENV{WASM_SYSROOT}")
+endif()
+set(CMAKE_FIND_PACKAGE_PREFER_CONFIG ON)
+
 if (CMAKE_HOST_WIN32)
 	set(EMCC_SUFFIX ".bat")
 else()
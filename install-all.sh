@@ -3,3 +3,4 @@
 cargo install --force --path ./src/clang
 cargo install --force --path ./src/cmake
 cargo install --force --path ./src/ld
+cargo install --force --path ./src/opt
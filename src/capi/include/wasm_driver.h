@@ -0,0 +1,26 @@
+/* The drivers, in process; see `src/capi/src/lib.rs`. Link against
+ * `libwasm_driver_capi`. */
+
+#ifndef WASM_DRIVER_H
+#define WASM_DRIVER_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Run the driver named by `argv[0]` (`wasm-clang`, `wasm-clang++` or
+ * `wasm-ld`) with `argv[1..argc]`, returning its exit code. If `out_diag`
+ * isn't null, it's set to the driver's diagnostics, as a JSON array of
+ * `{"severity", "location", "message"}`; free it with
+ * `wasm_driver_free_diag`. Calls are serialized, and each call's global
+ * flags (`--hermetic`, etc) are undone after it. No signal handlers are
+ * set. */
+int wasm_driver_compile(int argc, const char *const *argv, char **out_diag);
+
+void wasm_driver_free_diag(char *diag);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,197 @@
+//! A C interface to the drivers, so build tools which aren't written in
+//! Rust can run them in process; see `include/wasm_driver.h`.
+//!
+//! `argv[0]` picks the driver, like the binaries' names do: `wasm-clang`,
+//! `wasm-clang++` (or `wasm-clangxx`) or `wasm-ld`. The rest are the
+//! driver's args, global flags (`--dry-run`, etc) included.
+//!
+//! The global flags (ie `--hermetic`, `--tool-env`) a call is given are
+//! kept in a `GlobalFlags` value which is only current for that call; the
+//! host's environment isn't touched. Calls are still serialized (by
+//! `RUNNING`). The Ctrl-C handler the binaries set isn't; Ctrl-C is the
+//! host's. Only the drivers' own diagnostics are captured; the
+//! tools they run still write to stderr, and the flags which only print
+//! something to stdout (ie `--print-targets`) are rejected.
+
+extern crate clang_driver;
+extern crate ld_driver;
+#[macro_use]
+extern crate lazy_static;
+extern crate wasm_driver_utils as util;
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+use std::sync::Mutex;
+
+use clang_driver::DriverMode;
+use util::{CommandQueueError, json_string};
+use util::diag::{self, Diagnostic, Severity};
+use util::toolchain::WasmToolchain;
+
+lazy_static! {
+  static ref RUNNING: Mutex<()> = Mutex::new(());
+}
+
+/// Flags which print to stdout, which would be the host's.
+const PRINT_FLAGS: &'static [&'static str] = &[
+  "--print-toolchain-manifest",
+  "--print-effective-commandline",
+  "--print-targets",
+  "--print-link-command",
+  "--print-memory-layout",
+];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Driver {
+  Clang(DriverMode),
+  Ld,
+}
+impl Driver {
+  fn from_argv0(argv0: &str) -> Option<Driver> {
+    match Path::new(argv0).file_stem()?.to_str()? {
+      "wasm-clang++" | "wasm-clangxx" => Some(Driver::Clang(DriverMode::CXX)),
+      "wasm-clang" => Some(Driver::Clang(DriverMode::CC)),
+      "wasm-ld" => Some(Driver::Ld),
+      _ => None,
+    }
+  }
+  fn run(self, args: Vec<String>) -> Result<(), CommandQueueError> {
+    let tc = WasmToolchain::new();
+    match self {
+      Driver::Clang(mode) => {
+        let invocation = clang_driver::Invocation::new_with_toolchain(tc, mode);
+        util::main_inner_with_args(invocation, args)?;
+      },
+      Driver::Ld => {
+        let invocation = ld_driver::Invocation::new_with_toolchain(tc);
+        util::main_inner_with_args(invocation, args)?;
+      },
+    }
+    Ok(())
+  }
+}
+
+fn error(message: String) -> Diagnostic {
+  Diagnostic {
+    severity: Severity::Error,
+    location: None,
+    message,
+  }
+}
+
+/// `args` (`argv[0]` first) run by their driver: the exit code, and
+/// every diagnostic, including the error which stopped it, if any.
+pub fn run(args: Vec<String>) -> (c_int, Vec<Diagnostic>) {
+  let _running = RUNNING.lock()
+    .unwrap_or_else(|poisoned| poisoned.into_inner() );
+
+  let driver = match args.first() {
+    Some(argv0) => Driver::from_argv0(argv0)
+      .ok_or_else(|| format!("`{}` isn't a driver; expected `wasm-clang`, \
+                              `wasm-clang++` or `wasm-ld`", argv0) ),
+    None => Err("no `argv[0]`".to_string()),
+  };
+  let driver = match driver {
+    Ok(driver) => driver,
+    Err(msg) => return (1, vec![error(msg)]),
+  };
+  if let Some(flag) = args.iter().find(|arg| PRINT_FLAGS.contains(&&arg[..]) ) {
+    return (1, vec![error(format!("`{}` prints to stdout, which isn't captured \
+                                   in process; run the driver instead", flag))]);
+  }
+
+  util::interrupt::set_embedded();
+  util::interrupt::reset();
+  // the global flags the args give are only current for the run.
+  let args = args.into_iter().skip(1).collect();
+  let (result, mut diags) = diag::capture(|| {
+    catch_unwind(AssertUnwindSafe(|| driver.run(args) ))
+  });
+  let code = match result {
+    Ok(Ok(())) => 0,
+    Ok(Err(CommandQueueError::Error(msg))) => {
+      diags.push(error(msg.to_string().trim_end().to_string()));
+      1
+    },
+    Ok(Err(CommandQueueError::ProcessError(code))) => {
+      let code = code.unwrap_or(1);
+      diags.push(error(format!("a command failed with exit code {}", code)));
+      code
+    },
+    Err(payload) => {
+      let msg = payload.downcast_ref::<&str>()
+        .map(|msg| msg.to_string() )
+        .or_else(|| payload.downcast_ref::<String>().cloned() )
+        .unwrap_or_else(|| "<unknown>".into() );
+      diags.push(error(format!("the driver panicked: {}", msg)));
+      127
+    },
+  };
+  (code, diags)
+}
+
+
+/// `diags` as a JSON array of `{"severity", "location", "message"}`.
+pub fn diagnostics_json(diags: &[Diagnostic]) -> String {
+  let mut out = String::from("[");
+  for (idx, d) in diags.iter().enumerate() {
+    if idx != 0 { out.push(','); }
+    let location = d.location.as_ref()
+      .map(|l| json_string(l) )
+      .unwrap_or_else(|| "null".into() );
+    out.push_str(&format!("\n  {{\"severity\": {}, \"location\": {}, \"message\": {}}}",
+                          json_string(d.severity.name()), location, json_string(&d.message)));
+  }
+  if diags.len() != 0 { out.push('\n'); }
+  out.push(']');
+  out
+}
+
+/// Run the driver named by `argv[0]` with `argv[1..argc]`, returning its
+/// exit code. If `out_diag` isn't null, it's set to the diagnostics as a
+/// JSON array (see `diagnostics_json`), to be freed with
+/// `wasm_driver_free_diag`.
+#[no_mangle]
+pub unsafe extern "C" fn wasm_driver_compile(argc: c_int,
+                                             argv: *const *const c_char,
+                                             out_diag: *mut *mut c_char)
+  -> c_int
+{
+  let mut args = Vec::new();
+  let mut bad_arg = None;
+  if !argv.is_null() {
+    for idx in 0..argc.max(0) as isize {
+      let arg = *argv.offset(idx);
+      if arg.is_null() { break; }
+      match CStr::from_ptr(arg).to_str() {
+        Ok(arg) => args.push(arg.to_string()),
+        Err(_) => {
+          bad_arg = Some(idx);
+          break;
+        },
+      }
+    }
+  }
+
+  let (code, diags) = match bad_arg {
+    Some(idx) => (1, vec![error(format!("`argv[{}]` isn't valid UTF-8", idx))]),
+    None => run(args),
+  };
+  if !out_diag.is_null() {
+    *out_diag = CString::new(diagnostics_json(&diags[..]))
+      .map(|json| json.into_raw() )
+      .unwrap_or(ptr::null_mut());
+  }
+  code
+}
+
+/// Free the diagnostics `wasm_driver_compile` returned.
+#[no_mangle]
+pub unsafe extern "C" fn wasm_driver_free_diag(diag: *mut c_char) {
+  if !diag.is_null() {
+    drop(CString::from_raw(diag));
+  }
+}
@@ -13,8 +13,8 @@ use std::ffi::OsString;
 use util::{EhMode, OptimizationGoal, Tool, ToolInvocation, CommandQueue, ToolArgs,
            process_invocation_args, regex, };
 use util::{need_nacl_toolchain};
-use util::toolchain::{WasmToolchain, WasmToolchainTool, };
-use util::command_queue::RunState;
+use util::toolchain::{ThreadModel, WasmToolchain, WasmToolchainTool, };
+use util::ldtools::LinkerFlagValues;
 
 #[macro_use]
 extern crate wasm_driver_utils as util;
@@ -37,6 +37,15 @@ fn get_inc_path() -> PathBuf {
     .join("include")
 }
 
+/// The (whitespace separated) link args configured for `key`, or
+/// `default`.
+fn configured_libs(key: &str, default: &[&str]) -> Vec<String> {
+  match util::config::get(key) {
+    Some(libs) => libs.split_whitespace().map(|lib| lib.to_string() ).collect(),
+    None => default.iter().map(|lib| lib.to_string() ).collect(),
+  }
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub enum DriverMode {
   CC,
@@ -79,6 +88,8 @@ impl DriverMode {
 enum GccMode {
   Dashc,
   DashE,
+  /// Stop after generating assembly; LLVM IR if `-emit-llvm` is also given.
+  DashS,
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
@@ -89,6 +100,8 @@ enum FileLang {
   Cxx,
   CxxHeader,
   CxxCppOut,
+  /// A C++20 module interface unit.
+  CxxModule,
 }
 
 impl FromStr for FileLang {
@@ -108,6 +121,9 @@ impl FromStr for FileLang {
       "C" => FileLang::Cxx,
 
       "h" => FileLang::CHeader,
+      "c-header" => FileLang::CHeader,
+      "cpp-output" => FileLang::CppOut,
+      "c++-cpp-output" => FileLang::CxxCppOut,
 
       "hh" => FileLang::CxxHeader,
       "H" => FileLang::CxxHeader,
@@ -117,6 +133,14 @@ impl FromStr for FileLang {
       "HPP" => FileLang::CxxHeader,
       "h++" => FileLang::CxxHeader,
       "tcc" => FileLang::CxxHeader,
+      "c++-header" => FileLang::CxxHeader,
+
+      "cppm" => FileLang::CxxModule,
+      "ccm" => FileLang::CxxModule,
+      "cxxm" => FileLang::CxxModule,
+      "c++m" => FileLang::CxxModule,
+      "ixx" => FileLang::CxxModule,
+      "c++-module" => FileLang::CxxModule,
 
       _ => return Err(From::from("unknown file language")),
     };
@@ -125,6 +149,12 @@ impl FromStr for FileLang {
 }
 
 impl FileLang {
+  fn is_header(&self) -> bool {
+    match self {
+      &FileLang::CHeader | &FileLang::CxxHeader => true,
+      _ => false,
+    }
+  }
   fn from_path<P: AsRef<Path>>(p: P) -> Option<FileLang> {
     p.as_ref().extension()
       .and_then(|os_str| os_str.to_str() )
@@ -143,6 +173,7 @@ impl fmt::Display for FileLang {
       &FileLang::Cxx => write!(f, "c++"),
       &FileLang::CxxHeader => write!(f, "c++-header"),
       &FileLang::CxxCppOut => write!(f, "c++-cpp-output"),
+      &FileLang::CxxModule => write!(f, "c++-module"),
     }
   }
 }
@@ -163,6 +194,8 @@ pub struct MakeDeps {
 
   output: Option<MakeDepOutput>,
   dest: Option<PathBuf>,
+  /// `-MJ`: append a compilation database entry for each TU here.
+  compile_db: Option<PathBuf>,
 }
 impl Default for MakeDeps {
   fn default() -> Self {
@@ -175,6 +208,7 @@ impl Default for MakeDeps {
 
       output: None,
       dest: None,
+      compile_db: None,
     }
   }
 }
@@ -184,34 +218,87 @@ pub struct Invocation {
   pub tc: WasmToolchain,
   pub driver_mode: DriverMode,
   gcc_mode: Option<GccMode>,
+  /// `--eh=<mode>`: how exceptions are compiled, and so which build of the
+  /// C++ runtime is linked.
   eh_mode: EhMode,
 
   pub make_deps: MakeDeps,
 
   optimization: OptimizationGoal,
+  /// `-Ofast`: `-O3 -ffast-math`.
+  fast_math: bool,
 
   no_default_libs: bool,
+  /// `-nostdlib`: `-nodefaultlibs`, and no crt1 either.
   no_std_lib: bool,
+  no_std_libxx: bool,
+  no_libc: bool,
+  /// What `-lc` and `-lc++ -lc++abi` are linked as by default; they can
+  /// be replaced in the config (`default-libc`, `default-cxx-libs`).
+  libc: Vec<String>,
+  cxx_libs: Vec<String>,
   no_std_inc: bool,
   no_std_incxx: bool,
+  /// `--isystem-before-libcxx=<dir>`/`--isystem-after-libc=<dir>`:
+  /// extra dirs at a specific layer of the standard includes, so a sysroot
+  /// build can shadow some of the headers it's bootstrapping.
+  pub isystem_before_libcxx: Vec<PathBuf>,
+  pub isystem_after_libc: Vec<PathBuf>,
   pic: bool,
 
   shared: bool,
+  /// `-r`/`-Wl,-r`: a partial link, producing a relocatable object.
+  relocatable: bool,
 
   pub emit_wast: bool,
+  emit_llvm: bool,
+  /// `-femit-llvm-bc-only`: `-c` produces LLVM bitcode (`.bc`) rather
+  /// than wasm objects.
+  emit_bc: bool,
+
+  /// `-fmodules`.
+  modules: bool,
+  /// `-fvisibility=hidden`; the link then exports whatever was left visible.
+  visibility_hidden: bool,
+
+  /// `-e`/`--entry`; overrides crt1's `_start_c`.
+  entry: Option<String>,
+  /// `--no-entry` or `-mexec-model=reactor`: no crt1 and no entry point.
+  no_entry: bool,
+  /// `-gsource-map`: link with a source map, `<output>.map`.
+  source_map: bool,
+  /// `--source-map-base=`; the URL prefix of the map, for the linker.
+  source_map_base: Option<String>,
+  /// `-fmodules-cache-path=`; defaults to a dir in the sysroot.
+  module_cache_path: Option<PathBuf>,
 
   file_type: Option<FileLang>,
   inputs: Vec<(PathBuf, Option<FileLang>)>,
   header_inputs: Vec<PathBuf>,
 
-  linker_args: Vec<String>,
+  /// `-l`, `-L` and `-Wl,` args, and the object and library inputs, in the
+  /// order they were given, since that order matters to the linker (ie
+  /// `-Wl,--whole-archive -la -Wl,--no-whole-archive -lb`). Sources (by
+  /// `-x` or extension) are compiled first, and headers aren't linked.
+  link_args: Vec<String>,
+  link_arg_values: LinkerFlagValues,
   driver_args: Vec<OsString>,
+  /// Unknown `-m` flags passed to clang anyway
+  /// (`--forward-unknown-machine-flags`), for the summary.
+  forwarded_machine_flags: Vec<String>,
 
   output: Option<PathBuf>,
 
+  /// ccache/sccache/etc, run with clang as its first argument.
+  compiler_launcher: Option<String>,
+
+  /// `-target`, with aliases resolved; `None` is the canonical target.
+  pub target: Option<String>,
+
   verbose: bool,
 
   print_version: bool,
+  print_targets: bool,
 }
 
 impl Default for Invocation {
@@ -234,31 +321,58 @@ impl Invocation {
       make_deps: Default::default(),
 
       optimization: Default::default(),
+      fast_math: false,
 
       no_default_libs: false,
       no_std_lib: false,
+      no_std_libxx: false,
+      no_libc: false,
+      libc: configured_libs("default-libc", &["-lc"]),
+      cxx_libs: configured_libs("default-cxx-libs", &["-lc++", "-lc++abi"]),
       no_std_inc: false,
       no_std_incxx: false,
+      isystem_before_libcxx: Vec::new(),
+      isystem_after_libc: Vec::new(),
 
       pic: false,
 
       shared: false,
+      relocatable: false,
       emit_wast: false,
+      emit_llvm: false,
+      emit_bc: false,
+
+      modules: false,
+      visibility_hidden: false,
+
+      entry: None,
+      no_entry: false,
+      source_map: false,
+      source_map_base: None,
+      module_cache_path: None,
 
       file_type: None,
       inputs: Default::default(),
       header_inputs: Default::default(),
 
-      linker_args: Default::default(),
+      link_args: Default::default(),
+      link_arg_values: Default::default(),
       driver_args: Default::default(),
+      forwarded_machine_flags: Default::default(),
 
       output: Default::default(),
 
+      compiler_launcher: util::config::get("compiler-launcher")
+        .map(|launcher| launcher.to_string() ),
+
+      target: None,
+
       verbose: false,
       print_version: false,
+      print_targets: false,
     }
   }
-  fn new_with_toolchain(tc: WasmToolchain, mode: DriverMode) -> Self {
+  pub fn new_with_toolchain(tc: WasmToolchain, mode: DriverMode) -> Self {
     Invocation {
       tc,
       driver_mode: mode,
@@ -268,28 +382,55 @@ impl Invocation {
       make_deps: Default::default(),
 
       optimization: Default::default(),
+      fast_math: false,
 
       no_default_libs: false,
       no_std_lib: false,
+      no_std_libxx: false,
+      no_libc: false,
+      libc: configured_libs("default-libc", &["-lc"]),
+      cxx_libs: configured_libs("default-cxx-libs", &["-lc++", "-lc++abi"]),
       no_std_inc: false,
       no_std_incxx: false,
+      isystem_before_libcxx: Vec::new(),
+      isystem_after_libc: Vec::new(),
 
       pic: false,
 
       shared: false,
+      relocatable: false,
       emit_wast: false,
+      emit_llvm: false,
+      emit_bc: false,
+
+      modules: false,
+      visibility_hidden: false,
+
+      entry: None,
+      no_entry: false,
+      source_map: false,
+      source_map_base: None,
+      module_cache_path: None,
 
       file_type: None,
       inputs: Default::default(),
       header_inputs: Default::default(),
 
-      linker_args: Default::default(),
+      link_args: Default::default(),
+      link_arg_values: Default::default(),
       driver_args: Default::default(),
+      forwarded_machine_flags: Default::default(),
 
       output: Default::default(),
 
+      compiler_launcher: util::config::get("compiler-launcher")
+        .map(|launcher| launcher.to_string() ),
+
+      target: None,
+
       verbose: false,
       print_version: false,
+      print_targets: false,
     }
   }
   pub fn with_toolchain<T>(tool: &T) -> Self
@@ -305,33 +446,101 @@ impl Invocation {
 This is a \"GCC-compatible\" driver using clang under the hood.
 Usage: {} [options] <inputs> ...
 BASIC OPTIONS:
-  -o <file>             Output to <file>.
+  -o <file>             Output to <file>. A `.a` output archives the
+                        (compiled) inputs instead of linking them.
   -E                    Only run the preprocessor.
-  -S                    Generate bitcode assembly.
-  -c                    Generate bitcode object.
+  -S                    Generate wasm assembly (LLVM IR with -emit-llvm).
+  -c                    Generate wasm object.
+  -femit-llvm-bc-only   Make -c generate LLVM bitcode (`.bc`) instead.
   -I <dir>              Add header search path.
   -L <dir>              Add library search path.
   -D<key>[=<val>]       Add definition for the preprocessor.
   -W<id>                Toggle warning <id>.
   -f<feature>           Enable <feature>.
+  -fmodules             Enable clang modules. The module cache defaults to
+                        `<sysroot>/module-cache`.
+  -fmodule-file=[<name>=]<file>
+                        Use the precompiled module <file>.
+  -fprebuilt-module-path=<dir>
+                        Look for precompiled modules in <dir>.
+  -fvisibility=hidden   Hide symbols by default; when linking, anything
+                        left with default visibility is exported.
   -Wl,<arg>             Pass <arg> to the linker.
   -Xlinker <arg>        Pass <arg> to the linker.
   -Wp,<arg>             Pass <arg> to the preprocessor.
-  -Xpreprocessor,<arg>  Pass <arg> to the preprocessor.
+  -Xpreprocessor <arg>  Pass <arg> to the preprocessor.
+  -Wa,<arg>             Pass <arg> to the assembler.
+  -Xassembler <arg>     Pass <arg> to the assembler.
   -x <language>         Treat subsequent input files as having type <language>.
   -static               Produce a static executable (the default).
+  -r                    Partially link the inputs into a relocatable object.
+  -e <sym> | --entry=<sym>
+                        Use <sym> as the entry point, instead of crt1's.
+  --no-entry | -mexec-model=reactor
+                        Link without crt1 or an entry point, ie for a
+                        library-like module driven by its exports.
+  --emit-runner=<node|html>
+                        Also write <output>.js (for node) or <output>.html
+                        which runs the linked executable.
+  --eh=<none|wasm|sjlj> Compile with exceptions enabled, using wasm's
+                        exception handling or emscripten's JS based
+                        scheme, and link the matching C++ runtime (the
+                        sysroot has to be built with the same `--eh`).
+  --isystem-before-libcxx=<dir>
+                        Search <dir> for system headers just before libc++'s.
+  --isystem-after-libc=<dir>
+                        Search <dir> for system headers just after libc's
+                        (and before clang's builtin headers).
+  -nodefaultlibs        Don't link any of the default libraries.
+  -nostdlib             -nodefaultlibs, and no crt1 either.
+  -nostdlib++           Don't link the C++ runtime (libc++ and libc++abi).
+  -nolibc               Don't link libc.
+                        The default libc and C++ runtime can be replaced
+                        with the `default-libc` and `default-cxx-libs`
+                        config keys, ie `default-libc = -lmylibc`.
   -Bstatic              Link subsequent libraries statically (ignored).
   -Bdynamic             Link subsequent libraries dynamically (ignored).
   -fPIC                 Ignored (only used by translator backend)
                         (accepted for compatibility).
   -pipe                 Ignored (for compatibility).
-  -O<n>                 Optimation level <n>: 0, 1, 2, 3, 4 or s.
+  -O<n>                 Optimation level <n>: 0, 1, 2, 3, 4, s, z, g (ie 1)
+                        or fast (ie 3 with -ffast-math).
   -g                    Generate complete debug information.
   -gline-tables-only    Generate debug line-information only
                         (allowing for stack traces).
   -flimit-debug-info    Generate limited debug information.
+  -gsource-map          Generate debug information, and link with a source
+                        map (<output>.map) instead of the DWARF.
+  --source-map-base=<url>
+                        Where the source map will be served from; prefixes
+                        the map's name in the output's sourceMappingURL.
   -save-temps           Keep intermediate compilation results.
   -v                    Verbose output / show commands.
+  -target <triple>      Target <triple>; see --print-targets.
+  --print-targets       List the accepted target triples.
+  --threads=<model>     pthread support: none (the default), emulated or
+                        atomics. Must match the sysroot's.
+  --compiler-launcher=<prog>
+                        Run clang through <prog> (ie ccache or sccache).
+                        Defaults to `compiler-launcher` in the config file;
+                        `none` disables it.
+  --relaxed-flags       Warn about and ignore unknown -m flags, like GCC,
+                        instead of failing. -f flags are all passed to
+                        clang, which checks them itself.
+  --forward-unknown-machine-flags
+                        Pass unknown -m flags to clang, instead of failing,
+                        and warn about them all once.
+  --allow-unused-args   Warn about arguments nothing accepted, instead of
+                        failing.
+  --ignore-host-args    Warn about and ignore macOS specific arguments, like
+                        -framework, instead of failing.
+  --no-color            Don't color diagnostics (also `WASM_DRIVER_COLOR=never`).
+  --tool-env NAME=VALUE Set NAME to VALUE in the environment of every tool
+                        run (clang, wasm-ld, etc). Repeatable.
+  --step-timeout=<secs> Kill any tool run which takes longer than <secs>.
+  --print-toolchain-manifest
+                        Print the path, version and sha256 of each toolchain
+                        component, as JSON.
   -h | --help           Show this help.
   --help-full           Show underlying clang driver's help message
                         (warning: not all options supported).
@@ -354,11 +563,16 @@ BASIC OPTIONS:
     let sysroot_inc = sysroot.join("include");
     if !self.no_std_inc {
       isystem.push(sysroot_inc.join("compat"));
+      isystem.extend(self.isystem_before_libcxx.iter().cloned());
       if !self.no_std_incxx && self.driver_mode == DriverMode::CXX {
         isystem.push(sysroot_inc.join("c++/v1"));
         isystem.push(sysroot_inc.join("c++/v1/support/musl"));
       }
       isystem.push(sysroot_inc.clone());
+      isystem.extend(self.isystem_after_libc.iter().cloned());
+      if let Some(ref dir) = self.tc.clang_resource_dir {
+        isystem.push(dir.join("include"));
+      }
     }
     isystem
       .into_iter()
@@ -370,17 +584,23 @@ BASIC OPTIONS:
     let mut libs = Vec::new();
     libs.push(PathBuf::from("-L"));
     libs.push(self.tc.sysroot_lib());
-    if self.no_default_libs || self.no_std_lib {
+    if self.no_default_libs || self.no_std_lib || self.relocatable {
       libs
     } else {
-      match self.driver_mode {
-        DriverMode::CXX => {
-          libs.push(PathBuf::from("-lc++"));
-          libs.push(PathBuf::from("-lc++abi"));
-        },
-        _ => {}
+      if self.driver_mode == DriverMode::CXX && !self.no_std_libxx {
+        libs.extend(self.cxx_libs.iter().map(PathBuf::from));
+        // libc++abi's personality needs the unwinder for wasm's EH.
+        if self.eh_mode == EhMode::Zerocost {
+          libs.push(PathBuf::from("-lunwind"));
+        }
+      }
+      // green threads are built against our libc.
+      if !self.no_libc {
+        if self.tc.threads == ThreadModel::Emulated {
+          libs.push(PathBuf::from("-lgreen-threads"));
+        }
+        libs.extend(self.libc.iter().map(PathBuf::from));
       }
-      libs.push(PathBuf::from("-lc"));
       libs.push(PathBuf::from("-lcompiler-rt"));
       libs
     }
@@ -416,6 +636,15 @@ BASIC OPTIONS:
     }
   }
 
+  /// `-c`, `-E` or `-S`, if one of them stops short of linking.
+  fn compile_only_mode(&self) -> Option<&'static str> {
+    match self.gcc_mode {
+      Some(GccMode::Dashc) => Some("-c"),
+      Some(GccMode::DashE) => Some("-E"),
+      Some(GccMode::DashS) => Some("-S"),
+      None => None,
+    }
+  }
   fn is_pch_mode(&self) -> bool {
     self.header_inputs.len() > 0 && self.gcc_mode != Some(GccMode::DashE)
   }
@@ -423,6 +652,17 @@ BASIC OPTIONS:
   fn should_link_output(&self) -> bool {
     self.gcc_mode == None
   }
+  /// `cc -c foo.c bar.c -o libfoo.a`, or the same with objects: some build
+  /// systems expect the driver to archive (ie libtool's static convenience
+  /// libraries), and wasm-ld can't write archives.
+  fn is_archive_output(&self) -> bool {
+    let is_archive = self.output.as_ref()
+      .and_then(|out| out.extension() )
+      .map(|ext| ext == "a" )
+      .unwrap_or(false);
+    is_archive && !self.shared && !self.relocatable && !self.is_pch_mode() &&
+      (self.gcc_mode == None || self.gcc_mode == Some(GccMode::Dashc))
+  }
 
   #[cfg(all(not(target_os = "nacl"), not(windows)))]
   fn set_ld_library_path(cmd: &mut Command) {
@@ -448,8 +688,15 @@ BASIC OPTIONS:
   fn set_ld_library_path(_cmd: &mut Command) { }
 
   fn clang_base_cmd(&self) -> Command {
-    let clang = self.driver_mode.get_clang_name();
-    let mut cmd = Command::new(self.tc.llvm_tool(clang));
+    let clang = self.tc.llvm_tool(self.driver_mode.get_clang_name());
+    let mut cmd = match self.compiler_launcher {
+      Some(ref launcher) => {
+        let mut cmd = Command::new(launcher);
+        cmd.arg(clang);
+        cmd
+      },
+      None => Command::new(clang),
+    };
     cmd.stdin(Stdio::inherit());
     cmd.stdout(Stdio::inherit());
     cmd.stderr(Stdio::inherit());
@@ -459,15 +706,44 @@ BASIC OPTIONS:
     cmd
   }
 
+  /// The canonical `-target`.
+  fn target(&self) -> &str {
+    self.target.as_ref()
+      .map(|t| &t[..] )
+      .unwrap_or(util::WASM_TARGETS[0].0)
+  }
+
   fn clang_add_std_args(&self, cmd: &mut Command) {
-    cmd.args(&[
-      "-target", "wasm32-unknown-unknown-wasm",
-      "-mthread-model", "single",
-      "-fno-threadsafe-statics",
-    ]);
+    cmd.args(&["-target", self.target()]);
+    match self.tc.threads {
+      ThreadModel::Atomics => {
+        cmd.args(&[
+          "-mthread-model", "posix",
+          "-pthread",
+          "-matomics",
+          "-mbulk-memory",
+        ]);
+      },
+      // emulated threads never run concurrently.
+      ThreadModel::None | ThreadModel::Emulated => {
+        cmd.args(&[
+          "-mthread-model", "single",
+          "-fno-threadsafe-statics",
+        ]);
+      },
+    }
+
+    cmd.args(self.eh_mode.clang_flags());
+    match util::diag::color_choice() {
+      util::diag::ColorChoice::Always => { cmd.arg("-fcolor-diagnostics"); },
+      util::diag::ColorChoice::Never => { cmd.arg("-fno-color-diagnostics"); },
+      util::diag::ColorChoice::Auto => {},
+    }
 
-    self.optimization.check();
     cmd.arg(format!("{}", self.optimization));
+    if self.fast_math {
+      cmd.arg("-ffast-math");
+    }
     cmd.args(&[
       "-nostdinc",
       "-D__EMSCRIPTEN__",
@@ -487,6 +763,15 @@ BASIC OPTIONS:
         },
         Some(GccMode::Dashc) => {
           cmd.arg("-c");
+          if self.emit_bc {
+            cmd.arg("-emit-llvm");
+          }
+        },
+        Some(GccMode::DashS) => {
+          cmd.arg("-S");
+          if self.emit_llvm {
+            cmd.arg("-emit-llvm");
+          }
         },
       }
     }
@@ -494,6 +779,13 @@ BASIC OPTIONS:
     if self.pic {
       cmd.arg("-fPIC");
     }
+    if util::deterministic() {
+      if let Ok(cwd) = env::current_dir() {
+        cmd.arg(format!("-ffile-prefix-map={}=.", cwd.display()));
+      }
+      cmd.arg(format!("-ffile-prefix-map={}=/sysroot",
+                      self.tc.sysroot().display()));
+    }
 
     match self.make_deps {
       MakeDeps {
@@ -552,9 +844,20 @@ BASIC OPTIONS:
     if let Some(ref dest) = self.make_deps.dest {
       cmd.arg("-MF").arg(dest);
     }
+    if let Some(ref db) = self.make_deps.compile_db {
+      cmd.arg("-MJ").arg(db);
+    }
 
     cmd.args(&self.get_std_inc_args()[..]);
     cmd.args(&self.driver_args[..]);
+
+    if self.modules {
+      // keep the implicit module cache out of the user's home dir; its
+      // contents depend on our headers anyway.
+      let cache = self.module_cache_path.clone()
+        .unwrap_or_else(|| self.tc.sysroot_cache().join("module-cache") );
+      cmd.arg(format!("-fmodules-cache-path={}", cache.display()));
+    }
   }
   fn clang_add_input_args(&self, cmd: &mut Command) {
     let mut last = None;
@@ -579,6 +882,11 @@ BASIC OPTIONS:
     }
   }
 
+  /// The extension of objects `-c` produces.
+  fn object_ext(&self) -> &'static str {
+    if self.emit_bc { "bc" } else { "o" }
+  }
+
   fn queue_clang(&mut self, queue: &mut CommandQueue<Self>) {
     // build the cmd:
     if !self.is_pch_mode() {
@@ -586,8 +894,12 @@ BASIC OPTIONS:
       self.clang_add_std_args(&mut cmd);
       self.clang_add_input_args(&mut cmd);
 
-      cmd.arg("-o")
-        .arg(self.get_output());
+      // with multiple `-S` inputs and no `-o`, clang names each output after
+      // its input.
+      if self.output.is_some() || self.gcc_mode != Some(GccMode::DashS) {
+        cmd.arg("-o")
+          .arg(self.get_output());
+      }
 
       let _ = queue
         .enqueue_simple_external(Some("clang"), cmd,
@@ -612,69 +924,151 @@ BASIC OPTIONS:
       }
     }
 
-    if self.emit_wast {
+    // nothing to disassemble if the object went to stdout.
+    if self.emit_wast && !self.emit_bc &&
+      !util::command_queue::is_stdout(&self.get_output())
+    {
       if let Some(GccMode::Dashc) = self.gcc_mode {
-        let f = |this: &mut &mut Self, _: &mut RunState| {
-          // Do this manual to avoid polluting the previous outputs.
-          let wasm_dis = this.tc.binaryen_tool("wasm-dis");
-          let out = this.get_output();
-          let mut cmd = Command::new("sh");
-          cmd.arg("-c")
-            .arg(r#"$0 $1 | c++filt > ${1%.*}.wast"#)
-            .arg(wasm_dis)
-            .arg(out);
-
-          // ignore result, we just want to wait till it's done.
-          let _ = cmd.spawn()?.wait();
-
-          Ok(())
-        };
-
-        queue.enqueue_state_function(Some("--emit-wast"), f)
-          .prev_outputs = false;
+        let wasm_dis = self.tc.binaryen_tool("wasm-dis");
+        let out = self.get_output();
+        let wast = out.with_extension("wast");
+        queue.enqueue_disassemble(Some("--emit-wast"), wasm_dis,
+                                  Some(out), wast);
       }
     }
   }
 
   fn queue_ld(&mut self, queue: &mut CommandQueue<Self>) -> Result<(), Box<Error>> {
-    let mut ld = ld_driver::Invocation::default();
-    ld.tc = self.tc.clone();
+    // sources mixed in with the objects are compiled first; the linker gets
+    // the objects after its other inputs, which wasm-ld doesn't mind.
+    let mut compile = self.clone();
+    compile.gcc_mode = Some(GccMode::Dashc);
+    compile.header_inputs.clear();
+    for (idx, &(ref path, lang)) in self.inputs.iter().enumerate() {
+      match lang.or_else(|| FileLang::from_path(path) ) {
+        Some(lang) if !lang.is_header() => {
+          compile.queue_compile_input(idx, queue);
+        },
+        _ => { },
+      }
+    }
+
+    let mut ld = ld_driver::Invocation::new_with_toolchain(self.tc.clone());
     ld.optimize = Some(self.optimization);
     ld.emit_wast = self.emit_wast;
-    ld.relocatable = self.shared;
+    ld.relocatable = self.shared || self.relocatable;
 
-    let mut args = self.linker_args.clone();
+    let mut args = self.link_args.clone();
+    if self.eh_mode != EhMode::None {
+      // before any `-l`, so the library lookups see it.
+      args.insert(0, format!("--eh={}", self.eh_mode));
+    }
+    if self.visibility_hidden {
+      // ahead of the user's args, so `-Wl,--no-export-visible` still wins.
+      args.insert(0, "--export-visible".to_string());
+    }
     // first, add crt1 (which contains `_start_c`). It'll depend on
     // `main` and `__libc_start_main` which will be found in later
     // inputs.
-    if !self.shared && self.gcc_mode == None {
+    if !self.shared && !self.relocatable && !self.no_entry &&
+      !self.no_std_lib && self.gcc_mode == None
+    {
       let p = self.tc.sysroot_lib().join("crt1.o");
       ld.add_input(ld_driver::Input::File(p))?;
 
       ld.entry = Some("_start_c".into());
     }
+    if let Some(ref entry) = self.entry {
+      ld.entry = Some(entry.clone());
+    }
+    if self.source_map {
+      args.push(format!("--source-map={}.map", self.get_output().display()));
+    }
+    if let Some(ref base) = self.source_map_base {
+      args.push(format!("--source-map-base={}", base));
+    }
 
-    let inputs = self.inputs.iter()
-      .map(|&(ref f, _)| format!("{}", f.display()) );
-    args.extend(inputs);
     // XXX
     let i = self.get_default_lib_args()
       .into_iter()
       .map(|v| v.to_str().expect("non-utf8 path").to_string() );
     args.extend(i);
     args.push("-target".to_string());
-    args.push("wasm32-unknown-unknown-wasm".to_string());
+    args.push(self.target().to_string());
 
     process_invocation_args(&mut ld, args, true)?;
     queue.enqueue_simple_tool(Some("linker"), ld);
     Ok(())
   }
 
+  /// Compile `self.inputs[idx]` on its own (`self` has to be in `-c` mode)
+  /// into an intermediate object, which is left in `prev_outputs`.
+  fn queue_compile_input(&self, idx: usize, queue: &mut CommandQueue<Self>) {
+    let (ref path, lang) = self.inputs[idx];
+    let mut cmd = self.clang_base_cmd();
+    self.clang_add_std_args(&mut cmd);
+    if let Some(lang) = lang {
+      cmd.arg("-x").arg(format!("{}", lang));
+    }
+    cmd.arg(path);
+
+    let stem = path.file_stem()
+      .map(|stem| stem.to_string_lossy().into_owned() )
+      .unwrap_or_default();
+    let name = PathBuf::from(format!("{}-{}.{}", idx, stem, self.object_ext()));
+    let step = queue.enqueue_simple_external(Some("clang"), cmd,
+                                             Some("-o".into()));
+    step.prev_outputs = false;
+    step.intermediate_name = Some(name);
+  }
+
+  /// Compile each source on its own, then `llvm-ar` the object inputs and
+  /// the compiled sources together. Headers aren't archived.
+  fn queue_archive(&mut self, queue: &mut CommandQueue<Self>) -> Result<(), Box<Error>> {
+    self.gcc_mode = Some(GccMode::Dashc);
+
+    let output = self.get_output();
+    let mut cmd = Command::new(self.tc.llvm_tool("llvm-ar"));
+    cmd.arg(if util::deterministic() { "crsD" } else { "crs" })
+      .arg(&output);
+    for (idx, &(ref path, lang)) in self.inputs.iter().enumerate() {
+      match lang.or_else(|| FileLang::from_path(path) ) {
+        None => { cmd.arg(path); },
+        Some(lang) if lang.is_header() => {},
+        // the compiled objects are left in `prev_outputs` for `llvm-ar`.
+        Some(_) => { self.queue_compile_input(idx, queue); },
+      }
+    }
+
+    queue.enqueue_state_function(Some("remove-old-archive"), move |_, state| {
+      // `-o` replaces the output, but `ar` would add to an old archive.
+      if !state.is_dry_run() && output.exists() {
+        ::std::fs::remove_file(&output)?;
+      }
+      Ok(())
+    });
+    let archive = queue.enqueue_simple_external(Some("archive"), cmd, None);
+    archive.output_override = false;
+    Ok(())
+  }
+
   fn add_driver_arg<T: Into<OsString>>(&mut self, arg: T) {
     self.driver_args.push(arg.into());
   }
   fn add_linker_arg<T: AsRef<str>>(&mut self, arg: T) {
-    self.linker_args.push(arg.as_ref().into());
+    if self.link_arg_values.is_value(arg.as_ref()) {
+      self.link_args.push(arg.as_ref().into());
+      return;
+    }
+    match arg.as_ref() {
+      "-r" | "--relocatable" => {
+        // the ld invocation needs more than just the flag.
+        self.relocatable = true;
+        return;
+      },
+      _ => {},
+    }
+    self.link_args.push(arg.as_ref().into());
   }
   fn add_input_file<T: AsRef<Path>>(&mut self, file: T,
                                     file_lang: Option<FileLang>) {
@@ -683,13 +1077,14 @@ BASIC OPTIONS:
     self.inputs.push((file.clone(), file_lang.clone()));
     let file_lang = file_lang
       .or_else(|| FileLang::from_path(file.clone()) );
-    let is_header_input = match file_lang {
-      Some(FileLang::CHeader) | Some(FileLang::CxxHeader) => true,
-      _ => false,
-    };
-
-    if is_header_input {
-      self.header_inputs.push(file.clone());
+    match file_lang {
+      Some(lang) if lang.is_header() => {
+        self.header_inputs.push(file.clone());
+      },
+      Some(_) => { },
+      None => {
+        self.link_args.push(format!("{}", file.display()));
+      },
     }
   }
 
@@ -720,6 +1115,31 @@ impl WasmToolchainTool for Invocation {
 
 impl Tool for Invocation {
   fn enqueue_commands(&mut self, queue: &mut CommandQueue<Self>) -> Result<(), Box<Error>> {
+    if self.forwarded_machine_flags.len() != 0 {
+      let flags: Vec<String> = self.forwarded_machine_flags.iter()
+        .map(|flag| format!("`{}`", flag) )
+        .collect();
+      util::diag::warning(&format!("forwarded {} unknown machine flag(s) to clang, \
+                                    which may ignore them for wasm: {}",
+                                   flags.len(), flags.join(", ")));
+    }
+    // like gcc and clang, which configure probes rely on.
+    if let Some(mode) = self.compile_only_mode() {
+      if self.shared {
+        util::diag::warning(&format!("`-shared` unused with `{}`; nothing is linked", mode));
+      }
+      if self.relocatable {
+        util::diag::warning(&format!("`-r` unused with `{}`; nothing is linked", mode));
+      }
+    }
+
+    if self.print_targets {
+      queue.enqueue_function(Some("print-targets"), |_| {
+        util::print_targets();
+        Ok(())
+      });
+      return Ok(());
+    }
     if self.print_version {
       let mut clang_ver = self.clang_base_cmd();
       self.clang_add_std_args(&mut clang_ver);
@@ -728,17 +1148,38 @@ impl Tool for Invocation {
       return Ok(());
     }
 
+    // name intermediates after the first source, ie `foo.c.lld.wasm`.
+    if let Some(&(ref path, _)) = self.inputs.iter().next() {
+      queue.source_stem = path.file_name()
+        .map(|name| name.to_string_lossy().into_owned() );
+    }
+
+    if self.is_archive_output() {
+      return self.queue_archive(queue);
+    }
+
     // force -o if -c is used and -o is not given (zlib's configure does this shit).
     // libtool also does it. smile.
     if let Some(GccMode::Dashc) = self.gcc_mode {
       if self.output.is_none() {
         if let Some(&(ref path, _)) = self.inputs.iter().next() {
           if let Some(file_name) = path.file_name().map(Path::new) {
-            self.output = Some(file_name.with_extension("o"));
+            self.output = Some(file_name.with_extension(self.object_ext()));
           }
         }
       }
     }
+    if let Some(GccMode::DashS) = self.gcc_mode {
+      let ext = if self.emit_llvm { "ll" } else { "s" };
+      if self.inputs.len() > 1 && self.output.is_some() {
+        return Err("cannot specify `-o` with `-S` and multiple inputs".into());
+      } else if self.output.is_none() && self.inputs.len() == 1 {
+        let file_name = self.inputs[0].0.file_name().map(Path::new);
+        if let Some(file_name) = file_name {
+          self.output = Some(file_name.with_extension(ext));
+        }
+      }
+    }
 
     // force -c mode if we're given source files (zlib's configure does this shit).
     if self.gcc_mode.is_none() {
@@ -777,7 +1218,7 @@ impl Tool for Invocation {
   }
 
   fn get_output(&self) -> Option<&PathBuf> {
-    if self.print_version {
+    if self.print_version || self.print_targets {
       None
     } else {
       self.output.as_ref()
@@ -789,8 +1230,56 @@ impl Tool for Invocation {
 }
 impl ToolInvocation for Invocation {
   fn check_state(&mut self, _iteration: usize, _skip_inputs_check: bool) -> Result<(), Box<Error>> {
+    self.link_arg_values.check()?;
+    Ok(())
+  }
+  fn forward_compiler_args(&mut self, args: Vec<String>) -> Result<(), Box<Error>> {
+    for arg in args.into_iter() {
+      self.add_driver_arg(arg);
+    }
     Ok(())
   }
+  fn forward_linker_args(&mut self, args: Vec<String>) -> Result<(), Box<Error>> {
+    for arg in args.into_iter() {
+      self.add_linker_arg(arg);
+    }
+    Ok(())
+  }
+  fn conflicts(&self) -> Vec<String> {
+    let mut out = Vec::new();
+
+    if self.no_entry && self.entry.is_some() {
+      out.push("`--entry` with `--no-entry`/`-mexec-model=reactor`".to_string());
+    }
+    if self.entry.is_some() && (self.shared || self.relocatable) {
+      out.push("`--entry` with `-shared` or `-r`".to_string());
+    }
+    if self.emit_wast && (self.shared || self.relocatable) {
+      out.push("`--emit-wast` with `-shared` or `-r`".to_string());
+    }
+    if let Some(ref output) = self.output {
+      if output.is_dir() || output.to_string_lossy().ends_with('/') {
+        out.push(format!("`-o {}` is a directory", output.display()));
+      }
+    }
+
+    out
+  }
+  fn derived_state(&self) -> Vec<(&'static str, String)> {
+    let default_libs: Vec<String> = self.get_default_lib_args()
+      .into_iter()
+      .map(|lib| format!("{}", lib.display()) )
+      .collect();
+    vec![
+      ("pch-mode", format!("{}", self.is_pch_mode())),
+      ("link", format!("{}", self.should_link_output())),
+      ("std-include-args", self.get_std_inc_args().join(" ")),
+      ("default-lib-args", default_libs.join(" ")),
+      ("link-args", self.link_args.join(" ")),
+      ("compiler-launcher", self.compiler_launcher.clone()
+        .unwrap_or_else(|| "none".into() )),
+    ]
+  }
   fn args(&self, iteration: usize) -> Option<ToolArgs<Invocation>> {
     use util::ToolArg;
     use std::borrow::Cow;
@@ -800,6 +1289,7 @@ impl ToolInvocation for Invocation {
     match iteration {
       0 => return tool_arguments!(Invocation => [
         VERSION,
+        PRINT_TARGETS,
         IGNORED0,
         IGNORED1,
         IGNORED2,
@@ -807,16 +1297,24 @@ impl ToolInvocation for Invocation {
         IGNORED4,
         IGNORED5,
         IGNORED6,
-        IGNORED7,
+        EMIT_LLVM,
+        EMIT_LLVM_BC_ONLY,
         IGNORED8,
         IGNORED9,
         STDLIB_LIBCXX,
         RTLIB,
+        COMPILER_LAUNCHER,
       ]),
       1 => return tool_arguments!(Invocation => [
         NO_DEFAULT_LIBS,
+        NO_STD_LIB,
+        NO_STD_LIBXX,
+        NO_LIBC,
         NO_STD_INC,
         NO_STD_INCXX,
+        // before `F_FLAGS` gets `-framework`.
+        FRAMEWORK,
+        DARWIN_FLAGS,
       ]),
       2 => return tool_arguments!(Invocation => [
         CAP_M_FLAGS,
@@ -826,6 +1324,7 @@ impl ToolInvocation for Invocation {
         CAP_MF_FLAGS,
         CAP_MT_FLAGS,
         CAP_MQ_FLAGS,
+        CAP_MJ_FLAGS,
         SUPPRESS_WARNINGS,
       ]),
       3 => {
@@ -836,30 +1335,57 @@ impl ToolInvocation for Invocation {
         INCLUDE,
         INCLUDE_DIR,
         SYSTEM_INCLUDE,
+        ISYSTEM_BEFORE_LIBCXX,
+        ISYSTEM_AFTER_LIBC,
+        EH_MODE,
         SYSROOT_INCLUDE,
         QUOTE_INCLUDE,
         DIR_AFTER_INCLUDE,
         LINKER_FLAGS0,
         LINKER_FLAGS1,
         M_FLOAT_ABI,
+        EXEC_MODEL,
+        NO_ENTRY,
+        ENTRY,
+        EMIT_RUNNER,
 
         F_POSITION_INDEPENDENT_CODE,
+        MODULES,
+        MODULES_CACHE_PATH,
+        MODULE_FILE,
+        PREBUILT_MODULE_PATH,
+        VISIBILITY,
         F_FLAGS,
         D_FLAGS,
-        W_FLAGS,
         PEDANTIC,
         SHARED,
+        RELOCATABLE,
         SEARCH_PATH,
         LIBRARY,
         STD_VERSION,
         OPTIMIZE_FLAG,
         DEBUG_FLAGS,
-        COMPILE, PREPROCESS,
+        SOURCE_MAP,
+        SOURCE_MAP_BASE,
+        COMPILE, PREPROCESS, ASSEMBLE,
         OUTPUT,
       ]),
-      5 => return tool_arguments!(Self => [EMIT_WAST, ]),
-      6 => return tool_arguments!(Invocation => [X_ARG, INPUTS,]),
-      7 => return tool_arguments!(Invocation => [UNSUPPORTED,]),
+      5 => return tool_arguments!(Invocation => [
+        PREPROCESSOR_FLAGS0,
+        PREPROCESSOR_FLAGS1,
+        ASSEMBLER_FLAGS0,
+        ASSEMBLER_FLAGS1,
+        W_FLAGS,
+      ]),
+      6 => return tool_arguments!(Self => [EMIT_WAST, ]),
+      7 => return tool_arguments!(Invocation => [
+        X_ARG,
+        WASM_MACHINE_FLAGS,
+        MLLVM,
+        UNKNOWN_MACHINE_FLAGS,
+        INPUTS,
+      ]),
+      8 => return tool_arguments!(Invocation => [UNSUPPORTED,]),
       _ => return None,
     }
 
@@ -907,9 +1433,15 @@ argument!(impl IGNORED6 where { Some(r"^-m32$"), None } for Invocation {
       // ignore
     }
 });
-argument!(impl IGNORED7 where { Some(r"^-emit-llvm$"), None } for Invocation {
-    fn ignored7(_this, _single, _cap) {
-      // ignore
+argument!(impl EMIT_LLVM where { Some(r"^-emit-llvm$"), None } for Invocation {
+    fn emit_llvm_flag(this, _single, _cap) {
+      // only has an effect with `-S`.
+      this.emit_llvm = true;
+    }
+});
+argument!(impl EMIT_LLVM_BC_ONLY where { Some(r"^-femit-llvm-bc-only$"), None } for Invocation {
+    fn emit_llvm_bc_only(this, _single, _cap) {
+      this.emit_bc = true;
     }
 });
 argument!(impl IGNORED8 where { Some(r"^-msse$"), None } for Invocation {
@@ -927,6 +1459,21 @@ argument!(impl NO_DEFAULT_LIBS where { Some(r"^-nodefaultlibs$"), None } for Inv
       this.no_default_libs = true;
     }
 });
+argument!(impl NO_STD_LIB where { Some(r"^-nostdlib$"), None } for Invocation {
+    fn no_std_lib_arg(this, _single, _cap) {
+      this.no_std_lib = true;
+    }
+});
+argument!(impl NO_STD_LIBXX where { Some(r"^-nostdlib\+\+$"), None } for Invocation {
+    fn no_std_libxx_arg(this, _single, _cap) {
+      this.no_std_libxx = true;
+    }
+});
+argument!(impl NO_LIBC where { Some(r"^-nolibc$"), None } for Invocation {
+    fn no_libc_arg(this, _single, _cap) {
+      this.no_libc = true;
+    }
+});
 argument!(impl NO_STD_INC where { Some(r"^-nostdinc$"), None } for Invocation {
     fn no_std_inc_arg(this, _single, _cap) {
       this.no_std_inc = true;
@@ -938,13 +1485,25 @@ argument!(impl NO_STD_INCXX where { Some(r"^-nostdinc\+\+$"), None } for Invocat
     }
 });
 argument!(impl TARGET where { Some(r"^--?target=(.+)$"), Some(r"^-target$") } for Invocation {
-    fn target_arg(_this, single, cap) {
+    fn target_arg(this, single, cap) {
       let target = cap.get(if single { 1 } else { 0 }).unwrap().as_str();
-      if target != "wasm32-unknown-unknown" && target != "wasm32-unknown-unknown-wasm" {
-        Err("unknown target triple")?;
-      }
+      this.target = Some(util::canonical_target(target)?.to_string());
     }
 });
+argument!(impl PRINT_TARGETS where { Some(r"^--print-targets$"), None } for Invocation {
+  fn print_targets_flag(this, _single, _cap) {
+    this.print_targets = true;
+  }
+});
+argument!(impl COMPILER_LAUNCHER where { Some(r"^--compiler-launcher=(.+)$"), Some(r"^--compiler-launcher$") } for Invocation {
+  fn compiler_launcher_arg(this, single, cap) {
+    let launcher = cap.get(if single { 1 } else { 0 }).unwrap().as_str();
+    this.compiler_launcher = match launcher {
+      "none" => None,
+      launcher => Some(launcher.to_string()),
+    };
+  }
+});
 argument!(impl INCLUDE_DIR where { Some(r"^-I(.+)$"), Some(r"^-I$") } for Invocation {
     fn include_dir_arg(this, single, cap) {
       let dir = cap.get(if single { 1 } else { 0 })
@@ -1014,6 +1573,37 @@ argument!(impl M_FLOAT_ABI where { Some(r"^-mfloat-abi=(.+)$"), Some(r"^-mfloat-
       this.add_driver_arg(arg);
     }
 });
+argument!(impl MODULES where { Some(r"^-fmodules$"), None } for Invocation {
+    fn modules_flag(this, _single, _cap) {
+      this.modules = true;
+      this.add_driver_arg("-fmodules");
+    }
+});
+argument!(impl MODULES_CACHE_PATH where { Some(r"^-fmodules-cache-path=(.+)$"), None } for Invocation {
+    fn modules_cache_path_arg(this, _single, cap) {
+      let path = cap.get(1).unwrap().as_str();
+      this.module_cache_path = Some(PathBuf::from(path));
+    }
+});
+argument!(impl MODULE_FILE where { Some(r"^-fmodule-file=(.+)$"), None } for Invocation {
+    fn module_file_arg(this, _single, cap) {
+      // `[<name>=]<path>`
+      let arg = cap.get(0).unwrap().as_str();
+      this.add_driver_arg(arg.to_string());
+    }
+});
+argument!(impl PREBUILT_MODULE_PATH where { Some(r"^-fprebuilt-module-path=(.+)$"), None } for Invocation {
+    fn prebuilt_module_path_arg(this, _single, cap) {
+      let arg = cap.get(0).unwrap().as_str();
+      this.add_driver_arg(arg.to_string());
+    }
+});
+argument!(impl VISIBILITY where { Some(r"^-fvisibility=(.+)$"), None } for Invocation {
+    fn visibility_arg(this, _single, cap) {
+      this.visibility_hidden = cap.get(1).unwrap().as_str() == "hidden";
+      this.add_driver_arg(cap.get(0).unwrap().as_str().to_string());
+    }
+});
 argument!(impl F_FLAGS where { Some(r"^-f(.+)$"), None } for Invocation {
     fn f_flags(this, _single, cap) {
       let arg = cap.get(0)
@@ -1028,7 +1618,10 @@ argument!(impl D_FLAGS where { Some(r"^-D(.+)$"), None } for Invocation {
       this.add_driver_arg(arg.to_string());
     }
 });
-argument!(impl W_FLAGS where { Some(r"^-W(.*)$"), None } for Invocation {
+// `-Wl,`, `-Wa,` and `-Wp,` are pass-throughs, not warnings. No warning flag
+// has a comma, so they're excluded here regardless of which stage they're
+// matched in.
+argument!(impl W_FLAGS where { Some(r"^-W([^,]+)$"), None } for Invocation {
     fn warning_flags(this, _single, cap) {
       let arg = cap.get(0)
         .unwrap().as_str();
@@ -1097,6 +1690,15 @@ argument!(impl CAP_MQ_FLAGS where { None, Some(r"^-MQ$") } for Invocation {
       md.output = Some(file);
     }
 });
+argument!(impl CAP_MJ_FLAGS where { Some(r"^-MJ(.+)$"), Some(r"^-MJ$") } for Invocation {
+    fn cap_mj_args(this, single, cap) {
+      let file = cap.get(if single { 1 } else { 0 }).unwrap().as_str();
+      let file = Path::new(file).to_path_buf();
+
+      let md = &mut this.make_deps;
+      md.compile_db = Some(file);
+    }
+});
 argument!(impl PEDANTIC where { Some(r"^-(no-)?pedantic$"), None } for Invocation {
     fn pedantic_arg(this, _single, cap) {
       let arg = cap.get(0)
@@ -1113,43 +1715,118 @@ argument!(impl LINKER_FLAGS0 where { Some(r"^-Wl,(.+)$"), None } for Invocation
       }
     }
 });
-argument!(impl LINKER_FLAGS1 where { Some(r"^-Xlinker=(.+)$"), Some(r"^-Xlinker") } for Invocation {
-    fn linker_flags1(this, _single, cap) {
-      let arg = cap.get(1).unwrap().as_str();
+argument!(impl LINKER_FLAGS1 where { Some(r"^-Xlinker=(.+)$"), Some(r"^-Xlinker$") } for Invocation {
+    fn linker_flags1(this, single, cap) {
+      let arg = cap.get(if single { 1 } else { 0 }).unwrap().as_str();
       this.add_linker_arg(arg.to_string());
     }
 });
+argument!(impl PREPROCESSOR_FLAGS0 where { Some(r"^-Wp,(.+)$"), None } for Invocation {
+    fn preprocessor_flags0(this, _single, cap) {
+      let arg = cap.get(0)
+        .unwrap().as_str();
+      this.add_driver_arg(arg.to_string());
+    }
+});
+argument!(impl PREPROCESSOR_FLAGS1 where { None, Some(r"^-Xpreprocessor$") } for Invocation {
+    fn preprocessor_flags1(this, _single, cap) {
+      let arg = cap.get(0).unwrap().as_str();
+      this.add_driver_arg("-Xpreprocessor");
+      this.add_driver_arg(arg.to_string());
+    }
+});
+argument!(impl ASSEMBLER_FLAGS0 where { Some(r"^-Wa,(.+)$"), None } for Invocation {
+    fn assembler_flags0(this, _single, cap) {
+      let arg = cap.get(0)
+        .unwrap().as_str();
+      this.add_driver_arg(arg.to_string());
+    }
+});
+argument!(impl ASSEMBLER_FLAGS1 where { None, Some(r"^-Xassembler$") } for Invocation {
+    fn assembler_flags1(this, _single, cap) {
+      let arg = cap.get(0).unwrap().as_str();
+      this.add_driver_arg("-Xassembler");
+      this.add_driver_arg(arg.to_string());
+    }
+});
+argument!(impl EXEC_MODEL where { Some(r"^-mexec-model=(.+)$"), None } for Invocation {
+    fn exec_model_arg(this, _single, cap) {
+      match cap.get(1).unwrap().as_str() {
+        "reactor" => { this.no_entry = true; },
+        "command" => { this.no_entry = false; },
+        model => Err(format!("unknown exec model `{}`; expected `command` \
+                              or `reactor`", model))?,
+      }
+    }
+});
+argument!(impl NO_ENTRY where { Some(r"^--no-entry$"), None } for Invocation {
+    fn no_entry_flag(this, _single, _cap) {
+      this.no_entry = true;
+    }
+});
+argument!(impl ENTRY where { Some(r"^--entry=(.+)$"), Some(r"^-(e|-entry)$") } for Invocation {
+    fn entry_arg(this, single, cap) {
+      let entry = cap.get(if single { 1 } else { 0 }).unwrap().as_str();
+      this.entry = Some(entry.to_string());
+    }
+});
+argument!(impl EMIT_RUNNER where { Some(r"^--emit-runner=(.+)$"), None } for Invocation {
+    fn emit_runner_arg(this, _single, cap) {
+      let arg = cap.get(0).unwrap().as_str();
+      this.add_linker_arg(arg);
+    }
+});
 argument!(impl SHARED where { Some(r"^-shared$"), None } for Invocation {
     fn shared_arg(this, _single, _cap) {
       this.shared = true;
     }
 });
+argument!(impl RELOCATABLE where { Some(r"^-r$"), None } for Invocation {
+    fn relocatable_arg(this, _single, _cap) {
+      this.relocatable = true;
+    }
+});
 argument!(impl COMPILE where { Some(r"^-c$"), None } for Invocation {
   fn compile_flag(this, _single, _cap) {
     this.gcc_mode = Some(GccMode::Dashc);
   }
 });
+argument!(impl ASSEMBLE where { Some(r"^-S$"), None } for Invocation {
+  fn assemble_flag(this, _single, _cap) {
+    this.gcc_mode = Some(GccMode::DashS);
+  }
+});
 argument!(impl PREPROCESS where { Some(r"^-E$"), None } for Invocation {
   fn preprocess_flag(this, _single, _cap) {
     this.gcc_mode = Some(GccMode::DashE);
   }
 });
 tool_argument!(SEARCH_PATH: Invocation = { Some(r"^-L(.+)$"), Some(r"^-(L|-library-path)$") };
-               fn add_search_path(this, _single, cap) {
-                 this.add_linker_arg(cap.get(0).unwrap().as_str().to_string());
+               fn add_search_path(this, single, cap) {
+                 let arg = cap.get(0).unwrap().as_str();
+                 if single {
+                   this.add_linker_arg(arg.to_string());
+                 } else {
+                   this.add_linker_arg(format!("-L{}", arg));
+                 }
                  Ok(())
                });
 tool_argument!(LIBRARY: Invocation = { Some(r"^-l(.+)$"), Some(r"^-(l|-library)$") };
-               fn add_library(this, _single, cap) {
-                 this.add_linker_arg(cap.get(0).unwrap().as_str().to_string());
+               fn add_library(this, single, cap) {
+                 let arg = cap.get(0).unwrap().as_str();
+                 if single {
+                   this.add_linker_arg(arg.to_string());
+                 } else {
+                   this.add_linker_arg(format!("-l{}", arg));
+                 }
                  Ok(())
                });
 
-tool_argument!(OPTIMIZE_FLAG: Invocation = { Some(r"^-O([0-4sz]?)$"), None };
+tool_argument!(OPTIMIZE_FLAG: Invocation = { Some(r"^-O([0-9]*|[a-z]+)$"), None };
                fn set_optimize(this, _single, cap) {
-                   this.optimization = cap.get(1)
-                       .and_then(|str| util::OptimizationGoal::parse(str.as_str()) )
-                       .unwrap();
+                   let level = cap.get(1).unwrap().as_str();
+                   this.optimization = util::OptimizationGoal::parse(level)?;
+                   this.fast_math = level == "fast";
                    Ok(())
                });
 argument!(impl DEBUG_FLAGS where { Some(r"^-g$"), None } for Invocation {
@@ -1159,6 +1836,18 @@ argument!(impl DEBUG_FLAGS where { Some(r"^-g$"), None } for Invocation {
       this.add_driver_arg(arg.to_string());
     }
 });
+argument!(impl SOURCE_MAP where { Some(r"^-gsource-map$"), None } for Invocation {
+    fn source_map_flag(this, _single, _cap) {
+      this.source_map = true;
+      this.add_driver_arg("-g");
+    }
+});
+argument!(impl SOURCE_MAP_BASE where { Some(r"^--source-map-base=(.+)$"), None } for Invocation {
+    fn source_map_base_arg(this, _single, cap) {
+      let base = cap.get(1).unwrap().as_str();
+      this.source_map_base = Some(base.to_string());
+    }
+});
 argument!(impl X_ARG where { Some(r"^-x(.+)$"), Some(r"^-x$") } for Invocation {
     fn x_arg(this, single, cap) {
       let file_type = cap.get(if single { 1 } else { 0 })
@@ -1186,6 +1875,24 @@ tool_argument! {
     }
   }
 }
+tool_argument! {
+  pub EH_MODE: Invocation = single_and_split_from_str(eh) "eh" =>
+  fn eh_mode_arg(this) {
+    this.eh_mode = eh;
+  }
+}
+tool_argument! {
+  pub ISYSTEM_BEFORE_LIBCXX: Invocation = single_and_split_abs_path(path) "isystem-before-libcxx" =>
+  fn isystem_before_libcxx_arg(this) {
+    this.isystem_before_libcxx.push(path);
+  }
+}
+tool_argument! {
+  pub ISYSTEM_AFTER_LIBC: Invocation = single_and_split_abs_path(path) "isystem-after-libc" =>
+  fn isystem_after_libc_arg(this) {
+    this.isystem_after_libc.push(path);
+  }
+}
 tool_argument! {
   pub INCLUDE: Invocation = single_and_split_abs_path(path, no_hyphen) "include" =>
   fn include_file_arg(this) {
@@ -1206,9 +1913,44 @@ tool_argument!(OUTPUT: Invocation = { Some(r"^-o(.+)$"), Some(r"^-(o|-output)$")
                    this.output = Some(out);
                    Ok(())
                });
+argument!(impl FRAMEWORK where { None, Some(r"^-(weak_)?framework$") } for Invocation {
+    fn framework_arg(_this, _single, cap) {
+      let framework = cap.get(0).unwrap().as_str();
+      util::suggest::host_only_arg(&format!("-framework {}", framework))?;
+    }
+});
+argument!(impl DARWIN_FLAGS where { Some(r"^(-dynamiclib|-bundle|-headerpad_max_install_names|-mmacosx-version-min=.+|-F.+)$"), None } for Invocation {
+    fn darwin_flags(_this, _single, cap) {
+      util::suggest::host_only_arg(cap.get(0).unwrap().as_str())?;
+    }
+});
+// clang's wasm target features.
+argument!(impl WASM_MACHINE_FLAGS where { Some(r"^-m((no-)?(simd128|relaxed-simd|atomics|bulk-memory|nontrapping-fptoint|sign-ext|mutable-globals|exception-handling|multivalue|tail-call|reference-types|extended-const|multimemory)|cpu=.+)$"), None } for Invocation {
+    fn wasm_machine_flag(this, _single, cap) {
+      this.add_driver_arg(cap.get(0).unwrap().as_str().to_string());
+    }
+});
+argument!(impl MLLVM where { None, Some(r"^-mllvm$") } for Invocation {
+    fn mllvm_arg(this, _single, cap) {
+      this.add_driver_arg("-mllvm");
+      this.add_driver_arg(cap.get(0).unwrap().as_str().to_string());
+    }
+});
+// Anything else would otherwise be taken for an input.
+argument!(impl UNKNOWN_MACHINE_FLAGS where { Some(r"^-m.+$"), None } for Invocation {
+    fn unknown_machine_flag(this, _single, cap) {
+      let arg = cap.get(0).unwrap().as_str();
+      if util::forward_unknown_machine_flags() {
+        this.forwarded_machine_flags.push(arg.to_string());
+        this.add_driver_arg(arg.to_string());
+      } else {
+        util::suggest::unsupported_arg(this, arg)?;
+      }
+    }
+});
 argument!(impl UNSUPPORTED where { Some(r"^-.+$"), None } for Invocation {
-    fn unsupported_flag(_this, _single, _cap) {
-        Err("unsupported argument")?;
+    fn unsupported_flag(this, _single, cap) {
+        util::suggest::unsupported_arg(this, cap.get(0).unwrap().as_str())?;
     }
 });
 argument!(impl VERSION where { Some(r"^-v$"), None } for Invocation {
@@ -1229,3 +1971,202 @@ tool_argument! {
     this.emit_wast = b;
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use util::process_invocation_args;
+  use util::toolchain::ThreadModel;
+
+  fn invocation() -> Invocation {
+    let tc = WasmToolchain {
+      binaryen: "/binaryen".into(),
+      emscripten: "/emscripten".into(),
+      llvm: "/llvm".into(),
+      sysroot: "/sysroot".into(),
+      threads: ThreadModel::None,
+      clang_resource_dir: None,
+    };
+    Invocation::new_with_toolchain(tc, DriverMode::CC)
+  }
+  fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string() ).collect()
+  }
+
+  #[test]
+  fn pass_through_flags_are_not_warnings() {
+    let mut invocation = invocation();
+    let args = args(&["-Wl,-z,stack-size=65536", "-Wall", "-Werror",
+                      "-Wp,-DFOO", "-Wa,--noexecstack", "-Xlinker",
+                      "--no-entry", "-Wno-error=unused"]);
+    process_invocation_args(&mut invocation, args, true).unwrap();
+
+    assert_eq!(invocation.link_args,
+               vec!["-z", "stack-size=65536", "--no-entry"]);
+    let driver_args: Vec<OsString> = vec!["-Wall", "-Werror", "-Wp,-DFOO",
+                                          "-Wa,--noexecstack",
+                                          "-Wno-error=unused"]
+      .into_iter()
+      .map(OsString::from)
+      .collect();
+    assert_eq!(invocation.driver_args, driver_args);
+  }
+
+  #[test]
+  fn linker_flag_values() {
+    let mut values = invocation();
+    let value_args = args(&["-Wl,-z", "-Wl,stack-size=65536", "-Xlinker", "--defsym",
+                      "-Xlinker", "foo=bar,baz", "-Wl,-soname,-r"]);
+    process_invocation_args(&mut values, value_args, true).unwrap();
+    assert_eq!(values.link_args,
+               vec!["-z", "stack-size=65536", "--defsym", "foo=bar,baz",
+                    "-soname", "-r"]);
+    assert!(!values.relocatable);
+
+    let mut dangling = invocation();
+    let err = process_invocation_args(&mut dangling, args(&["-Wl,-z"]), true)
+      .unwrap_err();
+    assert!(format!("{}", err).contains("`-z` is missing its value"), "{}", err);
+  }
+
+  #[test]
+  fn relocatable_link() {
+    use util::filetype::{override_filetype, Subtype, Type};
+
+    override_filetype("partial-input.o", Type::Object(Subtype::Bitcode));
+    for &flag in ["-r", "-Wl,-r"].iter() {
+      let mut invocation = invocation();
+      let link_args = args(&[flag, "partial-input.o", "-o", "partial.o"]);
+      process_invocation_args(&mut invocation, link_args, false).unwrap();
+      assert!(invocation.relocatable, "{}", flag);
+
+      let mut queue = CommandQueue::new(invocation.get_output().cloned());
+      invocation.enqueue_commands(&mut queue).unwrap();
+      let plan = queue.plan().unwrap();
+      let lld = plan.iter()
+        .find(|step| step.trim_start().starts_with("lld:") )
+        .unwrap_or_else(|| panic!("{}: no link in {:?}", flag, plan) );
+      assert!(lld.contains(" --relocatable "), "{}: {}", flag, lld);
+      assert!(!lld.contains("crt1.o"), "{}: {}", flag, lld);
+      assert!(!lld.contains("--entry"), "{}: {}", flag, lld);
+      for lib in ["libc", "compiler-rt", " -l"].iter() {
+        assert!(!lld.contains(lib), "{}: {}", flag, lld);
+      }
+    }
+  }
+
+  #[test]
+  fn canonical_target() {
+    let mut alias = invocation();
+    process_invocation_args(&mut alias, args(&["-target", "wasm32"]), true)
+      .unwrap();
+    assert_eq!(alias.target(), "wasm32-unknown-unknown-wasm");
+
+    let mut wasi = invocation();
+    process_invocation_args(&mut wasi, args(&["--target=wasm32-wasi"]), true)
+      .unwrap();
+    assert_eq!(wasi.target(), "wasm32-wasi");
+
+    assert_eq!(invocation().target(), "wasm32-unknown-unknown-wasm");
+  }
+
+  #[test]
+  fn entry_flags() {
+    let mut reactor = invocation();
+    process_invocation_args(&mut reactor, args(&["-mexec-model=reactor"]),
+                            true)
+      .unwrap();
+    assert!(reactor.no_entry);
+
+    let mut entry = invocation();
+    process_invocation_args(&mut entry, args(&["-e", "start"]), true)
+      .unwrap();
+    assert_eq!(entry.entry, Some("start".to_string()));
+
+    let mut conflict = invocation();
+    let args = args(&["--no-entry", "--entry=start"]);
+    assert!(process_invocation_args(&mut conflict, args, true).is_err());
+  }
+
+  #[test]
+  fn source_map_flags() {
+    let mut invocation = invocation();
+    let args = args(&["-gsource-map", "--source-map-base=http://localhost/"]);
+    process_invocation_args(&mut invocation, args, true).unwrap();
+
+    assert!(invocation.source_map);
+    assert_eq!(invocation.source_map_base,
+               Some("http://localhost/".to_string()));
+    assert_eq!(invocation.driver_args, vec![OsString::from("-g")]);
+  }
+
+  #[test]
+  fn archive_output() {
+    let mut archive = invocation();
+    let archive_args = args(&["-c", "foo.c", "bar.o", "-o", "libfoo.a"]);
+    process_invocation_args(&mut archive, archive_args, true).unwrap();
+    assert!(archive.is_archive_output());
+
+    let mut object = invocation();
+    let args = args(&["-c", "foo.c", "-o", "foo.o"]);
+    process_invocation_args(&mut object, args, true).unwrap();
+    assert!(!object.is_archive_output());
+  }
+
+  #[test]
+  fn language_tags_and_link_inputs() {
+    let mut invocation = invocation();
+    let args = args(&["-x", "c", "impl.txt", "-x", "c-header", "api.txt",
+                      "-x", "none", "util.h", "foo.o", "main.c"]);
+    process_invocation_args(&mut invocation, args, true).unwrap();
+
+    assert_eq!(invocation.link_args, vec!["foo.o"]);
+    assert_eq!(invocation.header_inputs,
+               vec![PathBuf::from("api.txt"), PathBuf::from("util.h")]);
+    let langs: Vec<Option<FileLang>> = invocation.inputs.iter()
+      .map(|&(_, lang)| lang )
+      .collect();
+    assert_eq!(langs, vec![Some(FileLang::C), Some(FileLang::CHeader),
+                           None, None, None]);
+  }
+
+  #[test]
+  fn default_lib_flags() {
+    let lib_args = |invocation: &Invocation| -> Vec<String> {
+      invocation.get_default_lib_args()
+        .iter()
+        .map(|lib| format!("{}", lib.display()) )
+        .collect()
+    };
+
+    let mut cxx = invocation();
+    cxx.driver_mode = DriverMode::CXX;
+    cxx.libc = vec!["-lmylibc".to_string()];
+    process_invocation_args(&mut cxx, args(&["-nostdlib++"]), true).unwrap();
+    assert_eq!(lib_args(&cxx),
+               vec!["-L", "/sysroot/lib", "-lmylibc", "-lcompiler-rt"]);
+
+    let mut no_libc = invocation();
+    process_invocation_args(&mut no_libc, args(&["-nolibc"]), true).unwrap();
+    assert_eq!(lib_args(&no_libc), vec!["-L", "/sysroot/lib", "-lcompiler-rt"]);
+  }
+
+  #[test]
+  fn conflicts() {
+    let mut invocation = invocation();
+    let args = args(&["-c", "-shared", "--no-entry", "--entry=start",
+                      "foo.c"]);
+    let err = process_invocation_args(&mut invocation, args, true)
+      .unwrap_err()
+      .to_string();
+    assert_eq!(err.lines().count(), 2);
+
+  }
+  #[test]
+  fn compile_only_with_link_flags() {
+    // just a warning, as with gcc.
+    let mut invocation = invocation();
+    let args = args(&["-c", "-shared", "foo.c"]);
+    process_invocation_args(&mut invocation, args, true).unwrap();
+  }
+}
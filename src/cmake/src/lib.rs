@@ -15,6 +15,9 @@ extern crate wasm_driver_utils as util;
 #[macro_use]
 extern crate lazy_static;
 
+pub mod meson;
+pub mod packages;
+
 const CRATE_ROOT: &'static str = env!("CARGO_MANIFEST_DIR");
 fn get_cmake_modules_dir() -> PathBuf {
   let pwd = Path::new(CRATE_ROOT);
@@ -29,6 +32,16 @@ pub struct Invocation {
   pub defines: HashMap<String, Var>,
 
   pub output_dir: PathBuf,
+
+  /// `-G`; if `None`, Ninja is used when it's in `$PATH`.
+  pub generator: Option<String>,
+  /// Run `cmake --build` after configuring.
+  pub build: bool,
+  /// Build the `install` target after configuring. Implies `build`.
+  pub install: bool,
+  pub jobs: Option<usize>,
+  /// Set as `CMAKE_C(XX)_COMPILER_LAUNCHER`, ie ccache or sccache.
+  pub compiler_launcher: Option<String>,
 }
 
 impl Invocation {
@@ -40,6 +53,12 @@ impl Invocation {
       args: vec![],
       defines: Default::default(),
       output_dir: out.into().create_if_not_exists()?,
+      generator: None,
+      build: false,
+      install: false,
+      jobs: None,
+      compiler_launcher: util::config::get("compiler-launcher")
+        .map(|launcher| launcher.to_string() ),
     })
   }
   pub fn with_toolchain<T, U>(tool: &T, out: U) -> Result<Self, Box<Error>>
@@ -145,10 +164,22 @@ impl Invocation {
   pub fn generator<K>(&mut self, gen: K) -> &mut Self
     where K: Into<String>,
   {
-    self.args.push("-G".into());
-    self.args.push(gen.into());
+    self.generator = Some(gen.into());
     self
   }
+
+  fn default_generator(&self) -> Option<&'static str> {
+    use std::env::{var_os, split_paths};
+
+    let paths = var_os("PATH")?;
+    let found = split_paths(&paths)
+      .any(|dir| dir.join("ninja").is_file() );
+    if found {
+      Some("Ninja")
+    } else {
+      None
+    }
+  }
 }
 impl Default for Invocation {
   fn default() -> Self {
@@ -158,6 +189,12 @@ impl Default for Invocation {
       defines: Default::default(),
       output_dir: std::env::current_dir()
         .expect("current_dir failed?"),
+      generator: None,
+      build: false,
+      install: false,
+      jobs: None,
+      compiler_launcher: util::config::get("compiler-launcher")
+        .map(|launcher| launcher.to_string() ),
     }
   }
 }
@@ -168,6 +205,20 @@ impl Tool for Invocation {
   {
     use std::process::Command;
 
+    if let Some(launcher) = self.compiler_launcher.clone() {
+      self.cmake_str("CMAKE_C_COMPILER_LAUNCHER", launcher.clone());
+      self.cmake_str("CMAKE_CXX_COMPILER_LAUNCHER", launcher);
+      // the launcher wraps the driver here, so the driver shouldn't wrap clang
+      // again with the configured one.
+      self.c_cxx_flag("--compiler-launcher=none");
+    }
+
+    queue.enqueue_function(Some("config-packages"), |sess: &mut &mut Invocation| {
+      packages::write_config_packages(sess.tc.sysroot())?;
+      Ok(())
+    })
+      .prev_outputs = false;
+
     let mut cmd = Command::new("cmake");
     self.tc.set_envs(&mut cmd);
     cmd.current_dir(self.output_dir.as_path());
@@ -178,6 +229,11 @@ impl Tool for Invocation {
                     toolchain_file.display()));
     cmd.arg(format!("-DCMAKE_CROSSCOMPILING_EMULATOR={}",
                     self.tc.binaryen_tool("wasm-shell").display()));
+    if let Some(ref generator) = self.generator {
+      cmd.arg("-G").arg(generator);
+    } else if let Some(generator) = self.default_generator() {
+      cmd.arg("-G").arg(generator);
+    }
     cmd.args(self.args.iter());
     cmd.arg("-DCMAKE_VERBOSE_MAKEFILE:BOOL=ON");
     cmd.arg("-DWASM:BOOL=ON");
@@ -190,6 +246,23 @@ impl Tool for Invocation {
 
     queue.enqueue_simple_external(Some("cmake"), cmd, None);
 
+    if self.build || self.install {
+      let mut cmd = Command::new("cmake");
+      self.tc.set_envs(&mut cmd);
+      cmd.arg("--build").arg(&self.output_dir);
+      if self.install {
+        cmd.arg("--target").arg("install");
+      }
+      if let Some(jobs) = self.jobs {
+        cmd.arg("-j").arg(format!("{}", jobs));
+      }
+
+      let name = if self.install { "cmake-install" } else { "cmake-build" };
+      let cmd = queue.enqueue_simple_external(Some(name), cmd, None);
+      cmd.prev_outputs = false;
+      cmd.output_override = false;
+    }
+
     Ok(())
   }
 
@@ -224,6 +297,11 @@ impl ToolInvocation for Invocation {
   fn args(&self, iteration: usize) -> Option<ToolArgs<Self>> {
     match iteration {
       0 => tool_arguments!(Invocation => [
+        GENERATOR,
+        BUILD,
+        INSTALL,
+        JOBS,
+        COMPILER_LAUNCHER,
         ARGS,
       ]),
       _ => None,
@@ -231,6 +309,51 @@ impl ToolInvocation for Invocation {
   }
 }
 
+tool_argument!(GENERATOR: Invocation = { Some(r"^(-G|--generator=)(.+)$"), Some(r"^-G$") };
+               fn generator_arg(this, single, cap) {
+                 let gen = if single { cap.get(2).unwrap() }
+                           else      { cap.get(0).unwrap() };
+                 let gen = match gen.as_str() {
+                   "ninja" => "Ninja",
+                   gen => gen,
+                 };
+                 this.generator = Some(gen.to_string());
+                 Ok(())
+               });
+tool_argument! {
+  pub BUILD: Invocation = simple_no_flag(b) "build" =>
+  fn build_flag(this) {
+    this.build = b;
+  }
+}
+tool_argument! {
+  pub INSTALL: Invocation = simple_no_flag(b) "install" =>
+  fn install_flag(this) {
+    this.install = b;
+  }
+}
+tool_argument! {
+  pub JOBS: Invocation = single_and_split_int(usize, jobs) "jobs" =>
+  fn jobs_arg(this) {
+    if jobs == 0 {
+      Err("`--jobs` must be at least 1")?;
+    }
+    this.jobs = Some(jobs);
+  }
+}
+
+tool_argument! {
+  pub COMPILER_LAUNCHER: Invocation = single_and_split_from_str(launcher) "compiler-launcher" =>
+  fn compiler_launcher_arg(this) {
+    let launcher: String = launcher;
+    this.compiler_launcher = if launcher == "none" {
+      None
+    } else {
+      Some(launcher)
+    };
+  }
+}
+
 argument!(impl ARGS where { Some(r"^(.*)$"), None } for Invocation {
     fn args(this, _single, cap) {
       let arg = cap.get(0)
@@ -0,0 +1,160 @@
+
+//! Generates a Meson cross file, the Meson analog of our cmake toolchain
+//! file.
+
+use std::error::Error;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use util::{ToolArgs, Tool, ToolInvocation, CommandQueue, };
+use util::toolchain::{WasmToolchain, WasmToolchainTool, };
+
+#[derive(Debug)]
+pub struct Invocation {
+  tc: WasmToolchain,
+
+  /// Where to write the cross file; stdout if `None`.
+  pub output: Option<PathBuf>,
+}
+
+impl Invocation {
+  pub fn new(tc: WasmToolchain) -> Self {
+    Invocation {
+      tc,
+      output: None,
+    }
+  }
+
+  pub fn cross_file(&self) -> Result<String, Box<Error>> {
+    fn quote<T: AsRef<Path>>(p: T) -> String {
+      let p = format!("{}", p.as_ref().display());
+      format!("'{}'", p.replace('\\', "\\\\").replace('\'', "\\'"))
+    }
+
+    let args = quote(format!("--sysroot={}", self.tc.sysroot().display()));
+
+    let mut out = String::new();
+    writeln!(out, "# Generated by wasm-meson-crossfile.")?;
+    writeln!(out, "[binaries]")?;
+    writeln!(out, "c = 'wasm-clang'")?;
+    writeln!(out, "cpp = 'wasm-clangxx'")?;
+    writeln!(out, "ld = 'wasm-ld'")?;
+    writeln!(out, "ar = {}", quote(self.tc.llvm_tool("llvm-ar")))?;
+    writeln!(out, "ranlib = {}", quote(self.tc.llvm_tool("llvm-ranlib")))?;
+    writeln!(out, "strip = {}", quote(self.tc.llvm_tool("llvm-strip")))?;
+    writeln!(out, "exe_wrapper = {}", quote(self.tc.binaryen_tool("wasm-shell")))?;
+    writeln!(out, "")?;
+    writeln!(out, "[properties]")?;
+    writeln!(out, "c_args = [{}]", args)?;
+    writeln!(out, "cpp_args = [{}]", args)?;
+    writeln!(out, "c_link_args = [{}]", args)?;
+    writeln!(out, "cpp_link_args = [{}]", args)?;
+    writeln!(out, "needs_exe_wrapper = true")?;
+    writeln!(out, "")?;
+    writeln!(out, "[host_machine]")?;
+    writeln!(out, "system = 'unknown'")?;
+    writeln!(out, "cpu_family = 'wasm32'")?;
+    writeln!(out, "cpu = 'wasm32'")?;
+    writeln!(out, "endian = 'little'")?;
+
+    Ok(out)
+  }
+  /// Write the cross file to `output`; only without one is it written to
+  /// stdout.
+  fn write_cross_file(&self) -> Result<(), Box<Error>> {
+    use std::fs::File;
+    use std::io::{self, Write};
+
+    let cross_file = self.cross_file()?;
+    match self.output {
+      Some(ref out) => {
+        let mut file = File::create(out)?;
+        file.write_all(cross_file.as_bytes())?;
+      },
+      None => {
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        stdout.write_all(cross_file.as_bytes())?;
+        stdout.flush()?;
+      },
+    }
+    Ok(())
+  }
+}
+impl Default for Invocation {
+  fn default() -> Self {
+    Invocation::new(Default::default())
+  }
+}
+
+impl WasmToolchainTool for Invocation {
+  fn wasm_toolchain(&self) -> &WasmToolchain { &self.tc }
+  fn wasm_toolchain_mut(&mut self) -> &mut WasmToolchain { &mut self.tc }
+}
+
+impl Tool for Invocation {
+  fn enqueue_commands(&mut self, queue: &mut CommandQueue<Self>)
+    -> Result<(), Box<Error>>
+  {
+    let f = move |this: &mut &mut Invocation| {
+      this.write_cross_file()?;
+      Ok(())
+    };
+    queue.enqueue_function(Some("meson-crossfile"), f);
+
+    Ok(())
+  }
+
+  fn get_name(&self) -> String {
+    "wasm-meson-crossfile".to_string()
+  }
+
+  fn add_tool_input(&mut self, _input: PathBuf)
+    -> Result<(), Box<Error>>
+  {
+    Err("unexpected input")?
+  }
+
+  fn get_output(&self) -> Option<&PathBuf> {
+    self.output.as_ref()
+  }
+  /// Unconditionally set the output file.
+  fn override_output(&mut self, out: PathBuf) {
+    self.output = Some(out);
+  }
+}
+
+impl ToolInvocation for Invocation {
+  fn check_state(&mut self, _iteration: usize, _skip_inputs_check: bool)
+    -> Result<(), Box<Error>>
+  {
+    Ok(())
+  }
+
+  fn args(&self, iteration: usize) -> Option<ToolArgs<Self>> {
+    use util::ToolArg;
+    use std::borrow::Cow;
+
+    const C: &'static [ToolArg<Invocation>] = &[];
+    let mut out = Cow::Borrowed(C);
+
+    match iteration {
+      0 => {
+        WasmToolchain::args(&mut out);
+      },
+      1 => return tool_arguments!(Invocation => [
+        OUTPUT,
+      ]),
+      _ => return None,
+    }
+
+    Some(out)
+  }
+}
+
+tool_argument! {
+  pub OUTPUT: Invocation = single_and_split_abs_path(path) "out" =>
+  fn output_arg(this) {
+    this.output = Some(path);
+  }
+}
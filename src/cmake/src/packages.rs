@@ -0,0 +1,92 @@
+
+//! CMake config packages for the libraries installed in the sysroot, so
+//! `find_package(ZLIB)` etc in user projects resolve to sysroot artifacts
+//! (as imported targets) instead of anything on the host. They're written to
+//! `<sysroot>/lib/cmake/<Package>/<Package>Config.cmake`, which
+//! `WebAssembly.cmake` puts on the search path.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use util::CreateIfNotExists;
+
+struct Package {
+  name: &'static str,
+  /// The header which has to be installed for the package to be usable.
+  header: &'static str,
+  /// `(imported target, archive in <sysroot>/lib)`.
+  targets: &'static [(&'static str, &'static str)],
+}
+
+const PACKAGES: &'static [Package] = &[
+  Package {
+    name: "ZLIB",
+    header: "zlib.h",
+    targets: &[("ZLIB::ZLIB", "libz.a")],
+  },
+  Package {
+    name: "ICU",
+    header: "unicode/utypes.h",
+    targets: &[
+      ("ICU::data", "libicudata.a"),
+      ("ICU::uc", "libicuuc.a"),
+      ("ICU::i18n", "libicui18n.a"),
+    ],
+  },
+  Package {
+    name: "LibFFI",
+    header: "ffi.h",
+    targets: &[("LibFFI::LibFFI", "libffi.a")],
+  },
+];
+
+fn config(package: &Package, sysroot: &Path) -> String {
+  let include = sysroot.join("include");
+  let lib = sysroot.join("lib");
+
+  let mut out = format!("# Generated by wasm-cmake; do not edit.\n\
+                         set({0}_FOUND TRUE)\n\
+                         set({0}_INCLUDE_DIR \"{1}\")\n\
+                         set({0}_INCLUDE_DIRS \"{1}\")\n",
+                        package.name, include.display());
+
+  let mut libraries = Vec::new();
+  for &(target, archive) in package.targets.iter() {
+    let archive = lib.join(archive);
+    out.push_str(&format!("if(NOT TARGET {0})\n  \
+                           add_library({0} STATIC IMPORTED)\n  \
+                           set_target_properties({0} PROPERTIES\n    \
+                           IMPORTED_LOCATION \"{1}\"\n    \
+                           INTERFACE_INCLUDE_DIRECTORIES \"{2}\")\n\
+                           endif()\n",
+                          target, archive.display(), include.display()));
+    libraries.push(format!("{}", archive.display()));
+  }
+  out.push_str(&format!("set({}_LIBRARIES \"{}\")\n", package.name,
+                        libraries.join(";")));
+  out
+}
+
+/// (Re)write the config packages for whichever libraries are installed in
+/// `sysroot`; returns the files written.
+pub fn write_config_packages(sysroot: &Path) -> Result<Vec<PathBuf>, Box<Error>> {
+  let mut written = Vec::new();
+  for package in PACKAGES.iter() {
+    let installed = sysroot.join("include").join(package.header).exists() &&
+      package.targets.iter()
+        .all(|&(_, archive)| sysroot.join("lib").join(archive).exists() );
+    if !installed { continue; }
+
+    let dir = sysroot.join("lib/cmake")
+      .join(package.name)
+      .create_if_not_exists()?;
+    let path = dir.join(format!("{}Config.cmake", package.name));
+    File::create(&path)?
+      .write_all(config(package, sysroot).as_bytes())?;
+    written.push(path);
+  }
+
+  Ok(written)
+}
@@ -0,0 +1,8 @@
+#include <vector>
+
+int main() {
+  std::vector<int> v{1, 2, 3};
+  int sum = 0;
+  for (int x : v) sum += x;
+  return sum == 6 ? 0 : 1;
+}
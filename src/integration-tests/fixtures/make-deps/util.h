@@ -0,0 +1 @@
+static inline int twice(int x) { return x * 2; }
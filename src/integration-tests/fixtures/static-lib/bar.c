@@ -0,0 +1 @@
+int bar(int x) { return x * 2; }
@@ -0,0 +1,3 @@
+int bar(int x);
+
+int foo(int x) { return bar(x) + 1; }
@@ -0,0 +1,757 @@
+//! Runs the drivers against the projects in `fixtures/`. Each test
+//! snapshots the commands the driver would run (`CommandQueue::plan`) in
+//! `snapshots/`; set `UPDATE_SNAPSHOTS=1` to rewrite them. When a real
+//! toolchain is configured (`$LLVM_ROOT`, etc, with a built sysroot), the
+//! fixtures are built for real too.
+
+extern crate wasm_driver_utils as util;
+extern crate clang_driver;
+extern crate ld_driver;
+extern crate tar;
+extern crate wasm_driver_capi as capi;
+extern crate opt_driver;
+extern crate wasm_sysroot_builder as sysroot_driver;
+
+use std::env;
+use std::ffi::{CStr, CString};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use clang_driver::DriverMode;
+use util::{CommandQueue, Tool, ToolInvocation, process_invocation_args};
+use util::toolchain::{ThreadModel, WasmToolchain};
+
+/// A toolchain which doesn't exist, so the commands are the same on every
+/// machine. The sysroot is just enough for the library lookups.
+fn fake_toolchain() -> WasmToolchain {
+    // so a user's config or environment doesn't change the commands.
+    env::set_var(util::config::CONFIG_ENV, "fixtures/no-config");
+    env::remove_var("WASM_DRIVER_DETERMINISTIC");
+    WasmToolchain {
+        binaryen: "/binaryen".into(),
+        emscripten: "/emscripten".into(),
+        llvm: "/llvm".into(),
+        sysroot: "fixtures/sysroot".into(),
+        threads: ThreadModel::None,
+        clang_resource_dir: None,
+    }
+}
+
+/// The configured toolchain, if it's there.
+fn real_toolchain() -> Option<WasmToolchain> {
+    for var in ["BINARYEN", "EMSCRIPTEN", "LLVM_ROOT"].iter() {
+        if env::var_os(var).is_none() {
+            return None;
+        }
+    }
+    let tc = WasmToolchain::new();
+    if !tc.llvm_tool("clang").exists() || !tc.sysroot_lib().join("libc.a").exists() {
+        return None;
+    }
+    Some(tc)
+}
+
+fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string() ).collect()
+}
+
+fn plan<T>(mut invocation: T, args: Vec<String>) -> String
+    where T: ToolInvocation + 'static,
+{
+    process_invocation_args(&mut invocation, args, false).unwrap();
+    let mut queue = CommandQueue::new(invocation.get_output().cloned());
+    invocation.enqueue_commands(&mut queue).unwrap();
+    let mut out = queue.plan().unwrap().join("\n");
+    out.push('\n');
+    out
+}
+
+fn check_snapshot(name: &str, actual: &str) {
+    let path = Path::new("snapshots").join(format!("{}.txt", name));
+    if env::var_os("UPDATE_SNAPSHOTS").is_some() {
+        File::create(&path).unwrap().write_all(actual.as_bytes()).unwrap();
+        return;
+    }
+
+    let mut expected = String::new();
+    File::open(&path)
+        .unwrap_or_else(|e| panic!("`{}`: {}; run with `UPDATE_SNAPSHOTS=1`",
+                                   path.display(), e))
+        .read_to_string(&mut expected)
+        .unwrap();
+    assert!(expected == actual,
+            "`{}` doesn't match (run with `UPDATE_SNAPSHOTS=1` to update):\n\
+             expected:\n{}\nactual:\n{}", path.display(), expected, actual);
+}
+
+fn clang_plan(mode: DriverMode, clang_args: &[&str]) -> String {
+    let invocation = clang_driver::Invocation::new_with_toolchain(fake_toolchain(), mode);
+    plan(invocation, args(clang_args))
+}
+
+/// Build with the real toolchain, if there is one, and check `output` was
+/// written.
+fn build(mode: DriverMode, clang_args: &[&str], output: &str) {
+    let tc = match real_toolchain() {
+        Some(tc) => tc,
+        None => return,
+    };
+
+    let dir = env::temp_dir()
+        .join(format!("integration-tests.{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let output: PathBuf = dir.join(output);
+    let mut clang_args = args(clang_args);
+    clang_args.push("-o".into());
+    clang_args.push(format!("{}", output.display()));
+
+    let mut invocation = clang_driver::Invocation::new_with_toolchain(tc, mode);
+    process_invocation_args(&mut invocation, clang_args, false).unwrap();
+    let mut queue = CommandQueue::new(invocation.get_output().cloned());
+    invocation.enqueue_commands(&mut queue).unwrap();
+    queue.run_all(&mut invocation).unwrap();
+    assert!(output.exists(), "`{}` wasn't written", output.display());
+}
+
+#[test]
+fn hello_world() {
+    check_snapshot("hello_world",
+                   &clang_plan(DriverMode::CC, &["-c", "fixtures/hello/hello.c",
+                                                 "-o", "hello.o"]));
+    check_snapshot("hello_world_link",
+                   &clang_plan(DriverMode::CC, &["fixtures/hello/hello.o", "-o", "hello"]));
+    build(DriverMode::CC, &["fixtures/hello/hello.c"], "hello");
+}
+
+#[test]
+fn bitcode_objects() {
+    let plan = clang_plan(DriverMode::CC, &["-femit-llvm-bc-only", "-c",
+                                            "fixtures/hello/hello.c"]);
+    check_snapshot("bitcode_objects", &plan);
+    let plan = clang_plan(DriverMode::CC, &["-femit-llvm-bc-only", "-c",
+                                            "fixtures/static-lib/foo.c",
+                                            "fixtures/static-lib/bar.c",
+                                            "-o", "libfoo.a"]);
+    check_snapshot("bitcode_static_lib", &plan);
+}
+
+#[test]
+fn mixed_languages_link() {
+    // the `-x c` source is compiled, the header is left out of the link.
+    let plan = clang_plan(DriverMode::CC, &["-x", "c", "fixtures/mixed-lang/impl.txt",
+                                            "-x", "none", "fixtures/make-deps/util.h",
+                                            "fixtures/hello/hello.o", "-o", "hello"]);
+    check_snapshot("mixed_languages_link", &plan);
+}
+
+#[test]
+fn static_lib() {
+    let plan = clang_plan(DriverMode::CC, &["-c", "fixtures/static-lib/foo.c",
+                                            "fixtures/static-lib/bar.c",
+                                            "-o", "libfoo.a"]);
+    check_snapshot("static_lib", &plan);
+    build(DriverMode::CC, &["-c", "fixtures/static-lib/foo.c",
+                            "fixtures/static-lib/bar.c"], "libfoo.a");
+}
+
+#[test]
+fn cxx_no_exceptions() {
+    let plan = clang_plan(DriverMode::CXX, &["-fno-exceptions", "-O2",
+                                             "fixtures/cxx-no-exceptions/main.cpp",
+                                             "-o", "main"]);
+    check_snapshot("cxx_no_exceptions", &plan);
+    build(DriverMode::CXX, &["-fno-exceptions", "-O2",
+                             "fixtures/cxx-no-exceptions/main.cpp"], "main");
+}
+
+#[test]
+fn cxx_wasm_exceptions() {
+    let plan = clang_plan(DriverMode::CXX, &["--eh=wasm",
+                                             "fixtures/cxx-no-exceptions/main.cpp",
+                                             "-o", "main"]);
+    check_snapshot("cxx_wasm_exceptions", &plan);
+}
+
+#[test]
+fn include_layers() {
+    let plan = clang_plan(DriverMode::CXX, &["--isystem-before-libcxx=/shadow/cxx",
+                                             "--isystem-after-libc", "/shadow/libc",
+                                             "-c", "fixtures/cxx-no-exceptions/main.cpp",
+                                             "-o", "main.o"]);
+    check_snapshot("include_layers", &plan);
+}
+
+#[test]
+fn make_deps() {
+    let plan = clang_plan(DriverMode::CC, &["-MD", "-MF", "main.d", "-c",
+                                            "fixtures/make-deps/main.c",
+                                            "-o", "main.o"]);
+    check_snapshot("make_deps", &plan);
+    build(DriverMode::CC, &["-MD", "-c", "fixtures/make-deps/main.c"], "main.o");
+}
+
+#[test]
+fn compile_db_fragment() {
+    let plan = clang_plan(DriverMode::CC, &["-MJ", "main.o.json", "-c",
+                                            "fixtures/make-deps/main.c",
+                                            "-o", "main.o"]);
+    check_snapshot("compile_db_fragment", &plan);
+    let joined = clang_plan(DriverMode::CC, &["-MJmain.o.json", "-c",
+                                              "fixtures/make-deps/main.c",
+                                              "-o", "main.o"]);
+    assert_eq!(plan, joined);
+}
+
+/// Configure probes for macOS args, like `-framework`, should fail plainly,
+/// unless `--ignore-host-args` was given.
+#[test]
+fn host_only_args() {
+    let mut invocation = clang_driver::Invocation::new_with_toolchain(fake_toolchain(),
+                                                                      DriverMode::CC);
+    let err = process_invocation_args(&mut invocation,
+                                      args(&["-framework", "CoreFoundation",
+                                             "fixtures/hello/hello.c"]),
+                                      false)
+        .unwrap_err();
+    assert!(format!("{}", err).contains("`-framework CoreFoundation` is macOS specific"),
+            "{}", err);
+    let mut invocation = ld_driver::Invocation::new_with_toolchain(fake_toolchain());
+    let err = process_invocation_args(&mut invocation,
+                                      args(&["fixtures/ld/foo.o", "-framework", "Foo"]),
+                                      false)
+        .unwrap_err();
+    assert!(format!("{}", err).contains("`-framework Foo` is macOS specific"), "{}", err);
+
+    let flags = util::GlobalFlags {
+        ignore_host_args: true,
+        .. Default::default()
+    }.enter();
+    let ignored = clang_plan(DriverMode::CC, &["-c", "fixtures/hello/hello.c",
+                                               "-framework", "CoreFoundation",
+                                               "-mmacosx-version-min=10.9",
+                                               "-o", "hello.o"]);
+    drop(flags);
+    assert_eq!(ignored, clang_plan(DriverMode::CC, &["-c", "fixtures/hello/hello.c",
+                                                     "-o", "hello.o"]));
+}
+
+/// Unknown `-m` flags are errors, unless `--forward-unknown-machine-flags`
+/// was given, in which case clang gets them.
+#[test]
+fn unknown_machine_flags() {
+    let mut invocation = clang_driver::Invocation::new_with_toolchain(fake_toolchain(),
+                                                                      DriverMode::CC);
+    let err = process_invocation_args(&mut invocation,
+                                      args(&["-mavx2", "fixtures/hello/hello.c"]),
+                                      false)
+        .unwrap_err();
+    assert!(format!("{}", err).contains("unsupported argument `-mavx2`"), "{}", err);
+
+    let flags = util::GlobalFlags {
+        forward_unknown_machine_flags: true,
+        .. Default::default()
+    }.enter();
+    let forwarded = clang_plan(DriverMode::CC, &["-c", "-mavx2", "-mtune=haswell",
+                                                 "fixtures/hello/hello.c", "-o", "hello.o"]);
+    drop(flags);
+    assert!(forwarded.contains(" -mavx2 -mtune=haswell "), "{}", forwarded);
+
+    // wasm's own are always passed on.
+    let wasm = clang_plan(DriverMode::CC, &["-c", "-msimd128", "-mllvm", "-debug-only=isel",
+                                            "fixtures/hello/hello.c", "-o", "hello.o"]);
+    assert!(wasm.contains(" -msimd128 -mllvm -debug-only=isel "), "{}", wasm);
+}
+
+#[test]
+fn optimization_levels() {
+    let fast = clang_plan(DriverMode::CC, &["-c", "-Ofast", "fixtures/hello/hello.c",
+                                            "-o", "hello.o"]);
+    assert!(fast.contains(" -O3 -ffast-math "), "{}", fast);
+    let debug = clang_plan(DriverMode::CC, &["-c", "-Og", "fixtures/hello/hello.c",
+                                             "-o", "hello.o"]);
+    assert!(debug.contains(" -O1 ") && !debug.contains("-ffast-math"), "{}", debug);
+
+    let mut invocation = clang_driver::Invocation::new_with_toolchain(fake_toolchain(),
+                                                                      DriverMode::CC);
+    let err = process_invocation_args(&mut invocation,
+                                      args(&["-O5", "fixtures/hello/hello.c"]),
+                                      false)
+        .unwrap_err();
+    assert!(format!("{}", err).contains("invalid optimization level `-O5`"), "{}", err);
+}
+
+#[test]
+fn capi_diagnostics() {
+    // `WasmToolchain::new` needs these, though nothing gets run.
+    for var in ["BINARYEN", "EMSCRIPTEN", "LLVM_ROOT"].iter() {
+        if env::var_os(var).is_none() {
+            env::set_var(var, "/nonexistent");
+        }
+    }
+
+    let (code, diags) = capi::run(args(&["wasm-gcc", "hello.c"]));
+    assert_eq!(code, 1);
+    assert!(diags[0].message.contains("`wasm-gcc` isn't a driver"), "{:?}", diags);
+
+    let argv: Vec<CString> = ["/usr/bin/wasm-clang", "-O5", "fixtures/hello/hello.c"].iter()
+        .map(|arg| CString::new(*arg).unwrap() )
+        .collect();
+    let argv: Vec<_> = argv.iter().map(|arg| arg.as_ptr() ).collect();
+    let mut diag = ptr::null_mut();
+    let code = unsafe {
+        capi::wasm_driver_compile(argv.len() as _, argv.as_ptr(), &mut diag)
+    };
+    assert_eq!(code, 1);
+    assert!(!diag.is_null());
+    let json = unsafe { CStr::from_ptr(diag) }.to_str().unwrap().to_string();
+    unsafe { capi::wasm_driver_free_diag(diag) };
+    assert!(json.starts_with("[\n  {\"severity\": \"error\", \"location\": null, "), "{}", json);
+    assert!(json.contains("invalid optimization level `-O5`"), "{}", json);
+
+    // a call's global flags don't stick for the next.
+    let (code, _) = capi::run(args(&["wasm-clang", "--hermetic", "--tool-env", "A=B",
+                                     "-O5", "fixtures/hello/hello.c"]));
+    assert_eq!(code, 1);
+    assert!(!util::hermetic() && util::tool_env().is_empty());
+    assert!(env::var_os("WASM_DRIVER_HERMETIC").is_none());
+    assert!(env::var_os("WASM_DRIVER_TOOL_ENV").is_none());
+
+    let (code, diags) = capi::run(args(&["wasm-clang", "--print-targets"]));
+    assert_eq!(code, 1);
+    assert!(diags[0].message.contains("`--print-targets` prints to stdout"), "{:?}", diags);
+    for &flag in ["--print-link-command", "--print-memory-layout"].iter() {
+        let (code, diags) = capi::run(args(&["wasm-ld", flag, "a.o", "-o", "a.wasm"]));
+        assert_eq!(code, 1);
+        assert!(diags[0].message.contains(&format!("`{}` prints to stdout", flag)),
+                "{:?}", diags);
+    }
+}
+
+#[test]
+fn opt_plan() {
+    let invocation = opt_driver::Invocation::new_with_toolchain(fake_toolchain());
+    let plan = plan(invocation, args(&["-O2", "-passes=strip,,globaldce",
+                                       "--passes=mergefunc", "-S",
+                                       "fixtures/ld/foo.o", "-o", "foo.ll"]));
+    check_snapshot("opt_plan", &plan);
+
+    let opt = |opt_args: &[&str]| {
+        let mut invocation = opt_driver::Invocation::new_with_toolchain(fake_toolchain());
+        process_invocation_args(&mut invocation, args(opt_args), false)
+            .map(|_| invocation )
+            .map_err(|e| e.to_string() )
+    };
+    let pipeline = |opt_args: &[&str]| opt(opt_args).unwrap().pipeline();
+    assert_eq!(pipeline(&["foo.bc"]), None);
+    assert_eq!(pipeline(&["-O4", "foo.bc"]), Some("default<O3>".to_string()));
+    assert_eq!(pipeline(&["-Os", "foo.bc"]), Some("default<Os>".to_string()));
+    assert_eq!(pipeline(&["-Oz", "-passes=dce", "foo.bc"]),
+               Some("default<Oz>,dce".to_string()));
+    assert_eq!(pipeline(&["-passes=dce", "foo.bc"]), Some("dce".to_string()));
+
+    let err = opt(&["foo.bc", "bar.bc"]).unwrap_err();
+    assert!(err.contains("opt takes a single input"), "{}", err);
+    let err = opt(&["-O2"]).unwrap_err();
+    assert!(err.contains("no input"), "{}", err);
+    let err = opt(&["-o", "a.bc", "-ob.bc", "foo.bc"]).unwrap_err();
+    assert!(err.contains("more than one output specified"), "{}", err);
+}
+
+#[test]
+fn translate_plan() {
+    let translate = || {
+        ld_driver::translate::Invocation::new_with_toolchain(fake_toolchain())
+    };
+    let plan = plan(translate(), args(&["-O2", "--threads=2", "fixtures/ld/foo.o",
+                                        "-o", "foo.o"]));
+    check_snapshot("translate_plan", &plan);
+
+    let err = process_invocation_args(&mut translate(), args(&["-Wl,-z", "foo.bc"]), false)
+        .unwrap_err();
+    assert!(format!("{}", err).contains("`-z` is missing its value"), "{}", err);
+}
+
+#[test]
+fn sysroot_targets() {
+    let dir = env::temp_dir()
+        .join(format!("integration-tests-sysroot.{}", std::process::id()));
+    let mut invocation = sysroot_driver::Invocation::default();
+    invocation.srcs = dir.join("srcs");
+    invocation.src_cache = dir.join("src-cache");
+    fs::create_dir_all(invocation.get_musl_root()).unwrap();
+    invocation.tc = Some(WasmToolchain {
+        sysroot: dir.join("sysroot"),
+        ..fake_toolchain()
+    });
+    let plan = plan(invocation.clone(),
+                    args(&["--offline", "--build=dlmalloc",
+                           "--targets=wasm32,wasm32-wasi,wasm32-unknown-unknown"]));
+    let config = |target: &str| {
+        let build = fs::read_dir(dir.join("srcs").join(target)).unwrap()
+            .map(|entry| entry.unwrap().path() )
+            .find(|path| path.to_str().unwrap().contains("musl-build-") )
+            .unwrap();
+        let mut config = String::new();
+        File::open(build.join("config.mak")).unwrap()
+            .read_to_string(&mut config).unwrap();
+        config
+    };
+    let wasm32_config = config("wasm32-unknown-unknown-wasm");
+    let wasi_config = config("wasm32-wasi");
+    fs::remove_dir_all(&dir).unwrap();
+
+    // the aliases are built once, as the canonical target.
+    let targets: Vec<&str> = plan.split("switch-target\n").skip(1).collect();
+    assert_eq!(targets.len(), 2, "{}", plan);
+    assert!(targets[0].contains(" -target wasm32-unknown-unknown-wasm "), "{}", plan);
+    assert!(targets[1].contains(" -target wasm32-wasi "), "{}", plan);
+    assert!(wasm32_config.contains("\nCFLAGS=--target=wasm32-unknown-unknown-wasm "),
+            "{}", wasm32_config);
+    assert!(wasi_config.contains("\nCFLAGS=--target=wasm32-wasi "), "{}", wasi_config);
+
+    for &target in ["wasm64", "x86_64-unknown-linux-gnu", "wasm32-unknown-linux"].iter() {
+        let mut invocation = invocation.clone();
+        let err = process_invocation_args(&mut invocation,
+                                          args(&[&format!("--targets=wasm32,{}", target)]),
+                                          false)
+            .unwrap_err();
+        assert!(format!("{}", err).contains(&format!("`{}` isn't a supported target", target)),
+                "{}", err);
+    }
+}
+
+#[test]
+fn ld_no_entry() {
+    let invocation = ld_driver::Invocation::new_with_toolchain(fake_toolchain());
+    let plan = plan(invocation, args(&["fixtures/ld/foo.o", "--no-entry",
+                                       "--export=foo", "-o", "foo.wasm"]));
+    check_snapshot("ld_no_entry", &plan);
+}
+
+#[test]
+fn ld_post_link_tools() {
+    let invocation = ld_driver::Invocation::new_with_toolchain(fake_toolchain());
+    let plan = plan(invocation, args(&["fixtures/ld/foo.o", "--no-entry",
+                                       "--size-preset=small",
+                                       "--post-link-tool",
+                                       "'/opt/my tools/strip' --keep-section=\"a b\"",
+                                       "-o", "foo.wasm"]));
+    check_snapshot("ld_post_link_tools", &plan);
+
+    let mut invocation = ld_driver::Invocation::new_with_toolchain(fake_toolchain());
+    process_invocation_args(&mut invocation,
+                            args(&["fixtures/ld/foo.o",
+                                   "--post-link-tool=strip '--keep-section=a b'"]),
+                            false)
+        .unwrap();
+    assert_eq!(invocation.post_link_tools,
+               vec![vec!["strip".to_string(), "--keep-section=a b".to_string()]]);
+
+    let mut invocation = ld_driver::Invocation::new_with_toolchain(fake_toolchain());
+    let err = process_invocation_args(&mut invocation,
+                                      args(&["fixtures/ld/foo.o", "--post-link-tool= "]),
+                                      false)
+        .unwrap_err();
+    assert!(format!("{}", err).contains("empty `--post-link-tool` command"), "{}", err);
+}
+
+#[test]
+fn ld_undefined() {
+    let invocation = ld_driver::Invocation::new_with_toolchain(fake_toolchain());
+    let plan = plan(invocation, args(&["fixtures/ld/foo.o", "--no-entry",
+                                       "-ufoo", "--undefined=bar",
+                                       "-u", "baz", "--undefined", "qux",
+                                       "-o", "foo.wasm"]));
+    check_snapshot("ld_undefined", &plan);
+}
+
+#[test]
+fn ld_export_visible() {
+    let link_command = |case: &[&str]| -> Vec<String> {
+        let mut invocation = ld_driver::Invocation::new_with_toolchain(fake_toolchain());
+        let mut case_args = args(case);
+        case_args.push("fixtures/ld/foo.o".into());
+        process_invocation_args(&mut invocation, case_args, false).unwrap();
+        invocation.link_command(&ld_driver::Loader::None, true)
+    };
+    let export_dynamic = "--export-dynamic".to_string();
+
+    let visible = link_command(&["--export-visible"]);
+    assert_eq!(visible.iter().filter(|&arg| arg == &export_dynamic ).count(), 1,
+               "{:?}", visible);
+    // the last one wins.
+    let hidden = link_command(&["--export-visible", "--no-export-visible"]);
+    assert!(!hidden.contains(&export_dynamic), "{:?}", hidden);
+    assert!(!link_command(&[]).contains(&export_dynamic));
+    // nothing's exported from a partial link.
+    let relocatable = link_command(&["--export-visible", "--relocatable"]);
+    assert!(!relocatable.contains(&export_dynamic), "{:?}", relocatable);
+}
+
+/// wasm-ld's command line for each set of driver args; see
+/// `ld_driver::Invocation::link_command`.
+#[test]
+fn ld_link_commands() {
+    let cases: &[(&str, &[&str])] = &[
+        ("exports", &["--export=foo", "--export", "bar", "--keep-symbol=baz",
+                      "--export-visible", "--entry=main"]),
+        ("undefined", &["-ufoo", "--undefined=bar"]),
+        ("defsym", &["--defsym=__heap_base=65536", "--defsym", "foo=bar",
+                     "-defsym=baz=0x10"]),
+        ("import memory", &["--import-memory", "--import-table",
+                            "--growable-table-import", "--global-base=1024"]),
+        ("strip all", &["-s"]),
+        ("strip debug", &["--strip-debug"]),
+        ("lto", &["-flto"]),
+        ("lto -O3", &["-flto", "-O3"]),
+        ("gc sections", &["-O2", "--no-entry"]),
+        ("no gc sections", &["-O2", "--no-gc-sections"]),
+        ("relocatable", &["--relocatable", "--export=foo"]),
+        ("libraries", &["-Lfixtures/sysroot/lib", "-lc", "-l:libc++.a"]),
+        ("loader none", &["--loader=none", "--trace", "--trace-symbol=foo"]),
+        ("z flags", &["-z", "stack-size=65536", "-znow", "-z", "noexecstack"]),
+    ];
+
+    let mut out = String::new();
+    for &(name, case) in cases.iter() {
+        let mut invocation = ld_driver::Invocation::new_with_toolchain(fake_toolchain());
+        let mut case_args = args(case);
+        case_args.push("fixtures/ld/foo.o".into());
+        process_invocation_args(&mut invocation, case_args, false).unwrap();
+        let loader = invocation.loader.clone()
+            .unwrap_or(ld_driver::Loader::Modkit);
+        out.push_str(&format!("{}: {}\n", name,
+                              invocation.link_command(&loader, true).join(" ")));
+    }
+
+    let mut atomics = fake_toolchain();
+    atomics.threads = ThreadModel::Atomics;
+    let mut invocation = ld_driver::Invocation::new_with_toolchain(atomics);
+    process_invocation_args(&mut invocation, args(&["fixtures/ld/foo.o"]), false).unwrap();
+    out.push_str(&format!("atomics: {}\n",
+                          invocation.link_command(&ld_driver::Loader::None, true).join(" ")));
+
+    check_snapshot("ld_link_commands", &out);
+}
+
+#[test]
+fn ld_diagnostics() {
+    let stderr = "wasm-ld: error: foo.o: undefined symbol: _Z3barv\n\
+                  wasm-ld: error: /sysroot/lib/libc.a(printf.o): undefined symbol: _Z3barv\n\
+                  wasm-ld: error: foo.o: undefined symbol: baz\n\
+                  wasm-ld: error: foo.o: undefined symbol: _Z3barv\n\
+                  wasm-ld: warning: /sysroot/lib/libc.a(abort.o): something\n";
+    let libs = vec![(PathBuf::from("/sysroot/lib/libc.a"), "-lc".to_string())];
+    assert_eq!(ld_driver::diagnostics::prettify(stderr, &libs[..]).lines().collect::<Vec<_>>(),
+               vec!["wasm-ld: error: undefined symbol: bar()",
+                    ">>> referenced by foo.o",
+                    ">>> referenced by -lc(printf.o)",
+                    "wasm-ld: error: undefined symbol: baz",
+                    ">>> referenced by foo.o",
+                    "wasm-ld: warning: -lc(abort.o): something"]);
+}
+
+/// `--dependent-libraries` is opt in, and an object llvm-dis can't read
+/// is only warned about.
+#[test]
+fn ld_dependent_libraries() {
+    let invocation = ld_driver::Invocation::new_with_toolchain(fake_toolchain());
+    assert!(!invocation.dependent_libraries);
+    let object = env::temp_dir()
+        .join(format!("integration-tests-deplibs.{}.bc", std::process::id()));
+    File::create(&object).unwrap().write_all(b"BC\xc0\xde").unwrap();
+    let plan = plan(invocation, args(&["--dependent-libraries", "--no-entry",
+                                       object.to_str().unwrap(), "-o", "foo.wasm"]));
+    fs::remove_file(&object).unwrap();
+    assert!(plan.contains("integration-tests-deplibs"), "{}", plan);
+}
+
+#[test]
+fn ld_duplicate_inputs() {
+    let plan = |inputs: &[&str]| {
+        let invocation = ld_driver::Invocation::new_with_toolchain(fake_toolchain());
+        let mut args = args(inputs);
+        args.extend(self::args(&["--no-entry", "-o", "foo.wasm"]));
+        self::plan(invocation, args)
+    };
+    assert_eq!(plan(&["fixtures/ld/foo.o", "fixtures/ld/../ld/foo.o"]),
+               plan(&["fixtures/ld/foo.o"]));
+}
+
+#[test]
+fn ld_fast_relink() {
+    use ld_driver::Input;
+    use ld_driver::relink::Cache;
+
+    let out = env::temp_dir()
+        .join(format!("integration-tests-relink.{}.wasm", std::process::id()));
+    let mut invocation = ld_driver::Invocation::new_with_toolchain(fake_toolchain());
+    process_invocation_args(&mut invocation,
+                            args(&["--fast-relink", "-lrelinked", "--no-entry"]), false)
+        .unwrap();
+    let foo = PathBuf::from("fixtures/ld/foo.o");
+    let mut cache = Cache::load(&out);
+    cache.set_resolution(false, Path::new("relinked"), &invocation.search_paths, false,
+                         &[Input::File(foo.clone())], &[foo.clone()]);
+    cache.save().unwrap();
+    invocation.override_output(out.clone());
+    let mut queue = CommandQueue::new(Some(out.clone()));
+    let planned = invocation.enqueue_commands(&mut queue)
+        .map_err(|e| e.to_string() )
+        .and_then(|_| queue.plan().map_err(|e| format!("{:?}", e) ) );
+    fs::remove_file(format!("{}.relink", out.display())).unwrap();
+    let plan = planned.unwrap().join("\n");
+    assert!(plan.contains(" fixtures/ld/foo.o "), "{}", plan);
+}
+
+#[test]
+fn ld_auto_link_order() {
+    use std::collections::BTreeMap;
+    use ld_driver::Input;
+    use ld_driver::linkorder::{self, Entry, Symbols};
+    use ld_driver::relink::Cache;
+
+    fn syms(defined: &[&str], undefined: &[&str]) -> Symbols {
+        Symbols {
+            defined: defined.iter().map(|s| s.to_string() ).collect(),
+            undefined: undefined.iter().map(|s| s.to_string() ).collect(),
+        }
+    }
+    fn missing(entries: &[Entry], order: &[usize], undefined: &[&str]) -> Vec<String> {
+        let undefined: Vec<String> = undefined.iter().map(|s| s.to_string() ).collect();
+        linkorder::simulate(entries, order, &undefined[..]).into_iter().collect()
+    }
+
+    // `main.o`, then `libb.a` before `liba.a`, which needs it.
+    let entries = vec![
+        Entry::Object(syms(&["main"], &["a"])),
+        Entry::Archive(vec![syms(&["b"], &[])]),
+        Entry::Archive(vec![syms(&["a"], &["b"]), syms(&["unused"], &["c"])]),
+    ];
+    assert_eq!(missing(&entries, &[0, 1, 2], &[]), vec!["b".to_string()]);
+    assert_eq!(linkorder::sorted_archives(&entries, &[1, 2]), vec![2, 1]);
+    assert!(missing(&entries, &[0, 2, 1], &[]).is_empty());
+    // `-u` pulls members in too.
+    assert_eq!(missing(&entries, &[0, 2, 1], &["unused"]), vec!["c".to_string()]);
+    assert_eq!(missing(&entries, &[0, 2, 1], &["d"]), vec!["d".to_string()]);
+
+    // archives which need each other keep their order.
+    let entries = vec![
+        Entry::Archive(vec![syms(&["a"], &["b"])]),
+        Entry::Archive(vec![syms(&["b"], &["a"])]),
+    ];
+    assert_eq!(linkorder::sorted_archives(&entries, &[0, 1]), vec![0, 1]);
+
+    // archives with a wasm member; their symbols come from the cache, so
+    // `llvm-nm` isn't needed.
+    let dir = env::temp_dir()
+        .join(format!("integration-tests-link-order.{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let archive = |name: &str, defined: &[&str], undefined: &[&str],
+                   cache: &mut Cache| -> PathBuf {
+        let path = dir.join(name);
+        let mut ar = b"!<arch>\n".to_vec();
+        ar.extend_from_slice(format!("{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n",
+                                     "m.o/", 0, 0, 0, 644, 8).as_bytes());
+        ar.extend_from_slice(b"\0asm\x01\0\0\0");
+        File::create(&path).unwrap().write_all(&ar).unwrap();
+        let mut symbols = BTreeMap::new();
+        symbols.insert("m.o".to_string(), syms(defined, undefined));
+        cache.set_symbols(&path, &symbols);
+        path
+    };
+    let out = dir.join("out.wasm");
+    let mut cache = Cache::load(&out);
+    let libb = archive("libb.a", &["b"], &[], &mut cache);
+    let liba = archive("liba.a", &["a"], &["b"], &mut cache);
+    let nm = dir.join("no-llvm-nm");
+
+    let undefined = vec!["a".to_string()];
+    let inputs = vec![Input::File(libb.clone()), Input::File(liba.clone())];
+    let reordered = linkorder::auto_link_order(&nm, &inputs[..], &undefined[..], &mut cache);
+    let reordered = reordered.unwrap().map(|(_, order)| order );
+    assert_eq!(reordered, Some(vec![liba.clone(), libb.clone()]));
+    // without the `-u`, nothing's missing.
+    let reordered = linkorder::auto_link_order(&nm, &inputs[..], &[], &mut cache);
+    assert!(reordered.unwrap().is_none());
+
+    // `--whole-archive` applies to `liba.a`, so the order is left alone.
+    let inputs = vec![
+        Input::File(libb.clone()),
+        Input::Flag("--whole-archive".to_string()),
+        Input::File(liba.clone()),
+        Input::Flag("--no-whole-archive".to_string()),
+    ];
+    let reordered = linkorder::auto_link_order(&nm, &inputs[..], &undefined[..], &mut cache);
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(reordered.unwrap().is_none());
+
+    // positional flags stay where they were given.
+    let invocation = ld_driver::Invocation::new_with_toolchain(fake_toolchain());
+    let plan = plan(invocation, args(&["--no-entry", "-Lfixtures/sysroot/lib",
+                                       "--whole-archive", "fixtures/ld/foo.o",
+                                       "--no-whole-archive",
+                                       "-Bstatic", "--start-group", "-lc",
+                                       "--end-group", "-o", "foo.wasm"]));
+    assert!(plan.contains(" --whole-archive fixtures/ld/foo.o --no-whole-archive -Bstatic \
+                           --start-group "), "{}", plan);
+}
+
+#[test]
+fn ld_driver_reproduce() {
+    use std::process::Command;
+
+    let tar_path = env::temp_dir()
+        .join(format!("integration-tests-repro.{}.tar", std::process::id()));
+    let script = tar_path.with_extension("map");
+    File::create(&script).unwrap().write_all(b"{ global: foo; local: *; };\n").unwrap();
+    let mut cmd = Command::new("/llvm/bin/wasm-ld");
+    cmd.arg("--no-entry")
+        .arg("fixtures/ld/foo.o")
+        .arg(format!("--version-script={}", script.display()))
+        .arg("--export=two words")
+        .arg("-L").arg("fixtures/sysroot/lib")
+        .arg("-lc")
+        .arg("-o").arg("/some/where/foo.wasm");
+    ld_driver::reproduce::write(&tar_path, &cmd, &fake_toolchain()).unwrap();
+
+    let cwd = env::current_dir().unwrap();
+    let cwd = cwd.strip_prefix("/").unwrap();
+    let mut archive = tar::Archive::new(File::open(&tar_path).unwrap());
+    let mut entries = Vec::new();
+    let mut response = String::new();
+    for entry in archive.entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let path = entry.path().unwrap().into_owned();
+        if path.ends_with("response.txt") {
+            entry.read_to_string(&mut response).unwrap();
+        }
+        entries.push(path);
+    }
+    fs::remove_file(&tar_path).unwrap();
+    fs::remove_file(&script).unwrap();
+
+    let root = Path::new(tar_path.file_stem().unwrap());
+    let script = script.strip_prefix("/").unwrap();
+    assert_eq!(entries, vec![root.join(cwd).join("fixtures/ld/foo.o"),
+                             root.join(script),
+                             root.join(cwd).join("fixtures/sysroot/lib/libc.a"),
+                             root.join("response.txt"),
+                             root.join("version.txt")]);
+    let lib_dir = cwd.join("fixtures/sysroot/lib");
+    assert_eq!(response.lines().collect::<Vec<_>>(),
+               vec!["--no-entry".to_string(),
+                    format!("{}", cwd.join("fixtures/ld/foo.o").display()),
+                    format!("--version-script={}", script.display()),
+                    "'--export=two words'".to_string(),
+                    "-L".to_string(), format!("{}", lib_dir.display()),
+                    "-lc".to_string(), "-o".to_string(), "foo.wasm".to_string()]);
+}
+
+#[test]
+fn preprocess_to_stdout() {
+    let plan = clang_plan(DriverMode::CC, &["-E", "fixtures/hello/hello.c", "-o", "-"]);
+    check_snapshot("preprocess_to_stdout", &plan);
+}
@@ -0,0 +1,58 @@
+//! `--write-link-depfile`: a make style depfile for the link, naming the
+//! output as the target and every input it was linked from (after `-l`
+//! lookups and linker scripts) as its dependencies, so a Ninja or Make based
+//! build relinks when any of them changes.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// `path`, escaped for a depfile.
+fn escape(path: &Path) -> String {
+  let path = path.to_string_lossy();
+  let mut out = String::with_capacity(path.len());
+  for c in path.chars() {
+    match c {
+      ' ' | '#' => {
+        out.push('\\');
+        out.push(c);
+      },
+      '$' => out.push_str("$$"),
+      _ => out.push(c),
+    }
+  }
+  out
+}
+
+/// The depfile for `target`, with each of `deps` (in order, less
+/// duplicates) on its own line.
+pub fn depfile(target: &Path, deps: &[PathBuf]) -> String {
+  let mut out = format!("{}:", escape(target));
+  let mut seen = vec![];
+  for dep in deps.iter() {
+    if seen.contains(&dep) { continue; }
+    seen.push(dep);
+    out.push_str(" \\\n  ");
+    out.push_str(&escape(dep));
+  }
+  out.push('\n');
+  out
+}
+
+pub fn write_depfile(path: &Path, target: &Path, deps: &[PathBuf]) -> io::Result<()> {
+  fs::write(path, depfile(target, deps))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn escaped_and_deduped() {
+    let deps = vec![PathBuf::from("foo.o"), PathBuf::from("sysroot/lib/libc.a"),
+                    PathBuf::from("my dir/#1/$lib.a"), PathBuf::from("foo.o")];
+    assert_eq!(depfile(Path::new("out.wasm"), &deps[..]),
+               "out.wasm: \\\n  foo.o \\\n  sysroot/lib/libc.a \\\n  \
+                my\\ dir/\\#1/$$lib.a\n");
+  }
+}
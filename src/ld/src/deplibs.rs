@@ -0,0 +1,108 @@
+//! `llvm.dependent-libraries`: the libraries a bitcode object asks for,
+//! ie with `#pragma comment(lib, "...")`. wasm-ld doesn't look at these, so
+//! with `--dependent-libraries` we add them as inputs ourselves. It's off by
+//! default, since it means disassembling every bitcode object before the
+//! link.
+//!
+//! Only object inputs are read. An archive member's dependencies would
+//! only count if the member were pulled in, which isn't known until wasm-ld
+//! runs.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use util::regex::Regex;
+
+lazy_static! {
+  static ref NAMED_RE: Regex =
+    Regex::new(r"^!llvm\.dependent-libraries\s*=\s*!\{(.*)\}\s*$").unwrap();
+  /// `!<n> = !{!"<lib>"}`; quotes and backslashes in `<lib>` are escaped.
+  static ref STRING_NODE_RE: Regex =
+    Regex::new(r#"^!(\d+)\s*=\s*(?:distinct\s+)?!\{\s*!"([^"]*)"\s*\}\s*$"#).unwrap();
+}
+
+/// Undo the `\XX` escapes of a metadata string.
+fn unescape(s: &str) -> String {
+  let bytes = s.as_bytes();
+  let mut out = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+  while i < bytes.len() {
+    if bytes[i] == b'\\' && i + 3 <= bytes.len() {
+      let hex = ::std::str::from_utf8(&bytes[i + 1..i + 3]).ok()
+        .and_then(|hex| u8::from_str_radix(hex, 16).ok() );
+      if let Some(b) = hex {
+        out.push(b);
+        i += 3;
+        continue;
+      }
+    }
+    out.push(bytes[i]);
+    i += 1;
+  }
+  String::from_utf8_lossy(&out).into_owned()
+}
+
+/// The dependent libraries named in the textual IR `ir`, in order.
+pub fn parse(ir: &str) -> Vec<String> {
+  let mut wanted: Vec<u32> = vec![];
+  let mut strings: BTreeMap<u32, String> = BTreeMap::new();
+  for line in ir.lines().map(|line| line.trim() ) {
+    if !line.starts_with('!') { continue; }
+    if let Some(cap) = NAMED_RE.captures(line) {
+      wanted = cap[1].split(',')
+        .filter_map(|id| id.trim().trim_start_matches('!').parse().ok() )
+        .collect();
+    } else if let Some(cap) = STRING_NODE_RE.captures(line) {
+      if let Ok(id) = cap[1].parse() {
+        strings.insert(id, unescape(&cap[2]));
+      }
+    }
+  }
+
+  wanted.iter()
+    .filter_map(|id| strings.get(id).cloned() )
+    .collect()
+}
+
+/// The dependent libraries of the bitcode object at `bitcode`.
+pub fn dependent_libraries(llvm_dis: &Path, bitcode: &Path)
+  -> Result<Vec<String>, Box<Error>>
+{
+  let mut cmd = Command::new(llvm_dis);
+  cmd.arg("-o").arg("-")
+    .arg(bitcode)
+    .stdin(Stdio::null());
+  ::util::hermetic_env(&mut cmd);
+  let output = cmd.output()
+    .map_err(|e| format!("failed to run `{:?}`: {}", cmd, e) )?;
+  if !output.status.success() {
+    Err(format!("`{:?}` failed: {}", cmd,
+                String::from_utf8_lossy(&output.stderr)))?;
+  }
+  Ok(parse(&String::from_utf8_lossy(&output.stdout)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_metadata() {
+    let ir = "target triple = \"wasm32-unknown-unknown\"\n\
+              !llvm.dependent-libraries = !{!0, !1, !2}\n\
+              !llvm.ident = !{!3}\n\
+              !0 = !{!\"m\"}\n\
+              !1 = !{!\"libfoo.a\"}\n\
+              !2 = !{!\"dir\\5Cwith \\22quotes\\22\"}\n\
+              !3 = !{!\"clang version 12\"}\n";
+    assert_eq!(parse(ir), vec!["m", "libfoo.a", "dir\\with \"quotes\""]);
+    assert!(parse("!0 = !{!\"m\"}\n").is_empty());
+    // spacing, `distinct` nodes and an escape at the end.
+    let ir = "!llvm.dependent-libraries = !{ !7,!8 }\n\
+              !7 = distinct !{ !\"pthread\" }\n\
+              !8=!{!\"trailing\\22\"}\n";
+    assert_eq!(parse(ir), vec!["pthread", "trailing\""]);
+  }
+}
@@ -0,0 +1,81 @@
+//! Cleans up wasm-ld's error output before it's shown: symbols are
+//! demangled, archives found through `-l` are named by that `-l`, and each
+//! undefined symbol is reported once, with what references it, instead of
+//! once per reference.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+use util::demangle::demangle_text;
+use util::regex::Regex;
+
+/// Max references listed per undefined symbol.
+const MAX_REFERENCES: usize = 5;
+
+lazy_static! {
+  static ref UNDEFINED_RE: Regex =
+    Regex::new(r"^(.*?error: )(.+?): undefined symbol: (.+)$").unwrap();
+}
+
+/// `stderr`, prettified. `libs` are the archives which came from `-l`,
+/// with the `-l` which found them (ie `-lc`).
+pub fn prettify(stderr: &str, libs: &[(PathBuf, String)]) -> String {
+  let libs: Vec<(String, &str)> = libs.iter()
+    .map(|&(ref path, ref request)| (format!("{}", path.display()), &request[..]) )
+    .collect();
+  let rename = |line: &str| {
+    let mut line = demangle_text(line).into_owned();
+    for &(ref path, request) in libs.iter() {
+      line = line.replace(&path[..], request);
+    }
+    line
+  };
+
+  // symbol => (error prefix, referenced by).
+  let mut undefined: BTreeMap<String, (String, Vec<String>)> = BTreeMap::new();
+  // `Err(symbol)` is where that symbol's error goes.
+  let mut lines: Vec<Result<String, String>> = Vec::new();
+  for line in stderr.lines() {
+    let line = rename(line);
+    let cap = match UNDEFINED_RE.captures(&line) {
+      Some(cap) => cap,
+      None => {
+        lines.push(Ok(line.clone()));
+        continue;
+      },
+    };
+    let prefix = cap.get(1).unwrap().as_str();
+    let file = cap.get(2).unwrap().as_str().to_string();
+    let sym = cap.get(3).unwrap().as_str().trim().to_string();
+    if !undefined.contains_key(&sym) {
+      lines.push(Err(sym.clone()));
+      undefined.insert(sym.clone(), (prefix.to_string(), vec![]));
+    }
+    let refs = &mut undefined.get_mut(&sym).unwrap().1;
+    if !refs.contains(&file) {
+      refs.push(file);
+    }
+  }
+
+  let mut out = String::new();
+  for line in lines.into_iter() {
+    let sym = match line {
+      Ok(line) => {
+        writeln!(out, "{}", line).unwrap();
+        continue;
+      },
+      Err(sym) => sym,
+    };
+    let &(ref prefix, ref refs) = &undefined[&sym];
+    writeln!(out, "{}undefined symbol: {}", prefix, sym).unwrap();
+    for file in refs.iter().take(MAX_REFERENCES) {
+      writeln!(out, ">>> referenced by {}", file).unwrap();
+    }
+    if refs.len() > MAX_REFERENCES {
+      writeln!(out, ">>> referenced by {} more files", refs.len() - MAX_REFERENCES)
+        .unwrap();
+    }
+  }
+  out
+}
@@ -0,0 +1,104 @@
+//! `--check-duplicate-symbols`: before linking, look for strong symbols
+//! defined by more than one of the object inputs, and report all of them at
+//! once with the objects which define them. wasm-ld stops at the first, late
+//! in LTO, and only names one of the objects for bitcode inputs.
+//!
+//! Archives aren't checked; which of their members get linked isn't known
+//! until wasm-ld runs.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The strong definitions in `llvm-nm --defined-only --print-file-name`
+/// output over `files`, by symbol.
+pub fn parse(nm_output: &str, files: &[PathBuf]) -> BTreeMap<String, Vec<PathBuf>> {
+  // longest first, so `a.o` doesn't take `a.o.o`'s lines.
+  let mut prefixes: Vec<(String, &PathBuf)> = files.iter()
+    .map(|file| (format!("{}: ", file.display()), file) )
+    .collect();
+  prefixes.sort_by(|a, b| b.0.len().cmp(&a.0.len()) );
+
+  let mut out: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+  for line in nm_output.lines() {
+    let (file, rest) = match prefixes.iter().find(|&&(ref p, _)| line.starts_with(&p[..]) ) {
+      Some(&(ref prefix, file)) => (file, &line[prefix.len()..]),
+      None => continue,
+    };
+    // `<value> <type> <name>`; the (demangled) name can have spaces.
+    let mut fields = rest.trim_start().splitn(3, ' ');
+    let (kind, name) = match (fields.next(), fields.next(), fields.next()) {
+      (Some(_), Some(kind), Some(name)) => (kind, name),
+      _ => continue,
+    };
+    match kind {
+      // weak and common definitions are allowed to repeat.
+      "W" | "V" | "C" | "w" | "v" => continue,
+      _ if kind.chars().all(|c| c.is_ascii_uppercase() ) => {},
+      // local.
+      _ => continue,
+    }
+    let defs = out.entry(name.to_string()).or_insert_with(Vec::new);
+    if !defs.contains(file) {
+      defs.push(file.clone());
+    }
+  }
+  out.retain(|_, defs| defs.len() > 1 );
+  out
+}
+
+pub fn check(nm: &Path, objects: &[PathBuf]) -> Result<(), Box<Error>> {
+  if objects.len() < 2 { return Ok(()); }
+
+  let mut cmd = Command::new(nm);
+  cmd.arg("--defined-only")
+    .arg("--extern-only")
+    .arg("--print-file-name")
+    .arg("--demangle")
+    .args(objects);
+  ::util::hermetic_env(&mut cmd);
+  let output = cmd.output()
+    .map_err(|e| format!("failed to run `{:?}`: {}", cmd, e) )?;
+  if !output.status.success() {
+    Err(format!("`{:?}` failed: {}", cmd,
+                String::from_utf8_lossy(&output.stderr)))?;
+  }
+
+  let dups = parse(&String::from_utf8_lossy(&output.stdout), objects);
+  if dups.len() == 0 { return Ok(()); }
+  for (sym, defs) in dups.iter() {
+    let defs: Vec<String> = defs.iter()
+      .map(|def| format!("`{}`", def.display()) )
+      .collect();
+    ::util::diag::error(&format!("duplicate symbol `{}`, defined in {}",
+                                 sym, defs.join(", ")));
+  }
+  Err(format!("{} symbol(s) are defined by more than one object", dups.len()).into())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn strong_definitions() {
+    let nm = "a.o: -------- T foo\n\
+              a.o: -------- W inline_fn\n\
+              a.o: -------- t local\n\
+              a.o.o: -------- T foo\n\
+              b.o: -------- T ns::bar(int)\n\
+              b.o: -------- W inline_fn\n\
+              b.o: -------- t local\n\
+              c.o: -------- D ns::bar(int)\n";
+    let files = vec![PathBuf::from("a.o"), PathBuf::from("a.o.o"),
+                     PathBuf::from("b.o"), PathBuf::from("c.o")];
+    let dups: Vec<(String, Vec<PathBuf>)> = parse(nm, &files[..])
+      .into_iter()
+      .collect();
+    assert_eq!(dups, vec![
+      ("foo".to_string(), vec![PathBuf::from("a.o"), PathBuf::from("a.o.o")]),
+      ("ns::bar(int)".to_string(), vec![PathBuf::from("b.o"), PathBuf::from("c.o")]),
+    ]);
+  }
+}
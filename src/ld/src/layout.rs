@@ -0,0 +1,544 @@
+//! `--emit-map-json`: a JSON description of a linked module's layout; its
+//! sections, each defined function's name and body size, the data
+//! segments, globals and table entries. For tracking code size over time.
+//!
+//! `--print-memory-layout`: where the linked module's memory puts the data,
+//! stack and heap, found from the linker's `__global_base`, `__data_end`,
+//! `__heap_base` and `__stack_pointer` globals (by their names in the name
+//! section, or their exports), for checking `--global-base` and
+//! `-z stack-size=` did what was wanted.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use util::json_string;
+
+use super::wasm::{self, read_byte, read_leb, read_limits, read_name, read_sleb,
+                  CODE_SECTION, CUSTOM_SECTION, DATA_SECTION, ELEMENT_SECTION,
+                  EXPORT_SECTION, GLOBAL_SECTION, IMPORT_SECTION, MEMORY_SECTION};
+
+
+const FUNCTION_NAMES: u8 = 1;
+const GLOBAL_NAMES: u8 = 7;
+const DATA_NAMES: u8 = 9;
+
+#[derive(Debug, Default)]
+struct Layout {
+  /// `(name, size)`.
+  sections: Vec<(String, usize)>,
+  imported_functions: u32,
+  imported_globals: u32,
+  /// `(initial, max, imported)`, in pages.
+  memory: Option<(u64, Option<u64>, bool)>,
+  /// `(offset, size)` of each defined function's body.
+  functions: Vec<(usize, usize)>,
+  /// `(type, mutable, init)`.
+  globals: Vec<(&'static str, bool, Option<i64>)>,
+  /// `(table, slot, function)`.
+  elements: Vec<(u32, u64, u32)>,
+  /// `(address, size)`; the address is `None` for passive segments.
+  data: Vec<(Option<i64>, usize)>,
+  function_names: BTreeMap<u32, String>,
+  global_names: BTreeMap<u32, String>,
+  data_names: BTreeMap<u32, String>,
+  global_exports: BTreeMap<u32, String>,
+}
+
+fn section_name(id: u8, payload: &[u8]) -> String {
+  if id == CUSTOM_SECTION {
+    let name = wasm::custom_section_name(payload).unwrap_or(b"");
+    return String::from_utf8_lossy(name).into_owned();
+  }
+  wasm::section_name(id).unwrap_or("unknown").to_string()
+}
+
+/// Read a constant expression. Its value if it's a single `i32.const` or
+/// `i64.const`; the function if it's a `ref.func`.
+fn const_expr(bytes: &[u8], pos: &mut usize)
+  -> Result<(Option<i64>, Option<u32>), Box<Error>>
+{
+  let mut value = None;
+  let mut func = None;
+  let mut ops = 0;
+  loop {
+    let op = read_byte(bytes, pos)?;
+    match op {
+      0x0b => break,
+      0x41 | 0x42 => { value = Some(read_sleb(bytes, pos)?); },
+      0x43 => { *pos += 4; },
+      0x44 => { *pos += 8; },
+      0x23 => { read_leb(bytes, pos)?; },
+      0xd0 => { read_byte(bytes, pos)?; },
+      0xd2 => { func = Some(read_leb(bytes, pos)? as u32); },
+      // the extended const arithmetic ops, which have no immediates.
+      0x6a | 0x6b | 0x6c | 0x7c | 0x7d | 0x7e => {},
+      op => Err(format!("unsupported constant expression op 0x{:02x}", op))?,
+    }
+    ops += 1;
+  }
+  if ops != 1 {
+    value = None;
+  }
+  Ok((value, func))
+}
+
+/// Step `pos` over `len` bytes, which have to be in `bytes`.
+fn skip(bytes: &[u8], pos: &mut usize, len: usize) -> Result<(), Box<Error>> {
+  match pos.checked_add(len) {
+    Some(end) if end <= bytes.len() => {
+      *pos = end;
+      Ok(())
+    },
+    _ => Err("unexpected end of module".into()),
+  }
+}
+
+fn read_names(payload: &[u8], pos: &mut usize, out: &mut BTreeMap<u32, String>)
+  -> Result<(), Box<Error>>
+{
+  for _ in 0..read_leb(payload, pos)? {
+    let idx = read_leb(payload, pos)? as u32;
+    let name = read_name(payload, pos)?;
+    out.insert(idx, String::from_utf8_lossy(name).into_owned());
+  }
+  Ok(())
+}
+
+fn parse(bytes: &[u8]) -> Result<Layout, Box<Error>> {
+  let mut l = Layout::default();
+  for (id, start, payload_start, end) in wasm::sections(bytes)?.into_iter() {
+    let s = &bytes[payload_start..end];
+    l.sections.push((section_name(id, s), end - start));
+    let mut pos = 0;
+    match id {
+      IMPORT_SECTION => {
+        for _ in 0..read_leb(s, &mut pos)? {
+          read_name(s, &mut pos)?;
+          read_name(s, &mut pos)?;
+          match read_byte(s, &mut pos)? {
+            0 => {
+              read_leb(s, &mut pos)?;
+              l.imported_functions += 1;
+            },
+            1 => {
+              read_byte(s, &mut pos)?;
+              read_limits(s, &mut pos)?;
+            },
+            2 => {
+              let m = read_limits(s, &mut pos)?;
+              l.memory = Some((m.min, m.max, true));
+            },
+            3 => {
+              pos += 2;
+              l.imported_globals += 1;
+            },
+            4 => {
+              read_byte(s, &mut pos)?;
+              read_leb(s, &mut pos)?;
+            },
+            k => Err(format!("unknown import kind {}", k))?,
+          }
+        }
+      },
+      MEMORY_SECTION => {
+        if read_leb(s, &mut pos)? != 0 {
+          let m = read_limits(s, &mut pos)?;
+          l.memory = Some((m.min, m.max, false));
+        }
+      },
+      GLOBAL_SECTION => {
+        for _ in 0..read_leb(s, &mut pos)? {
+          let ty = wasm::val_type(read_byte(s, &mut pos)?).unwrap_or("unknown");
+          let mutable = read_byte(s, &mut pos)? != 0;
+          let (init, _) = const_expr(s, &mut pos)?;
+          l.globals.push((ty, mutable, init));
+        }
+      },
+      EXPORT_SECTION => {
+        for _ in 0..read_leb(s, &mut pos)? {
+          let name = read_name(s, &mut pos)?;
+          let kind = read_byte(s, &mut pos)?;
+          let idx = read_leb(s, &mut pos)? as u32;
+          if kind == 3 {
+            l.global_exports.insert(idx, String::from_utf8_lossy(name).into_owned());
+          }
+        }
+      },
+      ELEMENT_SECTION => {
+        for _ in 0..read_leb(s, &mut pos)? {
+          let flags = read_leb(s, &mut pos)?;
+          let passive = flags & 1 != 0;
+          let table = if flags & 2 != 0 && !passive {
+            read_leb(s, &mut pos)? as u32
+          } else {
+            0
+          };
+          let offset = if !passive {
+            const_expr(s, &mut pos)?.0
+          } else {
+            None
+          };
+          if flags & 3 != 0 {
+            // the element kind or reference type.
+            read_byte(s, &mut pos)?;
+          }
+          for slot in 0..read_leb(s, &mut pos)? {
+            let func = if flags & 4 != 0 {
+              const_expr(s, &mut pos)?.1
+            } else {
+              Some(read_leb(s, &mut pos)? as u32)
+            };
+            if let (Some(offset), Some(func)) = (offset, func) {
+              l.elements.push((table, offset as u64 + slot, func));
+            }
+          }
+        }
+      },
+      CODE_SECTION => {
+        for _ in 0..read_leb(s, &mut pos)? {
+          let size = read_leb(s, &mut pos)? as usize;
+          let start = pos;
+          skip(s, &mut pos, size)?;
+          l.functions.push((payload_start + start, size));
+        }
+      },
+      DATA_SECTION => {
+        for _ in 0..read_leb(s, &mut pos)? {
+          let flags = read_leb(s, &mut pos)?;
+          if flags == 2 {
+            read_leb(s, &mut pos)?;
+          }
+          let address = if flags & 1 == 0 {
+            const_expr(s, &mut pos)?.0
+          } else {
+            None
+          };
+          let size = read_leb(s, &mut pos)? as usize;
+          skip(s, &mut pos, size)?;
+          l.data.push((address, size));
+        }
+      },
+      CUSTOM_SECTION if wasm::custom_section_name(s) == Some(b"name") => {
+        read_name(s, &mut pos)?;
+        while pos < s.len() {
+          let sub = read_byte(s, &mut pos)?;
+          let len = read_leb(s, &mut pos)? as usize;
+          let mut sub_pos = pos;
+          skip(s, &mut pos, len)?;
+          let sub_payload = &s[..pos];
+          let names = match sub {
+            FUNCTION_NAMES => &mut l.function_names,
+            GLOBAL_NAMES => &mut l.global_names,
+            DATA_NAMES => &mut l.data_names,
+            _ => continue,
+          };
+          read_names(sub_payload, &mut sub_pos, names)?;
+        }
+      },
+      _ => {},
+    }
+  }
+  Ok(l)
+}
+
+fn name(names: &BTreeMap<u32, String>, idx: u32) -> String {
+  names.get(&idx)
+    .map(|name| json_string(name) )
+    .unwrap_or_else(|| "null".into() )
+}
+fn array(items: &[String]) -> String {
+  if items.len() == 0 {
+    "[]".into()
+  } else {
+    format!("[\n{}\n  ]", items.join(",\n"))
+  }
+}
+fn number(n: Option<i64>) -> String {
+  n.map(|n| format!("{}", n) ).unwrap_or_else(|| "null".into() )
+}
+
+fn to_json(l: &Layout) -> String {
+  let mut items = Vec::new();
+  for &(ref section, size) in l.sections.iter() {
+    items.push(format!("    {{\"name\": {}, \"size\": {}}}", json_string(section), size));
+  }
+  let mut out = format!("{{\n  \"sections\": {},\n", array(&items));
+
+  items = Vec::new();
+  for (i, &(offset, size)) in l.functions.iter().enumerate() {
+    let idx = l.imported_functions + i as u32;
+    items.push(format!("    {{\"index\": {}, \"name\": {}, \"offset\": {}, \"size\": {}}}",
+                       idx, name(&l.function_names, idx), offset, size));
+  }
+  out.push_str(&format!("  \"functions\": {},\n", array(&items)));
+
+  items = Vec::new();
+  for (idx, &(address, size)) in l.data.iter().enumerate() {
+    let idx = idx as u32;
+    items.push(format!("    {{\"index\": {}, \"name\": {}, \"address\": {}, \"size\": {}}}",
+                       idx, name(&l.data_names, idx), number(address), size));
+  }
+  out.push_str(&format!("  \"data\": {},\n", array(&items)));
+
+  items = Vec::new();
+  for (i, &(ty, mutable, init)) in l.globals.iter().enumerate() {
+    let idx = l.imported_globals + i as u32;
+    items.push(format!("    {{\"index\": {}, \"name\": {}, \"type\": {}, \"mutable\": {}, \
+                        \"init\": {}}}",
+                       idx, name(&l.global_names, idx), json_string(ty), mutable,
+                       number(init)));
+  }
+  out.push_str(&format!("  \"globals\": {},\n", array(&items)));
+
+  items = Vec::new();
+  for &(table, slot, func) in l.elements.iter() {
+    items.push(format!("    {{\"table\": {}, \"slot\": {}, \"function\": {}, \"name\": {}}}",
+                       table, slot, func, name(&l.function_names, func)));
+  }
+  out.push_str(&format!("  \"table\": {}\n}}\n", array(&items)));
+  out
+}
+
+/// Write the layout of the module at `module` to `out`.
+pub fn write_layout(module: &Path, out: &Path) -> Result<(), Box<Error>> {
+  let mut bytes = Vec::new();
+  File::open(module)?.read_to_end(&mut bytes)?;
+  let l = parse(&bytes[..])
+    .map_err(|e| format!("reading `{}`: {}", module.display(), e) )?;
+
+  File::create(out)?.write_all(to_json(&l).as_bytes())?;
+  Ok(())
+}
+
+const PAGE_SIZE: u64 = 65536;
+
+/// Where a linked module's memory puts things; `None` for what the module
+/// doesn't say, ie a global the linker didn't keep (or name).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MemoryLayout {
+  /// `__global_base`, else the first data segment's address.
+  pub global_base: Option<u64>,
+  /// `__data_end`, else the end of the last data segment.
+  pub data_end: Option<u64>,
+  pub heap_base: Option<u64>,
+  /// `__stack_pointer`'s initial value, ie the top of the stack.
+  pub stack_pointer: Option<u64>,
+  /// `(initial, max, imported)`, in pages.
+  pub memory: Option<(u64, Option<u64>, bool)>,
+}
+impl MemoryLayout {
+  pub fn of_module(module: &[u8]) -> Result<MemoryLayout, Box<Error>> {
+    let l = parse(module)?;
+    let global = |name: &str| {
+      let idx = l.global_names.iter()
+        .chain(l.global_exports.iter())
+        .find(|&(_, n)| n == name )
+        .map(|(&idx, _)| idx )?;
+      let &(ty, _, init) = l.globals.get(idx.checked_sub(l.imported_globals)? as usize)?;
+      match ty {
+        "i32" => init.map(|init| init as u32 as u64 ),
+        _ => init.map(|init| init as u64 ),
+      }
+    };
+    let active = || l.data.iter()
+      .filter_map(|&(address, size)| address.map(|a| (a as u32 as u64, size as u64) ) );
+
+    Ok(MemoryLayout {
+      global_base: global("__global_base")
+        .or_else(|| active().map(|(a, _)| a ).min() ),
+      data_end: global("__data_end")
+        .or_else(|| active().map(|(a, size)| a + size ).max() ),
+      heap_base: global("__heap_base"),
+      stack_pointer: global("__stack_pointer"),
+      memory: l.memory,
+    })
+  }
+  pub fn of_file(path: &Path) -> Result<MemoryLayout, Box<Error>> {
+    let mut bytes = Vec::new();
+    File::open(path)
+      .and_then(|mut f| f.read_to_end(&mut bytes) )
+      .map_err(|e| format!("reading `{}`: {}", path.display(), e) )?;
+    MemoryLayout::of_module(&bytes[..])
+      .map_err(|e| format!("reading `{}`: {}", path.display(), e).into() )
+  }
+
+  /// `(bottom, top)` of the stack. It's below the data if the linker put
+  /// it first (`--stack-first`), else between the data and the heap.
+  pub fn stack(&self) -> Option<(u64, u64)> {
+    let top = self.stack_pointer?;
+    match (self.global_base, self.data_end) {
+      (Some(base), _) if top <= base => Some((0, top)),
+      (_, Some(end)) if top >= end => Some((end, top)),
+      _ => None,
+    }
+  }
+
+  pub fn report(&self, module: &Path) -> String {
+    fn address(a: Option<u64>) -> String {
+      a.map(|a| format!("{} (0x{:x})", a, a) )
+        .unwrap_or_else(|| "unknown".into() )
+    }
+    let mut out = format!("memory layout of `{}`:\n", module.display());
+    out.push_str(&format!("  global base: {}\n", address(self.global_base)));
+    out.push_str(&format!("  data end:    {}\n", address(self.data_end)));
+    let stack = match (self.stack(), self.stack_pointer) {
+      (Some((bottom, top)), _) => {
+        let place = if Some(bottom) == self.data_end {
+          "after the data"
+        } else {
+          "before the data"
+        };
+        format!("0x{:x}..0x{:x} ({} bytes, {})", bottom, top, top - bottom, place)
+      },
+      (None, Some(top)) => format!("top at {}", address(Some(top))),
+      (None, None) => "unknown".into(),
+    };
+    out.push_str(&format!("  stack:       {}\n", stack));
+    out.push_str(&format!("  heap base:   {}\n", address(self.heap_base)));
+    let memory = match self.memory {
+      Some((min, max, imported)) => {
+        let mut memory = format!("{} pages ({} bytes)", min, min * PAGE_SIZE);
+        if let Some(max) = max {
+          memory.push_str(&format!(", max {} pages ({} bytes)", max, max * PAGE_SIZE));
+        }
+        if imported {
+          memory.push_str(", imported");
+        }
+        memory
+      },
+      None => "none".into(),
+    };
+    out.push_str(&format!("  memory:      {}\n", memory));
+    out
+  }
+}
+
+/// Print the memory layout of the module at `module`.
+pub fn print_memory_layout(module: &Path) -> Result<(), Box<Error>> {
+  print!("{}", MemoryLayout::of_file(module)?.report(module));
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn map_json() {
+    let module: &[u8] = &[
+      0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+      // type: () -> ()
+      0x01, 0x04, 0x01, 0x60, 0x00, 0x00,
+      // import: env.f
+      0x02, 0x09, 0x01, 0x03, b'e', b'n', b'v', 0x01, b'f', 0x00, 0x00,
+      // function, table, memory
+      0x03, 0x02, 0x01, 0x00,
+      0x04, 0x04, 0x01, 0x70, 0x00, 0x01,
+      0x05, 0x03, 0x01, 0x00, 0x01,
+      // global: mut i32 = 1024
+      0x06, 0x07, 0x01, 0x7f, 0x01, 0x41, 0x80, 0x08, 0x0b,
+      // element: table[0] = main
+      0x09, 0x07, 0x01, 0x00, 0x41, 0x00, 0x0b, 0x01, 0x01,
+      // code: main
+      0x0a, 0x04, 0x01, 0x02, 0x00, 0x0b,
+      // data: "hi" at 16
+      0x0b, 0x08, 0x01, 0x00, 0x41, 0x10, 0x0b, 0x02, b'h', b'i',
+      // name: functions and globals
+      0x00, 0x18, 0x04, b'n', b'a', b'm', b'e',
+      0x01, 0x0a, 0x02, 0x00, 0x01, b'f', 0x01, 0x04, b'm', b'a', b'i', b'n',
+      0x07, 0x05, 0x01, 0x00, 0x02, b's', b'p',
+    ];
+    assert_eq!(to_json(&parse(module).unwrap()), r#"{
+  "sections": [
+    {"name": "type", "size": 6},
+    {"name": "import", "size": 11},
+    {"name": "function", "size": 4},
+    {"name": "table", "size": 6},
+    {"name": "memory", "size": 5},
+    {"name": "global", "size": 9},
+    {"name": "element", "size": 9},
+    {"name": "code", "size": 6},
+    {"name": "data", "size": 10},
+    {"name": "name", "size": 26}
+  ],
+  "functions": [
+    {"index": 1, "name": "main", "offset": 62, "size": 2}
+  ],
+  "data": [
+    {"index": 0, "name": null, "address": 16, "size": 2}
+  ],
+  "globals": [
+    {"index": 0, "name": "sp", "type": "i32", "mutable": true, "init": 1024}
+  ],
+  "table": [
+    {"table": 0, "slot": 0, "function": 1, "name": "main"}
+  ]
+}
+"#);
+  }
+
+  #[test]
+  fn memory_layout() {
+    let module: &[u8] = &[
+      0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+      // memory: 2 pages, max 16
+      0x05, 0x04, 0x01, 0x01, 0x02, 0x10,
+      // globals: mut i32 = 66576, i32 = 1040, i32 = 66576
+      0x06, 0x15, 0x03,
+      0x7f, 0x01, 0x41, 0x90, 0x88, 0x04, 0x0b,
+      0x7f, 0x00, 0x41, 0x90, 0x08, 0x0b,
+      0x7f, 0x00, 0x41, 0x90, 0x88, 0x04, 0x0b,
+      // export: the third global, as __heap_base
+      0x07, 0x0f, 0x01, 0x0b, b'_', b'_', b'h', b'e', b'a', b'p', b'_', b'b', b'a', b's',
+      b'e', 0x03, 0x02,
+      // data: "hi" at 1024
+      0x0b, 0x09, 0x01, 0x00, 0x41, 0x80, 0x08, 0x0b, 0x02, b'h', b'i',
+      // name: the first two globals
+      0x00, 0x25, 0x04, b'n', b'a', b'm', b'e',
+      0x07, 0x1e, 0x02,
+      0x00, 0x0f, b'_', b'_', b's', b't', b'a', b'c', b'k', b'_', b'p', b'o', b'i', b'n',
+      b't', b'e', b'r',
+      0x01, 0x0a, b'_', b'_', b'd', b'a', b't', b'a', b'_', b'e', b'n', b'd',
+    ];
+    let layout = MemoryLayout::of_module(module).unwrap();
+    assert_eq!(layout.stack(), Some((1040, 66576)));
+    assert_eq!(layout.report(Path::new("out.wasm")),
+               "memory layout of `out.wasm`:\n\
+                \x20 global base: 1024 (0x400)\n\
+                \x20 data end:    1040 (0x410)\n\
+                \x20 stack:       0x410..0x10410 (65536 bytes, after the data)\n\
+                \x20 heap base:   66576 (0x10410)\n\
+                \x20 memory:      2 pages (131072 bytes), max 16 pages (1048576 bytes)\n");
+
+    // `--stack-first`.
+    let layout = MemoryLayout {
+      global_base: Some(65536),
+      data_end: Some(65538),
+      stack_pointer: Some(65536),
+      ..layout
+    };
+    assert_eq!(layout.stack(), Some((0, 65536)));
+  }
+
+  #[test]
+  fn body_past_section_end() {
+    let mut module = wasm::WASM_MAGIC.to_vec();
+    module.extend_from_slice(&[1, 0, 0, 0]);
+    // code: one 127 byte body, in a 3 byte section.
+    module.extend_from_slice(&[0x0a, 0x03, 0x01, 0x7f, 0x0b]);
+    let err = parse(&module).unwrap_err();
+    assert_eq!(format!("{}", err), "unexpected end of module");
+
+    // data: "hi", claiming `u64::MAX` bytes.
+    let mut module = wasm::WASM_MAGIC.to_vec();
+    module.extend_from_slice(&[1, 0, 0, 0]);
+    module.extend_from_slice(&[0x0b, 0x11, 0x01, 0x00, 0x41, 0x00, 0x0b,
+                               0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01,
+                               b'h', b'i']);
+    let err = parse(&module).unwrap_err();
+    assert_eq!(format!("{}", err), "unexpected end of module");
+  }
+}
@@ -1,14 +1,174 @@
 
+use std::collections::BTreeSet;
 use std::error::Error;
 use std::path::{Path, PathBuf};
 
 use util::{Arch, CommandQueue, regex, };
-use util::toolchain::{WasmToolchain, WasmToolchainTool, };
+use util::command_queue::{InputArgsTransformResult, StderrFilter, };
+use util::toolchain::{ThreadModel, WasmToolchain, WasmToolchainTool, };
 
 pub use util::ldtools::{Input, };
 
+pub mod deplibs;
+pub mod depfile;
+pub mod diagnostics;
+pub mod duplicates;
+pub mod layout;
+pub mod linkorder;
+pub mod relink;
+pub mod rename;
+pub mod reproduce;
+pub mod runner;
+pub mod size;
+pub mod translate;
+pub mod sourcemap;
+pub mod symfile;
+pub mod undefined;
+pub mod verify;
+pub mod version_script;
+mod wasm;
+
 #[macro_use] extern crate wasm_driver_utils as util;
 #[macro_use] extern crate lazy_static;
+extern crate glob;
+extern crate opt_driver;
+extern crate tar;
+
+/// wasm-ld requires a maximum for shared memories.
+const SHARED_MEMORY_MAX: usize = 1 << 31;
+
+/// Extensions of the files taken from a directory input.
+const DIR_INPUT_EXTENSIONS: &'static [&'static str] = &["o", "obj", "bc", "a", "lo"];
+
+/// The `-l` which finds the library at `path`, ie `-lc` for `.../libc.a`.
+fn library_request(path: &Path) -> String {
+  let name = path.file_name().unwrap()
+    .to_str()
+    .unwrap();
+  let name = if name.starts_with("lib") { &name[3..] } else { name };
+  let name = if name.ends_with(".so") {
+    &name[..name.len() - 3]
+  } else if name.ends_with(".a") {
+    &name[..name.len() - 2]
+  } else {
+    name
+  };
+  format!("-l{}", name)
+}
+
+/// Expand a directory (its objects and archives) or a glob which the shell
+/// didn't expand (ie on Windows, or from a response file). Sorted, so the
+/// link order doesn't depend on the filesystem.
+fn expand_path_input(path: &Path) -> Result<Option<Vec<PathBuf>>, Box<Error>> {
+  use std::fs::read_dir;
+
+  let mut expanded = Vec::new();
+  if path.is_dir() {
+    for entry in read_dir(path)? {
+      let entry = entry?.path();
+      let wanted = entry.extension()
+        .and_then(|ext| ext.to_str() )
+        .map(|ext| DIR_INPUT_EXTENSIONS.contains(&ext) )
+        .unwrap_or(false);
+      if wanted && entry.is_file() {
+        expanded.push(entry);
+      }
+    }
+  } else if !path.exists() {
+    let pattern = match path.to_str() {
+      Some(pattern) if pattern.contains(|c| c == '*' || c == '?' || c == '[' ) => pattern,
+      _ => return Ok(None),
+    };
+    for entry in glob::glob(pattern)? {
+      expanded.push(entry?);
+    }
+    if expanded.len() == 0 {
+      return Err(format!("`{}` didn't match any files", pattern).into());
+    }
+  } else {
+    return Ok(None);
+  }
+
+  expanded.sort();
+  Ok(Some(expanded))
+}
+
+/// How wasm-ld is told to produce a module for our loader.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum Loader {
+  /// `--modkit-loader`, which only modkit's lld knows.
+  Modkit,
+  /// Nothing, for stock wasm-ld.
+  None,
+  /// Some other flag for wasm-ld.
+  Flag(String),
+}
+impl Loader {
+  /// `loader` from the config file, or modkit.
+  pub fn configured() -> Result<Loader, Box<Error>> {
+    match util::config::get("loader") {
+      Some(loader) => Ok(loader.parse()?),
+      None => Ok(Loader::Modkit),
+    }
+  }
+  pub fn flag(&self) -> Option<&str> {
+    match self {
+      &Loader::Modkit => Some("--modkit-loader"),
+      &Loader::None => None,
+      &Loader::Flag(ref flag) => Some(&flag[..]),
+    }
+  }
+}
+impl ::std::str::FromStr for Loader {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Loader, String> {
+    match s {
+      "modkit" => Ok(Loader::Modkit),
+      "none" => Ok(Loader::None),
+      flag if flag.starts_with("-") => Ok(Loader::Flag(flag.into())),
+      _ => Err(format!("unknown loader `{}`; expected `modkit`, `none` or a wasm-ld flag",
+                       s)),
+    }
+  }
+}
+
+/// wasm-ld flags which apply to the inputs after them, so they're kept in
+/// order with the inputs.
+pub const POSITIONAL_FLAGS: &'static [&'static str] = &[
+  "--start-group", "--end-group",
+  "-Bstatic", "-Bdynamic",
+  "--as-needed", "--no-as-needed",
+  "--whole-archive", "--no-whole-archive",
+];
+
+/// Bundles of size related options, for `--size-preset`. Explicitly given
+/// options take precedence.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum SizePreset {
+  /// `-Os`, `--gc-sections`, `--merge-data-segments` and `wasm-opt -Os`.
+  Small,
+  /// `Small`, but with `-Oz` and identical code folding.
+  Smallest,
+}
+impl SizePreset {
+  pub fn optimize(&self) -> util::OptimizationGoal {
+    match self {
+      &SizePreset::Small => util::OptimizationGoal::Balanced,
+      &SizePreset::Smallest => util::OptimizationGoal::Size,
+    }
+  }
+}
+impl ::std::str::FromStr for SizePreset {
+  type Err = String;
+  fn from_str(s: &str) -> Result<SizePreset, String> {
+    match s {
+      "small" => Ok(SizePreset::Small),
+      "smallest" => Ok(SizePreset::Smallest),
+      _ => Err(format!("unknown size preset `{}`; expected `small` or `smallest`",
+                       s)),
+    }
+  }
+}
 
 #[derive(Clone, Debug)]
 pub struct Invocation {
@@ -37,28 +197,118 @@ pub struct Invocation {
   pub lto: bool,
   pub strip: util::StripMode,
 
+  /// `--eh=<mode>`: the C++ runtime is looked for in the sysroot's
+  /// libraries for this exception handling mode first.
   pub eh_mode: util::EhMode,
 
   pub arch: Option<Arch>,
 
   pub disabled_passes: Vec<String>,
+  /// `opt` passes to run over the merged bitcode objects before linking.
+  pub passes: Vec<String>,
 
   bitcode_inputs: Vec<Input>,
   native_inputs: Vec<Input>,
   has_native_inputs: bool,
   has_bitcode_inputs: bool,
+  /// Do we have any already compiled wasm objects (or archives of them)?
+  has_wasm_inputs: bool,
 
   output: Option<PathBuf>,
 
   pub entry: Option<String>,
   /// symbols which should be force-exported.
   pub exports: Vec<String>,
+  /// Export every default visibility symbol (`--export-dynamic`), so code
+  /// built with `-fvisibility=hidden` needs no `--export` list.
+  pub export_visible: bool,
+  /// `None` means `--gc-sections` at `-O2` and up (or `-Os`/`-Oz`) for
+  /// non-relocatable links.
+  pub gc_sections: Option<bool>,
+  /// symbols which must survive `--gc-sections`. wasm-ld only roots the
+  /// entry point and exports, so these are passed as `--export` too; ie a
+  /// kept symbol is also visible to the embedder. Use `--export` when that
+  /// is the point, and `--keep-symbol` when it's only about retention.
+  pub keep_symbols: Vec<String>,
+  /// `-u`/`--undefined`: symbols to pull in from archives, even if
+  /// nothing references them.
+  pub undefined: Vec<String>,
+  /// `--defsym`: `(name, value)` symbols for wasm-ld to define, the value
+  /// being a number or another symbol.
+  pub defsyms: Vec<(String, String)>,
   global_base: Option<usize>,
+  /// Run global ctors before the entry point. `None` means yes for
+  /// non-relocatable links.
+  pub init_ctors: Option<bool>,
   pub import_memory: bool,
   pub import_table: bool,
   pub growable_table_import: bool,
 
   pub trace: bool,
+  /// Check each input is well formed before linking.
+  pub verify_inputs: bool,
+  /// Check the object inputs for duplicate strong symbols before linking.
+  /// See `duplicates`.
+  pub check_duplicate_symbols: bool,
+  /// The canonical paths of the object inputs, so one given twice is only
+  /// linked once.
+  input_objects: BTreeSet<PathBuf>,
+  /// Reorder (and repeat) the archive inputs if, in the given order, they
+  /// wouldn't satisfy each other's undefined symbols. See `linkorder`.
+  pub auto_link_order: bool,
+  /// Export what its `global:` patterns match. See `version_script`.
+  pub version_script: Option<PathBuf>,
+  /// Add the libraries named by the bitcode objects'
+  /// `llvm.dependent-libraries`. See `deplibs`.
+  pub dependent_libraries: bool,
+  /// Reuse what's known about unchanged inputs from the last link. See
+  /// `relink`.
+  pub fast_relink: bool,
+  /// Loaded by `enqueue_commands`, once the output is known.
+  relink_cache: Option<relink::Cache>,
+  /// With `--fast-relink`, the inputs, in order, until the cache is loaded;
+  /// their `-l`s are looked up through it.
+  deferred_inputs: Vec<Input>,
+  /// Write a depfile for the link here. See `depfile`.
+  pub link_depfile: Option<PathBuf>,
+  /// Every file read while expanding the inputs, ie linker scripts, for
+  /// `link_depfile`.
+  input_files: Vec<PathBuf>,
+  /// Write a JSON manifest of the output's imports, exports, memory and
+  /// table here. See `symfile`.
+  pub sym_file: Option<PathBuf>,
+  /// `--emit-map-json`: write the output's layout to `<output>.map.json`.
+  /// See `layout`.
+  pub emit_map_json: bool,
+  /// Print the output's global base, data end, stack and heap base after
+  /// linking. See `layout::MemoryLayout`.
+  pub print_memory_layout: bool,
+  /// Put `util::manifest`'s toolchain manifest in the output's
+  /// `driver.toolchain` custom section.
+  pub embed_toolchain_manifest: bool,
+  /// Write a source map, built from the output's DWARF line table, here.
+  /// See `sourcemap`.
+  pub source_map: Option<PathBuf>,
+  /// Prefix of the `sourceMappingURL` the output gets with `source_map`.
+  pub source_map_base: Option<String>,
+  /// `(from, to)` import (field) names to rename in the output. See `rename`.
+  pub import_renames: Vec<(String, String)>,
+  /// `(from, to)` export names to rename in the output.
+  pub export_renames: Vec<(String, String)>,
+  /// Write a script which runs the output next to it. See `runner`.
+  pub emit_runner: Option<runner::Runner>,
+  /// `None` means the config file's `loader`, or modkit.
+  pub loader: Option<Loader>,
+  pub print_targets: bool,
+  /// `--print-link-command`: print wasm-ld's command line instead of
+  /// running it. Steps before the link still run.
+  pub print_link_command: bool,
+  /// `--trace-symbol`/`-y`, forwarded to wasm-ld.
+  pub trace_symbols: Vec<String>,
+  /// `--reproduce=<tar>`, forwarded to wasm-ld.
+  pub reproduce: Option<PathBuf>,
+  /// `--driver-reproduce=<tar>`; see `reproduce`.
+  pub driver_reproduce: Option<PathBuf>,
   pub verbose: bool,
 
   pub search_paths: Vec<PathBuf>,
@@ -68,7 +318,18 @@ pub struct Invocation {
   pub ld_flags: Vec<String>,
   ld_flags_native: Vec<String>,
 
+  /// Commands run, in order, on the linked module. Each is invoked as
+  /// `<cmd> <input.wasm> -o <output.wasm>` and must write a replacement
+  /// module to the output path. `--post-link-tool` is split like a shell
+  /// would.
+  pub post_link_tools: Vec<Vec<String>>,
+
   trans_flags: Vec<String>,
+  /// If set, and every input file is a bitcode or wasm object, codegen of
+  /// the bitcode is done by `wasm-translate` with this many threads instead
+  /// of by wasm-ld's LTO.
+  pub translate_threads: Option<usize>,
+  pub size_preset: Option<SizePreset>,
 
   // detect mismatched --start-group && --end-group
   grouped: usize,
@@ -113,22 +374,56 @@ impl Invocation {
       arch: Default::default(),
 
       disabled_passes: Default::default(),
+      passes: Default::default(),
 
       bitcode_inputs: Default::default(),
       native_inputs: Default::default(),
       has_native_inputs: false,
       has_bitcode_inputs: false,
+      has_wasm_inputs: false,
 
       output: Default::default(),
 
       entry: None,
       exports: Default::default(),
+      export_visible: false,
+      gc_sections: None,
+      keep_symbols: Default::default(),
+      undefined: Default::default(),
+      defsyms: Default::default(),
       global_base: None,
+      init_ctors: None,
       import_memory: false,
       import_table: false,
       growable_table_import: false,
 
       trace: false,
+      verify_inputs: false,
+      check_duplicate_symbols: false,
+      input_objects: Default::default(),
+      auto_link_order: false,
+      version_script: None,
+      dependent_libraries: false,
+      fast_relink: false,
+      relink_cache: None,
+      deferred_inputs: vec![],
+      link_depfile: None,
+      input_files: vec![],
+      sym_file: None,
+      emit_map_json: false,
+      print_memory_layout: false,
+      embed_toolchain_manifest: false,
+      source_map: None,
+      source_map_base: None,
+      import_renames: Default::default(),
+      export_renames: Default::default(),
+      emit_runner: None,
+      loader: None,
+      print_targets: false,
+      print_link_command: false,
+      trace_symbols: Default::default(),
+      reproduce: None,
+      driver_reproduce: None,
       verbose: false,
 
       search_paths: Default::default(),
@@ -138,7 +433,11 @@ impl Invocation {
       ld_flags: Default::default(),
       ld_flags_native: Default::default(),
 
+      post_link_tools: Default::default(),
+
       trans_flags: Default::default(),
+      translate_threads: None,
+      size_preset: None,
 
       grouped: 0,
     }
@@ -166,6 +465,15 @@ impl Invocation {
   pub fn has_native_inputs(&self) -> bool {
     self.has_native_inputs
   }
+  pub fn has_wasm_inputs(&self) -> bool {
+    self.has_wasm_inputs
+  }
+  /// Bitcode and wasm objects together, ie Rust objects with C bitcode.
+  /// The bitcode is either compiled by wasm-ld's LTO or, with
+  /// `--translate-threads`, precompiled by `wasm-translate`.
+  pub fn has_mixed_inputs(&self) -> bool {
+    self.has_bitcode_inputs && self.has_wasm_inputs
+  }
 
   pub fn get_output(&self) -> PathBuf {
     self.output
@@ -240,6 +548,68 @@ impl Invocation {
     }
   }
 
+  fn apply_size_preset(&mut self) {
+    let preset = match self.size_preset {
+      Some(preset) => preset,
+      None => return,
+    };
+
+    if self.optimize.is_none() {
+      self.optimize = Some(preset.optimize());
+    }
+    if self.relocatable {
+      // the rest only makes sense for the final link.
+      return;
+    }
+    if self.gc_sections.is_none() {
+      self.gc_sections = Some(true);
+    }
+    // first, so an explicit `--no-merge-data-segments` wins.
+    self.bitcode_inputs.insert(0, Input::Flag("--merge-data-segments".into()));
+    if preset == SizePreset::Smallest {
+      self.ld_flags.push("--icf=all".into());
+    }
+    let wasm_opt = self.tc.binaryen_tool("wasm-opt");
+    self.post_link_tools.push(vec![format!("{}", wasm_opt.display()),
+                                   format!("{}", self.optimize.unwrap())]);
+  }
+
+  /// Should we pass `--gc-sections` to wasm-ld?
+  pub fn gc_sections(&self) -> bool {
+    use util::OptimizationGoal;
+
+    if let Some(b) = self.gc_sections { return b; }
+    if self.relocatable { return false; }
+
+    match self.optimize {
+      Some(OptimizationGoal::Speed(n)) => n >= 2,
+      Some(OptimizationGoal::Balanced) |
+      Some(OptimizationGoal::Size) => true,
+      None => false,
+    }
+  }
+
+  pub fn init_ctors(&self) -> bool {
+    self.init_ctors.unwrap_or(!self.relocatable)
+  }
+  /// The entry point to give wasm-ld. If ctors need to be run and the
+  /// entry is musl's, the `_start_ctors` shim from `crtctors.o` is used
+  /// (returned as the second element). Otherwise, `__wasm_call_ctors` is
+  /// exported for the embedder to call.
+  fn ctors_entry(&self) -> (Option<String>, Option<PathBuf>) {
+    if self.relocatable || !self.init_ctors() {
+      return (self.entry.clone(), None);
+    }
+
+    let shim = self.tc.sysroot_lib().join("crtctors.o");
+    match self.entry {
+      Some(ref entry) if entry == "_start_c" && shim.exists() => {
+        (Some("_start_ctors".into()), Some(shim))
+      },
+      _ => (self.entry.clone(), None),
+    }
+  }
+
   pub fn add_search_path<T>(&mut self, p: T)
     where T: Into<PathBuf>,
   {
@@ -257,12 +627,42 @@ impl Invocation {
   /// Add a non-flag input.
   pub fn add_input(&mut self, input: Input) -> Result<(), Box<Error>> {
     use util::ldtools::*;
-    let expanded = expand_input(input, &self.search_paths[..],
-                                self.static_input)?;
+    use util::filetype;
+
+    if self.fast_relink && self.relink_cache.is_none() {
+      self.deferred_inputs.push(input);
+      return Ok(());
+    }
+    if let Input::File(ref path) = input {
+      if let Some(paths) = expand_path_input(path)? {
+        for path in paths.into_iter() {
+          self.add_input(Input::File(path))?;
+        }
+        return Ok(());
+      }
+    }
+
+    let expanded = self.expand_input(input)?;
     'outer: for input in expanded.into_iter() {
+      if let Input::File(ref path) = input {
+        if self.is_duplicate_object(path) {
+          util::diag::warning(&format!("`{}` is already an input; ignoring the duplicate",
+                                       path.display()));
+          continue 'outer;
+        }
+      }
       let into = 'inner: loop {
-        let _file: &PathBuf = match &input {
+        let file: &PathBuf = match &input {
           &Input::Library(_, ref p) => p,
+          &Input::Flag(ref flag) if POSITIONAL_FLAGS.contains(&&flag[..]) => {
+            // its arg handler added it; it applies to the inputs after it.
+            match &flag[..] {
+              "-Bstatic" => { self.static_input = true; },
+              "-Bdynamic" => { self.static_input = false; },
+              _ => {},
+            }
+            break 'inner &mut self.bitcode_inputs;
+          },
           &Input::Flag(ref flag) => {
             let flags = vec![flag.clone()];
             util::process_invocation_args(self, flags,
@@ -272,6 +672,25 @@ impl Invocation {
           &Input::File(ref path) => path,
         };
 
+        match filetype::file_type(file) {
+          Ok(Some(filetype::Type::Wasm)) |
+          Ok(Some(filetype::Type::Object(filetype::Subtype::Wasm))) |
+          Ok(Some(filetype::Type::Archive(filetype::Subtype::Wasm))) => {
+            self.has_wasm_inputs = true;
+            break 'inner &mut self.bitcode_inputs;
+          },
+          Ok(Some(filetype::Type::Object(filetype::Subtype::Elf))) |
+          Ok(Some(filetype::Type::Archive(filetype::Subtype::Elf))) => {
+            return Err(format!("`{}` contains native (ELF) code, which can't be linked into a wasm module",
+                               file.display()).into());
+          },
+          Ok(Some(filetype::Type::Pexe)) => {
+            return Err(format!("`{}` is a PNaCl pexe, not a linkable object",
+                               file.display()).into());
+          },
+          _ => {},
+        }
+
         self.has_bitcode_inputs = true;
         break 'inner &mut self.bitcode_inputs;
       };
@@ -281,10 +700,401 @@ impl Invocation {
     Ok(())
   }
 
+  /// Has the object at `path` (by its canonical path) been added already?
+  /// Archives can be given repeatedly, so they never are.
+  fn is_duplicate_object(&mut self, path: &Path) -> bool {
+    use util::filetype::{file_type, Type};
+
+    match file_type(path) {
+      Ok(Some(Type::Object(_))) | Ok(Some(Type::Wasm)) => {},
+      _ => { return false; },
+    }
+    let canonical = path.canonicalize()
+      .unwrap_or_else(|_| path.to_path_buf() );
+    !self.input_objects.insert(canonical)
+  }
+
+  /// `ldtools::expand_input`, through the `--fast-relink` cache for `-l`s.
+  fn expand_input(&mut self, input: Input) -> Result<Vec<Input>, Box<Error>> {
+    use util::ldtools::expand_input_reading;
+
+    let lib = match input {
+      Input::Library(abs, ref name) if self.fast_relink => Some((abs, name.clone())),
+      _ => None,
+    };
+    if let Some((abs, ref name)) = lib {
+      let cached = self.relink_cache.as_ref().unwrap()
+        .resolution(abs, name, &self.search_paths[..], self.static_input);
+      if let Some((expanded, read)) = cached {
+        self.input_files.extend(read);
+        return Ok(expanded);
+      }
+    }
+
+    let mut read = vec![];
+    let expanded = expand_input_reading(input, &self.search_paths[..],
+                                        self.static_input, &mut read)?;
+    if let Some((abs, ref name)) = lib {
+      self.relink_cache.as_mut().unwrap()
+        .set_resolution(abs, name, &self.search_paths[..], self.static_input,
+                        &expanded[..], &read[..]);
+    }
+    self.input_files.extend(read);
+    Ok(expanded)
+  }
+  /// Load the `--fast-relink` cache for the output, and add the inputs
+  /// which were waiting for it. The output isn't known until the commands
+  /// are enqueued (ie a nested link's is overridden).
+  fn load_relink_cache(&mut self) -> Result<(), Box<Error>> {
+    use std::mem::replace;
+
+    self.relink_cache = Some(relink::Cache::load(&self.get_output()));
+    for input in replace(&mut self.deferred_inputs, vec![]).into_iter() {
+      self.add_input(input)?;
+    }
+    Ok(())
+  }
+  fn relink_cache(&mut self) -> &mut relink::Cache {
+    self.relink_cache.as_mut()
+      .expect("the relink cache is loaded by `enqueue_commands`")
+  }
+
+  /// Export the symbols the object inputs define which `script` says are
+  /// global.
+  fn add_version_script_exports(&mut self, script: &Path) -> Result<(), Box<Error>> {
+    use util::filetype::{file_type, Type};
+
+    let script = version_script::VersionScript::from_file(script)?;
+    let objects: Vec<PathBuf> = self.bitcode_inputs.iter()
+      .filter_map(|input| match input {
+        &Input::File(ref p) => Some(p.clone()),
+        _ => None,
+      })
+      .filter(|p| match file_type(p) {
+        Ok(Some(Type::Object(_))) | Ok(Some(Type::Wasm)) => true,
+        _ => false,
+      })
+      .collect();
+    let nm = self.tc.llvm_tool("llvm-nm");
+    let mut scratch = relink::Cache::default();
+    let cache = if self.fast_relink {
+      self.relink_cache()
+    } else {
+      &mut scratch
+    };
+    let mut defined = BTreeSet::new();
+    for object in objects.iter() {
+      for (_, symbols) in linkorder::symbols(&nm, object, cache)? {
+        defined.extend(symbols.defined);
+      }
+    }
+    for export in script.exports(defined.iter()) {
+      if !self.exports.contains(&export) {
+        self.exports.push(export);
+      }
+    }
+    Ok(())
+  }
+
+  /// Add the dependent libraries of the bitcode object inputs which aren't
+  /// already inputs. Like lld's ELF port, a name is taken as a file if
+  /// there's one, then as `-l:<name>` if a search dir has it, and otherwise
+  /// as `-l<name>`.
+  fn add_dependent_libraries(&mut self) -> Result<(), Box<Error>> {
+    use util::filetype::{file_type, Type, Subtype};
+
+    let objects: Vec<PathBuf> = self.bitcode_inputs.iter()
+      .filter_map(|input| match input {
+        &Input::File(ref p) => Some(p.clone()),
+        _ => None,
+      })
+      .filter(|p| match file_type(p) {
+        Ok(Some(Type::Object(Subtype::Bitcode))) => true,
+        _ => false,
+      })
+      .collect();
+    if objects.len() == 0 { return Ok(()); }
+
+    let llvm_dis = self.tc.llvm_tool("llvm-dis");
+    let mut seen = BTreeSet::new();
+    for object in objects.into_iter() {
+      let libs = match deplibs::dependent_libraries(&llvm_dis, &object) {
+        Ok(libs) => libs,
+        Err(e) => {
+          util::diag::warning(&format!("ignoring the dependent libraries of `{}`: {}",
+                                       object.display(), e));
+          continue;
+        },
+      };
+      for lib in libs.into_iter() {
+        if !seen.insert(lib.clone()) { continue; }
+
+        let path = PathBuf::from(&lib);
+        let input = if path.is_file() {
+          let present = self.bitcode_inputs.iter()
+            .any(|input| match input {
+              &Input::File(ref p) => p == &path,
+              _ => false,
+            });
+          if present { continue; }
+          Input::File(path)
+        } else if self.search_paths.iter().any(|dir| dir.join(&path).is_file() ) {
+          Input::Library(true, path)
+        } else {
+          Input::Library(false, path)
+        };
+        self.add_input(input)
+          .map_err(|e| format!("`{}` depends on `{}`: {}", object.display(), lib, e) )?;
+      }
+    }
+    Ok(())
+  }
+
   fn check_native_allowed(&self) -> Result<(), Box<Error>> {
     Err("native code is never allowed".into())
   }
 
+  /// Merge the bitcode object inputs into `out` for `--emit-llvm`. Bitcode
+  /// archives are left out, since which of their members would be pulled
+  /// in isn't known until wasm-ld runs.
+  fn enqueue_llvm_link(&self, out: PathBuf, queue: &mut CommandQueue<Self>)
+    -> Result<(), Box<Error>>
+  {
+    use std::process::Command;
+    use util::filetype::{file_type, Type, Subtype};
+
+    let mut cmd = Command::new(self.tc.llvm_tool("llvm-link"));
+    let mut any = false;
+    for input in self.bitcode_inputs.iter() {
+      match input {
+        &Input::File(ref p) | &Input::Library(true, ref p) => {
+          if let Ok(Some(Type::Object(Subtype::Bitcode))) = file_type(p) {
+            cmd.arg(p);
+            any = true;
+          }
+        },
+        _ => {},
+      }
+    }
+    if !any {
+      return Err("`--emit-llvm` given, but there are no bitcode object inputs".into());
+    }
+    cmd.arg("-o").arg(out);
+
+    let link = queue.enqueue_simple_external(Some("llvm-link"), cmd, None);
+    link.prev_outputs = false;
+    link.output_override = false;
+    link.heavy = true;
+
+    Ok(())
+  }
+
+  /// If any passes were requested, merge the bitcode objects with
+  /// llvm-link and run `opt` over the result; returns the objects replaced
+  /// by the optimized module, which is left in `prev_outputs`. With
+  /// `run_passes_separately`, each pass gets its own `opt` run.
+  fn enqueue_opt_passes(&mut self, queue: &mut CommandQueue<Self>)
+    -> Result<Option<Vec<PathBuf>>, Box<Error>>
+  {
+    use std::process::Command;
+    use util::filetype::{file_type, Type, Subtype};
+
+    let passes: Vec<String> = self.passes.iter()
+      .filter(|pass| !self.disabled_passes.contains(pass) )
+      .cloned()
+      .collect();
+    if passes.len() == 0 { return Ok(None); }
+
+    let bitcode: Vec<PathBuf> = self.bitcode_inputs.iter()
+      .filter_map(|input| match input {
+        &Input::File(ref p) => Some(p),
+        _ => None,
+      })
+      .filter(|p| match file_type(p) {
+        Ok(Some(Type::Object(Subtype::Bitcode))) => true,
+        _ => false,
+      })
+      .cloned()
+      .collect();
+    if bitcode.len() == 0 { return Ok(None); }
+
+    let mut cmd = Command::new(self.tc.llvm_tool("llvm-link"));
+    cmd.args(bitcode.iter());
+    let link = queue.enqueue_simple_external(Some("llvm-link"), cmd,
+                                             Some("-o".into()));
+    link.prev_outputs = false;
+    link.intermediate_name = Some("linked.bc".into());
+    link.heavy = true;
+
+    let runs: Vec<Vec<String>> = if self.run_passes_separately {
+      passes.into_iter().map(|pass| vec![pass] ).collect()
+    } else {
+      vec![passes]
+    };
+    for (idx, run) in runs.into_iter().enumerate() {
+      let mut opt = opt_driver::Invocation::new_with_toolchain(self.tc.clone());
+      opt.passes = run;
+      let opt = queue.enqueue_simple_tool(Some("opt"), opt);
+      opt.intermediate_name = Some(format!("opt-{}.bc", idx).into());
+      opt.heavy = true;
+    }
+
+    self.bitcode_inputs.retain(|input| match input {
+      &Input::File(ref p) => !bitcode.contains(p),
+      _ => true,
+    });
+
+    Ok(Some(bitcode))
+  }
+
+  /// The bitcode objects to hand to `wasm-translate`, if we're offloading.
+  fn offloaded_bitcode(&self) -> Option<Vec<PathBuf>> {
+    use util::filetype::{file_type, Type, Subtype};
+
+    self.translate_threads?;
+
+    let mut out = vec![];
+    for input in self.bitcode_inputs.iter() {
+      if let &Input::File(ref path) = input {
+        match file_type(path) {
+          Ok(Some(Type::Object(Subtype::Bitcode))) => {
+            out.push(path.clone());
+          },
+          // already compiled, so these go straight to wasm-ld.
+          Ok(Some(Type::Wasm)) |
+          Ok(Some(Type::Object(Subtype::Wasm))) => {},
+          _ => { return None; },
+        }
+      }
+    }
+
+    if out.len() == 0 {
+      None
+    } else {
+      Some(out)
+    }
+  }
+
+  /// wasm-ld's command line (starting with wasm-ld itself) for the inputs
+  /// as they are, less `-o` and whatever earlier steps produce (ie the
+  /// translated or optimized bitcode). `lto_codegen` is whether wasm-ld
+  /// compiles the bitcode, rather than `wasm-translate`.
+  pub fn link_command(&self, loader: &Loader, lto_codegen: bool) -> Vec<String> {
+    let mut cmd = vec![format!("{}", self.tc.llvm_tool("wasm-ld").display())];
+    if let Some(flag) = loader.flag() {
+      cmd.push(flag.into());
+    }
+    if self.trace {
+      cmd.push("--trace".into());
+    }
+    for sym in self.trace_symbols.iter() {
+      cmd.push(format!("--trace-symbol={}", sym));
+    }
+    if let Some(ref tar) = self.reproduce {
+      cmd.push(format!("--reproduce={}", tar.display()));
+    }
+
+    if self.relocatable {
+      cmd.push("--relocatable".into());
+    } else if self.entry.is_none() {
+      cmd.push("--no-entry".into());
+    }
+    cmd.extend(self.ld_flags.iter().cloned());
+    let (entry, ctors_shim) = self.ctors_entry();
+    if let Some(entry) = entry {
+      cmd.push("--entry".into());
+      cmd.push(entry);
+    }
+    if let Some(ref shim) = ctors_shim {
+      cmd.push(format!("{}", shim.display()));
+    } else if self.init_ctors() && !self.relocatable {
+      cmd.push("--export=__wasm_call_ctors".into());
+    }
+    if let Some(base) = self.global_base {
+      cmd.push(format!("--global-base={}", base));
+    }
+    if self.import_memory {
+      cmd.push("--import-memory".into());
+    }
+    if self.import_table {
+      cmd.push("--import-table".into());
+    }
+    if self.tc.threads == ThreadModel::Atomics && !self.relocatable {
+      // workers need to be handed the same memory.
+      if !self.import_memory {
+        cmd.push("--import-memory".into());
+      }
+      cmd.push("--shared-memory".into());
+      cmd.push(format!("--max-memory={}", SHARED_MEMORY_MAX));
+    }
+    if self.verbose {
+      cmd.push("--verbose".into());
+    }
+    if self.growable_table_import {
+      cmd.push("--growable-table-import".into());
+    }
+    match self.strip {
+      util::StripMode::None => {},
+      util::StripMode::Debug => {
+        cmd.push("--strip-debug".into());
+      },
+      util::StripMode::All => {
+        cmd.push("--strip-all".into());
+      },
+    }
+    if (self.lto || self.has_mixed_inputs()) && lto_codegen {
+      // otherwise the bitcode in a mixed link is compiled at lld's default
+      // level, rather than ours.
+      use util::OptimizationGoal;
+      let lvl = match self.optimize.unwrap_or_default() {
+        OptimizationGoal::Speed(n) => ::std::cmp::min(n, 3),
+        // lld has no size levels.
+        OptimizationGoal::Balanced |
+        OptimizationGoal::Size => 2,
+      };
+      cmd.push(format!("--lto-O{}", lvl));
+    }
+    if self.gc_sections() {
+      cmd.push("--gc-sections".into());
+    } else if self.gc_sections == Some(false) {
+      cmd.push("--no-gc-sections".into());
+    }
+    if self.export_visible && !self.relocatable {
+      cmd.push("--export-dynamic".into());
+    }
+    for export in self.exports.iter().chain(self.keep_symbols.iter()) {
+      cmd.push(format!("--export={}", export));
+    }
+    for sym in self.undefined.iter() {
+      cmd.push(format!("--undefined={}", sym));
+    }
+    for &(ref name, ref value) in self.defsyms.iter() {
+      cmd.push(format!("--defsym={}={}", name, value));
+    }
+    for input in self.bitcode_inputs.iter() {
+      match input {
+        &Input::Library(false, ref p) => {
+          cmd.push("-L".into());
+          cmd.push(format!("{}", p.parent().unwrap().display()));
+          cmd.push(library_request(p));
+        },
+        &Input::Library(true, ref p) => {
+          cmd.push(format!("{}", p.display()));
+        },
+        _ => {
+          cmd.push(format!("{}", input));
+        },
+      }
+    }
+
+    if !self.relocatable {
+      // even in static mode, there will be functions which are provided by
+      // the runner.
+      cmd.push("--allow-undefined".into());
+    }
+    cmd
+  }
+
   pub fn add_native_ld_flag(&mut self, flag: &str) -> Result<(), Box<Error>> {
     self.check_native_allowed()?;
 
@@ -312,10 +1122,14 @@ impl util::ToolInvocation for Invocation {
           // lol
         }
       },
-      3 if !skip_inputs_check => {
-        if !self.has_native_inputs() && !self.has_bitcode_inputs() {
+      3 => {
+        if !skip_inputs_check && !self.print_targets &&
+          !self.has_native_inputs() && !self.has_bitcode_inputs() &&
+          !self.has_wasm_inputs() && self.deferred_inputs.len() == 0 {
           Err("no inputs")?;
         }
+        // last, so the flags it defers to are known.
+        self.apply_size_preset();
       },
 
       _ => {},
@@ -323,10 +1137,57 @@ impl util::ToolInvocation for Invocation {
 
     Ok(())
   }
+  fn conflicts(&self) -> Vec<String> {
+    let mut out = Vec::new();
+    if self.relocatable && self.entry.is_some() {
+      out.push("`--relocatable` with `--entry`".to_string());
+    }
+    if self.relocatable && self.emit_runner.is_some() {
+      out.push("`--relocatable` with `--emit-runner`; only executables can \
+                be run".to_string());
+    }
+    if self.relocatable && self.print_memory_layout {
+      out.push("`--relocatable` with `--print-memory-layout`; objects aren't \
+                laid out yet".to_string());
+    }
+    if self.source_map.is_some() && self.strip != util::StripMode::None {
+      out.push("`--source-map` with `--strip-debug`/`--strip-all`; the map \
+                is built from the debug info".to_string());
+    }
+    out
+  }
+  fn forward_linker_args(&mut self, args: Vec<String>) -> Result<(), Box<Error>> {
+    self.ld_flags.extend(args);
+    Ok(())
+  }
+  fn derived_state(&self) -> Vec<(&'static str, String)> {
+    let (entry, ctors_shim) = self.ctors_entry();
+    vec![
+      ("gc-sections", format!("{}", self.gc_sections())),
+      ("init-ctors", format!("{}", self.init_ctors())),
+      ("entry", entry.unwrap_or_default()),
+      ("ctors-shim", ctors_shim
+       .map(|p| format!("{}", p.display()) )
+       .unwrap_or_default()),
+      ("llvm-output", self.get_llvm_output()
+       .map(|p| format!("{}", p.display()) )
+       .unwrap_or_default()),
+      ("offload-to-translate", format!("{}", self.offloaded_bitcode().is_some())),
+      ("threads", format!("{}", self.tc.threads)),
+      ("eh", format!("{}", self.eh_mode)),
+      ("loader-flag", self.loader.clone()
+       .map(Ok)
+       .unwrap_or_else(Loader::configured)
+       .ok()
+       .and_then(|loader| loader.flag().map(|f| f.to_string() ) )
+       .unwrap_or_default()),
+    ]
+  }
   fn args(&self, iteration: usize) -> Option<util::ToolArgs<Invocation>> {
     match iteration {
       0 => {
-        tool_arguments!(Invocation => [TARGET, SEARCH_PATH, NO_STDLIB, LLD_FLAVOR_WASM, ])
+        tool_arguments!(Invocation => [TARGET, PRINT_TARGETS, SEARCH_PATH, NO_STDLIB,
+                                       LLD_FLAVOR_WASM, EH_MODE, ])
       },
       1 => tool_arguments!(Invocation => [
         EMIT_LLVM_FLAG,
@@ -345,27 +1206,63 @@ impl util::ToolInvocation for Invocation {
           LTO_FLAG,
           STRIP_ALL_FLAG,
           STRIP_DEBUG_FLAG,
-          LIBRARY,
           GC_SECTIONS,
+          KEEP_SYMBOL,
+          INIT_CTORS,
+          VERIFY_INPUTS,
+          CHECK_DUPLICATE_SYMBOLS,
+          AUTO_LINK_ORDER,
+          DEPENDENT_LIBRARIES,
+          FAST_RELINK,
+          PRINT_LINK_COMMAND,
+          SYM_FILE,
+          WRITE_LINK_DEPFILE,
+          EMIT_MAP_JSON,
+          PRINT_MEMORY_LAYOUT,
+          EMBED_TOOLCHAIN_MANIFEST,
+          SOURCE_MAP,
+          SOURCE_MAP_BASE,
+          LOADER,
+          OPT_PASSES,
+          DISABLE_PASS,
+          RUN_PASSES_SEPARATELY,
+          TRANSLATE_THREADS,
           MERGE_DATA_SEGMENTS,
-          AS_NEEDED_FLAG,
-          GROUP_FLAG,
-          WHOLE_ARCHIVE_FLAG,
-          LINKAGE_FLAG,
+          SIZE_PRESET,
           ENTRY,
+          NO_ENTRY,
           IMPORT_TABLE,
           IMPORT_MEMORY,
           GLOBAL_BASE,
           TRACE,
+          TRACE_SYMBOL,
+          REPRODUCE,
+          DRIVER_REPRODUCE,
           RELOCATABLE,
           VERBOSE,
           GROWABLE_TABLE_IMPORT,
           VERSION_SCRIPT,
           EXPORT,
+          EXPORT_VISIBLE,
+          IMPORT_RENAME,
+          EXPORT_RENAME,
+          EMIT_RUNNER,
+          POST_LINK_TOOL,
           UNDEFINED,
-          UNSUPPORTED,
+          DEFSYM,
         ]),
+      // these apply to the inputs which follow them, so they're parsed
+      // together with the inputs to keep their order.
       3 => tool_arguments!(Invocation => [
+        LIBRARY,
+        ABS_LIBRARY,
+        AS_NEEDED_FLAG,
+        GROUP_FLAG,
+        WHOLE_ARCHIVE_FLAG,
+        LINKAGE_FLAG,
+        FRAMEWORK,
+        DARWIN_FLAGS,
+        UNSUPPORTED,
         INPUTS,
       ]),
       _ => None,
@@ -377,113 +1274,340 @@ impl util::Tool for Invocation {
                       queue: &mut CommandQueue<Self>) -> Result<(), Box<Error>> {
     use std::process::Command;
 
-    let mut cmd = Command::new(self.tc.llvm_tool("wasm-ld"));
-    cmd.arg("--modkit-loader");
-    if self.trace {
-      cmd.arg("--trace");
+    if self.print_targets {
+      queue.enqueue_function(Some("print-targets"), |_| {
+        util::print_targets();
+        Ok(())
+      });
+      return Ok(());
     }
 
-    if self.relocatable {
-      cmd.arg("--relocatable");
-    } else if self.entry.is_none() {
-      cmd.arg("--no-entry");
+    if self.fast_relink {
+      self.load_relink_cache()?;
     }
-    cmd.args(&self.ld_flags);
-    if let Some(ref entry) = self.entry {
-      cmd.arg("--entry")
-        .arg(entry);
-    }
-    if let Some(base) = self.global_base {
-      cmd.arg(format!("--global-base={}", base));
-    }
-    if self.import_memory {
-      cmd.arg("--import-memory");
+
+    if self.dependent_libraries {
+      self.add_dependent_libraries()?;
     }
-    if self.import_table {
-      cmd.arg("--import-table");
+
+    // before the offloaded bitcode is taken out of the inputs.
+    let depfile_inputs: Vec<PathBuf> = self.input_files.iter()
+      .cloned()
+      .chain(self.bitcode_inputs.iter()
+             .filter_map(|input| match input {
+               &Input::File(ref p) | &Input::Library(_, ref p) => Some(p.clone()),
+               _ => None,
+             }))
+      .collect();
+
+    if self.verify_inputs {
+      let inputs: Vec<PathBuf> = self.bitcode_inputs.iter()
+        .filter_map(|input| match input {
+          &Input::File(ref p) | &Input::Library(_, ref p) => Some(p.clone()),
+          _ => None,
+        })
+        .collect();
+      let tc = self.tc.clone();
+      let verify = queue.enqueue_state_function(Some("verify-inputs"), move |_, state| {
+        if state.is_dry_run() { return Ok(()); }
+        verify::verify(&tc, &inputs[..])?;
+        Ok(())
+      });
+      verify.prev_outputs = false;
     }
-    if self.verbose {
-      cmd.arg("--verbose");
+
+    if self.check_duplicate_symbols {
+      use util::filetype::{file_type, Type};
+
+      let objects: Vec<PathBuf> = self.bitcode_inputs.iter()
+        .filter_map(|input| match input {
+          &Input::File(ref p) => Some(p.clone()),
+          _ => None,
+        })
+        .filter(|p| match file_type(p) {
+          Ok(Some(Type::Object(_))) | Ok(Some(Type::Wasm)) => true,
+          _ => false,
+        })
+        .collect();
+      let nm = self.tc.llvm_tool("llvm-nm");
+      let check = queue.enqueue_state_function(Some("duplicate-symbols"), move |_, state| {
+        if state.is_dry_run() { return Ok(()); }
+        duplicates::check(&nm, &objects[..])?;
+        Ok(())
+      });
+      check.prev_outputs = false;
     }
-    if self.growable_table_import {
-      cmd.arg("--growable-table-import");
+
+    // before the offloaded bitcode is taken out of the inputs, too.
+    if let Some(script) = self.version_script.clone() {
+      if !self.relocatable {
+        self.add_version_script_exports(&script)?;
+      }
     }
-    match self.strip {
-      util::StripMode::None => {},
-      util::StripMode::Debug => {
-        cmd.arg("--strip-debug");
-      },
-      util::StripMode::All => {
-        cmd.arg("--strip-all");
-      },
+
+    if let Some(llvm_output) = self.get_llvm_output() {
+      self.enqueue_llvm_link(llvm_output, queue)?;
+      if self.llvm_output_only() {
+        return Ok(());
+      }
     }
-    if self.lto {
-      let lvl = self.optimize.unwrap();
-      cmd.arg(format!("-lto{}", lvl));
+
+    let offloaded = self.offloaded_bitcode();
+    let optimized = self.enqueue_opt_passes(queue)?;
+    if let Some(ref bitcode) = offloaded {
+      let mut trans = translate::Invocation::new_with_toolchain(self.tc.clone());
+      if optimized.is_none() {
+        // otherwise, the optimized module is given to us by the queue.
+        trans.inputs = bitcode.clone();
+      }
+      trans.threads = self.translate_threads.unwrap();
+      trans.optimize = self.optimize;
+      let trans = queue.enqueue_simple_tool(Some("wasm-translate"), trans);
+      trans.intermediate_name = Some("translated.o".into());
+      trans.heavy = true;
+
+      self.bitcode_inputs.retain(|input| match input {
+        &Input::File(ref p) => !bitcode.contains(p),
+        _ => true,
+      });
     }
-    for export in self.exports.iter() {
-      cmd.arg(format!("--export={}", export));
+
+    if self.auto_link_order {
+      let nm = self.tc.llvm_tool("llvm-nm");
+      let mut scratch = relink::Cache::default();
+      let current = self.bitcode_inputs.clone();
+      let undefined = self.undefined.clone();
+      let cache = if self.fast_relink {
+        self.relink_cache()
+      } else {
+        &mut scratch
+      };
+      if let Some((inputs, order)) = linkorder::auto_link_order(&nm, &current[..], &undefined[..],
+                                                                  cache)? {
+        let order: Vec<String> = order.iter()
+          .map(|archive| format!("{}", archive.display()) )
+          .collect();
+        util::diag::note(&format!("`--auto-link-order`: the archives need to be linked \
+                                   in this order: {}", order.join(" ")));
+        self.bitcode_inputs = inputs;
+      }
     }
-    for input in self.bitcode_inputs.iter() {
-      match input {
-        &Input::Library(false, ref p) => {
-          cmd.arg("-L")
-            .arg(p.parent().unwrap());
 
-          let name = p.file_name().unwrap()
-            .to_str()
-            .unwrap();
+    let loader = match self.loader {
+      Some(ref loader) => loader.clone(),
+      None => Loader::configured()?,
+    };
+    let link = self.link_command(&loader, offloaded.is_none());
+    let mut cmd = Command::new(&link[0]);
+    cmd.args(&link[1..]);
+
+    // the rename, source map, sym file, layout, memory layout, runner and
+    // wast steps come last, so the module has to be copied to the output by
+    // whatever step produces it.
+    let output = if self.print_link_command {
+      None
+    } else if self.emit_wast || self.sym_file.is_some() || self.emit_map_json ||
+      self.print_memory_layout || self.embed_toolchain_manifest ||
+      self.source_map.is_some() || self.import_renames.len() != 0 ||
+      self.export_renames.len() != 0 || self.emit_runner.is_some()
+    {
+      Some(self.get_output())
+    } else {
+      None
+    };
 
-          let s = if name.starts_with("lib") {
-            &name[3..]
-          } else {
-            &name[..]
-          };
+    {
+      let intermediate_name: Option<PathBuf> = if self.post_link_tools.len() == 0 ||
+        self.print_link_command
+      {
+        None
+      } else {
+        Some("linked.wasm".into())
+      };
+      let copy_output_to = if self.post_link_tools.len() == 0 {
+        output.clone()
+      } else {
+        None
+      };
+      let inputs: Vec<PathBuf> = self.bitcode_inputs.iter()
+        .filter_map(|input| match input {
+          &Input::File(ref p) | &Input::Library(true, ref p) => Some(p.clone()),
+          _ => None,
+        })
+        .chain(offloaded.or(optimized).into_iter()
+               .flat_map(|bitcode| bitcode.into_iter() ))
+        .collect();
+      let libs: Vec<(PathBuf, String)> = self.bitcode_inputs.iter()
+        .filter_map(|input| match input {
+          &Input::Library(false, ref p) => Some((p.clone(), library_request(p))),
+          _ => None,
+        })
+        .collect();
+      let nm = self.tc.llvm_tool("llvm-nm");
+      let sysroot_lib = self.tc.sysroot_lib();
+      let print = self.print_link_command;
+      let final_output = self.get_output();
+      let plan = format!("{} <inputs> -o <output>",
+                         util::command_queue::plan_command(&cmd));
+
+      // written before the link, so there's one even if it fails.
+      if let (Some(tar), false) = (self.driver_reproduce.clone(), print) {
+        let link = link.clone();
+        let final_output = final_output.clone();
+        let tc = self.tc.clone();
+        queue.enqueue_state_function(Some("driver-reproduce"), move |_, state| {
+          let mut cmd = Command::new(&link[0]);
+          cmd.args(&link[1..])
+            .args(&state.prev_outputs)
+            .arg("-o").arg(&final_output);
+          reproduce::write(&tar, &cmd, &tc)
+            .map_err(|e| format!("writing `{}`: {}", tar.display(), e) )?;
+          util::diag::note(&format!("wrote a reproducer to `{}`", tar.display()));
+          Ok(())
+        });
+      }
 
-          let s = if s.ends_with(".so") {
-            &s[..s.len() - 3]
-          } else if s.ends_with(".a") {
-            &s[..s.len() - 2]
-          } else {
-            &s[..]
-          };
+      let lld = queue.enqueue_external_with_input_transform(Some("lld"), cmd, Some("-o"),
+                                                            move |cmd, prev| {
+        // the translated object or optimized module, if either happened.
+        cmd.args(prev);
+        if !print {
+          return InputArgsTransformResult::Normal;
+        }
+        let line: Vec<String> = Some(cmd.get_program()).into_iter()
+          .chain(cmd.get_args())
+          .chain(Some("-o".as_ref()))
+          .chain(Some(final_output.as_os_str()))
+          .map(|arg| util::report::shell_quote(&arg.to_string_lossy()) )
+          .collect();
+        println!("{}", line.join(" "));
+        InputArgsTransformResult::Skip
+      });
+      lld.intermediate_name = intermediate_name;
+      lld.copy_output_to = copy_output_to;
+      lld.output_ext = Some("wasm".into());
+      lld.heavy = true;
+      lld.plan = Some(plan);
+      // augment lld's errors with help for any undefined symbols.
+      lld.stderr_filter = Some(StderrFilter(Box::new(move |stderr, success| {
+        let mut out = diagnostics::prettify(stderr, &libs[..]);
+        if !success {
+          if let Some(report) = undefined::report(stderr, &nm, &inputs[..], &sysroot_lib) {
+            out.push_str(&report);
+          }
+        }
+        out
+      })));
+    }
+    if self.print_link_command {
+      return Ok(());
+    }
 
+    let post_link_tools = self.post_link_tools.len();
+    for (idx, tool) in self.post_link_tools.iter().enumerate() {
+      let mut cmd = Command::new(&tool[0]);
+      cmd.args(&tool[1..]);
 
-          cmd.arg(format!("-l{}", s));
-          continue;
-        },
-        &Input::Library(true, ref p) => {
-          cmd.arg(p);
-          continue;
-        },
-        _ => {},
+      let step = queue.enqueue_simple_external(Some("post-link-tool"), cmd,
+                                               Some("-o".into()));
+      step.intermediate_name = Some(format!("post-link-{}.wasm", idx).into());
+      if idx + 1 == post_link_tools {
+        step.copy_output_to = output.clone();
       }
-      cmd.arg(format!("{}", input));
     }
 
-    if !self.relocatable {
-      // even in static mode, there will be functions which are provided by
-      // the runner.
-      cmd.arg("--allow-undefined");
+    if let Some(ref output) = output {
+      if self.import_renames.len() != 0 || self.export_renames.len() != 0 {
+        let output = output.clone();
+        let imports = self.import_renames.clone();
+        let exports = self.export_renames.clone();
+        // before the source map, whose offsets this would shift.
+        queue.enqueue_state_function(Some("rename"), move |_, state| {
+          if state.is_dry_run() { return Ok(()); }
+          rename::rename_interface(&output, &imports[..], &exports[..])?;
+          Ok(())
+        });
+      }
+      if self.embed_toolchain_manifest {
+        let output = output.clone();
+        let tc = self.tc.clone();
+        queue.enqueue_state_function(Some("toolchain-manifest"), move |_, state| {
+          use util::manifest::{components, manifest};
+
+          if state.is_dry_run() { return Ok(()); }
+          let manifest = manifest(&components(&tc)[..]);
+          wasm::append_custom_section(&output, "driver.toolchain", manifest.as_bytes())?;
+          Ok(())
+        });
+      }
     }
-
-    let output = if self.emit_wast { self.output.take() } else { None };
-
-    queue.enqueue_simple_external(Some("lld"), cmd,
-                                  Some("-o".into()))
-      .copy_output_to = output.clone();
-
-    if self.emit_wast {
+    if let (Some(output), Some(map)) = (output.clone(), self.source_map.clone()) {
+      let dwarfdump = self.tc.llvm_tool("llvm-dwarfdump");
+      let base = self.source_map_base.clone();
+      queue.enqueue_state_function(Some("source-map"), move |_, state| {
+        if state.is_dry_run() { return Ok(()); }
+        sourcemap::write_source_map(&dwarfdump, &output, &map,
+                                    base.as_ref().map(|b| &b[..] ))?;
+        Ok(())
+      });
+    }
+    if let (Some(output), Some(sym_file)) = (output.clone(), self.sym_file.clone()) {
+      queue.enqueue_state_function(Some("sym-file"), move |_, state| {
+        if state.is_dry_run() { return Ok(()); }
+        symfile::write_sym_file(&output, &sym_file)?;
+        Ok(())
+      });
+    }
+    if let (Some(output), true) = (output.clone(), self.emit_map_json) {
+      let map = output.with_extension("map.json");
+      queue.enqueue_state_function(Some("map-json"), move |_, state| {
+        if state.is_dry_run() { return Ok(()); }
+        layout::write_layout(&output, &map)?;
+        Ok(())
+      });
+    }
+    if let (Some(output), true) = (output.clone(), self.print_memory_layout) {
+      queue.enqueue_state_function(Some("memory-layout"), move |_, state| {
+        if state.is_dry_run() { return Ok(()); }
+        layout::print_memory_layout(&output)?;
+        Ok(())
+      });
+    }
+    if let (Some(output), Some(runner)) = (output.clone(), self.emit_runner) {
+      let entry = self.ctors_entry().0;
+      queue.enqueue_state_function(Some("runner"), move |_, state| {
+        if state.is_dry_run() { return Ok(()); }
+        runner::write_runner(runner, &output, entry.as_ref().map(|e| &e[..] ))?;
+        Ok(())
+      });
+    }
+    if let (Some(output), true) = (output, self.emit_wast) {
       let wasm_dis = self.tc.binaryen_tool("wasm-dis");
-      let mut cmd = Command::new("sh");
-      cmd.arg("-c")
-        .arg(r#"echo "Writing wast to ${1%.*}.wast"; $0 $1 | c++filt > ${1%.*}.wast"#)
-        .arg(wasm_dis)
-        .arg(output.as_ref().unwrap());
-
-      queue.enqueue_simple_external(Some("--emit-wast"),
-                                    cmd, None)
-        .prev_outputs = false;
+      let wast = output.with_extension("wast");
+      queue.enqueue_disassemble(Some("--emit-wast"), wasm_dis,
+                                Some(output), wast);
+    }
+    if let Some(path) = self.link_depfile.clone() {
+      let target = self.get_output();
+      let write = queue.enqueue_state_function(Some("link-depfile"), move |_, state| {
+        if state.is_dry_run() { return Ok(()); }
+        depfile::write_depfile(&path, &target, &depfile_inputs[..])
+          .map_err(|e| format!("writing `{}`: {}", path.display(), e) )?;
+        Ok(())
+      });
+      write.prev_outputs = false;
+    }
+    if self.fast_relink {
+      // only kept once the link has worked.
+      let cache = self.relink_cache().clone();
+      let save = queue.enqueue_state_function(Some("relink-cache"), move |_, state| {
+        if state.is_dry_run() { return Ok(()); }
+        if let Err(e) = cache.save() {
+          util::diag::note(&format!("couldn't save the `--fast-relink` cache: {}", e));
+        }
+        Ok(())
+      });
+      save.prev_outputs = false;
     }
 
     Ok(())
@@ -523,12 +1647,32 @@ tool_argument!(TARGET: Invocation = { Some(r"^--?target=(.+)$"), Some(r"^--?targ
                    if this.arch.is_some() {
                        Err("the target has already been set")?;
                    }
-                   let arch = if single { cap.get(0).unwrap() }
-                              else      { cap.get(1).unwrap() };
+                   let arch = if single { cap.get(1).unwrap() }
+                              else      { cap.get(0).unwrap() };
                    let arch = try!(util::Arch::parse_from_triple(arch.as_str()));
                    this.arch = Some(arch);
                    Ok(())
                });
+tool_argument! {
+  pub PRINT_LINK_COMMAND: Invocation = simple_no_flag(b) "print-link-command" =>
+  fn print_link_command_flag(this) {
+    this.print_link_command = b;
+  }
+}
+argument!(impl PRINT_TARGETS where { Some(r"^--print-targets$"), None } for Invocation {
+  fn print_targets_flag(this, _single, _cap) {
+    this.print_targets = true;
+  }
+});
+tool_argument! {
+  pub TRANSLATE_THREADS: Invocation = single_and_split_int(usize, threads) "translate-threads" =>
+  fn translate_threads_arg(this) {
+    if threads == 0 {
+      Err("`--translate-threads` must be at least 1")?;
+    }
+    this.translate_threads = Some(threads);
+  }
+}
 tool_argument!(LLD_FLAVOR_WASM: Invocation = { None, Some(r#"^-flavor$"#) };
                fn lld_flavor_wasm_arg(_this, _single, cap) {
                    let flavor = cap.get(0).unwrap().as_str();
@@ -563,12 +1707,34 @@ tool_argument!(STATIC: Invocation = { Some(r"-static"), None };
                    Ok(())
                });
 
+tool_argument! {
+  pub EH_MODE: Invocation = single_and_split_from_str(eh) "eh" =>
+  fn eh_mode_arg(this) {
+    this.eh_mode = eh;
+    if eh != util::EhMode::None {
+      let dir = this.tc.sysroot_eh_lib(eh);
+      this.search_paths.insert(0, dir);
+    }
+  }
+}
 tool_argument! {
   pub TRACE: Invocation = simple_no_flag(b) "trace" =>
   fn trace_arg1(this) {
     this.trace = b;
   }
 }
+tool_argument! {
+  pub REPRODUCE: Invocation = single_and_split_abs_path(path) "reproduce" =>
+  fn reproduce_arg(this) {
+    this.reproduce = Some(path);
+  }
+}
+tool_argument! {
+  pub DRIVER_REPRODUCE: Invocation = single_and_split_abs_path(path) "driver-reproduce" =>
+  fn driver_reproduce_arg(this) {
+    this.driver_reproduce = Some(path);
+  }
+}
 tool_argument! {
   pub RELOCATABLE: Invocation = simple_no_flag(b) "relocatable" =>
   fn relocatable_arg1(this) {
@@ -594,9 +1760,9 @@ tool_argument! {
   }
 }
 tool_argument! {
-  pub VERSION_SCRIPT: Invocation = single_and_split_abs_path(_path) "version-script" =>
-  fn verion_script(_this) {
-    // ignore this
+  pub VERSION_SCRIPT: Invocation = single_and_split_abs_path(path) "version-script" =>
+  fn version_script_arg(this) {
+    this.version_script = Some(path);
   }
 }
 tool_argument! {
@@ -605,15 +1771,171 @@ tool_argument! {
     this.exports.push(symbol);
   }
 }
+tool_argument! {
+  pub EXPORT_VISIBLE: Invocation = simple_no_flag(b) "export-visible" =>
+  fn export_visible_flag(this) {
+    this.export_visible = b;
+  }
+}
+tool_argument!(TRACE_SYMBOL: Invocation = { Some(r"^(-y|--trace-symbol=)(.+)$"), Some(r"^(-y|--trace-symbol)$") };
+               fn trace_symbol_arg(this, single, cap) {
+                 let sym = if single { cap.get(2).unwrap() }
+                           else      { cap.get(0).unwrap() };
+                 this.trace_symbols.push(sym.as_str().to_string());
+                 Ok(())
+               });
+tool_argument! {
+  pub IMPORT_RENAME: Invocation = single_and_split_from_str(arg) "import-rename" =>
+  fn import_rename_arg(this) {
+    let arg: String = arg;
+    this.import_renames.push(rename::parse_rename(&arg)?);
+  }
+}
+tool_argument! {
+  pub EXPORT_RENAME: Invocation = single_and_split_from_str(arg) "export-rename" =>
+  fn export_rename_arg(this) {
+    let arg: String = arg;
+    this.export_renames.push(rename::parse_rename(&arg)?);
+  }
+}
+tool_argument! {
+  pub EMIT_RUNNER: Invocation = single_and_split_from_str(runner) "emit-runner" =>
+  fn emit_runner_arg(this) {
+    this.emit_runner = Some(runner);
+  }
+}
+tool_argument! {
+  pub POST_LINK_TOOL: Invocation = single_and_split_from_str(tool) "post-link-tool" =>
+  fn post_link_tool_arg(this) {
+    let tool: String = tool;
+    let tool = util::report::shell_split(&tool)?;
+    if tool.len() == 0 {
+      Err("empty `--post-link-tool` command")?;
+    }
+    this.post_link_tools.push(tool);
+  }
+}
 tool_argument! {
   pub GC_SECTIONS: Invocation = simple_no_flag(b) "gc-sections" =>
   fn gc_sections_flag(this) {
-    let input = if b {
-      "--gc-sections"
-    } else {
-      "--no-gc-sections"
-    };
-    this.bitcode_inputs.push(Input::Flag(input.into()));
+    this.gc_sections = Some(b);
+  }
+}
+tool_argument! {
+  pub OPT_PASSES: Invocation = single_and_split_from_str(passes) "passes" =>
+  fn opt_passes_arg(this) {
+    let passes: String = passes;
+    this.passes.extend(passes.split(',')
+                       .filter(|p| p.len() != 0 )
+                       .map(|p| p.to_string() ));
+  }
+}
+tool_argument! {
+  pub DISABLE_PASS: Invocation = single_and_split_from_str(pass) "disable-pass" =>
+  fn disable_pass_arg(this) {
+    this.disabled_passes.push(pass);
+  }
+}
+tool_argument! {
+  pub RUN_PASSES_SEPARATELY: Invocation = simple_no_flag(b) "run-passes-separately" =>
+  fn run_passes_separately_flag(this) {
+    this.run_passes_separately = b;
+  }
+}
+tool_argument! {
+  pub VERIFY_INPUTS: Invocation = simple_no_flag(b) "verify-inputs" =>
+  fn verify_inputs_flag(this) {
+    this.verify_inputs = b;
+  }
+}
+tool_argument! {
+  pub CHECK_DUPLICATE_SYMBOLS: Invocation = simple_no_flag(b) "check-duplicate-symbols" =>
+  fn check_duplicate_symbols_flag(this) {
+    this.check_duplicate_symbols = b;
+  }
+}
+tool_argument! {
+  pub AUTO_LINK_ORDER: Invocation = simple_no_flag(b) "auto-link-order" =>
+  fn auto_link_order_flag(this) {
+    this.auto_link_order = b;
+  }
+}
+tool_argument! {
+  pub DEPENDENT_LIBRARIES: Invocation = simple_no_flag(b) "dependent-libraries" =>
+  fn dependent_libraries_flag(this) {
+    this.dependent_libraries = b;
+  }
+}
+tool_argument! {
+  pub FAST_RELINK: Invocation = simple_no_flag(b) "fast-relink" =>
+  fn fast_relink_flag(this) {
+    this.fast_relink = b;
+  }
+}
+tool_argument! {
+  pub LOADER: Invocation = single_and_split_from_str(loader) "loader" =>
+  fn loader_arg(this) {
+    this.loader = Some(loader);
+  }
+}
+tool_argument! {
+  pub SYM_FILE: Invocation = single_and_split_from_str(path) "sym-file" =>
+  fn sym_file_arg(this) {
+    this.sym_file = Some(path);
+  }
+}
+tool_argument! {
+  pub WRITE_LINK_DEPFILE: Invocation = single_and_split_from_str(path) "write-link-depfile" =>
+  fn write_link_depfile_arg(this) {
+    this.link_depfile = Some(path);
+  }
+}
+tool_argument! {
+  pub EMIT_MAP_JSON: Invocation = simple_no_flag(b) "emit-map-json" =>
+  fn emit_map_json_flag(this) {
+    this.emit_map_json = b;
+  }
+}
+tool_argument! {
+  pub PRINT_MEMORY_LAYOUT: Invocation = simple_no_flag(b) "print-memory-layout" =>
+  fn print_memory_layout_flag(this) {
+    this.print_memory_layout = b;
+  }
+}
+tool_argument! {
+  pub EMBED_TOOLCHAIN_MANIFEST: Invocation = simple_no_flag(b) "embed-toolchain-manifest" =>
+  fn embed_toolchain_manifest_flag(this) {
+    this.embed_toolchain_manifest = b;
+  }
+}
+tool_argument! {
+  pub SOURCE_MAP: Invocation = single_and_split_from_str(path) "source-map" =>
+  fn source_map_arg(this) {
+    this.source_map = Some(path);
+  }
+}
+tool_argument! {
+  pub SOURCE_MAP_BASE: Invocation = single_and_split_from_str(base) "source-map-base" =>
+  fn source_map_base_arg(this) {
+    this.source_map_base = Some(base);
+  }
+}
+tool_argument! {
+  pub INIT_CTORS: Invocation = simple_no_flag(b) "init-ctors" =>
+  fn init_ctors_flag(this) {
+    this.init_ctors = Some(b);
+  }
+}
+tool_argument! {
+  pub KEEP_SYMBOL: Invocation = single_and_split_from_str(symbol) "keep-symbol" =>
+  fn keep_symbol_arg(this) {
+    this.keep_symbols.push(symbol);
+  }
+}
+tool_argument! {
+  pub SIZE_PRESET: Invocation = single_and_split_from_str(preset) "size-preset" =>
+  fn size_preset_arg(this) {
+    this.size_preset = Some(preset);
   }
 }
 tool_argument! {
@@ -653,9 +1975,14 @@ tool_argument!(SONAME: Invocation = { Some(r"-?-soname=(.+)"), Some(r"-?-soname"
                    Ok(())
                });
 
-argument!(impl Z_FLAGS where { None, Some(r"^-z$") } for Invocation {
-    fn z_flags(_this, _single, _cap) {
-      // TODO
+argument!(impl Z_FLAGS where { Some(r"^-z(.+)$"), Some(r"^-z$") } for Invocation {
+    fn z_flags(this, single, cap) {
+      let keyword = cap.get(if single { 1 } else { 0 }).unwrap().as_str();
+      // the rest (`now`, `relro`, `noexecstack`, etc) are ELF only.
+      if keyword.starts_with("stack-size=") {
+        this.ld_flags.push("-z".into());
+        this.ld_flags.push(keyword.into());
+      }
     }
 });
 
@@ -665,19 +1992,23 @@ tool_argument!(PIC_FLAG: Invocation = { Some(r"^-fPIC$"), None };
                    Ok(())
                });
 
-tool_argument!(OPTIMIZE_FLAG: Invocation = { Some(r"^-O([0-4sz]?)$"), None };
+tool_argument!(OPTIMIZE_FLAG: Invocation = { Some(r"^-O([0-9]*|[a-z]+)$"), None };
                fn set_optimize(this, _single, cap) {
-                   let optimize = cap.get(1)
-                       .and_then(|str| util::OptimizationGoal::parse(str.as_str()) )
-                       .unwrap();
+                   let optimize = util::OptimizationGoal::parse(cap.get(1).unwrap().as_str())?;
                    this.optimize = Some(optimize);
                    Ok(())
                });
-tool_argument!(ENTRY: Invocation = { None, Some(r"^-(e|-entry)$") };
-               fn entry_arg(this, _single, cap) {
-                   this.entry = Some(cap.get(0).unwrap().as_str().to_owned());
+tool_argument!(ENTRY: Invocation = { Some(r"^--entry=(.+)$"), Some(r"^-(e|-entry)$") };
+               fn entry_arg(this, single, cap) {
+                   let entry = cap.get(if single { 1 } else { 0 }).unwrap();
+                   this.entry = Some(entry.as_str().to_owned());
                    Ok(())
                });
+argument!(impl NO_ENTRY where { Some(r"^--no-entry$"), None } for Invocation {
+    fn no_entry_flag(this, _single, _cap) {
+      this.entry = None;
+    }
+});
 
 
 tool_argument! {
@@ -756,12 +2087,27 @@ tool_argument! {
   }
 }
 
-tool_argument!(UNDEFINED: Invocation = { Some(r"^-(-undefined=|u)(.+)$"), Some(r"^-u$") };
-               fn add_undefined(_this, single, cap) {
-                   let _sym = if single { cap.get(0).unwrap() }
-                             else { cap.get(1).unwrap() };
-
-                   unimplemented!();
+tool_argument!(UNDEFINED: Invocation = { Some(r"^(-u|--undefined=)(.+)$"), Some(r"^(-u|--undefined)$") };
+               fn add_undefined(this, single, cap) {
+                   let sym = if single { cap.get(2).unwrap() }
+                             else      { cap.get(0).unwrap() };
+                   this.undefined.push(sym.as_str().to_string());
+                   Ok(())
+               });
+tool_argument!(DEFSYM: Invocation = { Some(r"^--?defsym=(.+)$"), Some(r"^--?defsym$") };
+               fn add_defsym(this, single, cap) {
+                   let def = cap.get(if single { 1 } else { 0 }).unwrap().as_str();
+                   let mut parts = def.splitn(2, '=');
+                   let name = parts.next().unwrap();
+                   let value = match parts.next() {
+                     Some(value) if name.len() != 0 && value.len() != 0 => value,
+                     _ => {
+                       return Err(format!("`--defsym` expects `<name>=<value>`, got `{}`",
+                                          def).into());
+                     },
+                   };
+                   this.defsyms.push((name.to_string(), value.to_string()));
+                   Ok(())
                });
 
 
@@ -785,9 +2131,20 @@ argument!(impl NO_DEFAULTLIBS where { Some(r"^-nodefaultlibs$"), None } for Invo
         this.use_defaultlibs = false;
     }
 });
+argument!(impl FRAMEWORK where { None, Some(r"^-(weak_)?framework$") } for Invocation {
+    fn framework_arg(_this, _single, cap) {
+      let framework = cap.get(0).unwrap().as_str();
+      util::suggest::host_only_arg(&format!("-framework {}", framework))?;
+    }
+});
+argument!(impl DARWIN_FLAGS where { Some(r"^(-dylib|-bundle|-headerpad_max_install_names|-F.+)$"), None } for Invocation {
+    fn darwin_flags(_this, _single, cap) {
+      util::suggest::host_only_arg(cap.get(0).unwrap().as_str())?;
+    }
+});
 argument!(impl UNSUPPORTED where { Some(r"^-.+$"), None } for Invocation {
-    fn unsupported_flag(_this, _single, _cap) {
-        Err("unsupported argument")?;
+    fn unsupported_flag(this, _single, cap) {
+        util::suggest::unsupported_arg(this, cap.get(0).unwrap().as_str())?;
     }
 });
 
@@ -919,6 +2276,22 @@ mod tests {
     assert!(res.is_err());
   }
   #[test]
+  fn size_preset_args() {
+    override_filetype("input.bc", Type::Object(Subtype::Bitcode));
+
+    let args = vec!["input.bc".to_string(),
+                    "--size-preset=smallest".to_string(),
+                    "--no-gc-sections".to_string()];
+    let mut i: Invocation = Default::default();
+    util::process_invocation_args(&mut i, args, false).unwrap();
+
+    // applied by the args, so `--print-effective-commandline` shows it.
+    assert_eq!(i.optimize, Some(util::OptimizationGoal::Size));
+    assert_eq!(i.gc_sections, Some(false));
+    assert!(i.ld_flags.contains(&"--icf=all".to_string()));
+    assert_eq!(i.post_link_tools.len(), 1);
+  }
+  #[test]
   fn no_inputs() {
     let args = vec![];
     let mut i: Invocation = Default::default();
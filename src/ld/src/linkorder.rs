@@ -0,0 +1,287 @@
+
+//! `--auto-link-order`: if the archives, taken in the given order with
+//! traditional single pass semantics, would leave symbols undefined which
+//! one of them actually defines, put them in an order (repeating them if
+//! they depend on each other) which doesn't, and say what it was. This
+//! saves wrapping everything in `--start-group`/`--end-group`.
+//!
+//! Positional flags between the archives (ie `--whole-archive`) apply to
+//! the inputs after them, so the order is left alone if there are any.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use util::filetype::{file_type, Type};
+use util::ldtools::Input;
+
+use relink;
+
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+  pub defined: BTreeSet<String>,
+  pub undefined: BTreeSet<String>,
+}
+
+/// A (flattened) input; archives are split into their members.
+#[derive(Clone, Debug)]
+pub enum Entry {
+  Object(Symbols),
+  Archive(Vec<Symbols>),
+}
+
+/// `llvm-nm` over `path`, by member (objects have a single, unnamed one).
+pub fn symbols(nm: &Path, path: &Path, cache: &mut relink::Cache)
+  -> Result<BTreeMap<String, Symbols>, Box<Error>>
+{
+  if let Some(symbols) = cache.symbols(path) {
+    return Ok(symbols);
+  }
+
+  let mut cmd = Command::new(nm);
+  cmd.arg("--extern-only")
+    .arg("--print-file-name")
+    .arg(path);
+  ::util::hermetic_env(&mut cmd);
+  let output = cmd.output()?;
+  if !output.status.success() {
+    Err(format!("`{:?}` failed: {}", cmd,
+                String::from_utf8_lossy(&output.stderr)))?;
+  }
+
+  // `<path>: <value> <type> <name>`, or `<path>:<member>: ...` for archives.
+  let prefix = format!("{}:", path.display());
+  let mut out: BTreeMap<String, Symbols> = BTreeMap::new();
+  for line in String::from_utf8_lossy(&output.stdout).lines() {
+    if !line.starts_with(&prefix) { continue; }
+    let line = &line[prefix.len()..];
+    let (member, line) = if line.starts_with(' ') {
+      ("", line)
+    } else {
+      match line.find(": ") {
+        Some(idx) => (&line[..idx], &line[idx + 1..]),
+        None => continue,
+      }
+    };
+
+    let mut fields = line.split_whitespace().rev();
+    let (name, kind) = match (fields.next(), fields.next()) {
+      (Some(name), Some(kind)) => (name, kind),
+      _ => continue,
+    };
+    let symbols = out.entry(member.to_string()).or_insert_with(Default::default);
+    match kind {
+      "U" => { symbols.undefined.insert(name.to_string()); },
+      // weak references don't pull anything in.
+      "w" | "v" => {},
+      _ => { symbols.defined.insert(name.to_string()); },
+    }
+  }
+  cache.set_symbols(path, &out);
+  Ok(out)
+}
+
+/// Link `order` (indices into `entries`) the traditional way, starting
+/// with `undefined` (ie `-u`), returning what's still undefined at the end.
+pub fn simulate<'a>(entries: &'a [Entry], order: &[usize], undefined: &'a [String])
+  -> BTreeSet<String>
+{
+  fn add<'a>(symbols: &'a Symbols, defined: &mut BTreeSet<&'a str>,
+             undefined: &mut BTreeSet<&'a str>) {
+    for sym in symbols.defined.iter() {
+      defined.insert(&sym[..]);
+      undefined.remove(&sym[..]);
+    }
+    for sym in symbols.undefined.iter() {
+      if !defined.contains(&sym[..]) {
+        undefined.insert(&sym[..]);
+      }
+    }
+  }
+
+  let mut defined: BTreeSet<&str> = BTreeSet::new();
+  let mut undefined: BTreeSet<&str> = undefined.iter()
+    .map(|sym| &sym[..] )
+    .collect();
+  let mut pulled: BTreeSet<(usize, usize)> = BTreeSet::new();
+  for &idx in order.iter() {
+    match &entries[idx] {
+      &Entry::Object(ref symbols) => add(symbols, &mut defined, &mut undefined),
+      &Entry::Archive(ref members) => loop {
+        let next = members.iter()
+          .enumerate()
+          .find(|&(member, symbols)| {
+            !pulled.contains(&(idx, member)) &&
+              symbols.defined.iter().any(|sym| undefined.contains(&sym[..]) )
+          });
+        match next {
+          Some((member, symbols)) => {
+            pulled.insert((idx, member));
+            add(symbols, &mut defined, &mut undefined);
+          },
+          None => break,
+        }
+      },
+    }
+  }
+  undefined.into_iter()
+    .map(|sym| sym.to_string() )
+    .collect()
+}
+
+/// The archives which define any of `symbols`, in input order.
+fn defining_archives(entries: &[Entry], symbols: &BTreeSet<String>) -> Vec<usize> {
+  entries.iter()
+    .enumerate()
+    .filter(|&(_, entry)| match entry {
+      &Entry::Archive(ref members) => members.iter()
+        .any(|m| m.defined.iter().any(|sym| symbols.contains(sym) )),
+      _ => false,
+    })
+    .map(|(idx, _)| idx )
+    .collect()
+}
+
+/// `archives` (indices into `entries`) ordered so that, where possible,
+/// each comes before those it needs symbols from; ties (and cycles) keep
+/// the input order.
+pub fn sorted_archives(entries: &[Entry], archives: &[usize]) -> Vec<usize> {
+  let needs = |a: usize, b: usize| -> bool {
+    match (&entries[a], &entries[b]) {
+      (&Entry::Archive(ref a), &Entry::Archive(ref b)) => {
+        let a_defined: BTreeSet<&String> = a.iter()
+          .flat_map(|m| m.defined.iter() )
+          .collect();
+        a.iter()
+          .flat_map(|m| m.undefined.iter() )
+          .filter(|sym| !a_defined.contains(sym) )
+          .any(|sym| b.iter().any(|m| m.defined.contains(sym) ))
+      },
+      _ => false,
+    }
+  };
+
+  let mut left: Vec<usize> = archives.to_vec();
+  let mut out = Vec::with_capacity(left.len());
+  while left.len() != 0 {
+    // the first archive nothing else left needs; if there's a cycle, just
+    // the first.
+    let next = left.iter()
+      .position(|&a| !left.iter().any(|&b| b != a && needs(b, a) ))
+      .unwrap_or(0);
+    out.push(left.remove(next));
+  }
+  out
+}
+
+/// If `inputs`' archives need reordering, the reordered inputs and the
+/// archive order, for the note. `undefined` are the `-u` symbols.
+/// `cache` saves rereading unchanged inputs' symbols.
+pub fn auto_link_order(nm: &Path, inputs: &[Input], undefined: &[String],
+                       cache: &mut relink::Cache)
+  -> Result<Option<(Vec<Input>, Vec<PathBuf>)>, Box<Error>>
+{
+  let is_archive = |input: &Input| match input {
+    &Input::File(ref p) | &Input::Library(true, ref p) => match file_type(p) {
+      Ok(Some(Type::Archive(_))) => true,
+      _ => false,
+    },
+    _ => false,
+  };
+  let first = inputs.iter().position(&is_archive);
+  let last = inputs.iter().rposition(&is_archive);
+  if let (Some(first), Some(last)) = (first, last) {
+    let flag = inputs[first..last].iter()
+      .filter_map(|input| match input {
+        &Input::Flag(ref flag) => Some(flag),
+        _ => None,
+      })
+      .next();
+    if let Some(flag) = flag {
+      ::util::diag::note(&format!("`--auto-link-order`: leaving the archives in the \
+                                   given order, since `{}` is between them", flag));
+      return Ok(None);
+    }
+  }
+
+  let mut entries = Vec::new();
+  // `(input idx, entry idx)`
+  let mut archive_inputs = Vec::new();
+  for (input_idx, input) in inputs.iter().enumerate() {
+    let path = match input {
+      &Input::File(ref p) | &Input::Library(true, ref p) => p,
+      _ => continue,
+    };
+    let mut symbols = symbols(nm, path, cache)?;
+    if is_archive(input) {
+      archive_inputs.push((input_idx, entries.len()));
+      entries.push(Entry::Archive(symbols.into_iter().map(|(_, s)| s ).collect()));
+    } else {
+      let symbols = symbols.remove("").unwrap_or_default();
+      entries.push(Entry::Object(symbols));
+    }
+  }
+  if archive_inputs.len() == 0 { return Ok(None); }
+
+  // the archives all go where the last one was.
+  let last = archive_inputs.last().unwrap().1;
+  let archives: Vec<usize> = archive_inputs.iter()
+    .map(|&(_, a)| a )
+    .collect();
+  let with_archives = |order: &[usize]| -> Vec<usize> {
+    let mut out = Vec::with_capacity(entries.len() + order.len());
+    for idx in 0..entries.len() {
+      if idx == last {
+        out.extend_from_slice(order);
+      } else if !archives.contains(&idx) {
+        out.push(idx);
+      }
+    }
+    out
+  };
+
+  let original: Vec<usize> = (0..entries.len()).collect();
+  let missing = simulate(&entries, &original, undefined);
+  if defining_archives(&entries, &missing).len() == 0 {
+    return Ok(None);
+  }
+
+  // reorder, then repeat whatever's still needed until nothing more is
+  // found (each round has to resolve something, so this stops).
+  let mut order = sorted_archives(&entries, &archives[..]);
+  let mut missing = simulate(&entries, &with_archives(&order[..]), undefined);
+  loop {
+    let needed = defining_archives(&entries, &missing);
+    if needed.len() == 0 { break; }
+    let mut next = order.clone();
+    next.extend(needed.into_iter());
+    let next_missing = simulate(&entries, &with_archives(&next[..]), undefined);
+    if next_missing.len() >= missing.len() { break; }
+    order = next;
+    missing = next_missing;
+  }
+
+  let archive_input = |entry: usize| -> &Input {
+    let &(input_idx, _) = archive_inputs.iter()
+      .find(|&&(_, a)| a == entry )
+      .unwrap();
+    &inputs[input_idx]
+  };
+  let last = archive_inputs.last().unwrap().0;
+  let mut out = Vec::with_capacity(inputs.len() + order.len());
+  for (idx, input) in inputs.iter().enumerate() {
+    if idx == last {
+      out.extend(order.iter().map(|&a| archive_input(a).clone() ));
+    } else if archive_inputs.iter().all(|&(i, _)| i != idx ) {
+      out.push(input.clone());
+    }
+  }
+  let paths = order.iter()
+    .filter_map(|&a| match archive_input(a) {
+      &Input::File(ref p) | &Input::Library(_, ref p) => Some(p.clone()),
+      _ => None,
+    })
+    .collect();
+  Ok(Some((out, paths)))
+}
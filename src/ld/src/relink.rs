@@ -0,0 +1,276 @@
+//! `--fast-relink`: for quicker edit-compile-link cycles, where usually only
+//! a few objects have changed. The `-l` lookups (and whatever linker
+//! scripts they found), and the archives' symbol tables for
+//! `--auto-link-order`, are saved in `<output>.relink` and reused while
+//! the files involved are unchanged (by size and mtime). The inputs wait
+//! until the commands are enqueued, when the output is known, to be looked
+//! up through it.
+//!
+//! A library added to one of the search dirs since the last link isn't
+//! noticed; link once without `--fast-relink` to pick it up.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use util::ldtools::Input;
+
+use linkorder::Symbols;
+
+const HEADER: &'static str = "wasm-ld-relink 1";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Stamp {
+  len: u64,
+  mtime: u64,
+  mtime_nanos: u32,
+}
+impl Stamp {
+  fn of(path: &Path) -> Option<Stamp> {
+    let meta = fs::metadata(path).ok()?;
+    let mtime = meta.modified().ok()?
+      .duration_since(UNIX_EPOCH).ok()?;
+    Some(Stamp {
+      len: meta.len(),
+      mtime: mtime.as_secs(),
+      mtime_nanos: mtime.subsec_nanos(),
+    })
+  }
+  fn parse(len: &str, mtime: &str) -> Option<Stamp> {
+    let mut mtime = mtime.splitn(2, '.');
+    Some(Stamp {
+      len: len.parse().ok()?,
+      mtime: mtime.next()?.parse().ok()?,
+      mtime_nanos: mtime.next()?.parse().ok()?,
+    })
+  }
+  fn unchanged(&self, path: &Path) -> bool {
+    Stamp::of(path) == Some(*self)
+  }
+}
+
+#[derive(Clone, Debug)]
+struct Resolution {
+  search: Vec<PathBuf>,
+  static_only: bool,
+  inputs: Vec<Input>,
+  /// the library and any linker scripts it went through.
+  files: Vec<(PathBuf, Stamp)>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Cache {
+  path: PathBuf,
+  /// by `(abs, name)` of the `-l`.
+  resolutions: BTreeMap<(bool, PathBuf), Resolution>,
+  symbols: BTreeMap<PathBuf, (Stamp, BTreeMap<String, Symbols>)>,
+}
+
+impl Cache {
+  /// The cache for `output`. A missing or unreadable one is just empty.
+  pub fn load(output: &Path) -> Cache {
+    let path = PathBuf::from(format!("{}.relink", output.display()));
+    let mut contents = String::new();
+    let read = File::open(&path)
+      .and_then(|mut f| f.read_to_string(&mut contents) );
+    let mut cache = match read {
+      Ok(_) => Cache::parse(&contents).unwrap_or_default(),
+      Err(_) => Cache::default(),
+    };
+    cache.path = path;
+    cache
+  }
+
+  fn parse(contents: &str) -> Option<Cache> {
+    let mut lines = contents.lines();
+    if lines.next()? != HEADER { return None; }
+
+    let mut cache = Cache::default();
+    let mut resolution: Option<&mut Resolution> = None;
+    let mut member: Option<&mut Symbols> = None;
+    let mut symbols: Option<&mut BTreeMap<String, Symbols>> = None;
+    for line in lines {
+      let (kind, rest) = match line.find(' ') {
+        Some(idx) => (&line[..idx], &line[idx + 1..]),
+        None => (line, ""),
+      };
+      match kind {
+        "resolve" => {
+          let mut fields = rest.splitn(3, ' ');
+          let abs = fields.next()? == "1";
+          let static_only = fields.next()? == "1";
+          let name = PathBuf::from(fields.next()?);
+          let entry = cache.resolutions.entry((abs, name))
+            .or_insert(Resolution {
+              search: vec![],
+              static_only,
+              inputs: vec![],
+              files: vec![],
+            });
+          resolution = Some(entry);
+          symbols = None;
+          member = None;
+        },
+        "search" => resolution.as_mut()?.search.push(rest.into()),
+        "lib" => resolution.as_mut()?.inputs.push(Input::Library(true, rest.into())),
+        "file" => resolution.as_mut()?.inputs.push(Input::File(rest.into())),
+        "flag" => resolution.as_mut()?.inputs.push(Input::Flag(rest.into())),
+        "stamp" => {
+          let mut fields = rest.splitn(3, ' ');
+          let stamp = Stamp::parse(fields.next()?, fields.next()?)?;
+          resolution.as_mut()?.files.push((fields.next()?.into(), stamp));
+        },
+        "symbols" => {
+          let mut fields = rest.splitn(3, ' ');
+          let stamp = Stamp::parse(fields.next()?, fields.next()?)?;
+          let path = PathBuf::from(fields.next()?);
+          let entry = cache.symbols.entry(path)
+            .or_insert((stamp, BTreeMap::new()));
+          symbols = Some(&mut entry.1);
+          resolution = None;
+          member = None;
+        },
+        "member" => {
+          let entry = symbols.as_mut()?.entry(rest.to_string())
+            .or_insert_with(Default::default);
+          member = Some(entry);
+        },
+        "def" => { member.as_mut()?.defined.insert(rest.to_string()); },
+        "undef" => { member.as_mut()?.undefined.insert(rest.to_string()); },
+        _ => return None,
+      }
+    }
+    Some(cache)
+  }
+
+  pub fn save(&self) -> io::Result<()> {
+    use std::fmt::Write;
+
+    fn stamp(s: &Stamp) -> String {
+      format!("{} {}.{}", s.len, s.mtime, s.mtime_nanos)
+    }
+
+    let mut out = format!("{}\n", HEADER);
+    for (&(abs, ref name), r) in self.resolutions.iter() {
+      writeln!(out, "resolve {} {} {}", abs as u8, r.static_only as u8,
+               name.display()).unwrap();
+      for dir in r.search.iter() {
+        writeln!(out, "search {}", dir.display()).unwrap();
+      }
+      for input in r.inputs.iter() {
+        match input {
+          &Input::Library(_, ref p) => writeln!(out, "lib {}", p.display()),
+          &Input::File(ref p) => writeln!(out, "file {}", p.display()),
+          &Input::Flag(ref flag) => writeln!(out, "flag {}", flag),
+        }.unwrap();
+      }
+      for &(ref path, ref s) in r.files.iter() {
+        writeln!(out, "stamp {} {}", stamp(s), path.display()).unwrap();
+      }
+    }
+    for (path, &(ref s, ref members)) in self.symbols.iter() {
+      writeln!(out, "symbols {} {}", stamp(s), path.display()).unwrap();
+      for (name, symbols) in members.iter() {
+        writeln!(out, "member {}", name).unwrap();
+        for sym in symbols.defined.iter() {
+          writeln!(out, "def {}", sym).unwrap();
+        }
+        for sym in symbols.undefined.iter() {
+          writeln!(out, "undef {}", sym).unwrap();
+        }
+      }
+    }
+
+    // write then rename, so concurrent links don't see half a cache.
+    let tmp = self.path.with_extension("relink.tmp");
+    fs::write(&tmp, out)?;
+    fs::rename(&tmp, &self.path)
+  }
+
+  /// What `-l<name>` (or `-l:<name>`, if `abs`) found last time, and the
+  /// files the lookup read, if it'd find the same now.
+  pub fn resolution(&self, abs: bool, name: &Path, search: &[PathBuf],
+                    static_only: bool) -> Option<(Vec<Input>, Vec<PathBuf>)> {
+    let r = self.resolutions.get(&(abs, name.to_path_buf()))?;
+    if &r.search[..] != search || r.static_only != static_only {
+      return None;
+    }
+    if !r.files.iter().all(|&(ref path, ref s)| s.unchanged(path) ) {
+      return None;
+    }
+    let files = r.files.iter()
+      .map(|&(ref path, _)| path.clone() )
+      .collect();
+    Some((r.inputs.clone(), files))
+  }
+  /// `files` are those the lookup read; the library, and any linker
+  /// scripts.
+  pub fn set_resolution(&mut self, abs: bool, name: &Path, search: &[PathBuf],
+                        static_only: bool, inputs: &[Input], files: &[PathBuf]) {
+    let files: Option<Vec<(PathBuf, Stamp)>> = files.iter()
+      .map(|path| Stamp::of(path).map(|s| (path.clone(), s) ) )
+      .collect();
+    let key = (abs, name.to_path_buf());
+    match files {
+      Some(files) => {
+        self.resolutions.insert(key, Resolution {
+          search: search.to_vec(),
+          static_only,
+          inputs: inputs.to_vec(),
+          files,
+        });
+      },
+      None => { self.resolutions.remove(&key); },
+    }
+  }
+
+  /// `path`'s symbols, by member, if it hasn't changed since they were
+  /// read.
+  pub fn symbols(&self, path: &Path) -> Option<BTreeMap<String, Symbols>> {
+    let &(ref s, ref symbols) = self.symbols.get(path)?;
+    if !s.unchanged(path) { return None; }
+    Some(symbols.clone())
+  }
+  pub fn set_symbols(&mut self, path: &Path, symbols: &BTreeMap<String, Symbols>) {
+    if let Some(s) = Stamp::of(path) {
+      self.symbols.insert(path.to_path_buf(), (s, symbols.clone()));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::env;
+  use std::io::Write;
+  use std::process;
+
+  #[test]
+  fn cache() {
+    let dir = env::temp_dir()
+      .join(format!("ld-relink.{}", process::id()));
+    let lib_dir = dir.join("lib");
+    fs::create_dir_all(&lib_dir).unwrap();
+    let libc = lib_dir.join("libc.a");
+    File::create(&libc).unwrap().write_all(b"!<arch>\n").unwrap();
+    let out = dir.join("out.wasm");
+    let search = vec![lib_dir.clone()];
+
+    let mut cache = Cache::load(&out);
+    assert!(cache.resolution(false, Path::new("c"), &search, false).is_none());
+    let inputs = vec![Input::Library(true, libc.clone())];
+    cache.set_resolution(false, Path::new("c"), &search, false, &inputs, &[libc.clone()]);
+    cache.save().unwrap();
+
+    let cache = Cache::load(&out);
+    let cached = cache.resolution(false, Path::new("c"), &search, false);
+    // a different search path could find something else.
+    let other_search = cache.resolution(false, Path::new("c"), &[], false);
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(format!("{:?}", cached), format!("{:?}", Some((inputs, vec![libc]))));
+    assert!(other_search.is_none());
+  }
+}
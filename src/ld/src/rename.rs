@@ -0,0 +1,155 @@
+
+//! `--import-rename`/`--export-rename`: rename a linked module's imports
+//! and exports, to match whatever naming the host ABI expects, without
+//! touching the sources.
+
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fs::{File, rename};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use wasm::{IMPORT_SECTION, EXPORT_SECTION, read_leb, read_name, sections,
+           write_leb, write_name, write_section, };
+
+/// Parse a `<from>=<to>` rename.
+pub fn parse_rename(arg: &str) -> Result<(String, String), Box<Error>> {
+  let mut split = arg.splitn(2, '=');
+  match (split.next(), split.next()) {
+    (Some(from), Some(to)) if from.len() != 0 && to.len() != 0 => {
+      Ok((from.to_string(), to.to_string()))
+    },
+    _ => Err(format!("invalid rename `{}`; expected `<from>=<to>`", arg))?,
+  }
+}
+
+fn renamed<'a>(renames: &'a [(String, String)], name: &[u8],
+               used: &mut BTreeSet<usize>) -> Option<&'a [u8]>
+{
+  renames.iter()
+    .position(|&(ref from, _)| from.as_bytes() == name )
+    .map(|idx| {
+      used.insert(idx);
+      renames[idx].1.as_bytes()
+    })
+}
+
+/// Skip an import's description, returning its end.
+fn skip_import_desc(payload: &[u8], mut pos: usize) -> Result<usize, Box<Error>> {
+  let kind = *payload.get(pos).ok_or_else(|| "unexpected end of module" )?;
+  pos += 1;
+  let limits = |pos: &mut usize| -> Result<(), Box<Error>> {
+    let flags = *payload.get(*pos).ok_or_else(|| "unexpected end of module" )?;
+    *pos += 1;
+    read_leb(payload, pos)?;
+    if flags & 1 != 0 { read_leb(payload, pos)?; }
+    Ok(())
+  };
+  match kind {
+    0 => { read_leb(payload, &mut pos)?; },
+    1 => {
+      pos += 1; // ref type
+      limits(&mut pos)?;
+    },
+    2 => limits(&mut pos)?,
+    3 => { pos += 2; }, // value type, mutability
+    4 => {
+      pos += 1; // attribute
+      read_leb(payload, &mut pos)?;
+    },
+    k => Err(format!("unknown import kind {}", k))?,
+  }
+  Ok(pos)
+}
+
+fn rename_imports(payload: &[u8], renames: &[(String, String)],
+                  used: &mut BTreeSet<usize>) -> Result<Vec<u8>, Box<Error>> {
+  let mut pos = 0;
+  let count = read_leb(payload, &mut pos)?;
+  let mut out = Vec::with_capacity(payload.len());
+  write_leb(&mut out, count);
+  for _ in 0..count {
+    let module = read_name(payload, &mut pos)?;
+    let name = read_name(payload, &mut pos)?;
+    let desc_start = pos;
+    pos = skip_import_desc(payload, pos)?;
+    let desc = payload.get(desc_start..pos)
+      .ok_or_else(|| "unexpected end of module" )?;
+
+    write_name(&mut out, module);
+    write_name(&mut out, renamed(renames, name, used).unwrap_or(name));
+    out.extend_from_slice(desc);
+  }
+  Ok(out)
+}
+
+fn rename_exports(payload: &[u8], renames: &[(String, String)],
+                  used: &mut BTreeSet<usize>) -> Result<Vec<u8>, Box<Error>> {
+  let mut pos = 0;
+  let count = read_leb(payload, &mut pos)?;
+  let mut out = Vec::with_capacity(payload.len());
+  write_leb(&mut out, count);
+  let mut names = BTreeSet::new();
+  for _ in 0..count {
+    let name = read_name(payload, &mut pos)?;
+    let kind = *payload.get(pos).ok_or_else(|| "unexpected end of module" )?;
+    pos += 1;
+    let idx = read_leb(payload, &mut pos)?;
+
+    let name = renamed(renames, name, used).unwrap_or(name);
+    if !names.insert(name) {
+      Err(format!("`--export-rename`: duplicate export `{}`",
+                  String::from_utf8_lossy(name)))?;
+    }
+    write_name(&mut out, name);
+    out.push(kind);
+    write_leb(&mut out, idx);
+  }
+  Ok(out)
+}
+
+/// Rename `module`'s imports (by field name) and exports in place.
+pub fn rename_interface(module: &Path, imports: &[(String, String)],
+                        exports: &[(String, String)])
+  -> Result<(), Box<Error>>
+{
+  let mut bytes = Vec::new();
+  File::open(module)?.read_to_end(&mut bytes)?;
+  let sections = sections(&bytes[..])
+    .map_err(|e| format!("reading `{}`: {}", module.display(), e) )?;
+
+  let mut used_imports = BTreeSet::new();
+  let mut used_exports = BTreeSet::new();
+  let mut out = Vec::with_capacity(bytes.len());
+  out.extend_from_slice(&bytes[..8]);
+  for &(id, start, payload, end) in sections.iter() {
+    let payload = &bytes[payload..end];
+    match id {
+      IMPORT_SECTION if imports.len() != 0 => {
+        let payload = rename_imports(payload, imports, &mut used_imports)?;
+        write_section(&mut out, id, &payload[..]);
+      },
+      EXPORT_SECTION if exports.len() != 0 => {
+        let payload = rename_exports(payload, exports, &mut used_exports)?;
+        write_section(&mut out, id, &payload[..]);
+      },
+      _ => out.extend_from_slice(&bytes[start..end]),
+    }
+  }
+
+  for (idx, &(ref from, _)) in imports.iter().enumerate() {
+    if !used_imports.contains(&idx) {
+      util::diag::warning(&format!("`--import-rename`: no import named `{}`", from));
+    }
+  }
+  for (idx, &(ref from, _)) in exports.iter().enumerate() {
+    if !used_exports.contains(&idx) {
+      util::diag::warning(&format!("`--export-rename`: no export named `{}`", from));
+    }
+  }
+
+  let tmp: PathBuf = module.with_extension("wasm.tmp");
+  File::create(&tmp)?.write_all(&out[..])?;
+  rename(&tmp, module)?;
+  Ok(())
+}
@@ -0,0 +1,151 @@
+
+//! `--driver-reproduce=<tar>`: a self contained reproducer for link bugs,
+//! like lld's own `--reproduce`, but taken from our side: every file the
+//! final wasm-ld command reads (including our intermediates), that command
+//! line rewritten to refer to the copies, and what the toolchain is.
+//! Unpack it and run `wasm-ld @response.txt` from the top dir.
+
+use std::env;
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+
+use tar::{Builder, Header};
+
+use util::report::shell_quote;
+use util::toolchain::WasmToolchain;
+
+/// Where `path` goes in the tarball: absolute paths, minus the root, so
+/// files from different dirs with the same name don't collide.
+fn archived_path(path: &Path) -> Result<PathBuf, Box<Error>> {
+  let path = env::current_dir()?.join(path);
+  Ok(path.components()
+    .filter_map(|c| match c {
+      Component::Normal(c) => Some(c),
+      _ => None,
+    })
+    .collect())
+}
+
+/// The flag and file of a `--flag=<path>` arg, if `path` is a file.
+fn joined_file(arg: &str) -> Option<(&str, &Path)> {
+  if !arg.starts_with('-') {
+    return None;
+  }
+  let eq = arg.find('=')?;
+  let path = Path::new(&arg[eq + 1..]);
+  if path.is_file() {
+    Some((&arg[..eq + 1], path))
+  } else {
+    None
+  }
+}
+
+fn append_data(builder: &mut Builder<File>, path: &Path, data: &[u8])
+  -> Result<(), Box<Error>>
+{
+  let mut header = Header::new_gnu();
+  header.set_size(data.len() as u64);
+  header.set_mode(0o644);
+  header.set_cksum();
+  builder.append_data(&mut header, path, data)?;
+  Ok(())
+}
+
+fn version_info(tc: &WasmToolchain, lld: &Path) -> String {
+  let mut out = format!("wasm-ld driver: {}\n", env!("CARGO_PKG_VERSION"));
+  out.push_str(&format!("llvm: {}\n", tc.llvm.display()));
+  out.push_str(&format!("binaryen: {}\n", tc.binaryen.display()));
+  out.push_str(&format!("sysroot: {}\n", tc.sysroot.display()));
+  out.push_str(&format!("threads: {}\n", tc.threads.as_str()));
+  let mut cmd = Command::new(lld);
+  cmd.arg("--version");
+  ::util::hermetic_env(&mut cmd);
+  let version = cmd.output();
+  match version {
+    Ok(ref output) if output.status.success() => {
+      out.push_str(&format!("\n{}", String::from_utf8_lossy(&output.stdout)));
+    },
+    Ok(output) => out.push_str(&format!("\n`{} --version` failed: {}\n",
+                                        lld.display(), output.status)),
+    Err(e) => out.push_str(&format!("\n`{} --version` failed: {}\n",
+                                    lld.display(), e)),
+  }
+  out
+}
+
+/// Write a reproducer for `cmd` (the final wasm-ld command) to `tar`.
+pub fn write(tar: &Path, cmd: &Command, tc: &WasmToolchain) -> Result<(), Box<Error>> {
+  let root = tar.file_stem()
+    .map(Path::new)
+    .unwrap_or(Path::new("repro"))
+    .to_path_buf();
+  let mut builder = Builder::new(File::create(tar)?);
+
+  let mut response = Vec::new();
+  let mut args = cmd.get_args();
+  while let Some(arg) = args.next() {
+    let arg = arg.to_string_lossy().into_owned();
+    let path = Path::new(&arg);
+    if arg == "-o" {
+      // the output is rewritten so the reproducer doesn't clobber anything.
+      let output = args.next()
+        .and_then(|out| Path::new(out).file_name() )
+        .unwrap_or(OsStr::new("a.out"));
+      response.push("-o".to_string());
+      response.push(output.to_string_lossy().into_owned());
+    } else if arg == "-L" {
+      // libraries are archived individually, below their dirs.
+      if let Some(dir) = args.next() {
+        response.push(arg.clone());
+        response.push(format!("{}", archived_path(Path::new(dir))?.display()));
+      }
+    } else if !arg.starts_with('-') && path.is_file() {
+      let archived = archived_path(path)?;
+      builder.append_path_with_name(path, root.join(&archived))?;
+      response.push(format!("{}", archived.display()));
+    } else if let Some((flag, path)) = joined_file(&arg) {
+      // ie `--version-script=<path>`.
+      let archived = archived_path(path)?;
+      builder.append_path_with_name(path, root.join(&archived))?;
+      response.push(format!("{}{}", flag, archived.display()));
+    } else {
+      response.push(arg.clone());
+    }
+  }
+
+  // `-l` libs are in the -L dirs; only the ones which are used matter.
+  let all_args: Vec<&OsStr> = cmd.get_args().collect();
+  let lib_dirs: Vec<PathBuf> = all_args.windows(2)
+    .filter(|pair| pair[0] == "-L" )
+    .map(|pair| PathBuf::from(pair[1]) )
+    .collect();
+  for arg in cmd.get_args() {
+    let name = match arg.to_str() {
+      Some(arg) if arg.starts_with("-l") => &arg[2..],
+      _ => continue,
+    };
+    let lib = lib_dirs.iter()
+      .flat_map(|dir| {
+        vec![dir.join(format!("lib{}.so", name)), dir.join(format!("lib{}.a", name))]
+      })
+      .find(|lib| lib.is_file() );
+    if let Some(lib) = lib {
+      builder.append_path_with_name(&lib, root.join(archived_path(&lib)?))?;
+    }
+  }
+
+  // lld reads response files with GNU quoting, which takes POSIX shell
+  // quoting as is.
+  let response: Vec<String> = response.iter()
+    .map(|arg| shell_quote(arg) )
+    .collect();
+  append_data(&mut builder, &root.join("response.txt"),
+              format!("{}\n", response.join("\n")).as_bytes())?;
+  append_data(&mut builder, &root.join("version.txt"),
+              version_info(tc, Path::new(cmd.get_program())).as_bytes())?;
+  builder.finish()?;
+  Ok(())
+}
@@ -0,0 +1,153 @@
+
+//! `--emit-runner`: a small node script or HTML page next to a linked
+//! executable which instantiates it and calls its entry point, so it can be
+//! run straight away, `a.out` style. Imported memory/tables are created
+//! with the limits the module asks for; other imports are stubs which throw
+//! when called.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use util::json_string;
+
+use symfile::{self, Limits};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Runner {
+  /// `<output>.js`; `node a.out.js`.
+  Node,
+  /// `<output>.html`, which has to be served alongside the module.
+  Html,
+}
+impl FromStr for Runner {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Runner, String> {
+    match s {
+      "node" => Ok(Runner::Node),
+      "html" => Ok(Runner::Html),
+      _ => Err(format!("unknown runner `{}`; expected `node` or `html`", s)),
+    }
+  }
+}
+impl Runner {
+  pub fn path(&self, module: &Path) -> PathBuf {
+    let ext = match self {
+      &Runner::Node => "js",
+      &Runner::Html => "html",
+    };
+    PathBuf::from(format!("{}.{}", module.display(), ext))
+  }
+}
+
+/// A JSON string is a JS string too; `<` is escaped as well, so a
+/// `</script>` can't end the HTML runner's script early.
+fn js_string(s: &str) -> String {
+  json_string(s).replace('<', "\\u003c")
+}
+
+fn descriptor(l: &Option<Limits>, extra: &str) -> String {
+  match l {
+    &Some(ref l) => {
+      let mut out = format!("{{{}initial: {}", extra, l.min);
+      if let Some(max) = l.max {
+        out.push_str(&format!(", maximum: {}", max));
+      }
+      if l.shared {
+        out.push_str(", shared: true");
+      }
+      out.push_str("}");
+      out
+    },
+    &None => "null".into(),
+  }
+}
+
+/// The harness both runners share: `run(bytes)` resolves to whatever the
+/// entry point returns.
+fn run_fn(memory: &Option<Limits>, table: &Option<Limits>,
+          entry: Option<&str>) -> String {
+  format!(r#"const MEMORY = {memory};
+const TABLE = {table};
+const ENTRY = {entry};
+
+async function run(bytes) {{
+  const module = await WebAssembly.compile(bytes);
+  const imports = {{}};
+  for (const imp of WebAssembly.Module.imports(module)) {{
+    const ns = imports[imp.module] = imports[imp.module] || {{}};
+    const name = `${{imp.module}}.${{imp.name}}`;
+    if (imp.kind === "memory" && MEMORY) {{
+      ns[imp.name] = new WebAssembly.Memory(MEMORY);
+    }} else if (imp.kind === "table" && TABLE) {{
+      ns[imp.name] = new WebAssembly.Table(TABLE);
+    }} else if (imp.kind === "function") {{
+      ns[imp.name] = () => {{ throw new Error(`unimplemented import \`${{name}}\``); }};
+    }} else {{
+      throw new Error(`can't provide ${{imp.kind}} import \`${{name}}\``);
+    }}
+  }}
+
+  const instance = await WebAssembly.instantiate(module, imports);
+  const exports = instance.exports;
+  if (ENTRY && exports[ENTRY]) {{
+    return exports[ENTRY]();
+  }}
+  if (exports.__wasm_call_ctors) {{
+    exports.__wasm_call_ctors();
+  }}
+}}
+"#,
+          memory = descriptor(memory, ""),
+          table = descriptor(table, "element: \"anyfunc\", "),
+          entry = entry.map(js_string).unwrap_or_else(|| "null".into() ))
+}
+
+/// Write `runner` for `module`, next to it.
+pub fn write_runner(runner: Runner, module: &Path, entry: Option<&str>)
+  -> Result<(), Box<Error>>
+{
+  let (memory, table) = symfile::imported_limits(module)?;
+  let name = module.file_name()
+    .ok_or_else(|| format!("invalid output `{}`", module.display()) )?
+    .to_string_lossy()
+    .into_owned();
+  let run = run_fn(&memory, &table, entry);
+
+  let out = match runner {
+    Runner::Node => format!(r#"// Generated by wasm-ld; runs `{name}`.
+"use strict";
+{run}
+const bytes = require("fs").readFileSync(require("path").join(__dirname, {path}));
+run(bytes).then(ret => {{
+  if (typeof ret === "number") {{ process.exitCode = ret; }}
+}}, err => {{
+  console.error(err);
+  process.exitCode = 1;
+}});
+"#, name = name, run = run, path = js_string(&name)),
+    Runner::Html => format!(r#"<!DOCTYPE html>
+<!-- Generated by wasm-ld; runs `{name}`. -->
+<html>
+<head><meta charset="utf-8"><title>{name}</title></head>
+<body>
+<script>
+"use strict";
+{run}
+fetch({path})
+  .then(response => response.arrayBuffer())
+  .then(run)
+  .then(ret => console.log(`{name} returned ${{ret}}`), err => console.error(err));
+</script>
+</body>
+</html>
+"#, name = name.replace('&', "&amp;").replace('<', "&lt;"), run = run,
+                            path = js_string(&name)),
+  };
+
+  let path = runner.path(module);
+  File::create(&path)?.write_all(out.as_bytes())?;
+  Ok(())
+}
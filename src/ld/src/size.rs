@@ -0,0 +1,350 @@
+//! `wasm-size`: a module's footprint by section, like `llvm-size`. `text` is
+//! the code and every other non-custom section except data (ie the types,
+//! imports and exports); `data` is the data section; `custom` is every
+//! custom section (debug info, names, etc). `--diff old.wasm new.wasm`
+//! compares two modules section by section, for spotting size
+//! regressions.
+
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use util::{CommandQueue, Tool, ToolArgs, ToolInvocation, json_string, };
+
+use wasm::{self, CUSTOM_SECTION, DATA_SECTION, sections, custom_section_name};
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Format {
+  Berkeley,
+  SysV,
+  Json,
+}
+impl Default for Format {
+  fn default() -> Format { Format::Berkeley }
+}
+impl FromStr for Format {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Format, String> {
+    match s {
+      "berkeley" => Ok(Format::Berkeley),
+      "sysv" => Ok(Format::SysV),
+      "json" => Ok(Format::Json),
+      _ => Err(format!("unknown format `{}`; expected `berkeley`, `sysv` or `json`",
+                       s)),
+    }
+  }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Section {
+  pub name: String,
+  pub custom: bool,
+  pub data: bool,
+  /// of the payload.
+  pub offset: usize,
+  pub size: usize,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Sizes {
+  pub sections: Vec<Section>,
+}
+impl Sizes {
+  pub fn of_module(module: &[u8]) -> Result<Sizes, Box<Error>> {
+    let mut out = Vec::new();
+    for (id, _, payload, end) in sections(module)?.into_iter() {
+      let name = if id == CUSTOM_SECTION {
+        custom_section_name(&module[payload..end])
+          .map(|name| String::from_utf8_lossy(name).into_owned() )
+          .unwrap_or_else(|| "<custom>".into() )
+      } else {
+        wasm::section_name(id)
+          .map(|name| name.to_uppercase() )
+          .unwrap_or_else(|| format!("<section {}>", id) )
+      };
+      out.push(Section {
+        name,
+        custom: id == CUSTOM_SECTION,
+        data: id == DATA_SECTION,
+        offset: payload,
+        size: end - payload,
+      });
+    }
+    Ok(Sizes { sections: out })
+  }
+  pub fn of_file(path: &Path) -> Result<Sizes, Box<Error>> {
+    let module = fs::read(path)
+      .map_err(|e| format!("reading `{}`: {}", path.display(), e) )?;
+    Sizes::of_module(&module)
+      .map_err(|e| format!("`{}`: {}", path.display(), e).into() )
+  }
+
+  pub fn text(&self) -> usize {
+    self.sections.iter()
+      .filter(|s| !s.custom && !s.data )
+      .map(|s| s.size )
+      .sum()
+  }
+  pub fn data(&self) -> usize {
+    self.sections.iter()
+      .filter(|s| s.data )
+      .map(|s| s.size )
+      .sum()
+  }
+  pub fn custom(&self) -> usize {
+    self.sections.iter()
+      .filter(|s| s.custom )
+      .map(|s| s.size )
+      .sum()
+  }
+  pub fn total(&self) -> usize {
+    self.sections.iter().map(|s| s.size ).sum()
+  }
+
+  /// Total size by section name, in order of appearance; custom sections
+  /// can repeat.
+  fn by_name(&self) -> Vec<(String, usize)> {
+    let mut out: Vec<(String, usize)> = Vec::new();
+    for s in self.sections.iter() {
+      match out.iter_mut().find(|&&mut (ref name, _)| name == &s.name ) {
+        Some(&mut (_, ref mut size)) => { *size += s.size; },
+        None => out.push((s.name.clone(), s.size)),
+      }
+    }
+    out
+  }
+}
+
+
+/// The report for each of `files`.
+pub fn report(format: Format, files: &[(PathBuf, Sizes)]) -> String {
+  let mut out = String::new();
+  match format {
+    Format::Berkeley => {
+      out.push_str(&format!("{:>10} {:>10} {:>10} {:>10} {:>10} filename\n",
+                            "text", "data", "custom", "dec", "hex"));
+      for &(ref path, ref sizes) in files.iter() {
+        out.push_str(&format!("{:>10} {:>10} {:>10} {:>10} {:>10x} {}\n",
+                              sizes.text(), sizes.data(), sizes.custom(),
+                              sizes.total(), sizes.total(), path.display()));
+      }
+    },
+    Format::SysV => {
+      for &(ref path, ref sizes) in files.iter() {
+        out.push_str(&format!("{}  :\n", path.display()));
+        out.push_str(&format!("{:<20} {:>10} {:>10}\n", "section", "size", "addr"));
+        for s in sizes.sections.iter() {
+          out.push_str(&format!("{:<20} {:>10} {:>10}\n", s.name, s.size, s.offset));
+        }
+        out.push_str(&format!("{:<20} {:>10}\n\n", "Total", sizes.total()));
+      }
+    },
+    Format::Json => {
+      out.push('[');
+      for (idx, &(ref path, ref sizes)) in files.iter().enumerate() {
+        if idx != 0 { out.push(','); }
+        out.push_str(&format!("\n  {{\"file\": {}, \"text\": {}, \"data\": {}, \
+                               \"custom\": {}, \"total\": {}, \"sections\": [",
+                              json_string(&format!("{}", path.display())), sizes.text(),
+                              sizes.data(), sizes.custom(), sizes.total()));
+        for (idx, s) in sizes.sections.iter().enumerate() {
+          if idx != 0 { out.push_str(", "); }
+          out.push_str(&format!("{{\"name\": {}, \"size\": {}, \"offset\": {}}}",
+                                json_string(&s.name), s.size, s.offset));
+        }
+        out.push_str("]}");
+      }
+      if files.len() != 0 { out.push('\n'); }
+      out.push_str("]\n");
+    },
+  }
+  out
+}
+
+/// The section by section (by name) comparison of `old` and `new`.
+pub fn diff(format: Format, old: &(PathBuf, Sizes), new: &(PathBuf, Sizes)) -> String {
+  let old_sizes = old.1.by_name();
+  let new_sizes = new.1.by_name();
+  let mut rows: Vec<(String, usize, usize)> = new_sizes.iter()
+    .map(|&(ref name, size)| {
+      let prev = old_sizes.iter()
+        .find(|&&(ref n, _)| n == name )
+        .map(|&(_, size)| size )
+        .unwrap_or(0);
+      (name.clone(), prev, size)
+    })
+    .collect();
+  for &(ref name, size) in old_sizes.iter() {
+    if !new_sizes.iter().any(|&(ref n, _)| n == name ) {
+      rows.push((name.clone(), size, 0));
+    }
+  }
+  let totals = [
+    ("text", old.1.text(), new.1.text()),
+    ("data", old.1.data(), new.1.data()),
+    ("custom", old.1.custom(), new.1.custom()),
+    ("total", old.1.total(), new.1.total()),
+  ];
+
+  fn delta(old: usize, new: usize) -> String {
+    if new >= old {
+      format!("+{}", new - old)
+    } else {
+      format!("-{}", old - new)
+    }
+  }
+
+  let mut out = String::new();
+  match format {
+    Format::Berkeley | Format::SysV => {
+      out.push_str(&format!("{} -> {}\n", old.0.display(), new.0.display()));
+      out.push_str(&format!("{:<20} {:>10} {:>10} {:>10}\n", "section", "old", "new", "delta"));
+      for &(ref name, old, new) in rows.iter() {
+        out.push_str(&format!("{:<20} {:>10} {:>10} {:>10}\n", name, old, new, delta(old, new)));
+      }
+      for &(name, old, new) in totals.iter() {
+        out.push_str(&format!("{:<20} {:>10} {:>10} {:>10}\n", name, old, new, delta(old, new)));
+      }
+    },
+    Format::Json => {
+      out.push_str(&format!("{{\n  \"old\": {},\n  \"new\": {},\n",
+                            json_string(&format!("{}", old.0.display())),
+                            json_string(&format!("{}", new.0.display()))));
+      for &(name, old, new) in totals.iter() {
+        out.push_str(&format!("  \"{}\": {{\"old\": {}, \"new\": {}, \"delta\": {}}},\n",
+                              name, old, new, new as i64 - old as i64));
+      }
+      out.push_str("  \"sections\": [");
+      for (idx, &(ref name, old, new)) in rows.iter().enumerate() {
+        if idx != 0 { out.push(','); }
+        out.push_str(&format!("\n    {{\"name\": {}, \"old\": {}, \"new\": {}, \"delta\": {}}}",
+                              json_string(name), old, new, new as i64 - old as i64));
+      }
+      if rows.len() != 0 { out.push_str("\n  "); }
+      out.push_str("]\n}\n");
+    },
+  }
+  out
+}
+
+#[derive(Debug, Default)]
+pub struct Invocation {
+  pub inputs: Vec<PathBuf>,
+  pub format: Format,
+  /// Compare the two inputs, old then new.
+  pub diff: bool,
+}
+
+impl Tool for Invocation {
+  fn enqueue_commands(&mut self, queue: &mut CommandQueue<Self>) -> Result<(), Box<Error>> {
+    let inputs = self.inputs.clone();
+    let format = self.format;
+    let diff = self.diff;
+    queue.enqueue_function(Some("size"), move |_| {
+      let files = inputs.into_iter()
+        .map(|path| Sizes::of_file(&path).map(|sizes| (path, sizes) ) )
+        .collect::<Result<Vec<_>, _>>()?;
+      if diff {
+        print!("{}", self::diff(format, &files[0], &files[1]));
+      } else {
+        print!("{}", report(format, &files[..]));
+      }
+      Ok(())
+    });
+    Ok(())
+  }
+
+  fn get_name(&self) -> String { From::from("wasm-size") }
+
+  fn add_tool_input(&mut self, input: PathBuf) -> Result<(), Box<Error>> {
+    self.inputs.push(input);
+    Ok(())
+  }
+
+  fn get_output(&self) -> Option<&PathBuf> { None }
+  fn override_output(&mut self, _out: PathBuf) { }
+}
+
+impl ToolInvocation for Invocation {
+  fn check_state(&mut self, iteration: usize, skip_inputs_check: bool)
+    -> Result<(), Box<Error>>
+  {
+    match iteration {
+      1 if !skip_inputs_check && self.inputs.len() == 0 => {
+        Err("no inputs")?;
+      },
+      1 if self.diff && self.inputs.len() != 2 => {
+        Err("`--diff` compares exactly two modules, old then new")?;
+      },
+      _ => {},
+    }
+
+    Ok(())
+  }
+
+  fn args(&self, iteration: usize) -> Option<ToolArgs<Self>> {
+    match iteration {
+      0 => tool_arguments!(Invocation => [
+        FORMAT,
+        DIFF,
+      ]),
+      1 => tool_arguments!(Invocation => [
+        INPUTS,
+      ]),
+      _ => None,
+    }
+  }
+}
+
+tool_argument! {
+  pub FORMAT: Invocation = single_and_split_from_str(format) "format" =>
+  fn format_arg(this) {
+    this.format = format;
+  }
+}
+argument!(impl DIFF where { Some(r"^--diff$"), None } for Invocation {
+  fn diff_flag(this, _single, _cap) {
+    this.diff = true;
+  }
+});
+argument!(impl INPUTS where { Some(r"^(.+)$"), None } for Invocation {
+  fn add_input(this, _single, cap) {
+    let input = cap.get(0).unwrap().as_str();
+    this.inputs.push(PathBuf::from(input));
+  }
+});
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn berkeley_report_and_diff() {
+    // type, function and code sections, then a `name` custom section.
+    let old: &[u8] = b"\0asm\x01\0\0\0\x01\x04\x01\x60\0\0\x03\x02\x01\0\
+                       \x0a\x04\x01\x02\0\x0b\0\x08\x04name\x01\x02\x03";
+    // a bigger function, and a data section instead.
+    let new: &[u8] = b"\0asm\x01\0\0\0\x01\x04\x01\x60\0\0\x03\x02\x01\0\
+                       \x0a\x06\x01\x04\0\x01\x1a\x0b\x0b\x03\x01\0\0";
+    let old = (PathBuf::from("old.wasm"), Sizes::of_module(old).unwrap());
+    let new = (PathBuf::from("new.wasm"), Sizes::of_module(new).unwrap());
+    assert_eq!(report(Format::Berkeley, &[old.clone(), new.clone()]),
+               "      text       data     custom        dec        hex filename\n\
+                \x20       10          0          8         18         12 old.wasm\n\
+                \x20       12          3          0         15          f new.wasm\n");
+    let diff = diff(Format::Berkeley, &old, &new);
+    let rows: Vec<&str> = diff.lines().skip(2).collect();
+    assert_eq!(rows, vec![
+      "TYPE                          4          4         +0",
+      "FUNCTION                      2          2         +0",
+      "CODE                          4          6         +2",
+      "DATA                          0          3         +3",
+      "name                          8          0         -8",
+      "text                         10         12         +2",
+      "data                          0          3         +3",
+      "custom                        8          0         -8",
+      "total                        18         15         -3",
+    ]);
+  }
+}
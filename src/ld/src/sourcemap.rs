@@ -0,0 +1,213 @@
+
+//! `--source-map`: a source map (v3) for the linked module, built from its
+//! DWARF line table, so browser devtools can step through the C/C++ sources
+//! without the full DWARF. The module's `.debug_*` sections are dropped
+//! afterwards and a `sourceMappingURL` section pointing at the map added.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{File, rename};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use util::regex::Regex;
+use util::json_string;
+
+use wasm::{CUSTOM_SECTION, CODE_SECTION, custom_section_name, sections,
+           write_name, write_section, };
+
+lazy_static! {
+  static ref INCLUDE_DIR_RE: Regex =
+    Regex::new(r#"^include_directories\[\s*(\d+)\] = "(.*)"$"#).unwrap();
+  static ref FILE_NAME_RE: Regex =
+    Regex::new(r"^file_names\[\s*(\d+)\]:$").unwrap();
+  static ref NAME_RE: Regex = Regex::new(r#"^name: "(.*)"$"#).unwrap();
+  static ref DIR_INDEX_RE: Regex = Regex::new(r"^dir_index: (\d+)$").unwrap();
+  static ref ROW_RE: Regex =
+    Regex::new(r"^0x([0-9a-f]+)\s+(\d+)\s+(\d+)\s+(\d+)\s+\d+\s+\d+\s*(.*)$")
+    .unwrap();
+}
+
+#[derive(Debug)]
+struct Row {
+  address: u64,
+  file: String,
+  line: u64,
+  column: u64,
+}
+
+/// `llvm-dwarfdump --debug-line` output -> rows, with file indices resolved
+/// to paths. Each line table (one per CU) has its own files.
+fn parse_line_tables(dump: &str) -> Vec<Row> {
+  let mut rows = Vec::new();
+
+  let mut dirs: BTreeMap<u64, String> = BTreeMap::new();
+  let mut files: BTreeMap<u64, (String, u64)> = BTreeMap::new();
+  let mut current_file: Option<u64> = None;
+  for line in dump.lines() {
+    let line = line.trim();
+    if line.starts_with("debug_line[") {
+      dirs.clear();
+      files.clear();
+      current_file = None;
+    } else if let Some(cap) = INCLUDE_DIR_RE.captures(line) {
+      dirs.insert(cap[1].parse().unwrap(), cap[2].to_string());
+    } else if let Some(cap) = FILE_NAME_RE.captures(line) {
+      let idx = cap[1].parse().unwrap();
+      files.insert(idx, (String::new(), 0));
+      current_file = Some(idx);
+    } else if let (Some(cap), Some(idx)) = (NAME_RE.captures(line), current_file) {
+      files.get_mut(&idx).unwrap().0 = cap[1].to_string();
+    } else if let (Some(cap), Some(idx)) = (DIR_INDEX_RE.captures(line), current_file) {
+      files.get_mut(&idx).unwrap().1 = cap[1].parse().unwrap();
+    } else if let Some(cap) = ROW_RE.captures(line) {
+      if cap[5].contains("end_sequence") { continue; }
+      let line: u64 = cap[2].parse().unwrap();
+      if line == 0 { continue; }
+
+      let file = match files.get(&cap[4].parse().unwrap()) {
+        Some(&(ref name, dir)) => match dirs.get(&dir) {
+          Some(dir) if !Path::new(name).is_absolute() => {
+            format!("{}", Path::new(dir).join(name).display())
+          },
+          _ => name.clone(),
+        },
+        None => continue,
+      };
+      rows.push(Row {
+        address: u64::from_str_radix(&cap[1], 16).unwrap(),
+        file,
+        line,
+        column: cap[3].parse().unwrap(),
+      });
+    }
+  }
+
+  rows
+}
+
+fn vlq(out: &mut String, v: i64) {
+  const BASE64: &'static [u8] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+  let mut v = if v < 0 { ((-v) << 1) | 1 } else { v << 1 };
+  loop {
+    let mut digit = v & 0x1f;
+    v >>= 5;
+    if v != 0 { digit |= 0x20; }
+    out.push(BASE64[digit as usize] as char);
+    if v == 0 { break; }
+  }
+}
+
+
+/// Wasm source maps have a single "line"; the column is the byte offset
+/// into the module, ie DWARF's code section relative address plus
+/// `code_offset`.
+fn to_json(file: &str, mut rows: Vec<Row>, code_offset: u64) -> String {
+  rows.sort_by_key(|row| row.address );
+  rows.dedup_by_key(|row| row.address );
+
+  let mut sources: Vec<String> = Vec::new();
+  let mut mappings = String::new();
+  let (mut prev_col, mut prev_src, mut prev_line, mut prev_src_col) =
+    (0i64, 0i64, 0i64, 0i64);
+  for row in rows.into_iter() {
+    let src = match sources.iter().position(|s| s == &row.file ) {
+      Some(src) => src,
+      None => {
+        sources.push(row.file);
+        sources.len() - 1
+      },
+    } as i64;
+    let col = (row.address + code_offset) as i64;
+    let line = row.line as i64 - 1;
+    let src_col = (row.column as i64 - 1).max(0);
+
+    if mappings.len() != 0 { mappings.push(','); }
+    vlq(&mut mappings, col - prev_col);
+    vlq(&mut mappings, src - prev_src);
+    vlq(&mut mappings, line - prev_line);
+    vlq(&mut mappings, src_col - prev_src_col);
+    prev_col = col;
+    prev_src = src;
+    prev_line = line;
+    prev_src_col = src_col;
+  }
+
+  let sources: Vec<String> = sources.iter()
+    .map(|s| json_string(s) )
+    .collect();
+  format!("{{\"version\": 3, \"file\": {}, \"sources\": [{}], \"names\": [], \
+           \"mappings\": {}}}\n",
+          json_string(file), sources.join(", "), json_string(&mappings))
+}
+
+/// Write a source map for `module` to `map`, then replace `module`'s DWARF
+/// with a `sourceMappingURL` section (`base` + the map's file name).
+pub fn write_source_map(dwarfdump: &Path, module: &Path, map: &Path,
+                        base: Option<&str>)
+  -> Result<(), Box<Error>>
+{
+  let mut bytes = Vec::new();
+  File::open(module)?.read_to_end(&mut bytes)?;
+  let sections = sections(&bytes[..])
+    .map_err(|e| format!("reading `{}`: {}", module.display(), e) )?;
+
+  let mut cmd = Command::new(dwarfdump);
+  cmd.arg("--debug-line")
+    .arg(module);
+  ::util::hermetic_env(&mut cmd);
+  let output = cmd.output()?;
+  if !output.status.success() {
+    Err(format!("`{}` failed: {}", dwarfdump.display(),
+                String::from_utf8_lossy(&output.stderr)))?;
+  }
+  let rows = parse_line_tables(&String::from_utf8_lossy(&output.stdout));
+  if rows.len() == 0 {
+    Err(format!("`{}` has no line info for a source map; compile with `-g` \
+                 (and don't strip debug info)", module.display()))?;
+  }
+
+  let map_name = map.file_name()
+    .ok_or_else(|| format!("invalid source map path `{}`", map.display()) )?
+    .to_string_lossy()
+    .into_owned();
+  let url = format!("{}{}", base.unwrap_or(""), map_name);
+
+  // the offsets in the map are into the rewritten module.
+  let mut out = Vec::with_capacity(bytes.len());
+  out.extend_from_slice(&bytes[..8]);
+  let mut code_offset = None;
+  for &(id, start, payload, end) in sections.iter() {
+    if id == CUSTOM_SECTION {
+      match custom_section_name(&bytes[payload..end]) {
+        Some(name) if name.starts_with(b".debug_") ||
+          name == b"sourceMappingURL" => continue,
+        _ => {},
+      }
+    }
+    if id == CODE_SECTION {
+      code_offset = Some((out.len() + payload - start) as u64);
+    }
+    out.extend_from_slice(&bytes[start..end]);
+  }
+  let code_offset = code_offset
+    .ok_or_else(|| format!("`{}` has no code", module.display()) )?;
+
+  let mut payload = Vec::new();
+  write_name(&mut payload, b"sourceMappingURL");
+  write_name(&mut payload, url.as_bytes());
+  write_section(&mut out, CUSTOM_SECTION, &payload[..]);
+
+  let module_name = module.file_name()
+    .map(|name| name.to_string_lossy().into_owned() )
+    .unwrap_or_default();
+  File::create(map)?
+    .write_all(to_json(&module_name, rows, code_offset).as_bytes())?;
+
+  let tmp: PathBuf = module.with_extension("wasm.tmp");
+  File::create(&tmp)?.write_all(&out[..])?;
+  rename(&tmp, module)?;
+  Ok(())
+}
@@ -0,0 +1,342 @@
+
+//! `--sym-file`: a JSON manifest of a linked module's imports, exports and
+//! memory/table requirements, for loaders to check their host bindings
+//! against before instantiating anything.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use util::json_string;
+
+use wasm::{read_byte, read_leb, read_limits, read_name, read_val_type, sections,
+           EXPORT_SECTION, FUNCTION_SECTION, IMPORT_SECTION, MEMORY_SECTION,
+           TABLE_SECTION, TYPE_SECTION};
+pub use wasm::Limits;
+
+#[derive(Clone, Debug, Default)]
+struct FuncType {
+  params: Vec<&'static str>,
+  results: Vec<&'static str>,
+}
+
+#[derive(Clone, Debug)]
+enum Desc {
+  Function(u32),
+  Table(&'static str, Limits),
+  Memory(Limits),
+  Global(&'static str, bool),
+  Tag(u32),
+}
+impl Desc {
+  fn kind(&self) -> &'static str {
+    match self {
+      &Desc::Function(_) => "function",
+      &Desc::Table(..) => "table",
+      &Desc::Memory(_) => "memory",
+      &Desc::Global(..) => "global",
+      &Desc::Tag(_) => "tag",
+    }
+  }
+}
+
+#[derive(Debug, Default)]
+struct Module {
+  types: Vec<FuncType>,
+  imports: Vec<(String, String, Desc)>,
+  /// type indices of the defined functions.
+  functions: Vec<u32>,
+  tables: Vec<(&'static str, Limits)>,
+  memories: Vec<Limits>,
+  exports: Vec<(String, u8, u32)>,
+}
+
+fn u32(bytes: &[u8], pos: &mut usize) -> Result<u32, Box<Error>> {
+  Ok(read_leb(bytes, pos)? as u32)
+}
+fn string(bytes: &[u8], pos: &mut usize) -> Result<String, Box<Error>> {
+  Ok(String::from_utf8_lossy(read_name(bytes, pos)?).into_owned())
+}
+
+fn parse(bytes: &[u8]) -> Result<Module, Box<Error>> {
+  let mut m = Module::default();
+  for (id, _, start, end) in sections(bytes)?.into_iter() {
+    let s = &bytes[start..end];
+    let pos = &mut 0;
+    match id {
+      TYPE_SECTION => {
+        for _ in 0..u32(s, pos)? {
+          if read_byte(s, pos)? != 0x60 {
+            Err("unsupported type form")?;
+          }
+          let mut ty = FuncType::default();
+          for _ in 0..u32(s, pos)? { ty.params.push(read_val_type(s, pos)?); }
+          for _ in 0..u32(s, pos)? { ty.results.push(read_val_type(s, pos)?); }
+          m.types.push(ty);
+        }
+      },
+      IMPORT_SECTION => {
+        for _ in 0..u32(s, pos)? {
+          let module = string(s, pos)?;
+          let name = string(s, pos)?;
+          let desc = match read_byte(s, pos)? {
+            0 => Desc::Function(u32(s, pos)?),
+            1 => {
+              let ty = read_val_type(s, pos)?;
+              Desc::Table(ty, read_limits(s, pos)?)
+            },
+            2 => Desc::Memory(read_limits(s, pos)?),
+            3 => {
+              let ty = read_val_type(s, pos)?;
+              Desc::Global(ty, read_byte(s, pos)? != 0)
+            },
+            4 => {
+              read_byte(s, pos)?; // attribute
+              Desc::Tag(u32(s, pos)?)
+            },
+            k => Err(format!("unknown import kind {}", k))?,
+          };
+          m.imports.push((module, name, desc));
+        }
+      },
+      FUNCTION_SECTION => {
+        for _ in 0..u32(s, pos)? {
+          m.functions.push(u32(s, pos)?);
+        }
+      },
+      TABLE_SECTION => {
+        for _ in 0..u32(s, pos)? {
+          let ty = read_val_type(s, pos)?;
+          m.tables.push((ty, read_limits(s, pos)?));
+        }
+      },
+      MEMORY_SECTION => {
+        for _ in 0..u32(s, pos)? {
+          m.memories.push(read_limits(s, pos)?);
+        }
+      },
+      EXPORT_SECTION => {
+        for _ in 0..u32(s, pos)? {
+          let name = string(s, pos)?;
+          let kind = read_byte(s, pos)?;
+          let idx = u32(s, pos)?;
+          m.exports.push((name, kind, idx));
+        }
+      },
+      _ => {},
+    }
+  }
+
+  Ok(m)
+}
+
+fn list(items: &[&'static str]) -> String {
+  let items: Vec<String> = items.iter()
+    .map(|item| json_string(item) )
+    .collect();
+  format!("[{}]", items.join(", "))
+}
+fn limits(l: &Limits) -> String {
+  format!("\"min\": {}, \"max\": {}, \"shared\": {}", l.min,
+          l.max.map(|max| format!("{}", max) ).unwrap_or("null".into()),
+          l.shared)
+}
+fn func_type(m: &Module, idx: u32) -> String {
+  match m.types.get(idx as usize) {
+    Some(ty) => format!("\"params\": {}, \"results\": {}",
+                        list(&ty.params), list(&ty.results)),
+    None => "\"params\": null, \"results\": null".into(),
+  }
+}
+
+fn to_json(m: &Module) -> String {
+  let mut out = String::from("{\n  \"imports\": [");
+  for (i, &(ref module, ref name, ref desc)) in m.imports.iter().enumerate() {
+    out.push_str(if i == 0 { "\n" } else { ",\n" });
+    out.push_str(&format!("    {{\"module\": {}, \"name\": {}, \"kind\": {}",
+                          json_string(module), json_string(name), json_string(desc.kind())));
+    match desc {
+      &Desc::Function(ty) | &Desc::Tag(ty) => {
+        out.push_str(&format!(", {}", func_type(m, ty)));
+      },
+      &Desc::Table(ty, ref l) => {
+        out.push_str(&format!(", \"type\": {}, {}", json_string(ty), limits(l)));
+      },
+      &Desc::Memory(ref l) => {
+        out.push_str(&format!(", {}", limits(l)));
+      },
+      &Desc::Global(ty, mutable) => {
+        out.push_str(&format!(", \"type\": {}, \"mutable\": {}", json_string(ty),
+                              mutable));
+      },
+    }
+    out.push_str("}");
+  }
+  out.push_str("\n  ],\n  \"exports\": [");
+
+  let imported_funcs = m.imports.iter()
+    .filter(|&&(_, _, ref desc)| desc.kind() == "function" )
+    .count();
+  for (i, &(ref name, kind, idx)) in m.exports.iter().enumerate() {
+    out.push_str(if i == 0 { "\n" } else { ",\n" });
+    let kind_name = match kind {
+      0 => "function",
+      1 => "table",
+      2 => "memory",
+      3 => "global",
+      4 => "tag",
+      _ => "unknown",
+    };
+    out.push_str(&format!("    {{\"name\": {}, \"kind\": {}", json_string(name),
+                          json_string(kind_name)));
+    if kind == 0 && idx as usize >= imported_funcs {
+      if let Some(&ty) = m.functions.get(idx as usize - imported_funcs) {
+        out.push_str(&format!(", {}", func_type(m, ty)));
+      }
+    }
+    out.push_str("}");
+  }
+  out.push_str("\n  ],\n");
+
+  let imported_memory = m.imports.iter()
+    .filter_map(|&(_, _, ref desc)| match desc {
+      &Desc::Memory(l) => Some(l),
+      _ => None,
+    })
+    .next();
+  let memory = imported_memory.map(|l| (l, true) )
+    .or_else(|| m.memories.first().map(|&l| (l, false) ));
+  match memory {
+    Some((l, imported)) => {
+      out.push_str(&format!("  \"memory\": {{{}, \"imported\": {}}},\n",
+                            limits(&l), imported));
+    },
+    None => out.push_str("  \"memory\": null,\n"),
+  }
+
+  let imported_table = m.imports.iter()
+    .filter_map(|&(_, _, ref desc)| match desc {
+      &Desc::Table(ty, l) => Some((ty, l)),
+      _ => None,
+    })
+    .next();
+  let table = imported_table.map(|t| (t, true) )
+    .or_else(|| m.tables.first().map(|&t| (t, false) ));
+  match table {
+    Some(((ty, l), imported)) => {
+      out.push_str(&format!("  \"table\": {{\"type\": {}, {}, \"imported\": {}}}\n",
+                            json_string(ty), limits(&l), imported));
+    },
+    None => out.push_str("  \"table\": null\n"),
+  }
+
+  out.push_str("}\n");
+  out
+}
+
+/// Write the manifest for the module at `module` to `out`.
+pub fn write_sym_file(module: &Path, out: &Path) -> Result<(), Box<Error>> {
+  let mut bytes = Vec::new();
+  File::open(module)?.read_to_end(&mut bytes)?;
+  let m = parse(&bytes[..])
+    .map_err(|e| format!("reading `{}`: {}", module.display(), e) )?;
+
+  File::create(out)?.write_all(to_json(&m).as_bytes())?;
+  Ok(())
+}
+
+/// The limits of `module`'s imported memory and table, if it imports them.
+pub fn imported_limits(module: &Path)
+  -> Result<(Option<Limits>, Option<Limits>), Box<Error>>
+{
+  let mut bytes = Vec::new();
+  File::open(module)?.read_to_end(&mut bytes)?;
+  let m = parse(&bytes[..])
+    .map_err(|e| format!("reading `{}`: {}", module.display(), e) )?;
+
+  let mut memory = None;
+  let mut table = None;
+  for &(_, _, ref desc) in m.imports.iter() {
+    match desc {
+      &Desc::Memory(l) => { memory = Some(l); },
+      &Desc::Table(_, l) => { table = Some(l); },
+      _ => {},
+    }
+  }
+  Ok((memory, table))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::env;
+  use std::fs;
+  use std::process;
+
+  /// Imports a function and a shared memory; defines `main` and a table
+  /// and exports both.
+  const MODULE: &'static [u8] = &[
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+    // type: () -> (), (i32) -> i32
+    0x01, 0x09, 0x02, 0x60, 0x00, 0x00, 0x60, 0x01, 0x7f, 0x01, 0x7f,
+    // import: env.f, env.memory (shared, 1..2 pages)
+    0x02, 0x18, 0x02,
+    0x03, b'e', b'n', b'v', 0x01, b'f', 0x00, 0x00,
+    0x03, b'e', b'n', b'v', 0x06, b'm', b'e', b'm', b'o', b'r', b'y', 0x02, 0x03, 0x01, 0x02,
+    // function: main: (i32) -> i32
+    0x03, 0x02, 0x01, 0x01,
+    // table: funcref, min 1
+    0x04, 0x04, 0x01, 0x70, 0x00, 0x01,
+    // export: main, tbl
+    0x07, 0x0e, 0x02,
+    0x04, b'm', b'a', b'i', b'n', 0x00, 0x01,
+    0x03, b't', b'b', b'l', 0x01, 0x00,
+  ];
+
+  #[test]
+  fn sym_file() {
+    let wasm = env::temp_dir()
+      .join(format!("ld-sym-file.{}.wasm", process::id()));
+    let json = wasm.with_extension("sym.json");
+    File::create(&wasm).unwrap().write_all(MODULE).unwrap();
+    write_sym_file(&wasm, &json).unwrap();
+    let mut actual = String::new();
+    File::open(&json).unwrap().read_to_string(&mut actual).unwrap();
+
+    let (memory, table) = imported_limits(&wasm).unwrap();
+    let memory = memory.unwrap();
+    assert_eq!((memory.min, memory.max, memory.shared), (1, Some(2), true));
+    assert!(table.is_none());
+
+    fs::remove_file(&wasm).unwrap();
+    fs::remove_file(&json).unwrap();
+    assert_eq!(actual, r#"{
+  "imports": [
+    {"module": "env", "name": "f", "kind": "function", "params": [], "results": []},
+    {"module": "env", "name": "memory", "kind": "memory", "min": 1, "max": 2, "shared": true}
+  ],
+  "exports": [
+    {"name": "main", "kind": "function", "params": ["i32"], "results": ["i32"]},
+    {"name": "tbl", "kind": "table"}
+  ],
+  "memory": {"min": 1, "max": 2, "shared": true, "imported": true},
+  "table": {"type": "funcref", "min": 1, "max": null, "shared": false, "imported": false}
+}
+"#);
+  }
+
+  #[test]
+  fn sym_file_truncated() {
+    let wasm = env::temp_dir()
+      .join(format!("ld-sym-file-truncated.{}.wasm", process::id()));
+    let json = wasm.with_extension("sym.json");
+    // cut off in the middle of the import section.
+    File::create(&wasm).unwrap().write_all(&MODULE[..30]).unwrap();
+    let err = write_sym_file(&wasm, &json).unwrap_err();
+    fs::remove_file(&wasm).unwrap();
+    assert!(format!("{}", err).contains("unexpected end of module"), "{}", err);
+    assert!(!json.exists());
+  }
+}
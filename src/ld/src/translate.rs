@@ -0,0 +1,321 @@
+
+//! `wasm-translate`: LLVM bitcode -> wasm, via `llc`. Optionally splits the
+//! module with `llvm-split` so codegen can run on several threads.
+
+use std::error::Error;
+use std::path::PathBuf;
+use std::process::Command;
+use std::str::FromStr;
+
+use util::{CommandQueue, OptimizationGoal, Tool, ToolArgs, ToolInvocation, };
+use util::ldtools::LinkerFlagValues;
+use util::toolchain::{WasmToolchain, WasmToolchainTool, };
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum OutputMode {
+  /// wasm assembly (`-S`).
+  Asm,
+  /// A relocatable wasm object (`-c`).
+  Obj,
+  /// A linked module, via the ld driver.
+  Link,
+}
+impl Default for OutputMode {
+  fn default() -> OutputMode { OutputMode::Obj }
+}
+impl FromStr for OutputMode {
+  type Err = String;
+  fn from_str(s: &str) -> Result<OutputMode, String> {
+    match s {
+      "asm" => Ok(OutputMode::Asm),
+      "obj" => Ok(OutputMode::Obj),
+      "link" => Ok(OutputMode::Link),
+      _ => Err(format!("unknown output mode `{}`; expected `asm`, `obj` or `link`",
+                       s)),
+    }
+  }
+}
+
+#[derive(Debug)]
+pub struct Invocation {
+  tc: WasmToolchain,
+
+  pub inputs: Vec<PathBuf>,
+  output: Option<PathBuf>,
+
+  pub mode: OutputMode,
+  /// Split the module into this many pieces and codegen them in parallel.
+  pub threads: usize,
+  pub optimize: Option<OptimizationGoal>,
+
+  /// `-Wl,` args, given to the ld driver in `OutputMode::Link`.
+  pub ld_args: Vec<String>,
+  ld_arg_values: LinkerFlagValues,
+}
+
+impl Invocation {
+  pub fn new_with_toolchain(tc: WasmToolchain) -> Self {
+    Invocation {
+      tc,
+      inputs: vec![],
+      output: None,
+      mode: Default::default(),
+      threads: 1,
+      optimize: None,
+      ld_args: vec![],
+      ld_arg_values: Default::default(),
+    }
+  }
+  pub fn with_toolchain<T>(tool: &T) -> Self
+    where T: WasmToolchainTool,
+  {
+    let tc = tool.wasm_toolchain().clone();
+    Self::new_with_toolchain(tc)
+  }
+
+  fn llc_opt_level(&self) -> &'static str {
+    match self.optimize.unwrap_or_default() {
+      OptimizationGoal::Speed(0) => "-O0",
+      OptimizationGoal::Speed(1) => "-O1",
+      OptimizationGoal::Speed(2) => "-O2",
+      OptimizationGoal::Speed(_) => "-O3",
+      OptimizationGoal::Balanced |
+      OptimizationGoal::Size => "-O2",
+    }
+  }
+
+  fn llc_command(&self, filetype: &str) -> Command {
+    let mut cmd = Command::new(self.tc.llvm_tool("llc"));
+    cmd.arg("-mtriple=wasm32-unknown-unknown-wasm")
+      .arg(format!("-filetype={}", filetype))
+      .arg(self.llc_opt_level());
+    cmd
+  }
+
+  fn codegen(&self, queue: &mut CommandQueue<Self>) {
+    let (filetype, ext) = match self.mode {
+      OutputMode::Asm => ("asm", "s"),
+      _ => ("obj", "o"),
+    };
+    let threads = if self.mode == OutputMode::Asm { 1 } else { self.threads };
+    let input = if self.inputs.len() == 1 {
+      Some(self.inputs[0].clone())
+    } else {
+      None
+    };
+
+    if self.inputs.len() == 0 {
+      // run as a step, the previous outputs are added before we're
+      // enqueued, so this is only left for `--plan`.
+      queue.enqueue_state_function(Some("check-input"), |_, state| {
+        if state.prev_outputs.len() == 0 {
+          Err("no bitcode to translate".to_string())?;
+        }
+        Ok(())
+      });
+    }
+
+    if threads == 1 {
+      let mut cmd = self.llc_command(filetype);
+      if let Some(ref input) = input {
+        cmd.arg(input);
+      }
+      let llc = queue.enqueue_simple_external(Some("llc"), cmd, Some("-o".into()));
+      llc.prev_outputs = input.is_none();
+      llc.output_ext = Some(ext.into());
+      llc.heavy = true;
+      return;
+    }
+
+    // llvm-split names the pieces `<output>0`, `<output>1`, etc, so it's
+    // given its output as an intermediate arg rather than as its output.
+    let mut cmd = Command::new(self.tc.llvm_tool("llvm-split"));
+    if let Some(ref input) = input {
+      cmd.arg(input);
+    }
+    cmd.arg("-j").arg(format!("{}", threads))
+      .arg("-o");
+    let split = queue.enqueue_simple_external(Some("llvm-split"), cmd, None);
+    split.prev_outputs = input.is_none();
+    split.output_override = false;
+    split.add_intermediate_arg("split.bc");
+
+    // the shards' objects pile up in the previous outputs, for the merge.
+    for shard in 0..threads {
+      let cmd = self.llc_command(filetype);
+      let llc = queue.enqueue_simple_external(Some("llc"), cmd, Some("-o".into()));
+      llc.prev_outputs = false;
+      llc.add_intermediate_arg(format!("split.bc{}", shard));
+      llc.intermediate_name = Some(format!("shard{}.{}", shard, ext).into());
+      llc.heavy = true;
+    }
+  }
+}
+impl Default for Invocation {
+  fn default() -> Self {
+    Self::new_with_toolchain(Default::default())
+  }
+}
+
+impl WasmToolchainTool for Invocation {
+  fn wasm_toolchain(&self) -> &WasmToolchain { &self.tc }
+  fn wasm_toolchain_mut(&mut self) -> &mut WasmToolchain { &mut self.tc }
+}
+
+impl Tool for Invocation {
+  fn enqueue_commands(&mut self, queue: &mut CommandQueue<Self>)
+    -> Result<(), Box<Error>>
+  {
+    if self.mode == OutputMode::Asm && self.threads > 1 {
+      Err("`--threads` can't be used with `-S`")?;
+    }
+
+    if self.inputs.len() > 1 {
+      let mut cmd = Command::new(self.tc.llvm_tool("llvm-link"));
+      cmd.args(self.inputs.iter());
+      let link = queue.enqueue_simple_external(Some("llvm-link"), cmd,
+                                               Some("-o".into()));
+      link.prev_outputs = false;
+      link.intermediate_name = Some("linked.bc".into());
+      link.heavy = true;
+    }
+
+    self.codegen(queue);
+
+    match self.mode {
+      OutputMode::Obj if self.threads > 1 => {
+        // merge the shards back into a single object.
+        let mut cmd = Command::new(self.tc.llvm_tool("wasm-ld"));
+        cmd.arg("--relocatable");
+        let merge = queue.enqueue_simple_external(Some("merge-shards"), cmd,
+                                                  Some("-o".into()));
+        merge.output_ext = Some("o".into());
+        merge.heavy = true;
+      },
+      OutputMode::Link => {
+        let mut ld = ::Invocation::with_toolchain(self);
+        ld.optimize = self.optimize;
+        util::process_invocation_args(&mut ld, self.ld_args.clone(), true)?;
+        queue.enqueue_simple_tool(Some("wasm-ld"), ld);
+      },
+      _ => {},
+    }
+
+    Ok(())
+  }
+
+  fn get_name(&self) -> String { From::from("wasm-translate") }
+
+  fn add_tool_input(&mut self, input: PathBuf) -> Result<(), Box<Error>> {
+    self.inputs.push(input);
+    Ok(())
+  }
+
+  fn get_output(&self) -> Option<&PathBuf> { self.output.as_ref() }
+  fn override_output(&mut self, out: PathBuf) { self.output = Some(out); }
+}
+
+impl ToolInvocation for Invocation {
+  fn check_state(&mut self, iteration: usize, skip_inputs_check: bool)
+    -> Result<(), Box<Error>>
+  {
+    match iteration {
+      1 => {
+        self.ld_arg_values.check()?;
+      },
+      2 if !skip_inputs_check && self.inputs.len() == 0 => {
+        Err("no inputs")?;
+      },
+      _ => {},
+    }
+
+    Ok(())
+  }
+
+  fn args(&self, iteration: usize) -> Option<ToolArgs<Self>> {
+    use util::ToolArg;
+    use std::borrow::Cow;
+
+    const C: &'static [ToolArg<Invocation>] = &[];
+    let mut out = Cow::Borrowed(C);
+
+    match iteration {
+      0 => {
+        WasmToolchain::args(&mut out);
+      },
+      1 => return tool_arguments!(Invocation => [
+        OUTPUT,
+        OUTPUT_MODE,
+        ASM_FLAG,
+        OBJ_FLAG,
+        THREADS,
+        OPTIMIZE_FLAG,
+        LD_ARGS,
+      ]),
+      2 => return tool_arguments!(Invocation => [
+        INPUTS,
+      ]),
+      _ => return None,
+    }
+
+    Some(out)
+  }
+}
+
+tool_argument!(OUTPUT: Invocation = { Some(r"^-o(.+)$"), Some(r"^-o$") };
+               fn set_output(this, single, cap) {
+                 if this.output.is_some() {
+                   Err("more than one output specified")?;
+                 }
+                 let out = if single { cap.get(1).unwrap() }
+                           else      { cap.get(0).unwrap() };
+                 this.output = Some(PathBuf::from(out.as_str()));
+                 Ok(())
+               });
+tool_argument! {
+  pub OUTPUT_MODE: Invocation = single_and_split_from_str(mode) "output-mode" =>
+  fn output_mode_arg(this) {
+    this.mode = mode;
+  }
+}
+argument!(impl ASM_FLAG where { Some(r"^-S$"), None } for Invocation {
+  fn asm_flag(this, _single, _cap) {
+    this.mode = OutputMode::Asm;
+  }
+});
+argument!(impl OBJ_FLAG where { Some(r"^-c$"), None } for Invocation {
+  fn obj_flag(this, _single, _cap) {
+    this.mode = OutputMode::Obj;
+  }
+});
+tool_argument! {
+  pub THREADS: Invocation = single_and_split_int(usize, threads) "threads" =>
+  fn threads_arg(this) {
+    if threads == 0 {
+      Err("`--threads` must be at least 1")?;
+    }
+    this.threads = threads;
+  }
+}
+tool_argument!(OPTIMIZE_FLAG: Invocation = { Some(r"^-O([0-9]*|[a-z]+)$"), None };
+               fn set_optimize(this, _single, cap) {
+                 let optimize = OptimizationGoal::parse(cap.get(1).unwrap().as_str())?;
+                 this.optimize = Some(optimize);
+                 Ok(())
+               });
+argument!(impl LD_ARGS where { Some(r"^-Wl,(.+)$"), None } for Invocation {
+  fn ld_args(this, _single, cap) {
+    let args = cap.get(1).unwrap().as_str();
+    for arg in args.split(',').filter(|v| v.len() != 0 ) {
+      // a flag's value is kept as is, even if it looks like a flag.
+      this.ld_arg_values.is_value(arg);
+      this.ld_args.push(arg.to_string());
+    }
+  }
+});
+argument!(impl INPUTS where { Some(r"^(.+)$"), None } for Invocation {
+  fn add_input(this, _single, cap) {
+    let input = cap.get(0).unwrap().as_str();
+    this.inputs.push(PathBuf::from(input));
+  }
+});
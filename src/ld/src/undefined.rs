@@ -0,0 +1,146 @@
+
+//! Augments wasm-ld's undefined symbol errors with where the symbol (or
+//! something close to it) is actually defined, by running `llvm-nm` over
+//! the link inputs and the sysroot's libraries.
+
+use std::collections::BTreeSet;
+use std::fs::read_dir;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use util::diag::Severity;
+use util::regex::Regex;
+
+/// Max suggestions per undefined symbol.
+const MAX_NEAR_MISSES: usize = 5;
+
+lazy_static! {
+  static ref UNDEFINED_RE: Regex =
+    Regex::new(r"undefined symbol: (.+)$").unwrap();
+  static ref NM_LINE_RE: Regex =
+    Regex::new(r"^(.+?): +(?:[0-9a-fA-F]+ +)?[A-Za-z] (.+)$").unwrap();
+}
+
+struct Definition {
+  file: String,
+  symbol: String,
+}
+
+/// The undefined symbols in wasm-ld's error output.
+pub fn parse_undefined(stderr: &str) -> BTreeSet<String> {
+  stderr.lines()
+    .filter_map(|line| UNDEFINED_RE.captures(line) )
+    .map(|cap| cap.get(1).unwrap().as_str().trim().to_string() )
+    .collect()
+}
+
+fn defined_symbols(nm: &Path, files: &[PathBuf]) -> Option<Vec<Definition>> {
+  if files.len() == 0 { return Some(vec![]); }
+
+  let mut cmd = Command::new(nm);
+  cmd.arg("--defined-only")
+    .arg("--extern-only")
+    .arg("--print-file-name")
+    .arg("--demangle")
+    .args(files);
+  ::util::hermetic_env(&mut cmd);
+  let output = cmd.output().ok()?;
+
+  let defs = String::from_utf8_lossy(&output.stdout)
+    .lines()
+    .filter_map(|line| NM_LINE_RE.captures(line) )
+    .map(|cap| Definition {
+      file: cap.get(1).unwrap().as_str().to_string(),
+      symbol: cap.get(2).unwrap().as_str().to_string(),
+    })
+    .collect();
+  Some(defs)
+}
+
+/// The part of a (demangled) symbol which is worth comparing, ie `foo::bar`
+/// for `foo::bar(int)`.
+fn base_name(sym: &str) -> &str {
+  sym.split('(').next().unwrap_or(sym)
+}
+
+fn is_near_miss(undefined: &str, defined: &str) -> bool {
+  let u = base_name(undefined).to_lowercase();
+  let d = base_name(defined).to_lowercase();
+  if u == d { return true; }
+
+  let u = u.trim_start_matches('_');
+  let d = d.trim_start_matches('_');
+  u.len() > 3 && d.len() > 3 && (u == d || d.contains(u) || u.contains(d))
+}
+
+fn sysroot_archives(sysroot_lib: &Path) -> Vec<PathBuf> {
+  let mut out: Vec<PathBuf> = match read_dir(sysroot_lib) {
+    Ok(dir) => dir
+      .filter_map(|entry| entry.ok() )
+      .map(|entry| entry.path() )
+      .filter(|path| path.extension().map(|ext| ext == "a" ).unwrap_or(false) )
+      .collect(),
+    Err(_) => vec![],
+  };
+  out.sort();
+  out
+}
+
+/// A human readable explanation of where each undefined symbol in
+/// `stderr` can be found, if anywhere.
+pub fn report(stderr: &str, nm: &Path, inputs: &[PathBuf],
+              sysroot_lib: &Path) -> Option<String> {
+  let undefined = parse_undefined(stderr);
+  if undefined.len() == 0 { return None; }
+
+  let mut files = inputs.to_vec();
+  for archive in sysroot_archives(sysroot_lib).into_iter() {
+    if !files.contains(&archive) {
+      files.push(archive);
+    }
+  }
+  let defs = defined_symbols(nm, &files[..])?;
+  let is_input = |file: &str| {
+    inputs.iter()
+      .any(|input| file.starts_with(&format!("{}", input.display())) )
+  };
+
+  let color = util::diag::color_enabled();
+  let note = |msg: String| util::diag::format(Severity::Note, None, &msg, color);
+  let mut out = String::new();
+  for sym in undefined.iter() {
+    let exact: BTreeSet<&str> = defs.iter()
+      .filter(|def| &def.symbol == sym )
+      .map(|def| &def.file[..] )
+      .collect();
+    if exact.len() != 0 {
+      for file in exact.into_iter() {
+        if is_input(file) {
+          out.push_str(&note(format!("`{}` is defined in `{}`, but it wasn't pulled in; \
+                                      check the input order", sym, file)));
+        } else {
+          out.push_str(&note(format!("`{}` is defined in `{}`, which isn't being linked",
+                                     sym, file)));
+        }
+      }
+      continue;
+    }
+
+    let near: BTreeSet<(&str, &str)> = defs.iter()
+      .filter(|def| is_near_miss(sym, &def.symbol) )
+      .map(|def| (&def.symbol[..], &def.file[..]) )
+      .take(MAX_NEAR_MISSES)
+      .collect();
+    if near.len() == 0 {
+      out.push_str(&note(format!("`{}` isn't defined by any input or sysroot library",
+                                 sym)));
+      continue;
+    }
+    for (def, file) in near.into_iter() {
+      out.push_str(&note(format!("`{}` is undefined; did you mean `{}` (from `{}`)?",
+                                 sym, def, file)));
+    }
+  }
+
+  Some(out)
+}
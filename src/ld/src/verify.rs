@@ -0,0 +1,71 @@
+
+//! `--verify-inputs`: check each input parses before handing everything to
+//! wasm-ld, so a corrupt object is reported by name instead of as an lld
+//! crash in some temp path.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use util::filetype::{file_type, Type, Subtype};
+use util::toolchain::WasmToolchain;
+
+/// The command which checks `input`, if we know how.
+fn verify_cmd(tc: &WasmToolchain, input: &Path) -> Option<Command> {
+  let cmd = match file_type(input) {
+    Ok(Some(Type::Object(Subtype::Bitcode))) => {
+      let mut cmd = Command::new(tc.llvm_tool("opt"));
+      cmd.arg("-verify").arg("-disable-output");
+      cmd
+    },
+    Ok(Some(Type::Object(_))) => {
+      let mut cmd = Command::new(tc.llvm_tool("llvm-objdump"));
+      cmd.arg("--section-headers");
+      cmd
+    },
+    // llvm-nm parses every member.
+    Ok(Some(Type::Archive(_))) => {
+      let mut cmd = Command::new(tc.llvm_tool("llvm-nm"));
+      cmd.arg("--extern-only");
+      cmd
+    },
+    _ => { return None; },
+  };
+
+  let mut cmd = cmd;
+  cmd.arg(input);
+  Some(cmd)
+}
+
+/// Check every input; the error lists each malformed input along with
+/// what the checker said about it.
+pub fn verify(tc: &WasmToolchain, inputs: &[PathBuf]) -> Result<(), String> {
+  let mut out = String::new();
+  for input in inputs.iter() {
+    let mut cmd = match verify_cmd(tc, input) {
+      Some(cmd) => cmd,
+      None => { continue; },
+    };
+    ::util::hermetic_env(&mut cmd);
+
+    let output = match cmd.output() {
+      Ok(output) => output,
+      Err(e) => {
+        return Err(format!("failed to run `{:?}`: {}", cmd, e));
+      },
+    };
+    if output.status.success() { continue; }
+
+    out.push_str(&format!("input `{}` is malformed:\n", input.display()));
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+      out.push_str("  ");
+      out.push_str(line);
+      out.push('\n');
+    }
+  }
+
+  if out.len() == 0 {
+    Ok(())
+  } else {
+    Err(out)
+  }
+}
@@ -0,0 +1,255 @@
+//! `--version-script`: wasm has no symbol versions, so all that's left of a
+//! version script is which symbols are exported. The object inputs' symbols
+//! which a `global:` pattern matches are passed as `--export`s; those a
+//! `local:` pattern matches aren't. Symbols only archives define aren't
+//! exported, since exporting them would pull their members in.
+//!
+//! As with ld, exact names beat wildcards, and `*` loses to any other
+//! pattern. `extern "C++"` blocks are skipped (with a warning): their
+//! patterns are demangled names.
+
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct VersionScript {
+  pub global: Vec<String>,
+  pub local: Vec<String>,
+}
+
+fn tokens(src: &str) -> Result<Vec<String>, Box<Error>> {
+  let mut out = Vec::new();
+  let mut chars = src.chars().peekable();
+  while let Some(c) = chars.next() {
+    match c {
+      '#' => {
+        while let Some(c) = chars.next() {
+          if c == '\n' { break; }
+        }
+      },
+      '/' if chars.peek() == Some(&'*') => {
+        chars.next();
+        let mut prev = ' ';
+        loop {
+          match chars.next() {
+            Some('/') if prev == '*' => break,
+            Some(c) => { prev = c; },
+            None => Err("unterminated comment")?,
+          }
+        }
+      },
+      '"' => {
+        let mut s = String::new();
+        loop {
+          match chars.next() {
+            Some('"') => break,
+            Some(c) => s.push(c),
+            None => Err("unterminated string")?,
+          }
+        }
+        out.push(format!("\"{}\"", s));
+      },
+      '{' | '}' | ';' | ':' => out.push(c.to_string()),
+      c if c.is_whitespace() => {},
+      c => {
+        let mut s = c.to_string();
+        while let Some(&c) = chars.peek() {
+          if c.is_whitespace() || "{};:\"#".contains(c) { break; }
+          s.push(c);
+          chars.next();
+        }
+        out.push(s);
+      },
+    }
+  }
+  Ok(out)
+}
+
+impl VersionScript {
+  pub fn parse(src: &str) -> Result<VersionScript, Box<Error>> {
+    let tokens = tokens(src)?;
+    let mut out = VersionScript::default();
+    let mut tokens = tokens.iter().map(|t| &t[..] ).peekable();
+
+    fn expect<'a, I>(tokens: &mut I, what: &str) -> Result<(), Box<Error>>
+      where I: Iterator<Item = &'a str>,
+    {
+      match tokens.next() {
+        Some(t) if t == what => Ok(()),
+        Some(t) => Err(format!("expected `{}`, found `{}`", what, t).into()),
+        None => Err(format!("expected `{}`, found the end", what).into()),
+      }
+    }
+
+    while tokens.peek().is_some() {
+      // `[<version>] { ... } [<deps>...];`
+      if tokens.peek() != Some(&"{") {
+        tokens.next();
+      }
+      expect(&mut tokens, "{")?;
+      let mut global = true;
+      loop {
+        match tokens.next() {
+          Some("}") => break,
+          Some(label @ "global") | Some(label @ "local") => {
+            expect(&mut tokens, ":")?;
+            global = label == "global";
+          },
+          Some("extern") => {
+            let lang = tokens.next().unwrap_or("");
+            if lang != "\"C\"" {
+              ::util::diag::warning(&format!("version script: skipping the `extern {}` \
+                                              block", lang));
+            }
+            expect(&mut tokens, "{")?;
+            loop {
+              match tokens.next() {
+                Some("}") => break,
+                Some(";") => {},
+                Some(pattern) if lang == "\"C\"" => {
+                  let pattern = pattern.trim_matches('"').to_string();
+                  if global { out.global.push(pattern); } else { out.local.push(pattern); }
+                },
+                Some(_) => {},
+                None => Err("expected `}`, found the end")?,
+              }
+            }
+            if tokens.peek() == Some(&";") { tokens.next(); }
+          },
+          Some(";") => {},
+          Some(pattern) => {
+            let pattern = pattern.trim_matches('"').to_string();
+            if global { out.global.push(pattern); } else { out.local.push(pattern); }
+            expect(&mut tokens, ";")?;
+          },
+          None => Err("expected `}`, found the end")?,
+        }
+      }
+      // the versions this one depends on.
+      loop {
+        match tokens.next() {
+          Some(";") => break,
+          Some(_) => {},
+          None => Err("expected `;`, found the end")?,
+        }
+      }
+    }
+    Ok(out)
+  }
+  pub fn from_file(path: &Path) -> Result<VersionScript, Box<Error>> {
+    let src = fs::read_to_string(path)
+      .map_err(|e| format!("reading `{}`: {}", path.display(), e) )?;
+    VersionScript::parse(&src)
+      .map_err(|e| format!("version script `{}`: {}", path.display(), e).into() )
+  }
+
+  /// Should `name` be exported? `None` if no pattern matches it.
+  pub fn is_global(&self, name: &str) -> Option<bool> {
+    let exact = |patterns: &[String]| patterns.iter()
+      .any(|p| !is_wildcard(p) && p == name );
+    let wildcard = |patterns: &[String]| patterns.iter()
+      .any(|p| p != "*" && is_wildcard(p) && glob_match(p, name) );
+    let any = |patterns: &[String]| patterns.iter().any(|p| p == "*" );
+
+    if exact(&self.global) { return Some(true); }
+    if exact(&self.local) { return Some(false); }
+    if wildcard(&self.global) { return Some(true); }
+    if wildcard(&self.local) { return Some(false); }
+    if any(&self.global) { return Some(true); }
+    if any(&self.local) { return Some(false); }
+    None
+  }
+
+  /// Those of `defined` to export.
+  pub fn exports<'a, I>(&self, defined: I) -> Vec<String>
+    where I: IntoIterator<Item = &'a String>,
+  {
+    let defined: BTreeSet<&String> = defined.into_iter().collect();
+    defined.into_iter()
+      .filter(|name| self.is_global(name) == Some(true) )
+      .cloned()
+      .collect()
+  }
+}
+
+fn is_wildcard(pattern: &str) -> bool {
+  pattern.contains(|c| c == '*' || c == '?' || c == '[' )
+}
+
+/// Shell style `*`, `?` and `[...]` matching.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+  fn class(class: &[char], c: char) -> bool {
+    let (negate, class) = match class.first() {
+      Some(&'!') | Some(&'^') => (true, &class[1..]),
+      _ => (false, class),
+    };
+    let mut matched = false;
+    let mut i = 0;
+    while i < class.len() {
+      if i + 2 < class.len() && class[i + 1] == '-' {
+        matched |= class[i] <= c && c <= class[i + 2];
+        i += 3;
+      } else {
+        matched |= class[i] == c;
+        i += 1;
+      }
+    }
+    matched != negate
+  }
+  fn go(p: &[char], n: &[char]) -> bool {
+    match p.first() {
+      None => n.is_empty(),
+      Some(&'*') => (0..n.len() + 1).any(|skip| go(&p[1..], &n[skip..]) ),
+      Some(&'?') => !n.is_empty() && go(&p[1..], &n[1..]),
+      Some(&'[') => match p.iter().position(|&c| c == ']' ) {
+        Some(end) if end > 1 => {
+          !n.is_empty() && class(&p[1..end], n[0]) && go(&p[end + 1..], &n[1..])
+        },
+        _ => !n.is_empty() && n[0] == '[' && go(&p[1..], &n[1..]),
+      },
+      Some(&c) => !n.is_empty() && n[0] == c && go(&p[1..], &n[1..]),
+    }
+  }
+  let p: Vec<char> = pattern.chars().collect();
+  let n: Vec<char> = name.chars().collect();
+  go(&p, &n)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_and_export() {
+    let script = VersionScript::parse("# libfoo\n\
+                                       FOO_1.0 {\n\
+                                         global:\n\
+                                           foo_*; /* the API */ bar;\n\
+                                           extern \"C++\" { ns::*; };\n\
+                                         local:\n\
+                                           foo_internal;\n\
+                                           *;\n\
+                                       };\n\
+                                       FOO_2.0 { baz; } FOO_1.0;\n")
+      .unwrap();
+    assert_eq!(script.global, vec!["foo_*", "bar", "baz"]);
+    assert_eq!(script.local, vec!["foo_internal", "*"]);
+    let defined: Vec<String> = ["bar", "baz", "foo_init", "foo_internal", "helper"].iter()
+      .map(|s| s.to_string() )
+      .collect();
+    assert_eq!(script.exports(defined.iter()), vec!["bar", "baz", "foo_init"]);
+
+    let anonymous = VersionScript::parse("{ global: *; local: _*; };").unwrap();
+    assert_eq!(anonymous.is_global("foo"), Some(true));
+    assert_eq!(anonymous.is_global("_foo"), Some(false));
+    assert!(VersionScript::parse("{ foo; ").is_err());
+  }
+
+  #[test]
+  fn globs() {
+    assert!(glob_match("f?o[0-9]*", "foo1_bar"));
+    assert!(!glob_match("f?o[!0-9]*", "foo1_bar"));
+  }
+}
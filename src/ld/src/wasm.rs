@@ -0,0 +1,221 @@
+
+//! Just enough of the wasm binary format for the post-link transforms,
+//! which rewrite a few sections and copy the rest through untouched.
+
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+pub const WASM_MAGIC: &'static [u8] = b"\0asm";
+
+pub const CUSTOM_SECTION: u8 = 0;
+pub const TYPE_SECTION: u8 = 1;
+pub const IMPORT_SECTION: u8 = 2;
+pub const FUNCTION_SECTION: u8 = 3;
+pub const TABLE_SECTION: u8 = 4;
+pub const MEMORY_SECTION: u8 = 5;
+pub const GLOBAL_SECTION: u8 = 6;
+pub const EXPORT_SECTION: u8 = 7;
+pub const ELEMENT_SECTION: u8 = 9;
+pub const CODE_SECTION: u8 = 10;
+pub const DATA_SECTION: u8 = 11;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+  pub min: u64,
+  pub max: Option<u64>,
+  pub shared: bool,
+}
+
+/// The name of the non-custom section `id`, as the spec spells it.
+pub fn section_name(id: u8) -> Option<&'static str> {
+  let name = match id {
+    1 => "type",
+    2 => "import",
+    3 => "function",
+    4 => "table",
+    5 => "memory",
+    6 => "global",
+    7 => "export",
+    8 => "start",
+    9 => "element",
+    10 => "code",
+    11 => "data",
+    12 => "datacount",
+    13 => "tag",
+    _ => return None,
+  };
+  Some(name)
+}
+
+pub fn val_type(b: u8) -> Option<&'static str> {
+  let ty = match b {
+    0x7f => "i32",
+    0x7e => "i64",
+    0x7d => "f32",
+    0x7c => "f64",
+    0x7b => "v128",
+    0x70 => "funcref",
+    0x6f => "externref",
+    _ => return None,
+  };
+  Some(ty)
+}
+
+pub fn read_byte(bytes: &[u8], pos: &mut usize) -> Result<u8, Box<Error>> {
+  let b = *bytes.get(*pos)
+    .ok_or_else(|| "unexpected end of module" )?;
+  *pos += 1;
+  Ok(b)
+}
+
+pub fn read_leb(bytes: &[u8], pos: &mut usize) -> Result<u64, Box<Error>> {
+  let mut out = 0u64;
+  let mut shift = 0;
+  loop {
+    let b = read_byte(bytes, pos)?;
+    if shift < 64 {
+      out |= ((b & 0x7f) as u64) << shift;
+    }
+    if b & 0x80 == 0 { return Ok(out); }
+    shift += 7;
+  }
+}
+pub fn read_sleb(bytes: &[u8], pos: &mut usize) -> Result<i64, Box<Error>> {
+  let mut out = 0i64;
+  let mut shift = 0;
+  loop {
+    let b = read_byte(bytes, pos)?;
+    if shift < 64 {
+      out |= ((b & 0x7f) as i64) << shift;
+    }
+    shift += 7;
+    if b & 0x80 == 0 {
+      if shift < 64 && b & 0x40 != 0 {
+        out |= -1i64 << shift;
+      }
+      return Ok(out);
+    }
+  }
+}
+pub fn write_leb(out: &mut Vec<u8>, mut v: u64) {
+  loop {
+    let b = (v & 0x7f) as u8;
+    v >>= 7;
+    if v == 0 {
+      out.push(b);
+      return;
+    }
+    out.push(b | 0x80);
+  }
+}
+
+pub fn read_name<'a>(bytes: &'a [u8], pos: &mut usize)
+  -> Result<&'a [u8], Box<Error>>
+{
+  let len = read_leb(bytes, pos)? as usize;
+  let end = pos.checked_add(len)
+    .ok_or_else(|| "unexpected end of module" )?;
+  let name = bytes.get(*pos..end)
+    .ok_or_else(|| "unexpected end of module" )?;
+  *pos = end;
+  Ok(name)
+}
+pub fn read_val_type(bytes: &[u8], pos: &mut usize)
+  -> Result<&'static str, Box<Error>>
+{
+  let b = read_byte(bytes, pos)?;
+  val_type(b)
+    .ok_or_else(|| format!("unknown value type 0x{:02x}", b).into() )
+}
+
+pub fn read_limits(bytes: &[u8], pos: &mut usize) -> Result<Limits, Box<Error>> {
+  let flags = read_byte(bytes, pos)?;
+  let min = read_leb(bytes, pos)?;
+  let max = if flags & 1 != 0 {
+    Some(read_leb(bytes, pos)?)
+  } else {
+    None
+  };
+  Ok(Limits {
+    min,
+    max,
+    shared: flags & 2 != 0,
+  })
+}
+
+pub fn write_name(out: &mut Vec<u8>, name: &[u8]) {
+  write_leb(out, name.len() as u64);
+  out.extend_from_slice(name);
+}
+
+/// Append a section to `out`.
+pub fn write_section(out: &mut Vec<u8>, id: u8, payload: &[u8]) {
+  out.push(id);
+  write_leb(out, payload.len() as u64);
+  out.extend_from_slice(payload);
+}
+
+/// `(id, section start, payload start, end)` for each section.
+pub fn sections(bytes: &[u8]) -> Result<Vec<(u8, usize, usize, usize)>, Box<Error>> {
+  if bytes.len() < 8 || &bytes[..4] != WASM_MAGIC {
+    Err("not a wasm module")?;
+  }
+
+  let mut out = Vec::new();
+  let mut pos = 8;
+  while pos < bytes.len() {
+    let start = pos;
+    let id = bytes[pos];
+    pos += 1;
+    let len = read_leb(bytes, &mut pos)? as usize;
+    let end = match pos.checked_add(len) {
+      Some(end) if end <= bytes.len() => end,
+      _ => Err("unexpected end of module")?,
+    };
+    out.push((id, start, pos, end));
+    pos = end;
+  }
+  Ok(out)
+}
+
+pub fn custom_section_name(payload: &[u8]) -> Option<&[u8]> {
+  read_name(payload, &mut 0).ok()
+}
+
+/// Append a custom section named `name` to the module at `path`. Custom
+/// sections can come last, so nothing before it moves.
+pub fn append_custom_section(path: &Path, name: &str, contents: &[u8])
+  -> Result<(), Box<Error>>
+{
+  let mut module = fs::read(path)?;
+  if !module.starts_with(WASM_MAGIC) {
+    return Err(format!("`{}` isn't a wasm module", path.display()).into());
+  }
+  let mut payload = Vec::with_capacity(name.len() + contents.len() + 5);
+  write_name(&mut payload, name.as_bytes());
+  payload.extend_from_slice(contents);
+  write_section(&mut module, CUSTOM_SECTION, &payload);
+  fs::write(path, module)?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn corrupt_lengths() {
+    // a section, and a name in it, each claiming `u64::MAX` bytes.
+    let huge = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01];
+    let mut module = WASM_MAGIC.to_vec();
+    module.extend_from_slice(&[1, 0, 0, 0, CUSTOM_SECTION]);
+    module.extend_from_slice(&huge);
+    let err = sections(&module).unwrap_err();
+    assert_eq!(format!("{}", err), "unexpected end of module");
+
+    let err = read_name(&huge, &mut 0).unwrap_err();
+    assert_eq!(format!("{}", err), "unexpected end of module");
+    assert!(read_name(b"\x03abc", &mut 0).unwrap() == b"abc");
+  }
+}
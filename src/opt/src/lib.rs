@@ -0,0 +1,206 @@
+
+//! `wasm-llvm-opt`: LLVM's `opt`, targeting wasm32.
+
+use std::error::Error;
+use std::path::PathBuf;
+
+use util::{CommandQueue, OptimizationGoal, Tool, ToolArgs, ToolInvocation, };
+use util::toolchain::{WasmToolchain, WasmToolchainTool, };
+
+#[macro_use] extern crate wasm_driver_utils as util;
+#[macro_use] extern crate lazy_static;
+
+#[derive(Clone, Debug)]
+pub struct Invocation {
+  tc: WasmToolchain,
+
+  input: Option<PathBuf>,
+  output: Option<PathBuf>,
+
+  /// `-passes=`; run after `optimize`'s pipeline, if any.
+  pub passes: Vec<String>,
+  pub optimize: Option<OptimizationGoal>,
+  /// Write textual IR (`-S`).
+  pub emit_text: bool,
+}
+
+impl Invocation {
+  pub fn new_with_toolchain(tc: WasmToolchain) -> Self {
+    Invocation {
+      tc,
+      input: None,
+      output: None,
+      passes: vec![],
+      optimize: None,
+      emit_text: false,
+    }
+  }
+  pub fn with_toolchain<T>(tool: &T) -> Self
+    where T: WasmToolchainTool,
+  {
+    let tc = tool.wasm_toolchain().clone();
+    Self::new_with_toolchain(tc)
+  }
+
+  pub fn add_passes<T>(&mut self, passes: T)
+    where T: AsRef<str>,
+  {
+    let passes = passes.as_ref()
+      .split(',')
+      .filter(|p| p.len() != 0 )
+      .map(|p| p.to_string() );
+    self.passes.extend(passes);
+  }
+
+  /// The `-passes=` pipeline; the optimization level's default pipeline
+  /// (if any) comes first.
+  pub fn pipeline(&self) -> Option<String> {
+    let mut pipeline: Vec<String> = vec![];
+    match self.optimize {
+      Some(OptimizationGoal::Speed(n)) => {
+        pipeline.push(format!("default<O{}>", ::std::cmp::min(n, 3)));
+      },
+      Some(OptimizationGoal::Balanced) => {
+        pipeline.push("default<Os>".into());
+      },
+      Some(OptimizationGoal::Size) => {
+        pipeline.push("default<Oz>".into());
+      },
+      None => {},
+    }
+    pipeline.extend(self.passes.iter().cloned());
+
+    if pipeline.len() == 0 {
+      None
+    } else {
+      Some(pipeline.join(","))
+    }
+  }
+}
+impl Default for Invocation {
+  fn default() -> Self {
+    Self::new_with_toolchain(Default::default())
+  }
+}
+
+impl WasmToolchainTool for Invocation {
+  fn wasm_toolchain(&self) -> &WasmToolchain { &self.tc }
+  fn wasm_toolchain_mut(&mut self) -> &mut WasmToolchain { &mut self.tc }
+}
+
+impl Tool for Invocation {
+  fn enqueue_commands(&mut self, queue: &mut CommandQueue<Self>)
+    -> Result<(), Box<Error>>
+  {
+    use std::process::Command;
+
+    let input = self.input.as_ref()
+      .ok_or_else(|| "no input" )?;
+
+    let mut cmd = Command::new(self.tc.llvm_tool("opt"));
+    cmd.arg("-mtriple=wasm32-unknown-unknown-wasm");
+    if let Some(pipeline) = self.pipeline() {
+      cmd.arg(format!("-passes={}", pipeline));
+    }
+    if self.emit_text {
+      cmd.arg("-S");
+    }
+    cmd.arg(input);
+
+    let opt = queue.enqueue_simple_external(Some("opt"), cmd,
+                                            Some("-o".into()));
+    opt.prev_outputs = false;
+    opt.output_ext = Some(if self.emit_text { "ll" } else { "bc" }.into());
+
+    Ok(())
+  }
+
+  fn get_name(&self) -> String { From::from("wasm-llvm-opt") }
+
+  fn add_tool_input(&mut self, input: PathBuf) -> Result<(), Box<Error>> {
+    if self.input.is_some() {
+      Err("opt takes a single input")?;
+    }
+    self.input = Some(input);
+    Ok(())
+  }
+
+  fn get_output(&self) -> Option<&PathBuf> { self.output.as_ref() }
+  fn override_output(&mut self, out: PathBuf) { self.output = Some(out); }
+}
+
+impl ToolInvocation for Invocation {
+  fn check_state(&mut self, iteration: usize, skip_inputs_check: bool)
+    -> Result<(), Box<Error>>
+  {
+    match iteration {
+      2 if !skip_inputs_check && self.input.is_none() => {
+        Err("no input")?;
+      },
+      _ => {},
+    }
+
+    Ok(())
+  }
+
+  fn derived_state(&self) -> Vec<(&'static str, String)> {
+    vec![("passes", self.pipeline().unwrap_or_default())]
+  }
+  fn args(&self, iteration: usize) -> Option<ToolArgs<Self>> {
+    use util::ToolArg;
+    use std::borrow::Cow;
+
+    const C: &'static [ToolArg<Invocation>] = &[];
+    let mut out = Cow::Borrowed(C);
+
+    match iteration {
+      0 => {
+        WasmToolchain::args(&mut out);
+      },
+      1 => return tool_arguments!(Invocation => [
+        OUTPUT,
+        PASSES,
+        OPTIMIZE_FLAG,
+        EMIT_TEXT,
+      ]),
+      2 => return tool_arguments!(Invocation => [
+        INPUT,
+      ]),
+      _ => return None,
+    }
+
+    Some(out)
+  }
+}
+
+tool_argument!(OUTPUT: Invocation = { Some(r"^-o(.+)$"), Some(r"^-o$") };
+               fn set_output(this, single, cap) {
+                 if this.output.is_some() {
+                   Err("more than one output specified")?;
+                 }
+                 let out = if single { cap.get(1).unwrap() }
+                           else      { cap.get(0).unwrap() };
+                 this.output = Some(PathBuf::from(out.as_str()));
+                 Ok(())
+               });
+argument!(impl PASSES where { Some(r"^--?passes=(.*)$"), None } for Invocation {
+  fn passes_arg(this, _single, cap) {
+    this.add_passes(cap.get(1).unwrap().as_str());
+  }
+});
+tool_argument!(OPTIMIZE_FLAG: Invocation = { Some(r"^-O([0-9]*|[a-z]+)$"), None };
+               fn set_optimize(this, _single, cap) {
+                 let optimize = OptimizationGoal::parse(cap.get(1).unwrap().as_str())?;
+                 this.optimize = Some(optimize);
+                 Ok(())
+               });
+argument!(impl EMIT_TEXT where { Some(r"^-S$"), None } for Invocation {
+  fn emit_text_flag(this, _single, _cap) {
+    this.emit_text = true;
+  }
+});
+tool_argument!(INPUT: Invocation = { Some(r"^(.+)$"), None };
+               fn set_input(this, _single, cap) {
+                 let input = cap.get(0).unwrap().as_str();
+                 this.add_tool_input(PathBuf::from(input))
+               });
@@ -1,4 +1,4 @@
-use super::{Invocation, link};
+use super::{Invocation, SystemLibrary, link};
 use util::{CommandQueue, get_crate_root, CreateIfNotExists, Tool, };
 
 use clang_driver;
@@ -13,9 +13,30 @@ const BLACKLIST: &'static [&'static str] = &[
   "emutls.c",
 ];
 
+/// Builtins clang will emit calls to on wasm32; if any of these are missing
+/// from the archive, the failure won't show up until some unlucky program
+/// is linked.
+const REQUIRED_BUILTINS: &'static [&'static str] = &[
+  // 128-bit integer arithmetic
+  "__multi3", "__divti3", "__udivti3", "__modti3", "__umodti3",
+  "__ashlti3", "__ashrti3", "__lshrti3",
+  // long double (f128) arithmetic
+  "__addtf3", "__subtf3", "__multf3", "__divtf3",
+  "__eqtf2", "__netf2", "__lttf2", "__letf2", "__gttf2", "__getf2",
+  "__unordtf2",
+  // float conversions
+  "__extendsftf2", "__extenddftf2", "__trunctfsf2", "__trunctfdf2",
+  "__fixtfsi", "__fixtfdi", "__fixtfti",
+  "__fixunstfsi", "__fixunstfdi", "__fixunstfti",
+  "__floatsitf", "__floatditf", "__floattitf",
+  "__floatunsitf", "__floatunditf", "__floatuntitf",
+  "__fixsfti", "__fixdfti", "__fixunssfti", "__fixunsdfti",
+  "__floattisf", "__floattidf", "__floatuntisf", "__floatuntidf",
+];
+
 impl Invocation {
   pub fn compiler_rt_src(&self) -> PathBuf {
-    self.srcs.join(self.compiler_rt_repo.name.as_ref())
+    self.src_cache.join(self.compiler_rt_repo.name.as_ref())
   }
   pub fn compiler_rt_build(&self) -> PathBuf {
     self.srcs.join("compiler-rt-build")
@@ -24,7 +45,7 @@ impl Invocation {
     if self.compiler_rt_checkout { return Ok(()); }
     self.compiler_rt_checkout = true;
 
-    self.compiler_rt_repo.checkout_thin(self.compiler_rt_src())
+    self.checkout_repo(self.compiler_rt_repo.clone(), self.compiler_rt_src())
   }
 }
 
@@ -37,7 +58,7 @@ pub fn build_cc(invoc: &Invocation,
 {
   let file = Path::new(full_file.file_name().unwrap());
 
-  let mut clang = clang_driver::Invocation::with_toolchain(invoc);
+  let mut clang = invoc.clang_invocation();
   clang.driver_mode = clang_driver::DriverMode::CC;
   clang.emit_wast = invoc.emit_wast;
 
@@ -47,14 +68,7 @@ pub fn build_cc(invoc: &Invocation,
   args.push(format!("{}", full_file.display()));
 
   // compiler-rt needs some libc headers:
-  let include = invoc.get_musl_root().join("include");
-  let arch_include = invoc.get_musl_root().join("arch/wasm32");
-  let generic_include = invoc.get_musl_root().join("arch/generic");
-  let config_include = invoc.get_musl_root().join("obj/include");
-  clang.add_system_include_dir(config_include);
-  clang.add_system_include_dir(generic_include);
-  clang.add_system_include_dir(arch_include);
-  clang.add_system_include_dir(include);
+  invoc.musl_includes(&mut clang);
 
 
   let source_path = full_file.strip_prefix(compiler_rt_prefix)
@@ -70,6 +84,7 @@ pub fn build_cc(invoc: &Invocation,
 
   args.push("-Oz".to_string());
   super::add_default_args(&mut args);
+  args.extend(invoc.lib_cflags(SystemLibrary::CompilerRt).iter().cloned());
 
   let cmd = queue
     .enqueue_tool(Some("clang"),
@@ -124,7 +139,65 @@ pub fn build(invoc: &mut Invocation,
              &mut queue)?;
   }
 
-  link(invoc, queue, &[], "libcompiler-rt")?;
+  link(invoc, queue, SystemLibrary::CompilerRt, &[], "libcompiler-rt")?;
+  audit(invoc, queue)?;
+
+  Ok(())
+}
+
+/// Run `llvm-nm` over the freshly built archive and check that it defines
+/// everything in `REQUIRED_BUILTINS`.
+pub fn audit(invoc: &Invocation, queue: &mut CommandQueue<Invocation>)
+  -> Result<(), Box<Error>>
+{
+  use std::process::Command;
+
+  let archive = invoc.tc().sysroot_lib()
+    .join("libcompiler-rt.a");
+  let nm = invoc.tc().llvm_tool("llvm-nm");
+
+  let cmd = queue
+    .enqueue_state_function(Some("audit-compiler-rt"), move |_, state| {
+      if state.is_dry_run() { return Ok(()); }
+
+      let mut cmd = Command::new(&nm);
+      cmd.arg("--defined-only")
+        .arg("--extern-only")
+        .arg(&archive);
+      util::hermetic_env(&mut cmd);
+      let output = cmd.output()?;
+      if !output.status.success() {
+        return Err(format!("`{}` failed on `{}`:\n{}", nm.display(),
+                           archive.display(),
+                           String::from_utf8_lossy(&output.stderr)).into());
+      }
+
+      let stdout = String::from_utf8_lossy(&output.stdout);
+      let defined: HashSet<&str> = stdout
+        .lines()
+        .filter(|line| line.split_whitespace().count() >= 2 )
+        .filter_map(|line| line.split_whitespace().last() )
+        .collect();
+
+      let missing: Vec<_> = REQUIRED_BUILTINS
+        .iter()
+        .filter(|&&sym| !defined.contains(sym) )
+        .collect();
+      if missing.len() == 0 { return Ok(()); }
+
+      let mut msg = format!("`{}` is missing required builtins:\n",
+                            archive.display());
+      for sym in missing.into_iter() {
+        msg.push_str(&format!("  {}\n", sym));
+      }
+      msg.push_str("Check that compiler-rt's `lib/builtins` wasn't pruned by \
+                    the blacklist, and that the source files defining these \
+                    built (try `--clobber-compiler-rt-build`). Programs using \
+                    these will otherwise fail to link with undefined symbols.");
+      Err(msg.into())
+    });
+  cmd.prev_outputs = false;
+  cmd.output_override = false;
 
   Ok(())
 }
@@ -0,0 +1,49 @@
+use super::{Invocation, SystemLibrary, get_system_dir, };
+use util::{CommandQueue, CreateIfNotExists, Tool, };
+
+use clang_driver;
+
+use std::error::Error;
+
+/// Objects installed as is into the sysroot's `lib`.
+const FILES: &'static [&'static str] = &[
+  "crtctors.c",
+];
+
+impl Invocation {
+  pub fn build_crt(&mut self, queue: &mut CommandQueue<Invocation>)
+    -> Result<(), Box<Error>>
+  {
+    self.configure_musl(queue)?;
+
+    let lib_dir = self.tc().sysroot_lib()
+      .create_if_not_exists()?;
+    for &file in FILES.iter() {
+      let full_file = get_system_dir()
+        .join("crt")
+        .join(file);
+
+      let mut clang = self.clang_invocation();
+      clang.driver_mode = clang_driver::DriverMode::CC;
+      clang.emit_wast = self.emit_wast;
+      self.musl_includes(&mut clang);
+      clang.override_output(lib_dir.join(file).with_extension("o"));
+
+      let mut args = Vec::new();
+      args.push("-c".to_string());
+      args.push(format!("{}", full_file.display()));
+      args.push("-Oz".to_string());
+      super::add_default_args(&mut args);
+      args.extend(self.lib_cflags(SystemLibrary::Crt).iter().cloned());
+
+      let cmd = queue
+        .enqueue_tool(Some("clang"),
+                      clang, args, false,
+                      None::<Vec<::tempdir::TempDir> >)?;
+      cmd.prev_outputs = false;
+      cmd.output_override = false;
+    }
+
+    Ok(())
+  }
+}
@@ -0,0 +1,203 @@
+
+//! The dependencies between the sysroot's libraries, which decide the build
+//! order, and `--print-deps-graph`.
+
+use super::{Invocation, SystemLibrary, };
+
+use std::collections::btree_set::BTreeSet;
+use std::error::Error;
+use std::path::Path;
+use std::str::FromStr;
+
+use util::json_string;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DepsGraphFormat {
+  Dot,
+  Json,
+}
+impl FromStr for DepsGraphFormat {
+  type Err = Box<Error>;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "dot" => Ok(DepsGraphFormat::Dot),
+      "json" => Ok(DepsGraphFormat::Json),
+      _ => Err(format!("unknown graph format `{}`; expected `dot` or `json`", s))?,
+    }
+  }
+}
+
+impl SystemLibrary {
+  pub fn name(&self) -> &'static str {
+    match self {
+      &SystemLibrary::Compat => "compat",
+      &SystemLibrary::CompilerRt => "compiler-rt",
+      &SystemLibrary::DlMalloc => "dlmalloc",
+      &SystemLibrary::LibC => "libc",
+      &SystemLibrary::LibUnwind => "libunwind",
+      &SystemLibrary::LibCxxAbi => "libcxxabi",
+      &SystemLibrary::LibCxx => "libcxx",
+      &SystemLibrary::Zlib => "zlib",
+      &SystemLibrary::LibFfi => "libffi",
+      &SystemLibrary::Icu => "icu",
+      &SystemLibrary::GreenThreads => "green-threads",
+      &SystemLibrary::Crt => "crt",
+    }
+  }
+  /// What has to be built (or already be in the sysroot) first.
+  pub fn deps(&self) -> &'static [SystemLibrary] {
+    use self::SystemLibrary::*;
+    match self {
+      // compiler-rt only needs musl's (configured) headers, not libc.
+      &Compat | &CompilerRt | &DlMalloc => &[],
+      // musl's `install` takes dlmalloc's object.
+      &LibC => &[DlMalloc],
+      &LibUnwind | &Zlib | &LibFfi | &GreenThreads | &Crt => &[LibC],
+      &LibCxxAbi => &[LibC, LibUnwind],
+      &LibCxx => &[LibCxxAbi],
+      &Icu => &[LibCxx],
+    }
+  }
+  /// A file (relative to the sysroot) which is there once the library is
+  /// installed. dlmalloc only ends up inside libc.
+  fn installed_file(&self) -> Option<&'static str> {
+    match self {
+      &SystemLibrary::Compat => Some("include/compat/ctype.h"),
+      &SystemLibrary::CompilerRt => Some("lib/libcompiler-rt.a"),
+      &SystemLibrary::DlMalloc => None,
+      &SystemLibrary::LibC => Some("lib/libc.a"),
+      &SystemLibrary::LibUnwind => Some("lib/libunwind.a"),
+      &SystemLibrary::LibCxxAbi => Some("lib/libc++abi.a"),
+      &SystemLibrary::LibCxx => Some("lib/libc++.a"),
+      &SystemLibrary::Zlib => Some("lib/libz.a"),
+      &SystemLibrary::LibFfi => Some("lib/libffi.a"),
+      &SystemLibrary::Icu => Some("lib/libicuuc.a"),
+      &SystemLibrary::GreenThreads => Some("lib/libgreen-threads.a"),
+      &SystemLibrary::Crt => Some("lib/crtctors.o"),
+    }
+  }
+}
+
+/// `libraries`, deps first, otherwise in `SystemLibrary` order. Deps which
+/// weren't requested aren't added, but still order the libraries which
+/// depend on them.
+pub fn build_order(libraries: &BTreeSet<SystemLibrary>) -> Vec<SystemLibrary> {
+  fn visit(lib: SystemLibrary, libraries: &BTreeSet<SystemLibrary>,
+           visited: &mut BTreeSet<SystemLibrary>, out: &mut Vec<SystemLibrary>) {
+    if !visited.insert(lib) { return; }
+    for &dep in lib.deps().iter() {
+      visit(dep, libraries, visited, out);
+    }
+    if libraries.contains(&lib) {
+      out.push(lib);
+    }
+  }
+
+  let mut visited = BTreeSet::new();
+  let mut out = Vec::new();
+  for &lib in libraries.iter() {
+    visit(lib, libraries, &mut visited, &mut out);
+  }
+  out
+}
+
+impl Invocation {
+  /// libc brings its startup objects, and green threads when they're the
+  /// thread model.
+  pub fn add_implied_libraries(&self, libraries: &mut BTreeSet<SystemLibrary>) {
+    use util::toolchain::ThreadModel;
+
+    if libraries.contains(&SystemLibrary::LibC) {
+      libraries.insert(SystemLibrary::Crt);
+      if self.tc().threads == ThreadModel::Emulated {
+        libraries.insert(SystemLibrary::GreenThreads);
+      }
+    }
+  }
+
+  /// `None` if there's no telling, ie for dlmalloc.
+  pub fn installed(&self, lib: SystemLibrary) -> Option<bool> {
+    lib.installed_file()
+      .map(|file| {
+        let file = Path::new(file);
+        if lib.has_eh_variants() {
+          self.tc().sysroot_eh_lib(self.eh).join(file.file_name().unwrap())
+        } else {
+          self.tc().sysroot().join(file)
+        }
+      })
+      .map(|path| path.exists() )
+  }
+
+  /// The graph of `libraries` and everything they depend on, for the
+  /// current target.
+  pub fn deps_graph(&self, libraries: &BTreeSet<SystemLibrary>,
+                    format: DepsGraphFormat) -> String {
+    let mut libraries = libraries.clone();
+    self.add_implied_libraries(&mut libraries);
+    let order = build_order(&libraries);
+
+    let mut nodes = BTreeSet::new();
+    let mut stack: Vec<SystemLibrary> = libraries.iter().cloned().collect();
+    while let Some(lib) = stack.pop() {
+      if nodes.insert(lib) {
+        stack.extend(lib.deps().iter().cloned());
+      }
+    }
+
+    let target = self.current_target.as_ref()
+      .map(|t| &t[..] )
+      .unwrap_or("sysroot");
+    match format {
+      DepsGraphFormat::Dot => {
+        // dashed: a dep which wasn't requested; filled: already installed.
+        // DOT's quoted strings escape `"` and `\` like JSON's do.
+        let mut out = format!("digraph {} {{\n", json_string(target));
+        for &lib in nodes.iter() {
+          let mut style = Vec::new();
+          if !libraries.contains(&lib) {
+            style.push("dashed");
+          }
+          if self.installed(lib) == Some(true) {
+            style.push("filled");
+          }
+          out.push_str(&format!("  \"{}\" [style=\"{}\"];\n", lib.name(),
+                                style.join(",")));
+        }
+        for &lib in nodes.iter() {
+          for dep in lib.deps().iter() {
+            out.push_str(&format!("  \"{}\" -> \"{}\";\n", lib.name(), dep.name()));
+          }
+        }
+        out.push_str("}\n");
+        out
+      },
+      DepsGraphFormat::Json => {
+        let quoted = |libs: &mut Iterator<Item = &SystemLibrary>| -> String {
+          let libs: Vec<String> = libs
+            .map(|lib| json_string(lib.name()) )
+            .collect();
+          format!("[{}]", libs.join(", "))
+        };
+
+        let mut out = format!("{{\n  \"target\": {},\n  \"libraries\": [",
+                              json_string(target));
+        for (i, &lib) in nodes.iter().enumerate() {
+          out.push_str(if i == 0 { "\n" } else { ",\n" });
+          let installed = match self.installed(lib) {
+            Some(installed) => format!("{}", installed),
+            None => "null".into(),
+          };
+          out.push_str(&format!("    {{\"name\": {}, \"requested\": {}, \
+                                 \"installed\": {}, \"deps\": {}}}",
+                                json_string(lib.name()), libraries.contains(&lib),
+                                installed,
+                                quoted(&mut lib.deps().iter())));
+        }
+        out.push_str(&format!("\n  ],\n  \"build_order\": {}\n}}\n",
+                              quoted(&mut order.iter())));
+        out
+      },
+    }
+  }
+}
@@ -0,0 +1,64 @@
+
+//! `--populate-emscripten-cache`: copy the sysroot's libc/libcxx/libcxxabi
+//! archives into `~/.emscripten_cache/wasm`, under the names emscripten
+//! looks for, so emcc-built objects can link against our libraries.
+
+use super::{Invocation, SystemLibrary, };
+use util::CommandQueue;
+use util::toolchain::WasmToolchainTool;
+
+use std::fs::{copy, rename};
+
+/// `(library, sysroot archive, emscripten cache archive)`.
+const CACHE_ARCHIVES: &'static [(SystemLibrary, &'static str, &'static str)] = &[
+  (SystemLibrary::LibC, "libc.a", "libc.a"),
+  (SystemLibrary::LibCxx, "libc++.a", "libcxx.a"),
+  (SystemLibrary::LibCxxAbi, "libc++abi.a", "libcxxabi.a"),
+];
+
+impl Invocation {
+  /// If nothing was explicitly requested with `--build`, build whichever of
+  /// the cached libraries aren't in the sysroot yet.
+  pub fn add_missing_emscripten_cache_libraries(&mut self) {
+    if self.libraries.len() != 0 { return; }
+
+    let lib = self.tc().sysroot_lib();
+    for &(syslib, archive, _) in CACHE_ARCHIVES.iter() {
+      if !lib.join(archive).exists() {
+        self.add_library(syslib);
+      }
+    }
+  }
+
+  pub fn enqueue_populate_emscripten_cache(&self,
+                                           queue: &mut CommandQueue<Self>)
+  {
+    let f = |sess: &mut &mut Invocation| {
+      let lib = sess.tc().sysroot_lib();
+      let cache = sess.tc().emscripten_cache();
+
+      let missing: Vec<_> = CACHE_ARCHIVES.iter()
+        .map(|&(_, archive, _)| lib.join(archive) )
+        .filter(|archive| !archive.exists() )
+        .map(|archive| format!("  {}", archive.display()) )
+        .collect();
+      if missing.len() != 0 {
+        Err(format!("can't populate the emscripten cache; missing:\n{}",
+                    missing.join("\n")))?;
+      }
+
+      for &(_, archive, cached) in CACHE_ARCHIVES.iter() {
+        let from = lib.join(archive);
+        let to = cache.join(cached);
+        info!("copying `{}` to `{}`", from.display(), to.display());
+        // copy then rename, so a concurrent emcc never sees half an archive.
+        let tmp = cache.join(format!("{}.tmp", cached));
+        copy(&from, &tmp)?;
+        rename(&tmp, &to)?;
+      }
+
+      Ok(())
+    };
+    queue.enqueue_function(Some("populate-emscripten-cache"), f);
+  }
+}
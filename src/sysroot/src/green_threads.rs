@@ -0,0 +1,54 @@
+use super::{Invocation, SystemLibrary, link, get_system_dir, };
+use util::{CommandQueue, CreateIfNotExists, Tool, };
+
+use clang_driver;
+
+use std::error::Error;
+
+const FILES: &'static [&'static str] = &[
+  "green_threads.c",
+];
+
+impl Invocation {
+  pub fn green_threads_build_dir(&self) -> Result<::std::path::PathBuf, Box<Error>> {
+    Ok(self.srcs.join("green-threads-build").create_if_not_exists()?)
+  }
+
+  /// The pthread stubs used with `--threads=emulated`.
+  pub fn build_green_threads(&mut self, queue: &mut CommandQueue<Invocation>)
+    -> Result<(), Box<Error>>
+  {
+    self.configure_musl(queue)?;
+
+    let build_dir = self.green_threads_build_dir()?;
+    for &file in FILES.iter() {
+      let full_file = get_system_dir()
+        .join("green-threads")
+        .join(file);
+
+      let mut clang = self.clang_invocation();
+      clang.driver_mode = clang_driver::DriverMode::CC;
+      clang.emit_wast = self.emit_wast;
+      self.musl_includes(&mut clang);
+      clang.override_output(build_dir.join(file).with_extension("o"));
+
+      let mut args = Vec::new();
+      args.push("-c".to_string());
+      args.push(format!("{}", full_file.display()));
+      args.push("-Oz".to_string());
+      super::add_default_args(&mut args);
+      args.extend(self.lib_cflags(SystemLibrary::GreenThreads).iter().cloned());
+
+      let cmd = queue
+        .enqueue_tool(Some("clang"),
+                      clang, args, false,
+                      None::<Vec<::tempdir::TempDir> >)?;
+      cmd.prev_outputs = false;
+      cmd.output_override = false;
+    }
+
+    link(self, queue, SystemLibrary::GreenThreads, &[], "libgreen-threads")?;
+
+    Ok(())
+  }
+}
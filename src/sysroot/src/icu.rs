@@ -0,0 +1,172 @@
+//! ICU4C, built with only the locale data asked for; full ICU data is
+//! ~30MB, which is a lot to ship with a wasm module just to get
+//! `std::locale("de_DE")`.
+
+use super::{Invocation, SystemLibrary};
+use util::{CommandQueue, CreateIfNotExists, };
+
+use std::error::Error;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, };
+
+/// Locales included when none are specified.
+pub const DEFAULT_ICU_LOCALES: &'static [&'static str] = &["en"];
+
+impl Invocation {
+  pub fn icu_src_dir(&self) -> PathBuf {
+    self.src_cache.join(self.icu_repo.name.as_ref())
+  }
+  /// Where ICU's `configure` lives in the checkout.
+  fn icu_configure_dir(&self) -> PathBuf {
+    self.icu_src_dir().join("icu4c/source")
+  }
+  /// ICU can't build its data tools for the target, so a host build is
+  /// needed for cross compiling.
+  pub fn icu_host_build_dir(&self) -> PathBuf {
+    self.srcs.join("icu-host-build")
+  }
+  pub fn icu_build_dir(&self) -> PathBuf {
+    self.srcs.join("icu-build")
+  }
+  pub fn checkout_icu(&mut self) -> Result<(), Box<Error>> {
+    if self.icu_checkout { return Ok(()); }
+    self.icu_checkout = true;
+
+    self.checkout_repo(self.icu_repo.clone(), self.icu_src_dir())
+  }
+
+  /// ICU's data filter (see ICU's `docs/userguide/icu_data/buildtool.md`);
+  /// either the user's `--icu-data-filter` or one selecting
+  /// `--icu-locales`.
+  fn icu_data_filter(&self, build_dir: &PathBuf) -> Result<PathBuf, Box<Error>> {
+    if let Some(ref filter) = self.icu_data_filter {
+      return Ok(filter.clone());
+    }
+
+    let mut locales = String::new();
+    for (idx, locale) in self.icu_locales.iter().enumerate() {
+      if idx != 0 {
+        locales.push_str(", ");
+      }
+      locales.push_str(&format!("\"{}\"", locale));
+    }
+
+    let filter = build_dir.join("data-filter.json");
+    let mut file = File::create(&filter)?;
+    writeln!(file, "{{")?;
+    writeln!(file, "  \"localeFilter\": {{")?;
+    writeln!(file, "    \"filterType\": \"language\",")?;
+    writeln!(file, "    \"includelist\": [{}]", locales)?;
+    writeln!(file, "  }}")?;
+    writeln!(file, "}}")?;
+
+    Ok(filter)
+  }
+
+  pub fn build_icu(&self, queue: &mut CommandQueue<Invocation>)
+    -> Result<(), Box<Error>>
+  {
+    let configure = self.icu_configure_dir().join("configure");
+
+    if self.clobber_icu_build {
+      let f = move |sess: &mut &mut Invocation| {
+        for build in [sess.icu_host_build_dir(), sess.icu_build_dir()].iter() {
+          if build.exists() {
+            ::std::fs::remove_dir_all(build)?;
+            build.create_if_not_exists()?;
+          }
+        }
+
+        Ok(())
+      };
+      queue.enqueue_function(Some("clobber-icu-build"), f);
+    }
+
+    let host_build_dir = self.icu_host_build_dir()
+      .create_if_not_exists()?;
+    let build_dir = self.icu_build_dir()
+      .create_if_not_exists()?;
+    let install_dir = self.tc().sysroot_cache()
+      .create_if_not_exists()?;
+    let filter = self.icu_data_filter(&build_dir)?;
+
+    // the host build; only the tools are needed from it.
+    let mut host_conf = Command::new(&configure);
+    host_conf.current_dir(&host_build_dir)
+      .arg("--disable-tests")
+      .arg("--disable-samples")
+      .arg("--disable-extras");
+    {
+      let cmd = queue
+        .enqueue_simple_external(Some("configure host icu"),
+                                 host_conf, None);
+
+      cmd.prev_outputs = false;
+      cmd.output_override = false;
+    }
+
+    let mut host_make = Command::new("make");
+    host_make.current_dir(&host_build_dir)
+      .arg(self.jobs_arg());
+    {
+      let cmd = queue
+        .enqueue_simple_external(Some("build host icu"),
+                                 host_make, None);
+
+      cmd.prev_outputs = false;
+      cmd.output_override = false;
+    }
+
+    let mut cflags = self.c_cxx_linker_cflags();
+    cflags.push_str(" -Os");
+    if self.emit_wast {
+      cflags.push_str(" --emit-wast");
+    }
+
+    let mut conf = Command::new(&configure);
+    conf.current_dir(&build_dir)
+      .env("CC", self.cc())
+      .env("CXX", self.cxx())
+      .env("ICU_DATA_FILTER_FILE", &filter)
+      .arg("--host=wasm32-unknown-unknown")
+      .arg(format!("--with-cross-build={}", host_build_dir.display()))
+      .arg(format!("--prefix={}", install_dir.display()))
+      .arg("--enable-static")
+      .arg("--disable-shared")
+      .arg("--disable-tools")
+      .arg("--disable-tests")
+      .arg("--disable-samples")
+      .arg("--disable-extras")
+      .arg("--with-data-packaging=static");
+    self.lib_flags_env(SystemLibrary::Icu, &mut conf, &cflags);
+    self.tc().set_envs(&mut conf);
+
+    {
+      let cmd = queue
+        .enqueue_simple_external(Some("configure icu"),
+                                 conf, None);
+
+      cmd.prev_outputs = false;
+      cmd.output_override = false;
+    }
+
+    let mut install = Command::new("make");
+    install.current_dir(&build_dir)
+      .env("ICU_DATA_FILTER_FILE", &filter)
+      .arg(self.jobs_arg())
+      .arg("install");
+    self.tc().set_envs(&mut install);
+    {
+      let cmd = queue
+        .enqueue_simple_external(Some("install icu"),
+                                 install, None);
+
+      cmd.prev_outputs = false;
+      cmd.output_override = false;
+    }
+
+    Ok(())
+  }
+}
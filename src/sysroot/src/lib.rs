@@ -4,15 +4,21 @@ use std::cmp::Ordering;
 use std::collections::HashSet;
 use std::error::Error;
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
 use std::str::FromStr;
 
 use util::{ToolArgs, Tool, ToolInvocation, CommandQueue,
-           CreateIfNotExists, ToolArgAccessor, regex, };
+           CreateIfNotExists, ToolArgAccessor, EhMode, regex, };
 use util::toolchain::{WasmToolchain, WasmToolchainTool, };
-use util::repo::Repo;
+use util::lock::FileLock;
+use util::repo::{CheckoutConfig, Repo, default_src_cache};
 use std::fs::remove_file;
 use std::alloc::System;
 use std::collections::btree_set::BTreeSet;
+use std::collections::BTreeMap;
+
+pub use deps::DepsGraphFormat;
 
 pub mod libc;
 pub mod libcxx;
@@ -22,6 +28,14 @@ pub mod libdlmalloc;
 pub mod compiler_rt;
 pub mod compat;
 pub mod zlib;
+pub mod libffi;
+pub mod icu;
+pub mod green_threads;
+pub mod crt;
+pub mod stage;
+pub mod deps;
+pub mod emscripten_cache;
+pub mod prebuilt;
 
 #[macro_use]
 extern crate wasm_driver_utils as util;
@@ -31,6 +45,8 @@ extern crate lazy_static;
 extern crate log;
 extern crate tempdir;
 extern crate dirs;
+extern crate flate2;
+extern crate tar;
 
 extern crate clang_driver;
 extern crate cmake_driver;
@@ -56,8 +72,17 @@ pub struct Invocation {
   musl_inited: bool,
   musl_configured: bool,
 
+  /// The llvm source tree (ie `llvm-project/llvm`) libc++, libc++abi and
+  /// libunwind are configured against. If not given, `llvm_project_repo`
+  /// is checked out (just the parts they need) into `src_cache`.
   pub llvm_src: Option<PathBuf>,
+  /// Build trees.
   pub srcs: PathBuf,
+  /// Source checkouts, shared between build trees. Existing ones are
+  /// reused rather than fetched again. `srcs` without a `$HOME`.
+  pub src_cache: PathBuf,
+  /// Never touch the network; fail early if a source checkout is missing.
+  pub offline: bool,
 
   pub clobber_libunwind_build: bool,
   pub clobber_libcxxabi_build: bool,
@@ -65,24 +90,102 @@ pub struct Invocation {
   pub clobber_libc_build: bool,
   pub clobber_compiler_rt_build: bool,
   pub clobber_zlib_build: bool,
+  pub clobber_libffi_build: bool,
+  pub clobber_icu_build: bool,
 
   pub compiler_rt_repo: Repo,
   pub musl_repo: Repo,
   pub libcxx_repo: Repo,
   pub libcxxabi_repo: Repo,
   pub zlib_repo: Repo,
+  pub libffi_repo: Repo,
+  pub icu_repo: Repo,
   pub libunwind_repo: Repo,
+  pub llvm_project_repo: Repo,
 
   compiler_rt_checkout: bool,
   musl_checkout: bool,
   libcxx_checkout: bool,
   libcxxabi_checkout: bool,
   zlib_checkout: bool,
+  libffi_checkout: bool,
+  icu_checkout: bool,
   libunwind_checkout: bool,
+  llvm_project_checkout: bool,
 
   pub emit_wast: bool,
   pub emit_wasm: bool,
+
+  /// Number of parallel jobs to give to `make`/`ninja`.
+  pub jobs: Option<usize>,
+  /// `--max-heavy-jobs`: how many links (and other memory hungry steps)
+  /// the drivers `make`/`ninja` runs may have going at once. See
+  /// `util::jobs`.
+  pub max_heavy_jobs: Option<usize>,
+  /// `--checkout-jobs`: how many source checkouts run at once.
+  pub checkout_jobs: Option<usize>,
+  /// `--checkout-retries`: how many more times a failed checkout is tried.
+  pub checkout_retries: u32,
+  /// `--repo-mirror`s and `--repo-depth`.
+  pub checkout_config: CheckoutConfig,
+  /// Queued by `checkout_repo`, and run (in parallel) by `run_checkouts`.
+  pending_checkouts: Vec<(Repo, PathBuf)>,
+
+  /// Where the sysroot will eventually be installed. If set (or if
+  /// `$DESTDIR` is), libraries are staged elsewhere; see `stage`.
+  pub install_prefix: Option<PathBuf>,
+
+  /// Locales to include ICU data for.
+  pub icu_locales: Vec<String>,
+  /// An ICU data filter file; overrides `icu_locales`.
+  pub icu_data_filter: Option<PathBuf>,
+
+  /// Relink libraries even if their objects haven't changed since they
+  /// were last linked.
+  pub force_link: bool,
+
+  /// `--targets`: build everything once per target, each with its own build
+  /// dirs (`<srcs>/<target>`) and sysroot (`<sysroot>/<target>`), and
+  /// compiled with `--target=<target>`. Source checkouts are shared.
+  pub targets: Vec<String>,
+  current_target: Option<String>,
+
+  /// Copy the built libraries into the emscripten cache afterwards.
+  pub populate_emscripten_cache: bool,
+
+  /// `--sysroot-lock`: hold a lock on the sysroot (and the emscripten
+  /// cache, if we're populating it) while installing into it, so
+  /// concurrent builds wait their turn instead of clobbering each other.
+  pub sysroot_lock: bool,
+  /// Seconds to wait for the lock; `None` is forever.
+  pub sysroot_lock_timeout: Option<u64>,
+  locks: Vec<Rc<FileLock>>,
+
+  /// Print the requested libraries' dependency graph instead of building.
+  pub print_deps_graph: Option<DepsGraphFormat>,
+
+  /// `--cflags-for=<lib>=<flags>`: extra compile flags for a library's
+  /// build. They come after ours, so they win.
+  pub cflags_for: BTreeMap<SystemLibrary, Vec<String>>,
+  /// `--ldflags-for=<lib>=<flags>`: extra link flags. They're given to the
+  /// compiler driver, so linker flags need `-Wl,` (which is removed for the
+  /// libraries we link ourselves).
+  pub ldflags_for: BTreeMap<SystemLibrary, Vec<String>>,
+
+  /// `--eh=<mode>`: build libc++, libc++abi and libunwind with exceptions,
+  /// into `lib/eh-<mode>` (see `EhMode::lib_subdir`), so they sit beside
+  /// the no-exceptions build.
+  pub eh: EhMode,
+
+  /// `--download-prebuilt[=<version>]`: install this release's prebuilt
+  /// sysroot first. See `prebuilt`.
+  pub download_prebuilt: Option<String>,
+  /// Where releases are downloaded from.
+  pub prebuilt_url: String,
 }
+const DEFAULT_JOBS: usize = 8;
+const DEFAULT_CHECKOUT_JOBS: usize = 4;
+
 impl Invocation {
   pub fn add_all_libraries(&mut self) {
     self.add_library(SystemLibrary::Compat);
@@ -96,9 +199,90 @@ impl Invocation {
   pub fn add_library(&mut self, lib: SystemLibrary) {
     self.libraries.insert(lib);
   }
-  pub fn llvm_src(&self) -> &PathBuf {
-    self.llvm_src.as_ref()
-      .expect("Need `--llvm-src`")
+  pub fn lib_cflags(&self, lib: SystemLibrary) -> &[String] {
+    self.cflags_for.get(&lib)
+      .map(|flags| &flags[..] )
+      .unwrap_or(&[])
+  }
+  pub fn lib_ldflags(&self, lib: SystemLibrary) -> &[String] {
+    self.ldflags_for.get(&lib)
+      .map(|flags| &flags[..] )
+      .unwrap_or(&[])
+  }
+  /// The triple being built, ie the current `--targets` entry.
+  pub fn target(&self) -> &str {
+    self.current_target.as_ref()
+      .map(|t| &t[..] )
+      .unwrap_or(util::WASM_TARGETS[0].0)
+  }
+  /// A clang invocation for the current target.
+  fn clang_invocation(&self) -> clang_driver::Invocation {
+    let mut clang = clang_driver::Invocation::with_toolchain(self);
+    clang.target = Some(self.target().to_string());
+    clang
+  }
+  /// Point cmake's compilers at the current target.
+  fn target_cmake_flags(&self, cmake: &mut cmake_driver::Invocation) {
+    cmake.cmake_str("CMAKE_C_COMPILER_TARGET", self.target())
+      .cmake_str("CMAKE_CXX_COMPILER_TARGET", self.target());
+  }
+  /// `lib`'s `--cflags-for`/`--ldflags-for`, and the target, for builds
+  /// which go through configure, ie `CFLAGS=$(cflags)`.
+  fn lib_flags_env(&self, lib: SystemLibrary, cmd: &mut ::std::process::Command,
+                   cflags: &str) {
+    let mut cflags = format!("{} --target={}", cflags, self.target());
+    for flag in self.lib_cflags(lib).iter() {
+      cflags.push(' ');
+      cflags.push_str(flag);
+    }
+    cmd.env("CFLAGS", &cflags)
+      .env("CXXFLAGS", &cflags);
+    if self.lib_ldflags(lib).len() != 0 {
+      cmd.env("LDFLAGS", self.lib_ldflags(lib).join(" "));
+    }
+  }
+  /// `name`, for the build dir of one of the C++ runtime libraries. Each
+  /// `--eh` mode gets its own.
+  fn eh_build_dir_name(&self, name: &str) -> String {
+    match self.eh.lib_subdir() {
+      Some(dir) => format!("{}-{}", name, dir),
+      None => name.to_string(),
+    }
+  }
+  /// Compile with `--eh`'s flags and install into its lib dir. `prefix` is
+  /// the project's cmake prefix, ie `LIBCXX`.
+  fn eh_cmake_flags(&self, prefix: &str, cmake: &mut cmake_driver::Invocation) {
+    if let Some(dir) = self.eh.lib_subdir() {
+      cmake.cmake_str(format!("{}_LIBDIR_SUFFIX", prefix), format!("/{}", dir));
+    }
+    for flag in self.eh.clang_flags().iter() {
+      cmake.c_cxx_flag(flag);
+    }
+  }
+  /// `lib`'s `--cflags-for`/`--ldflags-for`, for builds which use cmake.
+  fn lib_cmake_flags(&self, lib: SystemLibrary,
+                     cmake: &mut cmake_driver::Invocation) {
+    for flag in self.lib_cflags(lib).iter() {
+      cmake.c_cxx_flag(flag);
+    }
+    for flag in self.lib_ldflags(lib).iter() {
+      cmake.shared_ld_flag(flag)
+        .exe_ld_flag(flag);
+    }
+  }
+  pub fn llvm_src(&self) -> PathBuf {
+    self.llvm_src.clone()
+      .unwrap_or_else(|| self.llvm_project_src().join("llvm") )
+  }
+  pub fn llvm_project_src(&self) -> PathBuf {
+    self.src_cache.join(self.llvm_project_repo.name.as_ref())
+  }
+  /// Fetch the llvm tree, unless `--llvm-src` was given.
+  pub fn checkout_llvm_src(&mut self) -> Result<(), Box<Error>> {
+    if self.llvm_src.is_some() || self.llvm_project_checkout { return Ok(()); }
+    self.llvm_project_checkout = true;
+
+    self.checkout_repo(self.llvm_project_repo.clone(), self.llvm_project_src())
   }
   pub fn c_cxx_linker_args(&self) -> Vec<Cow<'static, str>> {
     let mut v = vec![
@@ -126,35 +310,362 @@ impl Invocation {
     out
   }
 
-  pub fn cxx(&self) -> PathBuf {
+  /// The installed driver, else whatever's on the `$PATH` without a
+  /// `$HOME`.
+  fn installed_driver(name: &str) -> PathBuf {
     dirs::home_dir()
-      .expect("need a $HOME")
-      .join(".cargo/bin")
-      .join("wasm-clangxx")
+      .map(|home| home.join(".cargo/bin").join(name) )
+      .unwrap_or_else(|| name.into() )
+  }
+  pub fn cxx(&self) -> PathBuf {
+    Self::installed_driver("wasm-clangxx")
   }
   pub fn cc(&self) -> PathBuf {
-    dirs::home_dir()
-      .expect("need a $HOME")
-      .join(".cargo/bin")
-      .join("wasm-clang")
+    Self::installed_driver("wasm-clang")
+  }
+  /// Queue `repo`'s checkout into `dest`, unless we're offline, in which
+  /// case any existing checkout is used as is. Checkouts in `src_cache`
+  /// are never clobbered, since other build trees use them too.
+  fn checkout_repo(&mut self, repo: Repo, dest: PathBuf) -> Result<(), Box<Error>> {
+    let mut repo = self.checkout_config.apply(repo);
+    if dest.starts_with(&self.src_cache) {
+      repo.clobber = false;
+    }
+    if self.offline && repo.needs_network(&dest) {
+      if dest.exists() {
+        if let Some(ref commit) = repo.commit {
+          util::diag::warning(&format!("`--offline`: using `{}` as is; it isn't at the \
+                                        pinned commit `{}`", dest.display(), commit));
+        }
+        return Ok(());
+      }
+      return Err(format!("`--offline` given, but `{}` isn't checked out at `{}`",
+                         repo.name, dest.display()).into());
+    }
+
+    self.pending_checkouts.push((repo, dest));
+    Ok(())
+  }
+  /// Run the queued checkouts, `--checkout-jobs` at a time. Every one is
+  /// tried (again, up to `--checkout-retries` times); the error lists all
+  /// which failed.
+  fn run_checkouts(&mut self) -> Result<(), Box<Error>> {
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    let pending = ::std::mem::replace(&mut self.pending_checkouts, vec![]);
+    let total = pending.len();
+    if total == 0 { return Ok(()); }
+    let jobs = ::std::cmp::min(self.checkout_jobs.unwrap_or(DEFAULT_CHECKOUT_JOBS),
+                               total);
+    util::diag::note(&format!("checking out {} source(s), {} at a time",
+                              total, jobs));
+
+    let retries = self.checkout_retries;
+    let pending = Arc::new(Mutex::new(pending.into_iter()));
+    // (done, failures)
+    let results = Arc::new(Mutex::new((0usize, Vec::new())));
+    // the global flags, and where diagnostics go, are per thread.
+    let flags = util::GlobalFlags::current();
+    let sink = util::diag::current_sink();
+    let workers: Vec<_> = (0..jobs)
+      .map(|_| {
+        let pending = pending.clone();
+        let results = results.clone();
+        let flags = flags.clone();
+        let sink = sink.clone();
+        thread::spawn(move || {
+          let _flags = flags.enter();
+          let _sink = util::diag::enter_sink(sink);
+          loop {
+            let next = pending.lock().unwrap().next();
+            let (repo, dest) = match next {
+              Some(next) => next,
+              None => break,
+            };
+            let mut attempt = 0;
+            let res = loop {
+              let res = repo.checkout_thin(&dest)
+                .map_err(|e| e.to_string() );
+              match res {
+                Err(ref e) if attempt < retries => {
+                  attempt += 1;
+                  util::diag::warning(&format!("checking out `{}` failed: {}; \
+                                                retrying ({} of {})", repo.name,
+                                               e, attempt, retries));
+                },
+                res => break res,
+              }
+            };
+
+            let mut results = results.lock().unwrap();
+            results.0 += 1;
+            match res {
+              Ok(()) => {
+                util::diag::note(&format!("checked out `{}` ({}/{})", repo.name,
+                                          results.0, total));
+              },
+              Err(e) => {
+                results.1.push(format!("`{}` into `{}`: {}", repo.name,
+                                       dest.display(), e));
+              },
+            }
+          }
+        })
+      })
+      .collect();
+    for worker in workers.into_iter() {
+      let _ = worker.join();
+    }
+
+    let results = results.lock().unwrap();
+    if results.1.len() != 0 {
+      Err(format!("{} of {} checkout(s) failed:\n  {}", results.1.len(), total,
+                  results.1.join("\n  ")))?;
+    }
+    Ok(())
+  }
+  /// The source checkouts `lib` needs.
+  fn library_sources(&self, lib: SystemLibrary) -> Vec<(&Repo, PathBuf)> {
+    let libunwind = (&self.libunwind_repo, self.libunwind_src());
+    let libcxxabi = (&self.libcxxabi_repo, self.libcxxabi_src());
+    let libcxx = (&self.libcxx_repo, self.libcxx_src());
+    let mut sources = match lib {
+      SystemLibrary::Compat | SystemLibrary::DlMalloc |
+      SystemLibrary::GreenThreads | SystemLibrary::Crt => vec![],
+      SystemLibrary::LibC => vec![(&self.musl_repo, self.get_musl_root())],
+      SystemLibrary::CompilerRt => vec![(&self.compiler_rt_repo, self.compiler_rt_src())],
+      SystemLibrary::Zlib => vec![(&self.zlib_repo, self.zlib_src_dir())],
+      SystemLibrary::LibFfi => vec![(&self.libffi_repo, self.libffi_src_dir())],
+      SystemLibrary::Icu => vec![(&self.icu_repo, self.icu_src_dir())],
+      SystemLibrary::LibUnwind => vec![libunwind],
+      SystemLibrary::LibCxxAbi |
+      SystemLibrary::LibCxx => vec![libcxx, libcxxabi, libunwind],
+    };
+    if self.llvm_src.is_none() {
+      match lib {
+        SystemLibrary::LibUnwind | SystemLibrary::LibCxxAbi |
+        SystemLibrary::LibCxx => {
+          sources.push((&self.llvm_project_repo, self.llvm_project_src()));
+        },
+        _ => {},
+      }
+    }
+    sources
+  }
+  /// Error out, listing every missing source, before we try to checkout
+  /// anything.
+  fn check_offline_sources(&self, libraries: &BTreeSet<SystemLibrary>)
+    -> Result<(), Box<Error>>
+  {
+    let mut missing = BTreeSet::new();
+    for &lib in libraries.iter() {
+      for (repo, dest) in self.library_sources(lib).into_iter() {
+        if repo.needs_network(&dest) && !dest.exists() {
+          missing.insert(format!("  {} (expected at `{}`)", repo.name,
+                                 dest.display()));
+        }
+      }
+    }
+
+    if missing.len() == 0 { return Ok(()); }
+
+    let mut msg = "`--offline` given, but these sources are missing:".to_string();
+    for m in missing.into_iter() {
+      msg.push('\n');
+      msg.push_str(&m);
+    }
+    Err(msg.into())
   }
   pub fn tc(&self) -> &WasmToolchain {
     self.tc.as_ref()
       .expect("tc uninitialized")
   }
+  /// Take `dir`'s lock, unless we already have it. It's held until we
+  /// exit.
+  fn lock_dir(&mut self, dir: &Path, what: &str) -> Result<(), Box<Error>> {
+    let path = dir.join(".wasm-sysroot.lock");
+    if self.locks.iter().any(|lock| lock.path() == path ) {
+      return Ok(());
+    }
+    let timeout = self.sysroot_lock_timeout
+      .map(Duration::from_secs);
+    let lock = util::lock::lock(&path, what, timeout)?;
+    self.locks.push(Rc::new(lock));
+    Ok(())
+  }
+  pub fn jobs_arg(&self) -> String {
+    format!("-j{}", self.jobs.unwrap_or(DEFAULT_JOBS))
+  }
   pub fn init_wasm_tc(&mut self) {
     if self.tc.is_none() {
       self.tc = Some(Default::default());
     }
   }
 
+  /// Enqueue the builds of `libraries`, for the current target.
+  fn enqueue_libraries(&mut self, mut libraries: BTreeSet<SystemLibrary>,
+                       queue: &mut CommandQueue<Invocation>)
+    -> Result<(), Box<Error>>
+  {
+    self.add_implied_libraries(&mut libraries);
+    let install_prefix = self.stage_install()?;
+    if self.sysroot_lock {
+      let sysroot = self.tc().sysroot().clone().create_if_not_exists()?;
+      self.lock_dir(&sysroot, "the sysroot")?;
+    }
+    if let Some(target) = self.current_target.clone() {
+      self.enqueue_switch_target(target, queue);
+    }
+    match self.download_prebuilt.clone() {
+      Some(version) => self.enqueue_install_prebuilt(version, libraries, queue),
+      None => self.enqueue_library_builds(libraries, queue)?,
+    }
+
+    // before the rewrite, so the packages point at the final prefix.
+    queue.enqueue_function(Some("cmake-config-packages"), |sess: &mut &mut Invocation| {
+      cmake_driver::packages::write_config_packages(sess.tc().sysroot())?;
+      Ok(())
+    })
+      .prev_outputs = false;
+    if let Some(prefix) = install_prefix {
+      self.enqueue_rewrite_install_paths(prefix, queue);
+    }
+    if self.populate_emscripten_cache {
+      if self.sysroot_lock {
+        let cache = self.tc().emscripten_cache();
+        self.lock_dir(&cache, "the emscripten cache")?;
+      }
+      self.enqueue_populate_emscripten_cache(queue);
+    }
+
+
+    Ok(())
+  }
+  /// Check out `libraries`' sources and enqueue their builds.
+  fn enqueue_library_builds(&mut self, libraries: BTreeSet<SystemLibrary>,
+                            queue: &mut CommandQueue<Invocation>)
+    -> Result<(), Box<Error>>
+  {
+    if self.offline {
+      self.check_offline_sources(&libraries)?;
+    }
+    let libraries = deps::build_order(&libraries);
+
+    info!("sysroot build order: {:#?}", libraries);
+
+    let mut dlmalloc_built = false;
+
+    for &syslib in libraries.iter() {
+      match syslib {
+        SystemLibrary::LibC => {
+          self.checkout_musl()?;
+        },
+        SystemLibrary::LibCxx => {
+          self.checkout_libcxx()?;
+        },
+        SystemLibrary::LibCxxAbi => {
+          self.checkout_libcxxabi()?;
+        },
+        SystemLibrary::CompilerRt => {
+          self.checkout_compiler_rt()?;
+        },
+        SystemLibrary::Zlib => {
+          self.checkout_zlib()?;
+        },
+        SystemLibrary::LibFfi => {
+          self.checkout_libffi()?;
+        },
+        SystemLibrary::Icu => {
+          self.checkout_icu()?;
+        },
+        SystemLibrary::LibUnwind => {
+          self.checkout_libunwind()?;
+        }
+        _ => {},
+      }
+    }
+    self.run_checkouts()?;
+
+    for syslib in libraries.into_iter() {
+      match syslib {
+        SystemLibrary::Compat => {
+          self.build_compat(queue)?;
+        },
+        SystemLibrary::LibC => {
+          self.build_musl(queue, &mut dlmalloc_built)?;
+        },
+        SystemLibrary::LibCxx => {
+          self.build_libcxx(queue)?;
+        },
+        SystemLibrary::LibCxxAbi => {
+          self.build_libcxxabi(queue)?;
+        },
+        SystemLibrary::LibUnwind => {
+          self.build_libunwind(queue)?;
+        },
+        SystemLibrary::CompilerRt => {
+          compiler_rt::build(self, queue)?;
+        },
+        SystemLibrary::DlMalloc => {
+          self.build_dlmalloc(queue)?;
+          dlmalloc_built = true;
+        },
+        SystemLibrary::Zlib => {
+          self.build_zlib(queue)?;
+        },
+        SystemLibrary::LibFfi => {
+          self.build_libffi(queue)?;
+        },
+        SystemLibrary::Icu => {
+          self.build_icu(queue)?;
+        },
+        SystemLibrary::GreenThreads => {
+          self.build_green_threads(queue)?;
+        },
+        SystemLibrary::Crt => {
+          self.build_crt(queue)?;
+        },
+      }
+    }
+
+    Ok(())
+  }
+  fn enqueue_print_deps_graph(&self, libraries: &BTreeSet<SystemLibrary>,
+                              format: DepsGraphFormat,
+                              queue: &mut CommandQueue<Invocation>)
+  {
+    let graph = self.deps_graph(libraries, format);
+    queue.enqueue_function(Some("print-deps-graph"), move |_| {
+      print!("{}", graph);
+      Ok(())
+    })
+      .prev_outputs = false;
+  }
+  /// The build steps read the build dirs and sysroot when they're run, so
+  /// with `--targets` they need to be pointed at each target's in turn.
+  fn enqueue_switch_target(&self, target: String,
+                           queue: &mut CommandQueue<Invocation>)
+  {
+    let srcs = self.srcs.clone();
+    let sysroot = self.tc().sysroot().clone();
+    let f = move |sess: &mut &mut Invocation| {
+      sess.srcs = srcs;
+      sess.wasm_toolchain_mut().sysroot = sysroot;
+      sess.current_target = Some(target);
+      Ok(())
+    };
+    queue.enqueue_function(Some("switch-target"), f)
+      .prev_outputs = false;
+  }
+
   // compiler-rt and dlmalloc needs some libc installed headers:
   // but compiler-rt and dlmalloc must be built before musl.
   fn musl_includes(&self, clang: &mut clang_driver::Invocation) {
     let include = self.get_musl_root().join("include");
     let arch_include = self.get_musl_root().join("arch/wasm32");
     let generic_include = self.get_musl_root().join("arch/generic");
-    let config_include = self.get_musl_root().join("obj/include");
+    let config_include = self.musl_build_dir().join("obj/include");
     clang.add_system_include_dir(config_include);
     clang.add_system_include_dir(generic_include);
     clang.add_system_include_dir(arch_include);
@@ -174,6 +685,8 @@ impl Default for Invocation {
 
       llvm_src: None,
       srcs: get_system_dir(),
+      src_cache: default_src_cache().unwrap_or_else(get_system_dir),
+      offline: false,
 
       clobber_libunwind_build: false,
       clobber_libcxxabi_build: false,
@@ -181,6 +694,8 @@ impl Default for Invocation {
       clobber_libc_build: false,
       clobber_compiler_rt_build: false,
       clobber_zlib_build: false,
+      clobber_libffi_build: false,
+      clobber_icu_build: false,
 
       compiler_rt_repo: Repo::new_git_commit("compiler-rt", COMPILER_RT_REPO, "master",
                                              COMPILER_RT_COMMIT),
@@ -190,19 +705,62 @@ impl Default for Invocation {
       libcxxabi_repo: Repo::new_git_commit("libcxxabi", LIBCXXABI_REPO,
                                            "master", LIBCXXABI_COMMIT),
       zlib_repo: Repo::new_git_commit("zlib", ZLIB_REPO, "master",
-                                      ZLIB_COMMIT),
+                                      ZLIB_COMMIT)
+        .recursive_submodules(),
+      libffi_repo: Repo::new_git_commit("libffi", LIBFFI_REPO, "master",
+                                        LIBFFI_TAG),
+      icu_repo: Repo::new_git_commit("icu", ICU_REPO, "maint/maint-64", ICU_TAG),
       libunwind_repo: Repo::new_git_commit("libunwind", LIBUNWIND_REPO, "master",
                                            LIBUNWIND_COMMIT),
+      llvm_project_repo: Repo::new_git_commit("llvm-project", LLVM_PROJECT_REPO,
+                                              "main", LLVM_PROJECT_TAG)
+        .sparse(LLVM_PROJECT_PATHS.iter().cloned()),
 
       compiler_rt_checkout: false,
       musl_checkout: false,
       libcxx_checkout: false,
       libcxxabi_checkout: false,
       zlib_checkout: false,
+      libffi_checkout: false,
+      icu_checkout: false,
       libunwind_checkout: false,
+      llvm_project_checkout: false,
 
       emit_wast: false,
       emit_wasm: true,
+
+      jobs: None,
+      max_heavy_jobs: None,
+      checkout_jobs: None,
+      checkout_retries: 0,
+      checkout_config: Default::default(),
+      pending_checkouts: vec![],
+
+      install_prefix: None,
+
+      icu_locales: icu::DEFAULT_ICU_LOCALES.iter()
+        .map(|&l| l.to_string() )
+        .collect(),
+      icu_data_filter: None,
+
+      force_link: false,
+
+      targets: vec![],
+      current_target: None,
+
+      populate_emscripten_cache: false,
+      sysroot_lock: false,
+      sysroot_lock_timeout: None,
+      locks: vec![],
+      print_deps_graph: None,
+
+      cflags_for: Default::default(),
+      ldflags_for: Default::default(),
+
+      eh: Default::default(),
+
+      download_prebuilt: None,
+      prebuilt_url: prebuilt::DEFAULT_URL.to_string(),
     }
   }
 }
@@ -210,12 +768,27 @@ const COMPILER_RT_REPO: &'static str = "https://github.com/llvm-mirror/compiler-
 const COMPILER_RT_COMMIT: &'static str = "4e8e8d6b18fccced6738aa85dfc28105c7add469";
 const MUSL_REPO: &'static str = "https://github.com/DiamondLovesYou/musl.git";
 const MUSL_BRANCH: &'static str = "wasm-prototype-1";
+const LLVM_PROJECT_REPO: &'static str = "https://github.com/llvm/llvm-project.git";
+const LLVM_PROJECT_TAG: &'static str = "llvmorg-8.0.0";
+/// What `LLVM_PATH` needs, for libc++, libc++abi and libunwind.
+const LLVM_PROJECT_PATHS: &'static [&'static str] = &[
+  "llvm/cmake",
+  "llvm/utils/lit",
+  "libcxx",
+  "libcxxabi",
+  "libunwind",
+  "runtimes",
+];
 const LIBCXX_REPO: &'static str = "https://github.com/llvm-mirror/libcxx.git";
 const LIBCXX_COMMIT: &'static str = "2495dabf93b1d8b9f1c3a18815d23da4b09a1d1f";
 const LIBCXXABI_REPO: &'static str = "https://github.com/llvm-mirror/libcxxabi.git";
 const LIBCXXABI_COMMIT: &'static str = "dd73082d02640d8677d585c8a48243dcdd93e195";
 const ZLIB_REPO: &'static str = "https://github.com/madler/zlib.git";
 const ZLIB_COMMIT: &'static str = "cacf7f1d4e3d44d871b605da3b647f07d718623f";
+const LIBFFI_REPO: &'static str = "https://github.com/libffi/libffi.git";
+const LIBFFI_TAG: &'static str = "v3.3";
+const ICU_REPO: &'static str = "https://github.com/unicode-org/icu.git";
+const ICU_TAG: &'static str = "release-64-2";
 const LIBUNWIND_REPO: &'static str = "https://github.com/llvm-mirror/libunwind.git";
 const LIBUNWIND_COMMIT: &'static str = "1e1c6b739595098ba5c466bfe9d58b993e646b48";
 
@@ -229,14 +802,30 @@ pub enum SystemLibrary {
   LibCxxAbi,
   LibCxx,
   Zlib,
+  LibFfi,
+  /// Needs libcxx.
+  Icu,
+  /// Only for `--threads=emulated`; added automatically along with libc.
+  GreenThreads,
+  /// Startup shims, ie to run ctors; added automatically along with libc.
+  Crt,
+}
+impl SystemLibrary {
+  /// Built once per `--eh` mode.
+  pub fn has_eh_variants(&self) -> bool {
+    match self {
+      &SystemLibrary::LibUnwind | &SystemLibrary::LibCxxAbi |
+      &SystemLibrary::LibCxx => true,
+      _ => false,
+    }
+  }
 }
-impl SystemLibrary { }
 
 impl FromStr for SystemLibrary {
   type Err = Box<Error>;
   fn from_str(s: &str) -> Result<Self, Self::Err> {
     match s {
-      "libc" => Ok(SystemLibrary::LibC),
+      "libc" | "musl" => Ok(SystemLibrary::LibC),
       "libcxx" => Ok(SystemLibrary::LibCxx),
       "libcxxabi" => Ok(SystemLibrary::LibCxxAbi),
       "libunwind" => Ok(SystemLibrary::LibUnwind),
@@ -244,6 +833,10 @@ impl FromStr for SystemLibrary {
       "compat" => Ok(SystemLibrary::Compat),
       "dlmalloc" => Ok(SystemLibrary::DlMalloc),
       "zlib" => Ok(SystemLibrary::Zlib),
+      "libffi" => Ok(SystemLibrary::LibFfi),
+      "icu" => Ok(SystemLibrary::Icu),
+      "green-threads" => Ok(SystemLibrary::GreenThreads),
+      "crt" => Ok(SystemLibrary::Crt),
       _ => {
         Err(format!("unknown system library: {}", s))?
       },
@@ -266,65 +859,55 @@ impl Tool for Invocation {
   fn enqueue_commands(&mut self, queue: &mut CommandQueue<Invocation>)
     -> Result<(), Box<Error>>
   {
-    let libraries = self.libraries.clone();
-    self.libraries.clear();
+    // these builds take a while; let an interrupted one be `--resume`d.
+    queue.set_checkpoint(true);
 
-    info!("sysroot build order: {:#?}", libraries);
+    if let Some(n) = self.max_heavy_jobs {
+      use util::jobs::{HEAVY_JOBS_DIR_ENV, MAX_HEAVY_JOBS_ENV};
 
-    let mut dlmalloc_built = false;
+      // set on every build command, and so inherited by the drivers they
+      // run. The slots are this build's own, unless they're already shared.
+      let flags = queue.flags_mut();
+      flags.set_tool_env(MAX_HEAVY_JOBS_ENV, &n.to_string());
+      if ::std::env::var_os(HEAVY_JOBS_DIR_ENV).is_none() {
+        let dir = self.srcs.join("heavy-jobs");
+        flags.set_tool_env(HEAVY_JOBS_DIR_ENV, &dir.to_string_lossy());
+      }
+    }
 
-    for &syslib in libraries.iter() {
-      match syslib {
-        SystemLibrary::LibC => {
-          self.checkout_musl()?;
-        },
-        SystemLibrary::LibCxx => {
-          self.checkout_libcxx()?;
-        },
-        SystemLibrary::LibCxxAbi => {
-          self.checkout_libcxxabi()?;
-        },
-        SystemLibrary::CompilerRt => {
-          self.checkout_compiler_rt()?;
-        },
-        SystemLibrary::Zlib => {
-          self.checkout_zlib()?;
-        },
-        SystemLibrary::LibUnwind => {
-          self.checkout_libunwind()?;
-        }
-        _ => {},
+    if self.populate_emscripten_cache {
+      self.add_missing_emscripten_cache_libraries();
+    }
+
+    let libraries = self.libraries.clone();
+    self.libraries.clear();
+
+    if self.targets.len() == 0 {
+      if let Some(format) = self.print_deps_graph {
+        self.enqueue_print_deps_graph(&libraries, format, queue);
+        return Ok(());
       }
+      return self.enqueue_libraries(libraries, queue);
     }
 
-    for syslib in libraries.into_iter() {
-      match syslib {
-        SystemLibrary::Compat => {
-          self.build_compat(queue)?;
-        },
-        SystemLibrary::LibC => {
-          self.build_musl(queue, &mut dlmalloc_built)?;
-        },
-        SystemLibrary::LibCxx => {
-          self.build_libcxx(queue)?;
-        },
-        SystemLibrary::LibCxxAbi => {
-          self.build_libcxxabi(queue)?;
-        },
-        SystemLibrary::LibUnwind => {
-          self.build_libunwind(queue)?;
-        },
-        SystemLibrary::CompilerRt => {
-          compiler_rt::build(self, queue)?;
-        },
-        SystemLibrary::DlMalloc => {
-          self.build_dlmalloc(queue)?;
-          dlmalloc_built = true;
-        },
-        SystemLibrary::Zlib => {
-          self.build_zlib(queue)?;
-        },
+    let srcs = self.srcs.clone();
+    let sysroot = self.tc().sysroot().clone();
+    let install_prefix = self.install_prefix.clone();
+    for target in self.targets.clone().into_iter() {
+      info!("enqueuing sysroot build for `{}`", target);
+      self.srcs = srcs.join(&target).create_if_not_exists()?;
+      self.wasm_toolchain_mut().sysroot = sysroot.join(&target);
+      self.install_prefix = install_prefix.as_ref()
+        .map(|prefix| prefix.join(&target) );
+      self.musl_inited = false;
+      self.musl_configured = false;
+      self.current_target = Some(target);
+
+      if let Some(format) = self.print_deps_graph {
+        self.enqueue_print_deps_graph(&libraries, format, queue);
+        continue;
       }
+      self.enqueue_libraries(libraries.clone(), queue)?;
     }
 
     Ok(())
@@ -350,21 +933,10 @@ impl Tool for Invocation {
 }
 
 impl ToolInvocation for Invocation {
-  fn check_state(&mut self, iteration: usize, skip_inputs_check: bool)
+  fn check_state(&mut self, _iteration: usize, _skip_inputs_check: bool)
     -> Result<(), Box<Error>>
   {
     self.init_wasm_tc();
-    match iteration {
-      3 => {
-        if self.libraries.contains(&SystemLibrary::LibCxx) {
-          if self.llvm_src.is_none() {
-            return Err("Need --llvm-src".into());
-          }
-        }
-      },
-      _ => {},
-    }
-
     Ok(())
   }
 
@@ -395,7 +967,31 @@ impl ToolInvocation for Invocation {
         CLOBBER_LIBC_BUILD,
         CLOBBER_COMPILER_RT_BUILD,
         CLOBBER_ZLIB_BUILD,
+        CLOBBER_LIBFFI_BUILD,
+        CLOBBER_ICU_BUILD,
         CLOBBER_ALL_BUILDS,
+        JOBS,
+        MAX_HEAVY_JOBS,
+        CHECKOUT_JOBS,
+        CHECKOUT_RETRIES,
+        REPO_MIRROR,
+        REPO_DEPTH,
+        SRC_CACHE,
+        OFFLINE,
+        INSTALL_PREFIX,
+        ICU_LOCALES,
+        ICU_DATA_FILTER,
+        POPULATE_EMSCRIPTEN_CACHE,
+        SYSROOT_LOCK,
+        SYSROOT_LOCK_TIMEOUT,
+        FORCE_LINK,
+        TARGETS,
+        PRINT_DEPS_GRAPH,
+        CFLAGS_FOR,
+        LDFLAGS_FOR,
+        EH,
+        DOWNLOAD_PREBUILT,
+        PREBUILT_URL,
       ]),
       _ => return None,
     }
@@ -410,8 +1006,49 @@ pub fn add_default_args(args: &mut Vec<String>) {
   //args.push("-fPIC".to_string());
 }
 
+/// What a library was linked from: each input's size, and a hash of its
+/// contents, or its mtime if it's big. An mtime alone misses an object
+/// rebuilt with the same time, or one restored from a cache with an
+/// older one.
+fn inputs_stamp(inputs: &[PathBuf]) -> Option<String> {
+  use std::fmt::Write;
+  use std::fs::{metadata, read};
+  use std::time::UNIX_EPOCH;
+
+  const HASHED_SIZE: u64 = 1 << 20;
+
+  let mut out = String::new();
+  for input in inputs.iter() {
+    let meta = metadata(input).ok()?;
+    let id = if meta.len() <= HASHED_SIZE {
+      util::stable_hash(Some(read(input).ok()?))
+    } else {
+      let mtime = meta.modified().ok()?
+        .duration_since(UNIX_EPOCH).ok()?;
+      format!("{}.{}", mtime.as_secs(), mtime.subsec_nanos())
+    };
+    writeln!(out, "{} {} {}", meta.len(), id, input.display()).unwrap();
+  }
+  Some(out)
+}
+
+/// Were `outputs` linked from `inputs` as they are now, according to
+/// `stamp` (written by `link` after the last one)?
+fn up_to_date(inputs: &[PathBuf], outputs: &[&Path], stamp: &Path) -> bool {
+  use std::fs::read_to_string;
+
+  if inputs.len() == 0 || !outputs.iter().all(|output| output.exists() ) {
+    return false;
+  }
+  match (read_to_string(stamp), inputs_stamp(inputs)) {
+    (Ok(last), Some(now)) => last == now,
+    _ => false,
+  }
+}
+
 pub fn link(invoc: &Invocation,
             queue: &mut CommandQueue<Invocation>,
+            lib: SystemLibrary,
             s2wasm_libs: &[&str],
             out_name: &str)
   -> Result<PathBuf, Box<Error>>
@@ -436,9 +1073,30 @@ pub fn link(invoc: &Invocation,
       let mut queue = CommandQueue::new(None);
       let prev_outputs = &state.prev_outputs[..];
 
+      let static_out_name = format!("{}.a", out_name);
+      let static_out = invoc.tc().sysroot_lib()
+        .create_if_not_exists()?
+        .join(&static_out_name);
+
+      let stamp = PathBuf::from(format!("{}.inputs", out.display()));
+      if !invoc.force_link && up_to_date(prev_outputs, &[&out, &static_out], &stamp) {
+        info!("`{}` is up to date; skipping link", out_name);
+        return Ok(());
+      }
+      // so a failed link is never up to date.
+      let _ = remove_file(&stamp);
+
       let mut args = Vec::new();
       args.push("-o".to_string());
       args.push(format!("{}", out.display()));
+      // these are for the compiler driver; we run the linker directly.
+      for flag in invoc.lib_ldflags(lib).iter() {
+        if flag.starts_with("-Wl,") {
+          args.extend(flag[4..].split(',').map(|f| f.to_string() ));
+        } else {
+          args.push(flag.clone());
+        }
+      }
 
       let mut linker = ld_driver::Invocation::new_with_toolchain(invoc.wasm_toolchain().clone());
       linker.emit_wast = invoc.emit_wast;
@@ -470,15 +1128,10 @@ pub fn link(invoc: &Invocation,
         cmd.output_override = false;
       }
 
-      let static_out_name = format!("{}.a", out_name);
-      let out = invoc.tc().sysroot_lib()
-        .create_if_not_exists()?
-        .join(&static_out_name);
-
       let ar = invoc.tc().llvm_tool("llvm-ar");
       let mut ar = Command::new(ar);
-      ar.arg("crs")
-        .arg(out)
+      ar.arg(if util::deterministic() { "crsD" } else { "crs" })
+        .arg(static_out)
         .args(prev_outputs);
 
       {
@@ -490,6 +1143,14 @@ pub fn link(invoc: &Invocation,
         cmd.output_override = false;
       }
 
+      let inputs = prev_outputs.to_vec();
+      queue.enqueue_state_function(Some("stamp"), move |_, _| {
+        if let Some(inputs) = inputs_stamp(&inputs[..]) {
+          ::std::fs::write(&stamp, inputs)?;
+        }
+        Ok(())
+      });
+
       queue.run_all(*invoc)
     });
   cmd.prev_outputs = true;
@@ -516,6 +1177,120 @@ tool_argument! {
     this.llvm_src = Some(path);
   }
 }
+tool_argument! {
+  pub SRC_CACHE: Invocation = single_and_split_simple_path(path) "src-cache" =>
+  fn src_cache_arg(this) {
+    let path = this.start_dir.join(path);
+    this.src_cache = path;
+  }
+}
+tool_argument! {
+  pub INSTALL_PREFIX: Invocation = single_and_split_simple_path(path) "install-prefix" =>
+  fn install_prefix_arg(this) {
+    let path = this.start_dir.join(path);
+    this.install_prefix = Some(path);
+  }
+}
+tool_argument! {
+  pub ICU_LOCALES: Invocation = single_and_split_from_str(locales) "icu-locales" =>
+  fn icu_locales_arg(this) {
+    let locales: String = locales;
+    this.icu_locales = locales.split(',')
+      .filter(|l| l.len() != 0 )
+      .map(|l| l.to_string() )
+      .collect();
+    if this.icu_locales.len() == 0 {
+      Err("`--icu-locales` needs at least one locale")?;
+    }
+  }
+}
+argument!(impl TARGETS where { Some(r"^--targets=(.*)$"), None } for Invocation {
+    fn targets_arg(this, _single, cap) {
+      let targets = cap.get(1)
+        .unwrap().as_str();
+      this.targets.clear();
+      for target in targets.split(',').filter(|t| t.len() != 0 ) {
+        let target = util::canonical_target(target)?;
+        if !this.targets.iter().any(|t| t == target ) {
+          this.targets.push(target.to_string());
+        }
+      }
+      if this.targets.len() == 0 {
+        Err("`--targets` needs at least one target")?;
+      }
+    }
+});
+tool_argument! {
+  pub ICU_DATA_FILTER: Invocation = single_and_split_simple_path(path) "icu-data-filter" =>
+  fn icu_data_filter_arg(this) {
+    let path = this.start_dir.join(path);
+    this.icu_data_filter = Some(path);
+  }
+}
+tool_argument! {
+  pub POPULATE_EMSCRIPTEN_CACHE: Invocation = simple_no_flag(b) "populate-emscripten-cache" =>
+  fn populate_emscripten_cache_flag(this) {
+    this.populate_emscripten_cache = b;
+  }
+}
+tool_argument! {
+  pub FORCE_LINK: Invocation = simple_no_flag(b) "force-link" =>
+  fn force_link_flag(this) {
+    this.force_link = b;
+  }
+}
+argument!(impl PRINT_DEPS_GRAPH where { Some(r"^--print-deps-graph(=(.+))?$"), None } for Invocation {
+    fn print_deps_graph_arg(this, _single, cap) {
+      let format = match cap.get(2) {
+        Some(format) => format.as_str().parse()?,
+        None => DepsGraphFormat::Dot,
+      };
+      this.print_deps_graph = Some(format);
+    }
+});
+argument!(impl DOWNLOAD_PREBUILT where { Some(r"^--download-prebuilt(=(.+))?$"), None } for Invocation {
+    fn download_prebuilt_arg(this, _single, cap) {
+      let version = cap.get(2)
+        .map(|v| v.as_str() )
+        .unwrap_or(prebuilt::DEFAULT_VERSION);
+      this.download_prebuilt = Some(version.to_string());
+    }
+});
+tool_argument! {
+  pub PREBUILT_URL: Invocation = single_and_split_from_str(url) "prebuilt-url" =>
+  fn prebuilt_url_arg(this) {
+    this.prebuilt_url = url;
+  }
+}
+argument!(impl CFLAGS_FOR where { Some(r"^--cflags-for=([^=]+)=(.*)$"), None } for Invocation {
+    fn cflags_for_arg(this, _single, cap) {
+      let lib: SystemLibrary = FromStr::from_str(cap.get(1).unwrap().as_str())?;
+      let flags = cap.get(2).unwrap().as_str()
+        .split_whitespace()
+        .map(|flag| flag.to_string() );
+      this.cflags_for.entry(lib).or_insert_with(Vec::new).extend(flags);
+    }
+});
+argument!(impl LDFLAGS_FOR where { Some(r"^--ldflags-for=([^=]+)=(.*)$"), None } for Invocation {
+    fn ldflags_for_arg(this, _single, cap) {
+      let lib: SystemLibrary = FromStr::from_str(cap.get(1).unwrap().as_str())?;
+      let flags = cap.get(2).unwrap().as_str()
+        .split_whitespace()
+        .map(|flag| flag.to_string() );
+      this.ldflags_for.entry(lib).or_insert_with(Vec::new).extend(flags);
+    }
+});
+argument!(impl OFFLINE where { Some(r"^--offline$"), None } for Invocation {
+    fn offline_flag(this, _single, _cap) {
+      this.offline = true;
+    }
+});
+tool_argument! {
+  pub EH: Invocation = single_and_split_from_str(eh) "eh" =>
+  fn eh_arg(this) {
+    this.eh = eh;
+  }
+}
 tool_argument! {
   pub CLOBBER_LIBUNWIND_BUILD: Invocation = simple_no_flag(b) "clobber-libunwind-build" =>
   fn clobber_libunwind_build_arg(this) {
@@ -552,7 +1327,19 @@ tool_argument! {
     this.clobber_zlib_build = b;
   }
 }
+tool_argument! {
+  pub CLOBBER_LIBFFI_BUILD: Invocation = simple_no_flag(b) "clobber-libffi-build" =>
+  fn clobber_libffi_build_arg(this) {
+    this.clobber_libffi_build = b;
+  }
+}
 
+tool_argument! {
+  pub CLOBBER_ICU_BUILD: Invocation = simple_no_flag(b) "clobber-icu-build" =>
+  fn clobber_icu_build_arg(this) {
+    this.clobber_icu_build = b;
+  }
+}
 tool_argument! {
   pub CLOBBER_ALL_BUILDS: Invocation = simple_no_flag(b) "clobber-all-builds" =>
   fn clobber_all_builds_arg(this) {
@@ -562,6 +1349,70 @@ tool_argument! {
     this.clobber_libc_build = b;
     this.clobber_compiler_rt_build = b;
     this.clobber_zlib_build = b;
+    this.clobber_libffi_build = b;
+    this.clobber_icu_build = b;
+  }
+}
+tool_argument! {
+  pub JOBS: Invocation = single_and_split_int(usize, n) "jobs" =>
+  fn jobs_flag(this) {
+    if n == 0 {
+      return Err("`--jobs` must be at least 1".into());
+    }
+    this.jobs = Some(n);
+  }
+}
+tool_argument! {
+  pub SYSROOT_LOCK: Invocation = simple_no_flag(b) "sysroot-lock" =>
+  fn sysroot_lock_flag(this) {
+    this.sysroot_lock = b;
+  }
+}
+tool_argument! {
+  pub SYSROOT_LOCK_TIMEOUT: Invocation = single_and_split_int(u64, secs) "sysroot-lock-timeout" =>
+  fn sysroot_lock_timeout_arg(this) {
+    this.sysroot_lock = true;
+    this.sysroot_lock_timeout = Some(secs);
+  }
+}
+tool_argument! {
+  pub CHECKOUT_JOBS: Invocation = single_and_split_int(usize, n) "checkout-jobs" =>
+  fn checkout_jobs_flag(this) {
+    if n == 0 {
+      return Err("`--checkout-jobs` must be at least 1".into());
+    }
+    this.checkout_jobs = Some(n);
+  }
+}
+tool_argument! {
+  pub CHECKOUT_RETRIES: Invocation = single_and_split_int(u32, n) "checkout-retries" =>
+  fn checkout_retries_flag(this) {
+    this.checkout_retries = n;
+  }
+}
+tool_argument! {
+  pub REPO_MIRROR: Invocation = single_and_split_from_str(spec) "repo-mirror" =>
+  fn repo_mirror_arg(this) {
+    let spec: String = spec;
+    this.checkout_config.add_mirror(&spec)?;
+  }
+}
+tool_argument! {
+  pub REPO_DEPTH: Invocation = single_and_split_int(u32, depth) "repo-depth" =>
+  fn repo_depth_arg(this) {
+    if depth == 0 {
+      return Err("`--repo-depth` must be at least 1".into());
+    }
+    this.checkout_config.depth = Some(depth);
+  }
+}
+tool_argument! {
+  pub MAX_HEAVY_JOBS: Invocation = single_and_split_int(usize, n) "max-heavy-jobs" =>
+  fn max_heavy_jobs_flag(this) {
+    if n == 0 {
+      return Err("`--max-heavy-jobs` must be at least 1".into());
+    }
+    this.max_heavy_jobs = Some(n);
   }
 }
 
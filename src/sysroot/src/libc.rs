@@ -1,4 +1,4 @@
-use super::{Invocation, link, get_system_dir};
+use super::{Invocation, SystemLibrary, link, get_system_dir};
 use util::{CommandQueue, get_crate_root, CreateIfNotExists};
 
 use clang_driver;
@@ -10,14 +10,23 @@ use std::path::{Path, PathBuf};
 
 impl Invocation {
   pub fn get_musl_root(&self) -> PathBuf {
-    self.srcs.join(self.musl_repo.name.as_ref())
+    self.src_cache.join(self.musl_repo.name.as_ref())
   }
   pub fn musl_include_dir(&self) -> PathBuf {
     self.get_musl_root()
       .join("include")
   }
+  /// musl is built out-of-tree, in a directory keyed by the target and the
+  /// flags it is configured with, so differently configured builds don't
+  /// stomp on each other's objects.
+  pub fn musl_build_dir(&self) -> PathBuf {
+    let hash = util::stable_hash(&[self.musl_ld_flags().as_str(),
+                                   self.tc().threads.as_str()]);
+    let key = format!("{}-{}", self.target(), hash);
+    self.srcs.join(format!("musl-build-{}", key))
+  }
   pub fn musl_build_obj_dir(&self) -> Result<PathBuf, Box<Error>> {
-    Ok(self.get_musl_root().join("obj").create_if_not_exists()?)
+    Ok(self.musl_build_dir().join("obj").create_if_not_exists()?)
   }
   pub fn dlmalloc_obj_output(&self) -> Result<PathBuf, Box<Error>> {
     Ok(self.musl_build_obj_dir()?.join("dlmalloc.o"))
@@ -26,7 +35,27 @@ impl Invocation {
   pub fn checkout_musl(&mut self) -> Result<(), Box<Error>> {
     if self.musl_checkout { return Ok(()); }
     self.musl_checkout = true;
-    self.musl_repo.checkout_thin(self.get_musl_root())
+    self.checkout_repo(self.musl_repo.clone(), self.get_musl_root())
+  }
+
+  fn musl_ld_flags(&self) -> String {
+    let mut ld_flags = String::new();
+    for arg in self.c_cxx_linker_args().into_iter() {
+      ld_flags.push_str(arg.as_ref());
+      ld_flags.push(' ');
+    }
+    ld_flags
+  }
+
+  /// `make` invoked from the build dir, using musl's source tree Makefile.
+  fn musl_make_cmd(&self) -> ::std::process::Command {
+    let mut cmd = ::std::process::Command::new("make");
+    cmd.current_dir(self.musl_build_dir())
+      .arg("-f")
+      .arg(self.get_musl_root().join("Makefile"))
+      .arg(self.jobs_arg());
+    self.tc().set_envs(&mut cmd);
+    cmd
   }
 
   pub fn init_musl(&mut self) -> Result<(), Box<Error>> {
@@ -47,22 +76,20 @@ impl Invocation {
 
       let dlmalloc_o = self.dlmalloc_obj_output()?;
 
-      let config_mak = self.get_musl_root()
+      let config_mak = self.musl_build_dir()
+        .create_if_not_exists()?
         .join("config.mak");
       let mut config_mak = File::create(config_mak)?;
 
-      let mut ld_flags = String::new();
-      for arg in self.c_cxx_linker_args().into_iter() {
-        ld_flags.push_str(arg.as_ref());
-        ld_flags.push(' ');
-      }
+      let ld_flags = self.musl_ld_flags();
 
       let config = writeln!(config_mak, r#"
+srcdir={}
 CROSS_COMPILE={}
 CC={}
 LD={}
-CFLAGS=
-LDFLAGS={} -L{} -Oz
+CFLAGS=--target={} {}
+LDFLAGS={} -L{} -Oz {}
 
 prefix={}
 includedir=$(prefix)/include
@@ -73,11 +100,15 @@ LIBCC=-lcompiler-rt
 ARCH=wasm32
 EXTRA_OBJS := {}
 "#,
+                            self.get_musl_root().display(),
                             self.tc().llvm_tool("llvm-").display(),
                             clang.display(),
                             lld.display(),
+                            self.target(),
+                            self.lib_cflags(SystemLibrary::LibC).join(" "),
                             ld_flags,
                             lib_dir.display(),
+                            self.lib_ldflags(SystemLibrary::LibC).join(" "),
                             prefix.display(),
                             dlmalloc_o.display())?;
     }
@@ -90,20 +121,15 @@ EXTRA_OBJS := {}
   pub fn configure_musl(&mut self, queue: &mut CommandQueue<Self>)
     -> Result<(), Box<Error>>
   {
-    use std::process::Command;
-
     if self.musl_configured { return Ok(()); }
 
     self.init_musl()?;
 
     // configure arch/wasm32/bits/*.in
     // this needs to happen before compiler-rt can be built.
-    let mut cmd = Command::new("make");
-    cmd.current_dir(self.get_musl_root())
-      .arg("obj/include/bits/alltypes.h")
-      .arg("obj/include/bits/syscall.h")
-      .arg("-j8");
-    self.tc().set_envs(&mut cmd);
+    let mut cmd = self.musl_make_cmd();
+    cmd.arg("obj/include/bits/alltypes.h")
+      .arg("obj/include/bits/syscall.h");
     queue.enqueue_simple_external(Some("configure musl"), cmd, None);
 
     self.musl_configured = true;
@@ -117,16 +143,11 @@ EXTRA_OBJS := {}
                     dlmalloc_built: &mut bool)
     -> Result<(), Box<Error>>
   {
-    use std::env::home_dir;
-    use std::fs::File;
-    use std::io::Write;
-    use std::process::Command;
-
     self.init_musl()?;
 
     if self.clobber_libc_build {
       let f = |this: &mut &mut Self| {
-        let musl = this.get_musl_root();
+        let musl = this.musl_build_dir();
         let _ = remove_dir_all(musl.join("obj"));
         let _ = remove_dir_all(musl.join("lib"));
 
@@ -146,11 +167,8 @@ EXTRA_OBJS := {}
       *dlmalloc_built = true;
     }
 
-    let mut cmd = Command::new("make");
-    cmd.current_dir(self.get_musl_root())
-      .arg("install")
-      .arg("-j8");
-    self.tc().set_envs(&mut cmd);
+    let mut cmd = self.musl_make_cmd();
+    cmd.arg("install");
     queue.enqueue_simple_external(Some("install musl"),
                                   cmd, None);
 
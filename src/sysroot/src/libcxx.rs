@@ -1,6 +1,6 @@
 
-use super::{Invocation, link};
-use util::{CommandQueue, ToolInvocation, ToolArgs, CreateIfNotExists, Tool};
+use super::{Invocation, SystemLibrary, link};
+use util::{CommandQueue, ToolInvocation, ToolArgs, CreateIfNotExists, Tool, EhMode};
 
 use clang_driver;
 use cmake_driver;
@@ -10,24 +10,25 @@ use std::path::{Path, PathBuf};
 
 impl Invocation {
   pub fn libcxx_src(&self) -> PathBuf {
-    self.srcs.join(self.libcxx_repo.name.as_ref())
+    self.src_cache.join(self.libcxx_repo.name.as_ref())
   }
   pub fn checkout_libcxx(&mut self) -> Result<(), Box<Error>> {
     if self.libcxx_checkout { return Ok(()); }
     self.libcxx_checkout = true;
 
+    self.checkout_llvm_src()?;
     self.checkout_libcxxabi()?;
 
-    self.libcxx_repo.checkout_thin(self.libcxx_src())
+    self.checkout_repo(self.libcxx_repo.clone(), self.libcxx_src())
   }
   pub fn build_libcxx(&self, queue: &mut CommandQueue<Invocation>) -> Result<(), Box<Error>> {
     use std::process::Command;
     use tempdir::TempDir;
 
     if self.clobber_libcxx_build {
+      let build_dir = self.eh_build_dir_name("libcxx-build");
       let f = move |sess: &mut &mut Invocation| {
-        let libcxx_build = super::get_system_dir()
-          .join("libcxx-build");
+        let libcxx_build = sess.srcs.join(&build_dir);
         ::std::fs::remove_dir_all(&libcxx_build)?;
         libcxx_build.create_if_not_exists()?;
 
@@ -40,7 +41,7 @@ impl Invocation {
     let libcxxabi = self.libcxxabi_src();
 
     let libcxx_build = self.srcs
-      .join("libcxx-build")
+      .join(self.eh_build_dir_name("libcxx-build"))
       .create_if_not_exists()?;
 
     let sysroot = self.tc().sysroot_cache();
@@ -53,20 +54,20 @@ impl Invocation {
       .cmake_on("LIBCXX_ENABLE_SHARED")
       .cmake_on("LIBCXX_ENABLE_THREADS")
       .cmake_on("LIBCXX_INSTALL_SUPPORT_HEADERS")
+      .cmake_bool("LIBCXX_ENABLE_EXCEPTIONS", self.eh != EhMode::None)
       .cmake_off("LIBCXX_ENABLE_WERROR")
-      .cmake_off("LIBCXX_ENABLE_EXCEPTIONS")
       // cmake removes the trailing slash if it is a path type,
       // which is important for this var.
       .cmake_str("LIBCXX_INSTALL_PREFIX",
                  format!("{}/", sysroot.display()))
       .cmake_str("CMAKE_INSTALL_PREFIX",
                  format!("{}/", sysroot.display()))
-      .cmake_str("LIBCXX_TARGET_TRIPLE", "wasm32-unknown-unknown-wasm")
+      .cmake_str("LIBCXX_TARGET_TRIPLE", self.target())
       .cmake_str("LIBCXX_CXX_ABI", "libcxxabi")
       .cmake_str("CMAKE_BUILD_TYPE", "MinSizeRel")
       .cmake_path("LIBCXX_SYSROOT", &sysroot)
       .cmake_path("LIBCXX_CXX_ABI_LIBRARY_PATH",
-                  sysroot.join("lib"))
+                  self.tc().sysroot_eh_lib(self.eh))
       .cmake_path("LIBCXX_LIBRARY_DIR",
                   sysroot.join("lib"))
       .cmake_path("LLVM_PATH", self.llvm_src())
@@ -81,6 +82,9 @@ impl Invocation {
       .c_cxx_flag(format!("-I{}", libcxx.join("include/support/musl").display()))
       .c_cxx_flag("-D_LIBCPP_HAS_THREAD_API_PTHREAD")
       .generator("Ninja");
+    self.eh_cmake_flags("LIBCXX", &mut cmake);
+    self.target_cmake_flags(&mut cmake);
+    self.lib_cmake_flags(SystemLibrary::LibCxx, &mut cmake);
 
     {
       let cmd = queue.enqueue_tool(None, cmake,
@@ -92,6 +96,7 @@ impl Invocation {
 
     let mut cmd = Command::new("ninja");
     cmd.current_dir(libcxx_build)
+      .arg(self.jobs_arg())
       .arg("install");
     self.tc().set_envs(&mut cmd);
 
@@ -1,5 +1,5 @@
-use super::{Invocation, link};
-use util::{CommandQueue, ToolInvocation, ToolArgs, CreateIfNotExists, Tool};
+use super::{Invocation, SystemLibrary, link};
+use util::{CommandQueue, ToolInvocation, ToolArgs, CreateIfNotExists, Tool, EhMode};
 
 use clang_driver;
 use cmake_driver;
@@ -11,16 +11,17 @@ use std::path::{Path, PathBuf};
 
 impl Invocation {
   pub fn libcxxabi_src(&self) -> PathBuf {
-    self.srcs.join(self.libcxxabi_repo.name.as_ref())
+    self.src_cache.join(self.libcxxabi_repo.name.as_ref())
   }
   pub fn checkout_libcxxabi(&mut self) -> Result<(), Box<Error>> {
     if self.libcxxabi_checkout { return Ok(()); }
     self.libcxxabi_checkout = true;
 
+    self.checkout_llvm_src()?;
     self.checkout_libcxx()?;
     self.checkout_libunwind()?;
 
-    self.libcxxabi_repo.checkout_thin(self.libcxxabi_src())
+    self.checkout_repo(self.libcxxabi_repo.clone(), self.libcxxabi_src())
   }
   pub fn build_libcxxabi(&self, queue: &mut CommandQueue<Invocation>) -> Result<(), Box<Error>> {
     use std::process::Command;
@@ -29,9 +30,9 @@ impl Invocation {
     use cmake_driver::{Var};
 
     if self.clobber_libcxxabi_build {
+      let build_dir = self.eh_build_dir_name("libcxxabi-build");
       let f = move |sess: &mut &mut Invocation| {
-        let libcxxabi_build = super::get_system_dir()
-          .join("libcxxabi-build");
+        let libcxxabi_build = sess.srcs.join(&build_dir);
         ::std::fs::remove_dir_all(&libcxxabi_build)?;
         libcxxabi_build.create_if_not_exists()?;
 
@@ -44,7 +45,7 @@ impl Invocation {
     let libcxxabi = self.libcxxabi_src();
 
     let libcxxabi_build = self.srcs
-      .join("libcxxabi-build")
+      .join(self.eh_build_dir_name("libcxxabi-build"))
       .create_if_not_exists()?;
 
     let sysroot = self.tc().sysroot_cache();
@@ -55,9 +56,10 @@ impl Invocation {
       .cmake_on("LLVM_ENABLE_LIBCXX")
       .cmake_on("LIBCXXABI_ENABLE_SHARED")
       .cmake_on("LIBCXXABI_ENABLE_THREADS")
-      .cmake_off("LIBCXXABI_USE_LLVM_UNWINDER")
-      .cmake_off("LIBCXXABI_ENABLE_EXCEPTIONS")
-      .cmake_str("LIBCXXABI_TARGET_TRIPLE", "wasm32-unknown-unknown-wasm")
+      // wasm EH unwinds through libunwind; emscripten's doesn't need one.
+      .cmake_bool("LIBCXXABI_USE_LLVM_UNWINDER", self.eh == EhMode::Zerocost)
+      .cmake_bool("LIBCXXABI_ENABLE_EXCEPTIONS", self.eh != EhMode::None)
+      .cmake_str("LIBCXXABI_TARGET_TRIPLE", self.target())
       .cmake_path("LIBCXXABI_SYSROOT", &sysroot)
       // cmake removes the trailing slash if it is a path type,
       // which is important for this var.
@@ -75,6 +77,9 @@ impl Invocation {
       .c_cxx_flag("-D_LIBCPP_HAS_THREAD_API_PTHREAD")
       .c_cxx_flag(format!("-I{}", self.libunwind_src().join("include").display()))
       .generator("Ninja");
+    self.eh_cmake_flags("LIBCXXABI", &mut cmake);
+    self.target_cmake_flags(&mut cmake);
+    self.lib_cmake_flags(SystemLibrary::LibCxxAbi, &mut cmake);
 
     {
       let cmd = queue.enqueue_tool(None, cmake,
@@ -86,6 +91,7 @@ impl Invocation {
 
     let mut cmd = Command::new("ninja");
     cmd.current_dir(libcxxabi_build)
+      .arg(self.jobs_arg())
       .arg("install");
     self.tc().set_envs(&mut cmd);
 
@@ -1,4 +1,4 @@
-use super::{Invocation, get_system_dir, };
+use super::{Invocation, SystemLibrary, get_system_dir, };
 use util::{CommandQueue};
 
 use clang_driver;
@@ -18,7 +18,7 @@ impl Invocation {
   {
     let full_file = get_system_dir().join(file);
 
-    let mut clang = clang_driver::Invocation::with_toolchain(self);
+    let mut clang = self.clang_invocation();
     clang.driver_mode = clang_driver::DriverMode::CC;
 
     self.musl_includes(&mut clang);
@@ -31,6 +31,7 @@ impl Invocation {
 
     args.push("-O1".to_string());
     super::add_default_args(&mut args);
+    args.extend(self.lib_cflags(SystemLibrary::DlMalloc).iter().cloned());
 
     let cmd = queue
       .enqueue_tool(Some("clang"),
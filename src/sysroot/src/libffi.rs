@@ -0,0 +1,121 @@
+use super::{Invocation, SystemLibrary};
+use util::{CommandQueue, CreateIfNotExists, };
+
+use std::error::Error;
+use std::path::PathBuf;
+use std::process::{Command, };
+
+impl Invocation {
+  pub fn libffi_src_dir(&self) -> PathBuf {
+    self.src_cache.join(self.libffi_repo.name.as_ref())
+  }
+  pub fn libffi_build_dir(&self) -> PathBuf {
+    self.srcs.join("libffi-build")
+  }
+  pub fn checkout_libffi(&mut self) -> Result<(), Box<Error>> {
+    if self.libffi_checkout { return Ok(()); }
+    self.libffi_checkout = true;
+
+    self.checkout_repo(self.libffi_repo.clone(), self.libffi_src_dir())
+  }
+  pub fn build_libffi(&self, queue: &mut CommandQueue<Invocation>)
+    -> Result<(), Box<Error>>
+  {
+    let src = self.libffi_src_dir();
+
+    if self.clobber_libffi_build {
+      let f = move |sess: &mut &mut Invocation| {
+        let build = sess.libffi_build_dir();
+        if build.exists() {
+          ::std::fs::remove_dir_all(&build)?;
+          build.create_if_not_exists()?;
+        }
+
+        Ok(())
+      };
+      queue.enqueue_function(Some("clobber-libffi-build"), f);
+    }
+
+    let build_dir = self.libffi_build_dir()
+      .create_if_not_exists()?;
+    let install_dir = self.tc().sysroot_cache()
+      .create_if_not_exists()?;
+
+    // git checkouts don't ship a `configure`.
+    if !src.join("configure").exists() {
+      let mut autogen = Command::new("sh");
+      autogen.current_dir(&src)
+        .arg("autogen.sh");
+      let cmd = queue
+        .enqueue_simple_external(Some("autogen libffi"),
+                                 autogen, None);
+
+      cmd.prev_outputs = false;
+      cmd.output_override = false;
+    }
+
+    let mut cflags = self.c_cxx_linker_cflags();
+    cflags.push_str(" -Os");
+    if self.emit_wast {
+      cflags.push_str(" --emit-wast");
+    }
+
+    let mut conf = Command::new(src.join("configure"));
+    conf.current_dir(&build_dir)
+      .env("CC", self.cc())
+      .env("CXX", self.cxx())
+      .arg("--host=wasm32-unknown-unknown")
+      .arg(format!("--prefix={}", install_dir.display()))
+      .arg(format!("--includedir={}", install_dir.join("include").display()))
+      .arg("--enable-static")
+      .arg("--disable-shared")
+      .arg("--disable-docs")
+      .arg("--disable-multi-os-directory");
+    self.lib_flags_env(SystemLibrary::LibFfi, &mut conf, &cflags);
+    self.tc().set_envs(&mut conf);
+
+    {
+      let cmd = queue
+        .enqueue_simple_external(Some("configure libffi"),
+                                 conf, None);
+
+      cmd.prev_outputs = false;
+      cmd.output_override = false;
+    }
+
+    let mut install = Command::new("make");
+    install.current_dir(&build_dir)
+      .arg(self.jobs_arg())
+      .arg("install");
+    self.tc().set_envs(&mut install);
+    {
+      let cmd = queue
+        .enqueue_simple_external(Some("install libffi"),
+                                 install, None);
+
+      cmd.prev_outputs = false;
+      cmd.output_override = false;
+    }
+
+    // users find us via pkg-config, so make sure that actually works.
+    let f = move |sess: &mut &mut Invocation| {
+      let root = sess.tc().sysroot_cache();
+      let expected = [
+        root.join("include/ffi.h"),
+        root.join("include/ffitarget.h"),
+        root.join("lib/pkgconfig/libffi.pc"),
+      ];
+      for path in expected.iter() {
+        if !path.exists() {
+          return Err(format!("libffi install is missing `{}`",
+                             path.display()).into());
+        }
+      }
+
+      Ok(())
+    };
+    queue.enqueue_function(Some("check libffi install"), f);
+
+    Ok(())
+  }
+}
@@ -1,5 +1,6 @@
-use super::{Invocation, link};
-use util::{CommandQueue, ToolInvocation, ToolArgs, CreateIfNotExists, Tool};
+use super::{Invocation, SystemLibrary, link};
+use util::{CommandQueue, ToolInvocation, ToolArgs, CreateIfNotExists, Tool, EhMode};
+use util::toolchain::ThreadModel;
 
 use clang_driver;
 use cmake_driver;
@@ -7,18 +8,19 @@ use cmake_driver;
 use std::error::Error;
 use std::path::{Path, PathBuf};
 
-// this isn't built, but it's headers are needed for `libcxxabi`.
+// only installed for `--eh=wasm`; otherwise just its headers are needed
+// for `libcxxabi`.
 
 impl Invocation {
   pub fn libunwind_src(&self) -> PathBuf {
-    super::get_system_dir()
-      .join("libunwind")
+    self.src_cache.join(self.libunwind_repo.name.as_ref())
   }
   pub fn checkout_libunwind(&mut self) -> Result<(), Box<Error>> {
     if self.libunwind_checkout { return Ok(()); }
     self.libunwind_checkout = true;
 
-    self.libunwind_repo.checkout_thin(self.libunwind_src())
+    self.checkout_llvm_src()?;
+    self.checkout_repo(self.libunwind_repo.clone(), self.libunwind_src())
   }
   pub fn build_libunwind(&self, queue: &mut CommandQueue<Self>) -> Result<(), Box<Error>> {
     use std::process::Command;
@@ -27,9 +29,9 @@ impl Invocation {
     use cmake_driver::{Var};
 
     if self.clobber_libunwind_build {
+      let build_dir = self.eh_build_dir_name("libunwind-build");
       let f = move |sess: &mut &mut Invocation| {
-        let libunwind_build = super::get_system_dir()
-          .join("libunwind-build");
+        let libunwind_build = sess.srcs.join(&build_dir);
         ::std::fs::remove_dir_all(&libunwind_build)?;
         libunwind_build.create_if_not_exists()?;
 
@@ -42,8 +44,8 @@ impl Invocation {
     let libcxxabi = self.libcxxabi_src();
     let libunwind = self.libunwind_src();
 
-    let libunwind_build = super::get_system_dir()
-      .join("libunwind-build")
+    let libunwind_build = self.srcs
+      .join(self.eh_build_dir_name("libunwind-build"))
       .create_if_not_exists()?;
 
     let sysroot = self.tc().sysroot_cache();
@@ -54,8 +56,9 @@ impl Invocation {
       .cmake_on("LLVM_ENABLE_LIBCXX")
       .cmake_on("LIBUNWIND_ENABLE_SHARED")
       .cmake_off("LIBUNWIND_ENABLE_ASSERTIONS")
-      .cmake_off("LIBUNWIND_ENABLE_THREADS")
-      .cmake_str("LIBUNWIND_TARGET_TRIPLE", "wasm32-unknown-unknown-wasm")
+      .cmake_bool("LIBUNWIND_ENABLE_THREADS",
+                  self.tc().threads == ThreadModel::Atomics)
+      .cmake_str("LIBUNWIND_TARGET_TRIPLE", self.target())
       .cmake_path("LIBUNWIND_SYSROOT", &sysroot)
       // cmake removes the trailing slash if it is a path type,
       // which is important for this var.
@@ -69,6 +72,9 @@ impl Invocation {
       .c_cxx_flag(self.c_cxx_linker_cflags())
       .c_cxx_flag("-D_LIBUNWIND_DISABLE_VISIBILITY_ANNOTATIONS")
       .generator("Ninja");
+    self.eh_cmake_flags("LIBUNWIND", &mut cmake);
+    self.target_cmake_flags(&mut cmake);
+    self.lib_cmake_flags(SystemLibrary::LibUnwind, &mut cmake);
 
     {
       let cmd = queue.enqueue_tool(None, cmake,
@@ -79,8 +85,11 @@ impl Invocation {
     }
 
     let mut cmd = Command::new("ninja");
-    cmd.current_dir(libunwind_build);
-      //.arg("install");
+    cmd.current_dir(libunwind_build)
+      .arg(self.jobs_arg());
+    if self.eh == EhMode::Zerocost {
+      cmd.arg("install");
+    }
     self.tc().set_envs(&mut cmd);
 
     queue.enqueue_external(None, cmd, None,
@@ -0,0 +1,195 @@
+//! `--download-prebuilt[=<version>]`: install a released sysroot instead of
+//! building it. Each release has a `wasm-sysroot-<version>-<target>-<threads>.tar.gz`
+//! per target and thread model, and a `SHA256SUMS` listing their
+//! checksums. Downloads are kept in `<src-cache>/prebuilt/<version>`.
+//!
+//! Whatever the archive doesn't provide (ie there isn't one for the target,
+//! or it doesn't have an `--eh` variant) is built from source as usual, as
+//! are the libraries given `--cflags-for`/`--ldflags-for`. Which those are
+//! isn't known until the archive is unpacked, so they're built in a queue
+//! of their own, from the `install-prebuilt` step; `--dry-run` doesn't
+//! download anything, and plans every library from source.
+//!
+//! `SHA256SUMS` comes from the same place as the archives, so checking it
+//! only catches corrupt or partial downloads; it doesn't prove a release
+//! is authentic. Only use a `--prebuilt-url` you trust.
+
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use flate2::read::GzDecoder;
+
+use util::{CommandQueue, CreateIfNotExists};
+use util::manifest::sha256;
+
+use super::{Invocation, SystemLibrary};
+
+pub const DEFAULT_URL: &'static str =
+  "https://github.com/DiamondLovesYou/pnacl-clang-driver/releases/download";
+pub const DEFAULT_VERSION: &'static str = env!("CARGO_PKG_VERSION");
+
+const DEFAULT_TARGET: &'static str = "wasm32-unknown-unknown";
+const CHECKSUMS: &'static str = "SHA256SUMS";
+
+/// Download `url` to `dest`. `false` if it couldn't be, ie it doesn't exist.
+fn fetch(url: &str, dest: &Path) -> Result<bool, Box<Error>> {
+  let partial = dest.with_extension("partial");
+  let mut cmd = Command::new("curl");
+  cmd.arg("--fail")
+    .arg("--silent")
+    .arg("--show-error")
+    .arg("--location")
+    .arg("-o").arg(&partial)
+    .arg(url)
+    .stdin(Stdio::null());
+  util::hermetic_env(&mut cmd);
+  let status = cmd.status();
+  match status {
+    Ok(ref status) if status.success() => {},
+    Ok(_) => {
+      let _ = fs::remove_file(&partial);
+      return Ok(false);
+    },
+    Err(e) => {
+      util::diag::warning(&format!("couldn't run `curl`: {}", e));
+      return Ok(false);
+    },
+  }
+  fs::rename(&partial, dest)?;
+  Ok(true)
+}
+
+/// `name`'s checksum in a `sha256sum` style list.
+fn listed_checksum(sums: &str, name: &str) -> Option<String> {
+  sums.lines()
+    .filter_map(|line| {
+      let mut parts = line.split_whitespace();
+      let hash = parts.next()?;
+      let file = parts.next()?.trim_start_matches('*');
+      Some((hash, file))
+    })
+    .find(|&(_, file)| file == name )
+    .map(|(hash, _)| hash.to_lowercase() )
+}
+
+impl Invocation {
+  pub fn prebuilt_archive_name(&self, version: &str) -> String {
+    let target = self.current_target.as_ref()
+      .map(|t| &t[..] )
+      .unwrap_or(DEFAULT_TARGET);
+    format!("wasm-sysroot-{}-{}-{}.tar.gz", version, target, self.tc().threads)
+  }
+  fn prebuilt_cache(&self, version: &str) -> PathBuf {
+    self.src_cache.join("prebuilt").join(version)
+  }
+
+  /// Download (unless it's cached) and verify the archive for `version`.
+  /// `None` if there isn't one.
+  fn fetch_prebuilt(&self, version: &str) -> Result<Option<PathBuf>, Box<Error>> {
+    let cache = self.prebuilt_cache(version).create_if_not_exists()?;
+    let base = format!("{}/{}", self.prebuilt_url.trim_end_matches('/'), version);
+    let name = self.prebuilt_archive_name(version);
+
+    let sums_path = cache.join(CHECKSUMS);
+    if !self.offline {
+      // always refetched, so a republished release is noticed.
+      if !fetch(&format!("{}/{}", base, CHECKSUMS), &sums_path)? {
+        if !sums_path.exists() {
+          return Ok(None);
+        }
+        util::diag::warning(&format!("couldn't download `{}` for {}; using the cached copy",
+                                     CHECKSUMS, version));
+      }
+    }
+    let mut sums = String::new();
+    match File::open(&sums_path) {
+      Ok(mut f) => { f.read_to_string(&mut sums)?; },
+      Err(_) => { return Ok(None); },
+    }
+    let expected = match listed_checksum(&sums, &name) {
+      Some(expected) => expected,
+      None => { return Ok(None); },
+    };
+
+    let archive = cache.join(&name);
+    if archive.exists() && sha256(&archive)? == expected {
+      return Ok(Some(archive));
+    }
+    if self.offline {
+      return Ok(None);
+    }
+    util::diag::note(&format!("downloading `{}`", name));
+    if !fetch(&format!("{}/{}", base, name), &archive)? {
+      return Ok(None);
+    }
+    let actual = sha256(&archive)?;
+    if actual != expected {
+      fs::remove_file(&archive)?;
+      return Err(format!("`{}`'s checksum is {}, but {} was expected",
+                         name, actual, expected).into());
+    }
+    Ok(Some(archive))
+  }
+
+  /// Install the prebuilt sysroot for `version` into the sysroot, if
+  /// there's one for this target. Returns whether there was.
+  pub fn install_prebuilt(&self, version: &str) -> Result<bool, Box<Error>> {
+    let archive = match self.fetch_prebuilt(version)? {
+      Some(archive) => archive,
+      None => {
+        util::diag::note(&format!("no prebuilt sysroot `{}`; building from source",
+                                  self.prebuilt_archive_name(version)));
+        return Ok(false);
+      },
+    };
+
+    let sysroot = self.tc().sysroot().create_if_not_exists()?;
+    info!("unpacking `{}` into `{}`", archive.display(), sysroot.display());
+    let mut tar = ::tar::Archive::new(GzDecoder::new(File::open(&archive)?));
+    tar.unpack(&sysroot)
+      .map_err(|e| format!("unpacking `{}`: {}", archive.display(), e) )?;
+    Ok(true)
+  }
+
+  /// Enqueue the `install-prebuilt` step, which installs the prebuilt
+  /// sysroot for `version` and then builds the `libraries` it doesn't
+  /// provide.
+  pub fn enqueue_install_prebuilt(&self, version: String,
+                                  libraries: BTreeSet<SystemLibrary>,
+                                  queue: &mut CommandQueue<Invocation>)
+  {
+    queue.enqueue_state_function(Some("install-prebuilt"), move |invoc, state| {
+      let mut libraries = libraries;
+      if state.is_dry_run() {
+        util::diag::note(&format!("`--dry-run`: not downloading `{}`",
+                                  invoc.prebuilt_archive_name(&version)));
+      } else if invoc.install_prebuilt(&version)? {
+        libraries = libraries.into_iter()
+          .filter(|&lib| !invoc.prebuilt_provides(lib) )
+          .collect();
+      }
+
+      let mut builds = CommandQueue::new(None);
+      builds.set_dry_run(state.is_dry_run());
+      invoc.enqueue_library_builds(libraries, &mut builds)?;
+      builds.run_all(*invoc)
+    })
+      .prev_outputs = false;
+  }
+
+  /// Does the installed prebuilt sysroot provide `lib`?
+  pub fn prebuilt_provides(&self, lib: SystemLibrary) -> bool {
+    if self.lib_cflags(lib).len() != 0 || self.lib_ldflags(lib).len() != 0 {
+      return false;
+    }
+    match self.installed(lib) {
+      Some(installed) => installed,
+      // only dlmalloc, which is in libc.
+      None => self.prebuilt_provides(SystemLibrary::LibC),
+    }
+  }
+}
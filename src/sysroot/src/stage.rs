@@ -0,0 +1,107 @@
+
+//! `DESTDIR`/`--install-prefix` staging: everything is installed into
+//! `$DESTDIR/<prefix>`, and then any absolute paths to that staging dir in
+//! installed `.pc`/config files are rewritten to point at `<prefix>`.
+
+use super::Invocation;
+use util::{CommandQueue, CreateIfNotExists, };
+use util::toolchain::WasmToolchainTool;
+
+use std::error::Error;
+use std::fs::{read_dir, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Files which are known to embed the install prefix.
+fn has_install_paths(path: &Path) -> bool {
+  let name = match path.file_name().and_then(|name| name.to_str() ) {
+    Some(name) => name,
+    None => { return false; },
+  };
+
+  name.ends_with(".pc") || name.ends_with(".cmake") ||
+    name.ends_with("-config") || name.ends_with(".la")
+}
+
+fn rewrite_install_paths(dir: &Path, from: &str, to: &str)
+  -> Result<(), Box<Error>>
+{
+  for entry in read_dir(dir)? {
+    let entry = entry?;
+    let path = entry.path();
+    let ft = entry.file_type()?;
+    if ft.is_dir() {
+      rewrite_install_paths(&path, from, to)?;
+      continue;
+    }
+    if !ft.is_file() || !has_install_paths(&path) { continue; }
+
+    let mut contents = String::new();
+    if File::open(&path)?.read_to_string(&mut contents).is_err() {
+      // not text; leave it be.
+      continue;
+    }
+    if !contents.contains(from) { continue; }
+
+    info!("rewriting install paths in `{}`", path.display());
+    let contents = contents.replace(from, to);
+    File::create(&path)?
+      .write_all(contents.as_bytes())?;
+  }
+
+  Ok(())
+}
+
+impl Invocation {
+  /// The directory things are actually installed into, if it differs from
+  /// where the sysroot will eventually live.
+  pub fn staging_dir(&self) -> Option<PathBuf> {
+    use std::env::var_os;
+
+    let prefix = self.install_prefix.as_ref()
+      .unwrap_or_else(|| self.tc().sysroot() );
+
+    match var_os("DESTDIR") {
+      Some(ref destdir) if destdir.len() != 0 => {
+        let relative = prefix.strip_prefix("/")
+          .unwrap_or(prefix);
+        Some(Path::new(destdir).join(relative))
+      },
+      _ if self.install_prefix.is_some() => Some(prefix.clone()),
+      _ => None,
+    }
+  }
+
+  /// Redirect all installs into the staging dir; returns the final prefix,
+  /// if staging.
+  pub fn stage_install(&mut self) -> Result<Option<PathBuf>, Box<Error>> {
+    let staging = match self.staging_dir() {
+      Some(staging) => staging.create_if_not_exists()?,
+      None => { return Ok(None); },
+    };
+    let prefix = self.install_prefix.clone()
+      .unwrap_or_else(|| self.tc().sysroot().clone() );
+
+    info!("staging sysroot install into `{}` (prefix `{}`)",
+          staging.display(), prefix.display());
+    self.wasm_toolchain_mut().sysroot = staging;
+
+    Ok(Some(prefix))
+  }
+
+  pub fn enqueue_rewrite_install_paths(&self, prefix: PathBuf,
+                                       queue: &mut CommandQueue<Self>)
+  {
+    let f = move |sess: &mut &mut Invocation| {
+      let staging = sess.tc().sysroot().clone();
+      if staging == prefix { return Ok(()); }
+
+      let from = format!("{}", staging.display());
+      let to = format!("{}", prefix.display());
+      rewrite_install_paths(&staging, &from, &to)?;
+
+      Ok(())
+    };
+    queue.enqueue_function(Some("rewrite-install-paths"), f);
+  }
+}
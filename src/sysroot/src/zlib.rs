@@ -1,4 +1,4 @@
-use super::{Invocation, link};
+use super::{Invocation, SystemLibrary, link};
 use util::{CommandQueue, get_crate_root, CreateIfNotExists, Tool, };
 
 use clang_driver;
@@ -22,7 +22,7 @@ fn build_flags(flags: Vec<String>) -> String {
 }
 impl Invocation {
   pub fn zlib_src_dir(&self) -> PathBuf {
-    self.srcs.join(self.zlib_repo.name.as_ref())
+    self.src_cache.join(self.zlib_repo.name.as_ref())
   }
   pub fn zlib_build_dir(&self) -> PathBuf {
     self.srcs.join("zlib-build")
@@ -31,7 +31,7 @@ impl Invocation {
     if self.zlib_checkout { return Ok(()); }
     self.zlib_checkout = true;
 
-    self.zlib_repo.checkout_thin(self.zlib_src_dir())
+    self.checkout_repo(self.zlib_repo.clone(), self.zlib_src_dir())
   }
   pub fn build_zlib(&self, queue: &mut CommandQueue<Invocation>)
     -> Result<(), Box<Error>>
@@ -68,9 +68,8 @@ impl Invocation {
     conf.current_dir(&build_dir)
       .env("CC", self.cc())
       .env("CXX", self.cxx())
-      .env("CFLAGS", &cflags)
-      .env("CXXFLAGS", &cflags)
       .arg(format!("--prefix={}", install_dir.display()));
+    self.lib_flags_env(SystemLibrary::Zlib, &mut conf, &cflags);
     self.tc().set_envs(&mut conf);
 
 
@@ -85,6 +84,7 @@ impl Invocation {
 
     let mut install = Command::new("make");
     install.current_dir(&build_dir)
+      .arg(self.jobs_arg())
       .arg("install");
     self.tc().set_envs(&mut install);
     {
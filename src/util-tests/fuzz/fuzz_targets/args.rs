@@ -0,0 +1,34 @@
+//! `cargo fuzz run args`: no argv should be able to panic the clang or ld
+//! drivers' argument parsing. The input is split into args on NULs.
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate wasm_driver_utils as util;
+extern crate clang_driver;
+extern crate ld_driver;
+
+use std::env::set_var;
+use std::sync::Once;
+
+static INIT: Once = Once::new();
+
+fuzz_target!(|data: &[u8]| {
+    INIT.call_once(|| {
+        // only args are parsed; nothing in the toolchain is run.
+        for &var in ["BINARYEN", "EMSCRIPTEN", "LLVM_ROOT", "WASM_SYSROOT"].iter() {
+            set_var(var, "/nonexistent");
+        }
+    });
+
+    let args: Vec<String> = match ::std::str::from_utf8(data) {
+        Ok(args) => args.split('\0').map(String::from).collect(),
+        Err(_) => { return; },
+    };
+
+    let mut clang = clang_driver::Invocation::default();
+    let _ = util::process_invocation_args(&mut clang, args.clone(), true);
+
+    let mut ld = ld_driver::Invocation::default();
+    let _ = util::process_invocation_args(&mut ld, args, true);
+});
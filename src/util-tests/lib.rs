@@ -2,15 +2,16 @@ use std::error::Error;
 use std::path::PathBuf;
 
 #[macro_use]
-extern crate util;
+extern crate wasm_driver_utils as util;
 #[macro_use]
 extern crate lazy_static;
+extern crate tempdir;
 
-use util::{Tool, ToolInvocation, CommandQueue, ToolArgs, regex,
-           process_invocation_args, };
+use util::{Tool, ToolInvocation, CommandQueue, ToolArgs, OutputRole,
+           QueueObserver, StepInfo, process_invocation_args, };
 
 #[derive(Debug)]
-struct Test {
+pub struct Test {
     check_state: Option<String>,
     arg: Option<String>,
 }
@@ -25,10 +26,12 @@ impl Default for Test {
 }
 
 impl Tool for Test {
-    fn enqueue_commands(&mut self, _queue: &mut CommandQueue) -> Result<(), Box<Error>> { unimplemented!() }
+    fn enqueue_commands(&mut self, _queue: &mut CommandQueue<Self>) -> Result<(), Box<Error>> { unimplemented!() }
 
     fn get_name(&self) -> String { "test".to_string() }
 
+    fn add_tool_input(&mut self, _input: PathBuf) -> Result<(), Box<Error>> { unimplemented!() }
+
     fn get_output(&self) -> Option<&PathBuf> { unimplemented!() }
     fn override_output(&mut self, _out: PathBuf)  { unimplemented!() }
 }
@@ -47,41 +50,50 @@ argument!(impl BOTH where { Some(r"^-(both|single_only)(.+)$"), Some(r"^-(both|s
     fn set_both(this, is_single, cap) {
         let index;
         if is_single {
-            index = 1;
-        } else {
             index = 2;
+        } else {
+            index = 1;
         }
         this.arg = Some(cap.get(index).unwrap().as_str().to_string());
     }
 });
 argument!(impl ERROR where { Some(r"^--error$"), None } for Test {
     fn set_error(_this, _is_single, _cap) {
-        return Err("error".to_string());
+        Err("error")?;
     }
 });
 
 /// Tool argument processing.
 impl ToolInvocation for Test {
-    fn check_state(&mut self, _iteration: usize) -> Result<(), String> {
+    fn check_state(&mut self, _iteration: usize, _skip_inputs_check: bool) -> Result<(), Box<Error>> {
         if self.check_state.is_none() || self.arg == self.check_state { return Ok(()); }
-        else { return Err("invalid state".to_string()); }
+        else { return Err("invalid state".into()); }
     }
 
     /// Called until `None` is returned. Put args that override errors before
     /// the the args that can have those errors.
     fn args(&self, iteration: usize) -> Option<ToolArgs<Self>> {
         match iteration {
-            0 => {
-              lazy_static! {
-                static ref ARGS: ToolArgs<Test> = [
-                  &SINGLE, &SPLIT, &BOTH, &ERROR,
-                ];
-              }
-              Some(&ARGS[..])
-            },
+            0 => tool_arguments!(Test => [
+              SINGLE, SPLIT, BOTH, ERROR,
+            ]),
             _ => None,
         }
     }
+
+    fn forward_compiler_args(&mut self, args: Vec<String>) -> Result<(), Box<Error>> {
+        self.arg = Some(args.join(" "));
+        Ok(())
+    }
+
+    fn conflicts(&self) -> Vec<String> {
+        match self.arg {
+            Some(ref arg) if arg == "conflicting" => {
+                vec!["first".to_string(), "second".to_string()]
+            },
+            _ => vec![],
+        }
+    }
 }
 
 #[test]
@@ -89,8 +101,7 @@ fn single() {
     let args = vec!["--single=something".to_string()];
     let mut invocation: Test = Default::default();
 
-    assert_eq!(process_invocation_args(&mut invocation, args),
-               Ok(()));
+    process_invocation_args(&mut invocation, args, false).unwrap();
     assert_eq!(invocation.arg, Some("something".to_string()));
 }
 #[test]
@@ -100,8 +111,7 @@ fn split() {
                     ];
     let mut invocation: Test = Default::default();
 
-    assert_eq!(process_invocation_args(&mut invocation, args),
-               Ok(()));
+    process_invocation_args(&mut invocation, args, false).unwrap();
     assert_eq!(invocation.arg, Some("something".to_string()));
 }
 #[test]
@@ -111,16 +121,14 @@ fn both() {
                     ];
     let mut invocation: Test = Default::default();
 
-    assert_eq!(process_invocation_args(&mut invocation, args),
-               Ok(()));
+    process_invocation_args(&mut invocation, args, false).unwrap();
     assert_eq!(invocation.arg, Some("something".to_string()));
 
     let args = vec!["-bothsomething".to_string(),
                     ];
     let mut invocation: Test = Default::default();
 
-    assert_eq!(process_invocation_args(&mut invocation, args),
-               Ok(()));
+    process_invocation_args(&mut invocation, args, false).unwrap();
     assert_eq!(invocation.arg, Some("something".to_string()));
 }
 
@@ -130,11 +138,70 @@ fn error() {
                     ];
     let mut invocation: Test = Default::default();
 
-    assert_eq!(process_invocation_args(&mut invocation, args),
-               Err("error on argument `--error`: `error`\n".to_string()));
+    let err = process_invocation_args(&mut invocation, args, false).unwrap_err();
+    assert_eq!(err.to_string(), "error on argument `--error`: `error`\n");
     assert_eq!(invocation.arg, None);
 }
 
+#[test]
+fn suggestions() {
+    let invocation: Test = Default::default();
+    assert_eq!(util::suggest::suggestions(&invocation, "--singel=something"),
+               vec!["--single".to_string()]);
+    assert_eq!(util::suggest::suggestions(&invocation, "--unrelated"),
+               Vec::<String>::new());
+}
+
+#[test]
+fn split_at_end() {
+    for flag in ["--split", "-both"].iter() {
+        let args = vec![flag.to_string()];
+        let mut invocation: Test = Default::default();
+
+        let err = process_invocation_args(&mut invocation, args, false).unwrap_err();
+        assert_eq!(err.to_string(),
+                   format!("error on argument `{0}`: ``{0}` expects another argument`\n",
+                           flag));
+    }
+}
+
+#[test]
+fn split_empty() {
+    let args = vec!["--split".to_string(), "".to_string()];
+    let mut invocation: Test = Default::default();
+
+    process_invocation_args(&mut invocation, args, false).unwrap();
+    assert_eq!(invocation.arg, Some("".to_string()));
+}
+
+/// No sequence of args should panic the parser; they either parse or give
+/// an error.
+#[test]
+fn random_args_dont_panic() {
+    const TOKENS: &'static [&'static str] = &[
+        "--split", "-split_only", "-both", "-bothx", "--single=", "--single=x",
+        "-single", "--error", "", "-", "--", "=", "\n", "-\n", "é", "x",
+    ];
+
+    // xorshift, so failures are reproducible.
+    let mut state = 0x2545f4914f6cdd1du64;
+    let mut next = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+
+    for _ in 0..1000 {
+        let len = (next() % 6) as usize;
+        let args: Vec<String> = (0..len)
+            .map(|_| TOKENS[(next() % TOKENS.len() as u64) as usize].to_string() )
+            .collect();
+        let mut invocation: Test = Default::default();
+        let _ = process_invocation_args(&mut invocation, args, false);
+    }
+}
+
 #[test]
 fn check_state() {
     let args = vec!["-bothsomething".to_string(),
@@ -142,6 +209,748 @@ fn check_state() {
     let mut invocation: Test = Default::default();
     invocation.check_state = Some("not something".to_string());
 
-    assert_eq!(process_invocation_args(&mut invocation, args),
-               Err("invalid state".to_string()));
+    let err = process_invocation_args(&mut invocation, args, false).unwrap_err();
+    assert_eq!(err.to_string(), "invalid state");
+}
+
+#[test]
+fn output_roles() {
+    let mut queue: CommandQueue<Test> = CommandQueue::new(None);
+    queue.enqueue_state_function(Some("produce"), |_, _| Ok(()) )
+        .add_output(OutputRole::Primary, "out.o")
+        .add_output(OutputRole::Depfile, "out.d");
+    queue.enqueue_state_function(Some("consume"), |_, state| {
+        assert_eq!(state.outputs(OutputRole::Debug).len(), 0);
+
+        let deps = state.take_outputs(OutputRole::Depfile);
+        assert_eq!(deps.len(), 1);
+        assert!(deps[0].ends_with("out.d"));
+        assert_eq!(state.outputs(OutputRole::Depfile).len(), 0);
+
+        let primary = state.take_outputs(OutputRole::Primary);
+        assert_eq!(primary.len(), 1);
+        assert!(primary[0].ends_with("out.o"));
+        Ok(())
+    });
+
+    let mut invocation: Test = Default::default();
+    queue.run_all(&mut invocation).unwrap();
+}
+#[test]
+fn observers() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Duration;
+    use util::CommandQueueError;
+
+    struct Log(Rc<RefCell<Vec<String>>>);
+    impl QueueObserver for Log {
+        fn before_step(&mut self, step: &StepInfo) {
+            self.0.borrow_mut().push(format!("before {:?}", step.name));
+        }
+        fn after_step(&mut self, step: &StepInfo, _duration: Duration,
+                      outputs: &[PathBuf], result: Result<(), &CommandQueueError>) {
+            let outputs: Vec<_> = outputs.iter()
+                .map(|out| out.file_name().unwrap().to_string_lossy().into_owned() )
+                .collect();
+            self.0.borrow_mut().push(format!("after {:?} {:?} {}", step.name, outputs,
+                                             result.is_ok()));
+        }
+    }
+
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let mut queue: CommandQueue<Test> = CommandQueue::new(None);
+    queue.add_observer(Box::new(Log(log.clone())));
+    queue.enqueue_state_function(Some("produce"), |_, _| Ok(()) )
+        .add_output(OutputRole::Depfile, "out.d");
+    queue.enqueue_state_function(Some("fail"), |_, _| Err(CommandQueueError::ProcessError(Some(1))) );
+
+    let mut invocation: Test = Default::default();
+    assert!(queue.run_all(&mut invocation).is_err());
+    assert_eq!(&log.borrow()[..], &[
+        "before Some(\"produce\")",
+        "after Some(\"produce\") [\"out.d\"] true",
+        "before Some(\"fail\")",
+        "after Some(\"fail\") [] false",
+    ]);
+}
+/// A tool which only needs inputs, for `deferred_tool_args`.
+#[derive(Debug, Default)]
+pub struct NeedsInputs {
+    inputs: Vec<PathBuf>,
+}
+impl Tool for NeedsInputs {
+    fn enqueue_commands(&mut self, _queue: &mut CommandQueue<Self>) -> Result<(), Box<Error>> { Ok(()) }
+    fn get_name(&self) -> String { "needs-inputs".to_string() }
+    fn add_tool_input(&mut self, input: PathBuf) -> Result<(), Box<Error>> {
+        self.inputs.push(input);
+        Ok(())
+    }
+    fn get_output(&self) -> Option<&PathBuf> { None }
+    fn override_output(&mut self, _out: PathBuf) { }
+}
+argument!(impl NEEDS_INPUTS_ERROR where { Some(r"^--error$"), None } for NeedsInputs {
+    fn needs_inputs_error(_this, _is_single, _cap) {
+        Err("error")?;
+    }
+});
+impl ToolInvocation for NeedsInputs {
+    fn check_state(&mut self, _iteration: usize, skip_inputs_check: bool) -> Result<(), Box<Error>> {
+        if !skip_inputs_check && self.inputs.len() == 0 {
+            Err("no inputs")?;
+        }
+        Ok(())
+    }
+    fn args(&self, iteration: usize) -> Option<ToolArgs<Self>> {
+        match iteration {
+            0 => tool_arguments!(NeedsInputs => [NEEDS_INPUTS_ERROR,]),
+            _ => None,
+        }
+    }
+}
+#[test]
+fn deferred_tool_args() {
+    let run = |produce: bool, args: &[&str]| {
+        let mut queue: CommandQueue<Test> = CommandQueue::new(None);
+        if produce {
+            queue.enqueue_state_function(Some("produce"), |_, state| {
+                state.prev_outputs.push(PathBuf::from("out.o"));
+                Ok(())
+            });
+        }
+        let args = args.iter().map(|arg| arg.to_string() ).collect();
+        queue.enqueue_tool_deferred(Some("consume"), NeedsInputs::default(), args,
+                                    false, None::<Vec<tempdir::TempDir>>)
+            .output_override = false;
+        let mut invocation: Test = Default::default();
+        queue.run_all(&mut invocation)
+            .map_err(|e| match e {
+                util::CommandQueueError::Error(e) => e.to_string(),
+                e => panic!("{:?}", e),
+            })
+    };
+
+    // the inputs are the previous step's outputs.
+    run(true, &[]).unwrap();
+    assert_eq!(run(false, &[]).unwrap_err(), "step `consume`: no inputs");
+    let err = run(true, &["--error"]).unwrap_err();
+    assert!(err.starts_with("step `consume`: error on argument `--error`"), "{}", err);
+}
+#[test]
+fn conflicts_are_reported_together() {
+    let args = vec!["--single=conflicting".to_string()];
+    let mut invocation: Test = Default::default();
+
+    let err = process_invocation_args(&mut invocation, args, false)
+        .unwrap_err()
+        .to_string();
+    assert_eq!(err, "conflicting arguments: first\nconflicting arguments: second");
+}
+#[test]
+fn unused_args_are_errors() {
+    let args = vec!["--single=x".to_string(), "--singel=y".to_string(),
+                    "input".to_string()];
+    let mut invocation: Test = Default::default();
+
+    let err = process_invocation_args(&mut invocation, args, false)
+        .unwrap_err()
+        .to_string();
+    assert_eq!(err, "unused arguments (`--allow-unused-args` ignores them):\n  \
+                     `--singel=y`; did you mean `--single`?\n  `input`");
+}
+#[test]
+fn arg_plugins() {
+    use util::plugins::{parse, process_invocation_args};
+
+    let flags = parse(r#"
+[[flag]]
+match = "^--site-opt=(.+)$"
+action = "compiler"
+args = ["-DSITE=$1", "-O2"]
+
+[[flag]]
+match = "^--other-tool$"
+action = "linker"
+tools = ["not-test"]
+
+[[flag]]
+match = "^--site-cache=(.+)$"
+action = "env"
+var = "UTIL_TESTS_SITE_CACHE"
+value = "$1"
+"#).unwrap();
+    let args = |args: &[&str]| args.iter().map(|arg| arg.to_string() ).collect::<Vec<_>>();
+
+    let mut invocation: Test = Default::default();
+    process_invocation_args(&flags, &mut invocation, args(&["--site-opt=x"]), false).unwrap();
+    assert_eq!(invocation.arg, Some("-DSITE=x -O2".to_string()));
+
+    // an option's value is left alone.
+    let mut invocation: Test = Default::default();
+    process_invocation_args(&flags, &mut invocation, args(&["--split", "--site-opt=y"]),
+                            false)
+        .unwrap();
+    assert_eq!(invocation.arg, Some("--site-opt=y".to_string()));
+
+    let mut invocation: Test = Default::default();
+    let err = process_invocation_args(&flags, &mut invocation, args(&["--other-tool"]), false)
+        .unwrap_err();
+    assert!(err.to_string().starts_with("unused arguments"), "{}", err);
+
+    // nested drivers see the flag again, but the var is only set once, and
+    // only on the tools we run.
+    let _flags = util::GlobalFlags::default().enter();
+    for _ in 0..2 {
+        let mut invocation: Test = Default::default();
+        process_invocation_args(&flags, &mut invocation, args(&["--site-cache=/c"]), false)
+            .unwrap();
+    }
+    assert_eq!(util::tool_env(),
+               vec![("UTIL_TESTS_SITE_CACHE".to_string(), "/c".to_string())]);
+    assert!(std::env::var_os("UTIL_TESTS_SITE_CACHE").is_none());
+
+    let err = parse("[[flag]]\nmatch = \"x\"\naction = \"nope\"\n")
+        .unwrap_err();
+    assert_eq!(err.to_string(),
+               "flag 1: unknown action `nope`; expected `compiler`, `linker` or `env`");
+}
+#[test]
+fn thin_archives() {
+    use std::fs::{create_dir_all, File};
+    use std::io::Write;
+    use util::filetype::{file_type, Type, Subtype};
+    use util::ldtools::thin_archive_members;
+
+    let dir = std::env::temp_dir()
+        .join(format!("util-tests-thin-archive.{}", std::process::id()));
+    create_dir_all(dir.join("sub")).unwrap();
+    File::create(dir.join("sub/a_long_member_name.o")).unwrap()
+        .write_all(b"\0asm\x01\0\0\0").unwrap();
+    File::create(dir.join("b.o")).unwrap()
+        .write_all(b"\0asm\x01\0\0\0").unwrap();
+
+    // as written by `llvm-ar rcT`: names in the string table, no data.
+    let strtab = "sub/a_long_member_name.o/\nb.o/\n";
+    let mut archive = b"!<thin>\n".to_vec();
+    archive.extend(format!("{:<48}{:<10}`\n", "//", strtab.len()).bytes());
+    archive.extend(strtab.bytes());
+    archive.push(b'\n'); // padding to an even offset
+    for &offset in [0, 26].iter() {
+        archive.extend(format!("{:<16}{:<32}{:<10}`\n", format!("/{}", offset),
+                               "0     0     0     644", 8).bytes());
+    }
+    let path = dir.join("thin.a");
+    File::create(&path).unwrap().write_all(&archive[..]).unwrap();
+
+    let members = thin_archive_members(&path).unwrap().unwrap();
+    assert_eq!(members, vec![dir.join("sub/a_long_member_name.o"), dir.join("b.o")]);
+    assert_eq!(file_type(&path).unwrap(), Some(Type::Archive(Subtype::Wasm)));
+    assert_eq!(thin_archive_members(dir.join("b.o")).unwrap(), None);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn linker_script_sniffing() {
+    use util::filetype::{could_be_linker_script, file_type, get_cached_filetype,
+                         override_file_contents, Type};
+
+    override_file_contents("util-tests-script.so", b"/* libfoo */ GROUP ( libfoo.so.1 )");
+    override_file_contents("util-tests-notes.txt", b"just some notes");
+    assert!(could_be_linker_script("util-tests-script.so"));
+    assert!(!could_be_linker_script("util-tests-notes.txt"));
+    assert!(!could_be_linker_script("util-tests-missing.so"));
+
+    // remembered, but not as an override.
+    assert_eq!(file_type("util-tests-script.so").unwrap(), Some(Type::LinkerScript));
+    assert_eq!(get_cached_filetype("util-tests-script.so"), None);
+}
+
+#[test]
+fn clang_resource_dir() {
+    use std::fs::create_dir_all;
+    use util::toolchain::WasmToolchain;
+
+    std::env::set_var(util::config::CONFIG_ENV, "/nonexistent");
+    let llvm = std::env::temp_dir()
+        .join(format!("util-tests-llvm.{}", std::process::id()));
+    for version in ["5.0.0", "9.0.1", "16", "not-a-version"].iter() {
+        create_dir_all(llvm.join("lib/clang").join(version)).unwrap();
+    }
+    assert_eq!(WasmToolchain::detect_clang_resource_dir(&llvm),
+               Some(llvm.join("lib/clang/16")));
+
+    std::fs::remove_dir_all(&llvm).unwrap();
+    assert_eq!(WasmToolchain::detect_clang_resource_dir(&llvm), None);
+}
+
+#[cfg(unix)]
+#[test]
+fn file_lock_contention() {
+    use std::time::Duration;
+    use util::lock::lock;
+
+    let path = std::env::temp_dir()
+        .join(format!("util-tests-lock.{}", std::process::id()));
+    {
+        let _held = lock(&path, "the test dir", None).unwrap();
+        // a second lock is a different open file, so it contends with the first.
+        let err = lock(&path, "the test dir", Some(Duration::from_secs(0))).unwrap_err();
+        assert!(err.to_string().starts_with("timed out after 0s waiting for the test dir's lock"),
+                "{}", err);
+        assert!(err.to_string().contains(&format!("(held by pid {})", std::process::id())),
+                "{}", err);
+    }
+    lock(&path, "the test dir", Some(Duration::from_secs(0))).unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn diagnostic_format() {
+    use util::diag::{format, Severity};
+
+    assert_eq!(format(Severity::Warning, None, "ignoring `-m32`", false),
+               "warning: ignoring `-m32`\n");
+    assert_eq!(format(Severity::Error, Some("wasm.conf:3"), "expected `key = value`", false),
+               "wasm.conf:3: error: expected `key = value`\n");
+    assert_eq!(format(Severity::Note, None, "waiting", true),
+               "\x1b[1;36mnote:\x1b[0m waiting\n");
+}
+
+#[test]
+fn toolchain_manifest_json() {
+    use util::manifest::{manifest, Component};
+
+    assert_eq!(manifest(&[]), "{\n  \"components\": []\n}\n");
+    let components = vec![
+        Component {
+            name: "clang",
+            path: "/llvm/bin/clang".into(),
+            version: Some("clang version 12.0.0 (\"trunk\")".into()),
+            sha256: Some("00ff".into()),
+        },
+        Component {
+            name: "wasm-opt",
+            path: "/binaryen/bin/wasm-opt".into(),
+            version: None,
+            sha256: None,
+        },
+    ];
+    assert_eq!(manifest(&components[..]),
+               "{\n  \"components\": [\n    \
+                {\"name\": \"clang\", \"path\": \"/llvm/bin/clang\", \
+                \"version\": \"clang version 12.0.0 (\\\"trunk\\\")\", \"sha256\": \"00ff\"},\n    \
+                {\"name\": \"wasm-opt\", \"path\": \"/binaryen/bin/wasm-opt\", \
+                \"version\": null, \"sha256\": null}\n  ]\n}\n");
+}
+
+#[test]
+fn tool_env_is_set_on_tools() {
+    use std::ffi::OsStr;
+    use std::process::Command;
+
+    let mut flags = util::GlobalFlags::default();
+    flags.set_tool_env("SANDBOX_ROOT", "/sandbox");
+    flags.set_tool_env("CCACHE_DIR", "/tmp/a=b");
+    flags.deterministic = true;
+    let mut cmd = Command::new("true");
+    flags.apply_env(&mut cmd);
+
+    let envs: Vec<(&OsStr, Option<&OsStr>)> = cmd.get_envs().collect();
+    assert!(envs.contains(&(OsStr::new("SANDBOX_ROOT"), Some(OsStr::new("/sandbox")))));
+    assert!(envs.contains(&(OsStr::new("CCACHE_DIR"), Some(OsStr::new("/tmp/a=b")))));
+    // for nested drivers.
+    assert!(envs.contains(&(OsStr::new("WASM_DRIVER_DETERMINISTIC"), Some(OsStr::new("1")))));
+}
+
+#[test]
+fn step_timeouts_and_retries() {
+    use std::process::Command;
+    use std::time::{Duration, Instant};
+
+    let run = |cmd: Command, timeout: Option<Duration>, retries: u32| {
+        let mut queue: CommandQueue<Test> = CommandQueue::new(None);
+        {
+            let step = queue.enqueue_simple_external(Some("step"), cmd, None);
+            step.prev_outputs = false;
+            step.output_override = false;
+            step.timeout = timeout;
+            step.retries = retries;
+        }
+        let mut invocation: Test = Default::default();
+        queue.run_all(&mut invocation)
+    };
+
+    let mut sleep = Command::new("sleep");
+    sleep.arg("10");
+    let start = Instant::now();
+    match run(sleep, Some(Duration::from_secs(1)), 0) {
+        Err(util::CommandQueueError::Error(e)) => {
+            assert!(e.to_string().contains("timed out after 1s"), "{}", e);
+        },
+        r => panic!("{:?}", r),
+    }
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    // fails the first time only.
+    let dir = tempdir::TempDir::new("step-retries").unwrap();
+    let flag = dir.path().join("failed-once");
+    let flaky = || {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c")
+            .arg(format!("test -e {0} || {{ touch {0}; exit 1; }}", flag.display()));
+        cmd
+    };
+    assert!(run(flaky(), None, 0).is_err());
+    std::fs::remove_file(&flag).unwrap();
+    run(flaky(), None, 1).unwrap();
+    std::fs::remove_file(&flag).unwrap();
+}
+
+#[test]
+fn stderr_filter() {
+    use std::cell::RefCell;
+    use std::process::Command;
+    use std::rc::Rc;
+    use util::command_queue::StderrFilter;
+
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let mut queue: CommandQueue<Test> = CommandQueue::new(None);
+    {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg("echo undefined symbol: foo >&2; exit 1");
+        let step = queue.enqueue_simple_external(Some("step"), cmd, None);
+        step.prev_outputs = false;
+        step.output_override = false;
+        let seen = seen.clone();
+        step.stderr_filter = Some(StderrFilter(Box::new(move |stderr, success| {
+            seen.borrow_mut().push((stderr.to_string(), success));
+            String::new()
+        })));
+    }
+    let mut invocation: Test = Default::default();
+    assert!(queue.run_all(&mut invocation).is_err());
+    assert_eq!(*seen.borrow(), vec![("undefined symbol: foo\n".to_string(), false)]);
+}
+
+#[test]
+fn timeouts_kill_the_process_group() {
+    use std::fs::read_to_string;
+    use std::process::Command;
+    use std::time::Duration;
+
+    let dir = tempdir::TempDir::new("timeout-group").unwrap();
+    let pid_file = dir.path().join("pid");
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg(format!("sleep 30 & echo $! > {}; wait", pid_file.display()));
+    let (_, killed) = util::interrupt::status_timeout(&mut cmd, Some(Duration::from_secs(1)))
+        .unwrap();
+    assert!(killed);
+
+    // the grandchild went too; give init a moment to reap it.
+    let pid = read_to_string(&pid_file).unwrap();
+    let alive = || Command::new("kill").arg("-0").arg(pid.trim())
+        .status().unwrap().success();
+    for _ in 0..50 {
+        if !alive() { break; }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    assert!(!alive(), "`sleep` ({}) outlived the timeout", pid.trim());
+}
+
+#[test]
+fn step_timeout_split_form() {
+    let args = vec!["--step-timeout".to_string(), "soon".to_string()];
+    match util::main_inner_with_args(Test::default(), args) {
+        Err(util::CommandQueueError::Error(e)) => {
+            assert!(e.to_string().contains("invalid `--step-timeout=soon`"), "{}", e);
+        },
+        r => panic!("{:?}", r.map(|_| () )),
+    }
+}
+
+#[test]
+fn stream_output() {
+    use std::process::Command;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    let lines = Arc::new(Mutex::new(Vec::new()));
+    let start = Instant::now();
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg("echo first >&2; sleep 1; echo out; echo second >&2");
+    let (out, err) = (lines.clone(), lines.clone());
+    let (status, killed) = util::interrupt::stream_timeout(&mut cmd, None, move |bytes| {
+        out.lock().unwrap().push((String::from_utf8_lossy(bytes).into_owned(), start.elapsed()));
+    }, move |line| {
+        err.lock().unwrap().push((String::from_utf8_lossy(line).into_owned(), start.elapsed()));
+    }).unwrap();
+    assert!(status.success() && !killed);
+
+    let lines = lines.lock().unwrap();
+    let mut text: Vec<&str> = lines.iter().map(|&(ref line, _)| &line[..] ).collect();
+    assert_eq!(text[0], "first\n");
+    text.sort();
+    assert_eq!(text, vec!["first\n", "out\n", "second\n"]);
+    // the first line came through before the child finished.
+    assert!(lines[0].1 < Duration::from_millis(900), "{:?}", lines[0].1);
+}
+
+#[test]
+fn optimization_levels() {
+    use util::OptimizationGoal;
+
+    assert_eq!(OptimizationGoal::parse("0"), Ok(OptimizationGoal::Speed(0)));
+    assert_eq!(OptimizationGoal::parse(""), Ok(OptimizationGoal::Speed(2)));
+    assert_eq!(OptimizationGoal::parse("4"), Ok(OptimizationGoal::Speed(4)));
+    assert_eq!(OptimizationGoal::parse("z"), Ok(OptimizationGoal::Size));
+    assert_eq!(OptimizationGoal::parse("g"), Ok(OptimizationGoal::Speed(1)));
+    assert_eq!(OptimizationGoal::parse("fast"), Ok(OptimizationGoal::Speed(3)));
+
+    let err = OptimizationGoal::parse("5").unwrap_err();
+    assert!(err.contains("invalid optimization level `-O5`"), "{}", err);
+    assert!(OptimizationGoal::parse("bogus").is_err());
+    assert!(OptimizationGoal::parse("300").is_err());
+    assert!(OptimizationGoal::Speed(9).check().is_err());
+}
+
+#[test]
+fn sparse_checkout() {
+    use std::fs::{create_dir_all, write};
+    use std::process::Command;
+    use util::repo::Repo;
+
+    let dir = std::env::temp_dir()
+        .join(format!("util-tests-sparse-checkout.{}", std::process::id()));
+    let upstream = dir.join("upstream");
+    for sub in ["llvm/cmake", "libcxx", "clang"].iter() {
+        create_dir_all(upstream.join(sub)).unwrap();
+        write(upstream.join(sub).join("file"), sub).unwrap();
+    }
+    let git = |args: &[&str]| {
+        let status = Command::new("git")
+            .current_dir(&upstream)
+            .args(&["-c", "user.name=t", "-c", "user.email=t@t"])
+            .args(args)
+            .status()
+            .unwrap();
+        assert!(status.success(), "git {:?}", args);
+    };
+    git(&["init", "-q", "-b", "main"]);
+    git(&["add", "."]);
+    git(&["commit", "-q", "-m", "upstream"]);
+    git(&["tag", "v1"]);
+
+    let url = format!("file://{}", upstream.display());
+    let repo = Repo::new_git_commit("upstream", url, "main", "v1")
+        .sparse(vec!["llvm/cmake", "libcxx"]);
+    let dest = dir.join("checkout");
+    repo.checkout_thin(&dest).unwrap();
+    assert!(dest.join("llvm/cmake/file").exists());
+    assert!(dest.join("libcxx/file").exists());
+    assert!(!dest.join("clang").exists());
+
+    // again, over the existing checkout.
+    repo.checkout_thin(&dest).unwrap();
+    assert!(dest.join("libcxx/file").exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn repo_mirror() {
+    use std::fs::{create_dir_all, write};
+    use std::process::Command;
+    use util::repo::{CheckoutConfig, Repo};
+
+    let dir = std::env::temp_dir()
+        .join(format!("util-tests-repo-mirror.{}", std::process::id()));
+    let upstream = dir.join("upstream");
+    create_dir_all(&upstream).unwrap();
+    let git = |cwd: &std::path::Path, args: &[&str]| {
+        let out = Command::new("git")
+            .current_dir(cwd)
+            .args(&["-c", "user.name=t", "-c", "user.email=t@t"])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(out.status.success(), "git {:?}", args);
+        String::from_utf8(out.stdout).unwrap()
+    };
+    git(&upstream, &["init", "-q", "-b", "main"]);
+    for n in 0..3 {
+        write(upstream.join("file"), n.to_string()).unwrap();
+        git(&upstream, &["add", "."]);
+        git(&upstream, &["commit", "-q", "-m", "upstream"]);
+    }
+
+    let mut config = CheckoutConfig::default();
+    assert!(config.add_mirror("mirrored").is_err());
+    assert!(config.add_mirror("mirrored=").is_err());
+    config.add_mirror(&format!("mirrored={}", upstream.display())).unwrap();
+    let repo = Repo::new_git("mirrored", "https://example.invalid/mirrored.git", "main");
+    let repo = config.apply(repo);
+    let dest = dir.join("checkout");
+    assert!(!repo.needs_network(&dest));
+    repo.checkout_thin(&dest).unwrap();
+
+    assert_eq!(std::fs::read_to_string(dest.join("file")).unwrap(), "2");
+    // thin, so just the tip.
+    assert_eq!(git(&dest, &["rev-list", "--count", "HEAD"]).trim(), "1");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn thin_archive_members() {
+    use std::fs::write;
+    use util::ldtools::thin_archive_members;
+
+    let dir = tempdir::TempDir::new("thin-archive").unwrap();
+    let regular = dir.path().join("regular.a");
+    write(&regular, b"!<arch>\n").unwrap();
+    assert_eq!(thin_archive_members(&regular).unwrap(), None);
+    // shorter than the magic.
+    let short = dir.path().join("short.a");
+    write(&short, b"!<t").unwrap();
+    assert_eq!(thin_archive_members(&short).unwrap(), None);
+
+    let thin = dir.path().join("thin.a");
+    let mut bytes = b"!<thin>\n".to_vec();
+    bytes.extend_from_slice(format!("{:<16}{:<32}{:<10}`\n", "foo.o/", "", 8).as_bytes());
+    write(&thin, bytes).unwrap();
+    assert_eq!(thin_archive_members(&thin).unwrap(), Some(vec![dir.path().join("foo.o")]));
+}
+
+#[test]
+fn stable_hash() {
+    // must not change between builds; it names dirs on disk.
+    assert_eq!(util::stable_hash(&["ab", "c"]), "43ee655579de01ca");
+    assert_ne!(util::stable_hash(&["ab", "c"]), util::stable_hash(&["a", "bc"]));
+}
+
+#[test]
+fn json_string() {
+    assert_eq!(util::json_string("a \"b\" \\ c\n\t"), r#""a \"b\" \\ c\n\u0009""#);
+    assert_eq!(util::json_string("ü"), "\"ü\"");
+}
+
+#[test]
+fn shell_split() {
+    use util::report::{shell_quote, shell_split};
+
+    assert_eq!(shell_split("  wasm-opt  -O2 ").unwrap(), vec!["wasm-opt", "-O2"]);
+    assert_eq!(shell_split(r#"a 'b c' "d \"e\" \f" g\ h ''"#).unwrap(),
+               vec!["a", "b c", r#"d "e" \f"#, "g h", ""]);
+    assert!(shell_split("'a").is_err());
+    assert!(shell_split("a\\").is_err());
+
+    let words = vec!["/opt/my tools/wasm-opt", "it's", "--pass-arg=a b", ""];
+    let line: Vec<String> = words.iter().map(|word| shell_quote(word) ).collect();
+    assert_eq!(shell_split(&line.join(" ")).unwrap(), words);
+}
+
+#[test]
+fn user_temp_dir() {
+    let dir = util::user_temp_dir().unwrap();
+    assert!(dir.starts_with(std::env::temp_dir()));
+    assert_eq!(util::user_temp_dir().unwrap(), dir);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+    }
+}
+
+#[test]
+fn heavy_job_slots() {
+    use std::fs::OpenOptions;
+    use util::jobs::{acquire, HEAVY_JOBS_DIR_ENV, MAX_HEAVY_JOBS_ENV};
+    use util::lock::try_lock;
+
+    let dir = tempdir::TempDir::new("heavy-jobs").unwrap();
+    std::env::set_var(HEAVY_JOBS_DIR_ENV, dir.path());
+    std::env::set_var(MAX_HEAVY_JOBS_ENV, "1");
+    let slot_free = || {
+        let file = OpenOptions::new().write(true)
+            .open(dir.path().join("slot-0"))
+            .unwrap();
+        try_lock(&file).unwrap()
+    };
+
+    let slot = acquire().unwrap();
+    if cfg!(unix) {
+        assert!(!slot_free());
+    }
+    // a nested step already has its parent's slot.
+    let nested = acquire().unwrap();
+    drop(nested);
+    if cfg!(unix) {
+        assert!(!slot_free());
+    }
+    drop(slot);
+    if cfg!(unix) {
+        assert!(slot_free());
+    }
+    std::env::remove_var(MAX_HEAVY_JOBS_ENV);
+    std::env::remove_var(HEAVY_JOBS_DIR_ENV);
+}
+
+#[test]
+fn explain_changes() {
+    use util::explain::{deserialize, explain, serialize, Step};
+
+    let step = |name: &str, command: &[&str]| Step {
+        name: name.to_string(),
+        command: command.iter().map(|arg| arg.to_string() ).collect(),
+    };
+    let prev = vec![
+        step("clang", &["clang", "-O2", "-c", "a.c"]),
+        step("lld", &["wasm-ld", "a.o", "-o", "a.wasm"]),
+        step("wast", &[]),
+    ];
+    // escaped, so odd names and args survive.
+    let odd = vec![step("odd\nname", &["back\\slash", "new\nline"])];
+    assert_eq!(deserialize(&serialize(&odd)), odd);
+    assert_eq!(deserialize(&serialize(&prev)), prev);
+
+    let next = vec![
+        step("clang", &["clang", "-O3", "-c", "a.c"]),
+        step("lld", &["wasm-ld", "-o", "a.wasm", "a.o"]),
+        step("opt", &["wasm-opt", "a.wasm"]),
+    ];
+    assert_eq!(explain(&prev, &next, false),
+               "- wast (removed)\n\
+                ~ clang\n\
+                \x20   - -O2\n\
+                \x20   + -O3\n\
+                ~ lld\n\
+                \x20   (same args, reordered)\n\
+                + opt (new)\n\
+                0 step(s) unchanged\n");
+    assert_eq!(explain(&prev, &prev, false), "3 step(s) unchanged\n");
+}
+
+#[test]
+fn diag_capture() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use util::diag::{capture, current_sink, warning};
+
+    let ((), diags) = capture(|| warning("captured") );
+    assert_eq!(diags.len(), 1);
+    assert_eq!(diags[0].message, "captured");
+    assert!(current_sink().is_none());
+
+    // a panic doesn't leave diagnostics captured.
+    let _ = catch_unwind(AssertUnwindSafe(|| {
+        capture(|| panic!("in capture") )
+    }));
+    assert!(current_sink().is_none());
+
+    // nor does another thread's capture.
+    let other = std::thread::spawn(|| capture(|| current_sink().is_some() ).0 );
+    assert!(other.join().unwrap());
+    assert!(current_sink().is_none());
 }
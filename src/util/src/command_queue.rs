@@ -1,23 +1,23 @@
 
 use std;
 use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashSet;
 use std::error::Error;
 use std::fmt::{self, Debug, Formatter};
-use std::fs::{copy};
+use std::fs::{self, copy};
 use std::ops::{Deref, DerefMut};
-use std::path::{PathBuf};
+use std::path::{Path, PathBuf};
 use std::process;
 use std::rc::Rc;
-use std::sync::{Once, };
-use std::sync::atomic::{AtomicBool, Ordering, };
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
 
 use tempdir::TempDir;
 
+use super::lock::{lock, FileLock};
 use super::{ToolInvocation, process_invocation_args,
-            boolean_env};
-
-static STOP_BEFORE_NEXT_JOB: AtomicBool = AtomicBool::new(false);
-static CTRL_C_HANDLER: Once = Once::new();
+            boolean_env, GlobalFlags};
 
 #[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 pub enum InputArgsTransformResult {
@@ -35,7 +35,7 @@ impl Default for InputArgsTransformResult {
 /// arguments.
 /// ie Some("-o")
 #[derive(Debug)]
-pub struct CommandTool<T>(T);
+pub struct CommandTool<T>(T, Option<Vec<String>>);
 impl<T> Deref for CommandTool<T> {
   type Target = T;
   fn deref(&self) -> &T { &self.0 }
@@ -81,6 +81,35 @@ impl<T> Debug for FunctionCommandWithState<T> {
   }
 }
 
+/// What a command output is for. Every command has (at most) one implicit
+/// `Primary` output (see `RunState::output`); others have to be declared
+/// with `ConcreteCommand::add_output`.
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum OutputRole {
+  Primary,
+  /// ie split dwarf.
+  Debug,
+  /// Make style dependency files.
+  Depfile,
+}
+impl OutputRole {
+  fn as_str(&self) -> &'static str {
+    match self {
+      &OutputRole::Primary => "primary",
+      &OutputRole::Debug => "debug",
+      &OutputRole::Depfile => "depfile",
+    }
+  }
+  fn parse(s: &str) -> Option<OutputRole> {
+    match s {
+      "primary" => Some(OutputRole::Primary),
+      "debug" => Some(OutputRole::Debug),
+      "depfile" => Some(OutputRole::Depfile),
+      _ => None,
+    }
+  }
+}
+
 #[derive(Debug)]
 pub struct ConcreteCommand {
   pub name: Option<Cow<'static, str>>,
@@ -88,21 +117,155 @@ pub struct ConcreteCommand {
   pub cant_fail: bool,
   pub tmp_dirs: Vec<Rc<TempDir>>,
   pub intermediate_name: Option<PathBuf>,
+  /// Extension of the default intermediate name, which is otherwise
+  /// `<source-stem>.<stage>`.
+  pub output_ext: Option<Cow<'static, str>>,
   pub prev_outputs: bool,
   pub output_override: bool,
   pub copy_output_to: Option<PathBuf>,
+  /// Extra outputs this command writes. Relative paths are in the
+  /// intermediate dir.
+  pub outputs: Vec<(OutputRole, PathBuf)>,
+  /// Non-primary roles of previous outputs which should also be given to
+  /// this command as inputs (only if `prev_outputs`).
+  pub input_roles: Vec<OutputRole>,
+  /// Paths in the intermediate dir added to an external command's
+  /// arguments, ahead of the previous outputs; for files a step names
+  /// itself, ie `llvm-split`'s output prefix and the pieces it writes.
+  pub intermediate_args: Vec<PathBuf>,
+  /// What an in process step will run, for `CommandQueue::plan`; or how
+  /// an external command's arguments will end up, if it adds its own.
+  pub plan: Option<String>,
+  /// Whether this command can write its output to stdout itself when the
+  /// final output is `-`. Otherwise it's written to a file which is then
+  /// copied to stdout.
+  pub stdout_output: bool,
+  /// Memory hungry, ie a link; waits for a heavy job slot. See `jobs`.
+  pub heavy: bool,
+  /// Kill the command if it runs for longer; overrides `--step-timeout`.
+  pub timeout: Option<Duration>,
+  /// Run the command again, up to this many times, if it fails or times
+  /// out; for flaky steps.
+  pub retries: u32,
+  /// Rewrites an external command's stderr before it's shown, ie to add
+  /// help to a tool's errors.
+  pub stderr_filter: Option<StderrFilter>,
+}
+
+/// Given all of a command's stderr once it exits, and whether it
+/// succeeded; so that stderr isn't streamed.
+pub struct StderrFilter(pub Box<dyn Fn(&str, bool) -> String>);
+impl Debug for StderrFilter {
+  fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+    write!(fmt, "StderrFilter(..)")
+  }
 }
 
 impl ConcreteCommand {
+  /// A step which takes the previous outputs as inputs and writes to the
+  /// queue's output; every `enqueue_*` starts from this (or `in_process`).
+  pub fn new(name: Option<Cow<'static, str>>) -> Self {
+    ConcreteCommand {
+      name,
+      cant_fail: false,
+      tmp_dirs: Vec::new(),
+      intermediate_name: None,
+      output_ext: None,
+      prev_outputs: true,
+      output_override: true,
+      copy_output_to: None,
+      outputs: Vec::new(),
+      input_roles: Vec::new(),
+      intermediate_args: Vec::new(),
+      plan: None,
+      stdout_output: false,
+      heavy: false,
+      timeout: None,
+      retries: 0,
+      stderr_filter: None,
+    }
+  }
+  /// A step run in process, which handles its own inputs and outputs.
+  pub fn in_process(name: Option<Cow<'static, str>>) -> Self {
+    ConcreteCommand {
+      prev_outputs: false,
+      output_override: false,
+      .. ConcreteCommand::new(name)
+    }
+  }
+
+  pub fn add_output<T>(&mut self, role: OutputRole, path: T) -> &mut Self
+    where T: Into<PathBuf>,
+  {
+    self.outputs.push((role, path.into()));
+    self
+  }
+  pub fn add_input_role(&mut self, role: OutputRole) -> &mut Self {
+    self.input_roles.push(role);
+    self
+  }
+  pub fn add_intermediate_arg<T>(&mut self, path: T) -> &mut Self
+    where T: Into<PathBuf>,
+  {
+    self.intermediate_args.push(path.into());
+    self
+  }
+
   pub fn copy_output_to(&self, out: PathBuf) -> Result<(), Box<dyn Error>> {
-    if let Some(copy_to) = self.copy_output_to.as_ref() {
-      copy(out, copy_to)?;
+    match self.copy_output_to {
+      Some(ref copy_to) if is_stdout(copy_to) => write_to_stdout(&out)?,
+      Some(ref copy_to) => { copy(out, copy_to)?; },
+      None => {},
     }
 
     Ok(())
   }
 }
 
+fn collect_tmp_dirs<U>(tmp_dirs: Option<Vec<U>>) -> Vec<Rc<TempDir>>
+  where U: Into<Rc<TempDir>>,
+{
+  tmp_dirs
+    .map(|dirs| {
+      dirs.into_iter()
+        .map(|dir| dir.into() )
+        .collect::<Vec<_>>()
+    })
+    .unwrap_or_default()
+}
+fn inherit_stdio(mut cmd: process::Command) -> process::Command {
+  use std::process::Stdio;
+
+  cmd.stdout(Stdio::inherit())
+    .stderr(Stdio::inherit())
+    .stdin(Stdio::inherit());
+  cmd
+}
+
+/// Is `path` `-`, ie stdout?
+pub fn is_stdout(path: &Path) -> bool {
+  path == Path::new("-")
+}
+fn write_to_stdout(path: &Path) -> Result<(), Box<dyn Error>> {
+  use std::io::{copy, stdout, Write};
+
+  let stdout = stdout();
+  let mut stdout = stdout.lock();
+  copy(&mut fs::File::open(path)?, &mut stdout)?;
+  stdout.flush()?;
+  Ok(())
+}
+
+/// Disassemble a wasm module to text with `wasm-dis`, demangling the
+/// symbol names.
+#[derive(Debug)]
+pub struct DisassembleCommand {
+  wasm_dis: PathBuf,
+  /// `None` means the last previous output.
+  input: Option<PathBuf>,
+  output: PathBuf,
+}
+
 #[derive(Debug)]
 pub struct Command<T>
   where T: Debug,
@@ -128,6 +291,17 @@ impl<T> DerefMut for Command<T>
 impl<T> Command<T>
   where T: Debug,
 { }
+impl<T> Command<CommandTool<T>>
+  where T: ToolInvocation + 'static,
+{
+  /// A deferred tool's arg error, saying which step it came from.
+  fn step_error(&self, e: Box<dyn Error>) -> String {
+    let name = self.concrete.name.as_ref()
+      .map(|name| name.to_string() )
+      .unwrap_or_else(|| self.cmd.get_name() );
+    format!("step `{}`: {}", name, e)
+  }
+}
 impl<T, U> ICommand<U> for Command<CommandTool<T>>
   where T: ToolInvocation + 'static,
 {
@@ -141,6 +315,16 @@ impl<T, U> ICommand<U> for Command<CommandTool<T>>
       for prev in state.prev_outputs.drain(..) {
         self.cmd.add_tool_input(prev)?;
       }
+      for &role in self.concrete.input_roles.iter() {
+        for prev in state.take_outputs(role).into_iter() {
+          self.cmd.add_tool_input(prev)?;
+        }
+      }
+    }
+    // now the previous outputs are inputs, so they're checked too.
+    if let Some(args) = self.cmd.1.take() {
+      process_invocation_args(&mut self.cmd.0, args, false)
+        .map_err(|e| self.step_error(e) )?;
     }
 
     let mut queue = if self.output_override {
@@ -156,6 +340,10 @@ impl<T, U> ICommand<U> for Command<CommandTool<T>>
       }
       CommandQueue::new(o)
     };
+    queue.source_stem = Some(state.stem.clone());
+    queue.observers = state.observers.clone();
+    queue.flags = state.flags.clone();
+    queue.depth = state.depth + 1;
 
     info!("output: {}", out.display());
 
@@ -167,6 +355,21 @@ impl<T, U> ICommand<U> for Command<CommandTool<T>>
     Ok(())
   }
   fn concrete(&mut self) -> &mut ConcreteCommand { &mut self.concrete }
+  fn plan(&mut self, depth: usize, out: &mut Vec<String>)
+    -> Result<(), CommandQueueError>
+  {
+    // the previous outputs aren't known yet.
+    if let Some(args) = self.cmd.1.take() {
+      process_invocation_args(&mut self.cmd.0, args, true)
+        .map_err(|e| self.step_error(e) )?;
+    }
+    out.push(format!("{}{}: {}", plan_indent(depth), plan_name(&self.concrete),
+                     self.cmd.get_name()));
+    let output = self.cmd.get_output().cloned();
+    let mut queue = CommandQueue::new(output);
+    self.cmd.enqueue_commands(&mut queue)?;
+    queue.plan_into(depth + 1, out)
+  }
 }
 impl<T> ICommand<T> for Command<FunctionCommand<T>>
   where T: ToolInvocation,
@@ -179,6 +382,7 @@ impl<T> ICommand<T> for Command<FunctionCommand<T>>
     Ok((f)(invoc,)?)
   }
   fn concrete(&mut self) -> &mut ConcreteCommand { &mut self.concrete }
+  fn rerun_on_resume(&self) -> bool { true }
 }
 impl<T> ICommand<T> for Command<FunctionCommandWithState<T>>
   where T: ToolInvocation,
@@ -199,6 +403,10 @@ impl<U> ICommand<U> for Command<ExternalCommand> {
 
     let out = state.output(&self.intermediate_name);
 
+    for arg in self.concrete.intermediate_args.iter() {
+      self.cmd.0.arg(state.intermediate_dir().join(arg));
+    }
+
     if self.prev_outputs {
       if let Some(transform) = self.cmd.2.take() {
         let action = (transform)(&mut self.cmd.0, state.prev_outputs.as_ref());
@@ -222,6 +430,11 @@ impl<U> ICommand<U> for Command<ExternalCommand> {
         for prev in state.prev_outputs.drain(..) {
           self.cmd.0.arg(prev);
         }
+        for &role in self.concrete.input_roles.iter() {
+          for prev in state.take_outputs(role).into_iter() {
+            self.cmd.0.arg(prev);
+          }
+        }
       }
     }
 
@@ -235,16 +448,14 @@ impl<U> ICommand<U> for Command<ExternalCommand> {
         self.cmd.0.arg(out.as_path());
       }
 
-      let mut child = self.cmd.0.spawn()?;
-      let result = child.wait()?;
+      let result = run_with_retries(&self.concrete, &mut self.cmd.0, &state.flags)?;
 
       if !cant_fail && !result.success() {
         error!("command failed!");
         return Err(CommandQueueError::ProcessError(result.code()));
       }
     } else {
-      let mut child = self.cmd.0.spawn()?;
-      let result = child.wait()?;
+      let result = run_with_retries(&self.concrete, &mut self.cmd.0, &state.flags)?;
 
       if !cant_fail && !result.success() {
         error!("command failed!");
@@ -257,12 +468,218 @@ impl<U> ICommand<U> for Command<ExternalCommand> {
     Ok(())
   }
   fn concrete(&mut self) -> &mut ConcreteCommand { &mut self.concrete }
+  fn plan(&mut self, depth: usize, out: &mut Vec<String>)
+    -> Result<(), CommandQueueError>
+  {
+    if let Some(ref plan) = self.concrete.plan {
+      out.push(format!("{}{}: {}", plan_indent(depth), plan_name(&self.concrete), plan));
+      return Ok(());
+    }
+    let mut line = format!("{}{}: {}", plan_indent(depth), plan_name(&self.concrete),
+                           plan_command(&self.cmd.0));
+    for arg in self.concrete.intermediate_args.iter() {
+      line.push_str(&format!(" <intermediate:{}>", arg.display()));
+    }
+    // added when it runs, ahead of the previous outputs.
+    if let (&Some(ref out_arg), true) = (&self.cmd.1, self.output_override) {
+      line.push_str(&format!(" {} <output>", out_arg));
+    }
+    if self.prev_outputs {
+      line.push_str(" <inputs>");
+    }
+    out.push(line);
+    Ok(())
+  }
+}
+
+impl<U> ICommand<U> for Command<DisassembleCommand> {
+  fn run(&mut self, _: &mut &mut U,
+         state: &mut RunState) -> Result<(), CommandQueueError> {
+    use std::io::{Read, Write};
+
+    info!("on command: {:?} => {:?}", self.name, self.cmd);
+
+    let input = match self.cmd.input {
+      Some(ref input) => input.clone(),
+      None => state.prev_outputs.last()
+        .cloned()
+        .ok_or_else(|| "nothing to disassemble".to_string() )?,
+    };
+    let to_stdout = is_stdout(&self.cmd.output);
+    let output = if to_stdout {
+      state.intermediate_dir().join("disassembly.wast")
+    } else {
+      super::diag::note(&format!("writing wast to `{}`", self.cmd.output.display()));
+      self.cmd.output.clone()
+    };
+    if state.is_dry_run() {
+      return Ok(());
+    }
+
+    let mut cmd = process::Command::new(&self.cmd.wasm_dis);
+    cmd.arg(&input)
+      .arg("-o").arg(&output);
+    let result = run_with_retries(&self.concrete, &mut cmd, &state.flags)?;
+    if !result.success() {
+      return Err(CommandQueueError::ProcessError(result.code()));
+    }
+
+    let mut text = String::new();
+    fs::File::open(&output)?.read_to_string(&mut text)?;
+    let text = super::demangle::demangle_text(&text);
+    if to_stdout {
+      let stdout = std::io::stdout();
+      let mut stdout = stdout.lock();
+      stdout.write_all(text.as_bytes())?;
+      stdout.flush()?;
+    } else {
+      fs::File::create(&output)?.write_all(text.as_bytes())?;
+    }
+
+    Ok(())
+  }
+  fn concrete(&mut self) -> &mut ConcreteCommand { &mut self.concrete }
+}
+
+/// Run `concrete`'s `cmd`, killing it after its timeout (or
+/// `--step-timeout`), and running it again, up to `concrete.retries`
+/// times, if it fails or times out.
+fn run_with_retries(concrete: &ConcreteCommand, cmd: &mut process::Command,
+                    flags: &GlobalFlags)
+  -> Result<process::ExitStatus, CommandQueueError>
+{
+  let timeout = concrete.timeout.or(flags.step_timeout);
+  let name = concrete.name.as_ref()
+    .map(|name| &name[..] )
+    .unwrap_or("command");
+  let mut attempt = 0;
+  loop {
+    let status = spawn_and_wait(concrete, cmd, timeout, flags)?;
+    let failure = match status {
+      Some(status) if status.success() || concrete.cant_fail => return Ok(status),
+      Some(status) => format!("failed ({})", status),
+      None => format!("timed out after {}s and was killed",
+                      timeout.unwrap().as_secs()),
+    };
+    if attempt >= concrete.retries || super::interrupt::interrupted() {
+      return match status {
+        Some(status) => Ok(status),
+        None => Err(format!("[{}] {}: {:?}", name, failure, cmd).into()),
+      };
+    }
+    attempt += 1;
+    super::diag::warning(&format!("[{}] {}; retrying ({} of {})", name, failure,
+                                  attempt, concrete.retries));
+  }
+}
+
+/// Run `cmd` to completion, or until `timeout`; `None` if it timed out. In
+/// capture mode (see `capture_output`), its stderr is prefixed with the
+/// step name, a line at a time as it's written, so parallel/nested runs
+/// stay readable; stdout is passed on verbatim as it's often data (ie
+/// `-E`). With a `stderr_filter`, stderr is held until the command exits.
+fn spawn_and_wait(concrete: &ConcreteCommand, cmd: &mut process::Command,
+                  timeout: Option<Duration>, flags: &GlobalFlags)
+  -> Result<Option<process::ExitStatus>, CommandQueueError>
+{
+  use std::io::{self, Write};
+  use std::sync::{Arc, Mutex};
+
+  flags.apply_env(cmd);
+  super::report::step_command(format!("{:?}", cmd));
+  if let Some(ref pipeline) = flags.pipeline {
+    pipeline.record_command(cmd);
+  }
+
+  let capture = flags.capture_output();
+  let filter = concrete.stderr_filter.as_ref();
+  if !capture && filter.is_none() {
+    let (status, killed) = super::interrupt::status_timeout(cmd, timeout)?;
+    return Ok(if killed { None } else { Some(status) });
+  }
+
+  let name = concrete.name.as_ref()
+    .map(|name| name.to_string() )
+    .unwrap_or_else(|| "command".into() );
+  let write_stderr = move |name: &str, text: &str| {
+    if capture {
+      for line in text.lines() {
+        let _ = writeln!(io::stderr(), "[{}] {}", name, line);
+      }
+    } else {
+      let _ = io::stderr().write_all(text.as_bytes());
+    }
+  };
+  let held = Arc::new(Mutex::new(Vec::new()));
+  let hold = if filter.is_some() { Some(held.clone()) } else { None };
+  let prefix = name.clone();
+  let (status, killed) = super::interrupt::stream_timeout(cmd, timeout, |bytes| {
+    let _ = io::stdout().write_all(bytes);
+  }, move |line| {
+    match hold {
+      Some(ref hold) => hold.lock().unwrap().extend_from_slice(line),
+      None => write_stderr(&prefix, &String::from_utf8_lossy(line)),
+    }
+  })?;
+
+  if let Some(filter) = filter {
+    let stderr = String::from_utf8_lossy(&held.lock().unwrap()).into_owned();
+    write_stderr(&name, &(filter.0)(&stderr, !killed && status.success()));
+  }
+
+  if capture && (killed || !status.success()) {
+    use super::diag::{self, Severity};
+    let failed = if killed {
+      format!("[{}] command timed out: {:?}", name, cmd)
+    } else {
+      format!("[{}] command failed ({}): {:?}", name, status, cmd)
+    };
+    let failed = diag::format(Severity::Error, None, &failed, diag::color_enabled());
+    io::stderr().write_all(failed.as_bytes())?;
+  }
+
+  Ok(if killed { None } else { Some(status) })
 }
 
 pub trait ICommand<T>: Debug {
   fn run(&mut self, invoc: &mut &mut T,
          state: &mut RunState) -> Result<(), CommandQueueError>;
   fn concrete(&mut self) -> &mut ConcreteCommand;
+  /// Should this run again when `--resume` skips past it? Only for steps
+  /// which just change the invocation, since that isn't checkpointed.
+  fn rerun_on_resume(&self) -> bool { false }
+  /// Describe what `run` would do, without doing it. See
+  /// `CommandQueue::plan`.
+  fn plan(&mut self, depth: usize, out: &mut Vec<String>)
+    -> Result<(), CommandQueueError>
+  {
+    let concrete = self.concrete();
+    match concrete.plan {
+      Some(ref plan) => out.push(format!("{}{}: {}", plan_indent(depth),
+                                         plan_name(concrete), plan)),
+      None => out.push(format!("{}{}", plan_indent(depth), plan_name(concrete))),
+    }
+    Ok(())
+  }
+}
+
+/// `cmd` as a single line, for `CommandQueue::plan`.
+pub fn plan_command(cmd: &process::Command) -> String {
+  let mut line = format!("{}", Path::new(cmd.get_program()).display());
+  for arg in cmd.get_args() {
+    line.push(' ');
+    line.push_str(&arg.to_string_lossy());
+  }
+  line
+}
+
+fn plan_indent(depth: usize) -> String {
+  "  ".repeat(depth)
+}
+fn plan_name(concrete: &ConcreteCommand) -> String {
+  concrete.name.as_ref()
+    .map(|name| name.to_string() )
+    .unwrap_or_else(|| "<unnamed>".into() )
 }
 
 #[derive(Debug)]
@@ -270,25 +687,203 @@ pub struct RunState<'q> {
   pub idx: usize,
   pub final_output: Option<&'q PathBuf>,
   pub prev_outputs: Vec<PathBuf>,
-  pub intermediate: Option<TempDir>,
+  /// Non-primary outputs of previous commands, not yet consumed.
+  pub role_outputs: Vec<(OutputRole, PathBuf)>,
+  pub intermediate: Option<IntermediateDir>,
   pub is_last: bool,
   pub dry_run: bool,
+  /// Whether the current command can write to stdout itself; see
+  /// `ConcreteCommand::stdout_output`.
+  stdout_ok: bool,
+  /// Used to name intermediates; usually the basename of the first source.
+  pub stem: String,
+  /// The current command's default intermediate name.
+  default_name: Option<String>,
+  used_names: HashSet<String>,
+  /// Keep the intermediates if we don't finish, so `--resume` can pick up
+  /// where we left off.
+  checkpoint: bool,
+  completed: bool,
+  /// The global flags of the run; see `CommandQueue::set_flags`.
+  pub flags: GlobalFlags,
+  /// Passed on to the queues of tools run as a step.
+  observers: Observers,
+  depth: usize,
+}
+#[derive(Debug)]
+pub enum IntermediateDir {
+  Temp(TempDir),
+  /// Used in `--deterministic` mode; named by hashing the invocation, so
+  /// intermediate paths don't change from run to run. It's in
+  /// `user_temp_dir`, and locked, so identical concurrent runs take turns
+  /// rather than deleting each other's intermediates.
+  Stable(PathBuf, FileLock),
+}
+impl IntermediateDir {
+  fn new(final_output: Option<&PathBuf>, checkpoint: bool,
+         flags: &GlobalFlags)
+    -> Result<Self, Box<dyn Error>>
+  {
+    use std::env::{args_os, current_dir};
+    use std::sync::atomic::AtomicUsize;
+
+    // Queues can nest, so this distinguishes the nested dirs from the outer
+    // ones. Queues are always created in the same order, so this is stable.
+    static NEXT_QUEUE: AtomicUsize = AtomicUsize::new(0);
+
+    if !flags.deterministic && !checkpoint {
+      let dir = TempDir::new("wasm-driver-cmd-queue-intermediates")?;
+      return Ok(IntermediateDir::Temp(dir));
+    }
+
+    // `--resume` has to find the dir of the run it's resuming, even with
+    // a rebuilt driver, so the hash has to be stable.
+    let mut key = vec![current_dir()?.to_string_lossy().into_owned()];
+    key.extend(args_os()
+               .filter(|arg| arg != "--resume" )
+               .map(|arg| arg.to_string_lossy().into_owned() ));
+    key.push(final_output
+             .map(|out| out.to_string_lossy().into_owned() )
+             .unwrap_or_default());
+    key.push(NEXT_QUEUE.fetch_add(1, Ordering::SeqCst).to_string());
+
+    let name = format!("cmd-queue-intermediates.{}", super::stable_hash(&key));
+    let base = super::user_temp_dir()?;
+    let dir = base.join(&name);
+    let lock = lock(&base.join(format!("{}.lock", name)), "the intermediates dir",
+                    None)?;
+    if dir.exists() && !(checkpoint && flags.resume) {
+      // left over from a previous run which didn't clean up; no one else
+      // is using it, since we have the lock.
+      fs::remove_dir_all(&dir)?;
+    }
+    fs::create_dir_all(&dir)?;
+    Ok(IntermediateDir::Stable(dir, lock))
+  }
+  pub fn path(&self) -> &Path {
+    match self {
+      &IntermediateDir::Temp(ref dir) => dir.path(),
+      &IntermediateDir::Stable(ref dir, _) => dir.as_path(),
+    }
+  }
+  pub fn into_path(self) -> PathBuf {
+    match self {
+      IntermediateDir::Temp(dir) => dir.into_path(),
+      IntermediateDir::Stable(dir, _) => dir,
+    }
+  }
 }
 impl<'q> RunState<'q> {
-  fn new(final_output: Option<&'q PathBuf>) -> Result<RunState<'q>, Box<dyn Error>> {
+  fn new(final_output: Option<&'q PathBuf>, stem: String, checkpoint: bool,
+         flags: GlobalFlags)
+    -> Result<RunState<'q>, Box<dyn Error>>
+  {
+    let intermediate = IntermediateDir::new(final_output, checkpoint, &flags)?;
+    if !checkpoint {
+      super::interrupt::remove_on_exit(intermediate.path());
+    }
     Ok(RunState {
       idx: 0,
       final_output,
       prev_outputs: Vec::new(),
-      intermediate: Some(TempDir::new("wasm-driver-cmd-queue-intermediates")?),
+      role_outputs: Vec::new(),
+      intermediate: Some(intermediate),
       is_last: false,
       dry_run: false,
+      stdout_ok: false,
+      stem,
+      default_name: None,
+      used_names: HashSet::new(),
+      checkpoint,
+      completed: false,
+      flags,
+      observers: Default::default(),
+      depth: 0,
     })
   }
 
+  fn checkpoint_path(&self) -> PathBuf {
+    self.intermediate_dir().join("checkpoint")
+  }
+  /// Record that every command up to and including `self.idx` is done, and
+  /// the outputs they left for the rest.
+  fn save_checkpoint(&self) -> Result<(), Box<dyn Error>> {
+    use std::io::Write;
+
+    let mut out = format!("done {}\n", self.idx);
+    let outputs = self.prev_outputs.iter()
+      .map(|p| (OutputRole::Primary, p) )
+      .chain(self.role_outputs.iter().map(|&(r, ref p)| (r, p) ));
+    for (role, path) in outputs {
+      out.push_str(&format!("{} {}\n", role.as_str(), path.display()));
+    }
+
+    // write then rename, so an interrupted write can't leave a truncated
+    // checkpoint behind.
+    let tmp = self.intermediate_dir().join("checkpoint.tmp");
+    fs::File::create(&tmp)?.write_all(out.as_bytes())?;
+    fs::rename(tmp, self.checkpoint_path())?;
+    Ok(())
+  }
+  /// Restore the state saved by `save_checkpoint`, returning the number of
+  /// commands which can be skipped.
+  fn load_checkpoint(&mut self) -> Result<usize, Box<dyn Error>> {
+    use std::io::Read;
+
+    let path = self.checkpoint_path();
+    if !path.exists() {
+      return Ok(0);
+    }
+    let mut contents = String::new();
+    fs::File::open(&path)?.read_to_string(&mut contents)?;
+
+    let mut done = None;
+    for line in contents.lines() {
+      let mut split = line.splitn(2, ' ');
+      let (key, value) = match (split.next(), split.next()) {
+        (Some(key), Some(value)) => (key, value),
+        _ => Err(format!("malformed checkpoint `{}`", path.display()))?,
+      };
+      if key == "done" {
+        done = Some(value.parse::<usize>()?);
+        continue;
+      }
+      let role = OutputRole::parse(key)
+        .ok_or_else(|| format!("malformed checkpoint `{}`", path.display()) )?;
+      self.add_output(role, PathBuf::from(value));
+    }
+
+    Ok(done.map(|idx| idx + 1 ).unwrap_or(0))
+  }
+
+  /// Pick `<stem>.<stage>.<ext>` for the next command, adding the command
+  /// index if an earlier command already used that name.
+  fn name_next(&mut self, stage: Option<&str>, ext: Option<&str>) {
+    let stage = stage
+      .map(|stage| stage.trim_start_matches('-') )
+      .filter(|stage| stage.len() != 0 )
+      .map(|stage| stage.replace('/', "_") )
+      .unwrap_or_else(|| "step".into() );
+    let mut name = format!("{}.{}", self.stem, stage);
+    if !self.used_names.insert(name.clone()) {
+      name = format!("{}.{}-{}", self.stem, stage, self.idx);
+      self.used_names.insert(name.clone());
+    }
+    if let Some(ext) = ext {
+      name.push('.');
+      name.push_str(ext);
+    }
+    self.default_name = Some(name);
+  }
+
   pub fn output(&self, intermediate_name: &Option<PathBuf>) -> PathBuf {
     if self.is_last && self.final_output.is_some() {
-      self.final_output.as_ref().unwrap().to_path_buf()
+      let out = self.final_output.unwrap();
+      if is_stdout(out) && !self.stdout_ok {
+        self.stdout_intermediate()
+      } else {
+        out.to_path_buf()
+      }
     } else if let &Some(ref name) = intermediate_name {
       self.intermediate.as_ref()
         .unwrap()
@@ -298,19 +893,71 @@ impl<'q> RunState<'q> {
       self.intermediate.as_ref()
         .unwrap()
         .path()
-        .join(format!("{}", self.idx))
+        .join(self.default_name.clone()
+              .unwrap_or_else(|| format!("{}", self.idx) ))
     }
   }
   pub fn is_dry_run(&self) -> bool { self.dry_run }
+  /// Where the last command writes its output when it's going to stdout,
+  /// but the command can't do that itself.
+  fn stdout_intermediate(&self) -> PathBuf {
+    self.intermediate_dir().join("stdout")
+  }
+  /// Where intermediates live; unlike `output`, never the final output.
+  pub fn intermediate_dir(&self) -> &Path {
+    self.intermediate.as_ref().unwrap().path()
+  }
+
+  /// Record an output of the current command; `Primary` outputs go to
+  /// `prev_outputs`.
+  pub fn add_output(&mut self, role: OutputRole, path: PathBuf) {
+    match role {
+      OutputRole::Primary => self.prev_outputs.push(path),
+      role => self.role_outputs.push((role, path)),
+    }
+  }
+  /// Previous outputs with `role`, without consuming them.
+  pub fn outputs(&self, role: OutputRole) -> Vec<&PathBuf> {
+    match role {
+      OutputRole::Primary => self.prev_outputs.iter().collect(),
+      role => self.role_outputs.iter()
+        .filter(|&&(r, _)| r == role )
+        .map(|&(_, ref p)| p )
+        .collect(),
+    }
+  }
+  /// Consume the previous outputs with `role`.
+  pub fn take_outputs(&mut self, role: OutputRole) -> Vec<PathBuf> {
+    match role {
+      OutputRole::Primary => self.prev_outputs.drain(..).collect(),
+      role => {
+        let (taken, rest) = self.role_outputs
+          .drain(..)
+          .partition(|&(r, _)| r == role );
+        self.role_outputs = rest;
+        taken.into_iter()
+          .map(|(_, p)| p )
+          .collect()
+      },
+    }
+  }
 }
 impl<'q> Drop for RunState<'q> {
   fn drop(&mut self) {
+    if let Some(ref dir) = self.intermediate {
+      super::interrupt::keep_on_exit(dir.path());
+    }
     if boolean_env("WASM_TOOLCHAIN_SAVE_TMPS") {
       let tmp = self.intermediate
         .take()
         .unwrap()
         .into_path();
-      println!("Saving tmps in `{}`.", tmp.display());
+      super::diag::note(&format!("saving tmps in `{}`", tmp.display()));
+    } else if self.checkpoint && !self.completed {
+      // leave it for `--resume`.
+    } else if let Some(IntermediateDir::Stable(dir, lock)) = self.intermediate.take() {
+      let _ = fs::remove_dir_all(dir);
+      drop(lock);
     }
   }
 }
@@ -335,39 +982,80 @@ impl From<std::io::Error> for CommandQueueError {
     CommandQueueError::Error(From::from(v))
   }
 }
+/// What a `QueueObserver` is told about a step.
+#[derive(Debug)]
+pub struct StepInfo<'a> {
+  pub idx: usize,
+  /// 0 for the outermost queue's steps, 1 for the steps of a tool run as
+  /// one of those, etc.
+  pub depth: usize,
+  pub name: Option<&'a str>,
+  /// The step's `Debug` form, ie the command line for external commands.
+  /// Inputs from previous steps are added as the step runs, so they're
+  /// only in the form given to `after_step`.
+  pub command: String,
+}
+
+/// Callbacks around each step, for embedders which want progress or
+/// logging without parsing our output. Tools run as a step report their
+/// steps to the same observers.
+pub trait QueueObserver {
+  fn before_step(&mut self, _step: &StepInfo) {}
+  /// `outputs` are those the step produced; empty if it failed.
+  fn after_step(&mut self, _step: &StepInfo, _duration: Duration,
+                _outputs: &[PathBuf],
+                _result: Result<(), &CommandQueueError>) {}
+}
+
+#[derive(Clone, Default)]
+struct Observers(Vec<Rc<RefCell<Box<dyn QueueObserver>>>>);
+impl Debug for Observers {
+  fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+    write!(fmt, "Observers({})", self.0.len())
+  }
+}
+impl Observers {
+  fn each<F>(&self, mut f: F)
+    where F: FnMut(&mut dyn QueueObserver),
+  {
+    for observer in self.0.iter() {
+      f(&mut **observer.borrow_mut());
+    }
+  }
+}
+
 #[derive(Debug)]
 pub struct CommandQueue<T> {
   pub final_output: Option<PathBuf>,
+  /// Prefix for intermediate names. Defaults to the final output's stem.
+  pub source_stem: Option<String>,
 
   queue: Vec<Box<dyn ICommand<T>>>,
   verbose: bool,
   dry_run: bool,
+  checkpoint: bool,
+  flags: GlobalFlags,
+  observers: Observers,
+  depth: usize,
 }
 
 impl<T> CommandQueue<T>
   where T: ToolInvocation + 'static,
 {
   pub fn new(final_output: Option<PathBuf>) -> CommandQueue<T> {
-    CTRL_C_HANDLER.call_once(|| {
-      use ctrlc::set_handler;
-      let r = set_handler(|| {
-        if STOP_BEFORE_NEXT_JOB.load(Ordering::SeqCst) {
-          // exit now.
-          ::std::process::exit(1);
-        }
-        STOP_BEFORE_NEXT_JOB.store(true, Ordering::SeqCst);
-      });
-      if r.is_err() {
-        warn!("Couldn't set ctrl-c handler");
-      }
-    });
+    super::interrupt::install();
 
     CommandQueue {
       final_output,
+      source_stem: None,
 
       queue: Default::default(),
       verbose: false,
       dry_run: false,
+      checkpoint: false,
+      flags: GlobalFlags::current(),
+      observers: Default::default(),
+      depth: 0,
     }
   }
   pub fn set_verbose(&mut self, v: bool) {
@@ -376,84 +1064,55 @@ impl<T> CommandQueue<T>
   pub fn set_dry_run(&mut self, v: bool) {
     self.dry_run = v;
   }
+  /// Save progress after each command, so an interrupted run can be
+  /// continued with `--resume`. Only the outermost queue should set this;
+  /// nested queues are rerun in full.
+  pub fn set_checkpoint(&mut self, v: bool) {
+    self.checkpoint = v;
+  }
+  /// The global flags (`--deterministic`, `--hermetic`, etc) the steps are
+  /// run with. Defaults to the current ones.
+  pub fn set_flags(&mut self, flags: GlobalFlags) {
+    self.flags = flags;
+  }
+  pub fn flags_mut(&mut self) -> &mut GlobalFlags {
+    &mut self.flags
+  }
+  pub fn add_observer(&mut self, observer: Box<dyn QueueObserver>) {
+    self.observers.0.push(Rc::new(RefCell::new(observer)));
+  }
 
   pub fn enqueue_external<U>(&mut self, name: Option<&'static str>,
-                             mut cmd: process::Command,
+                             cmd: process::Command,
                              output_arg: Option<&'static str>,
                              cant_fail: bool,
                              tmp_dirs: Option<Vec<U>>)
     -> &mut ConcreteCommand
     where U: Into<Rc<TempDir>>,
   {
-    use std::process::{Stdio};
-
-    cmd.stdout(Stdio::inherit())
-      .stderr(Stdio::inherit())
-      .stdin(Stdio::inherit());
-
-    let kind =
-      ExternalCommand(cmd, output_arg.map(|v| From::from(v) ), None);
-    let concrete = ConcreteCommand {
-      name: name.map(|v| v.into() ),
-      cant_fail,
-      tmp_dirs: tmp_dirs
-        .map(|dirs| {
-          dirs.into_iter()
-            .map(|dir| dir.into() )
-            .collect::<Vec<_>>()
-        })
-        .unwrap_or_default(),
-      intermediate_name: None,
-      prev_outputs: true,
-      output_override: true,
-      copy_output_to: None,
-    };
-    let command = Command {
-      cmd: kind,
-      concrete,
-    };
-    let command = box command;
-
-    self.queue.push(command);
-    self.queue.last_mut().unwrap().concrete()
+    let mut concrete = ConcreteCommand::new(name.map(|v| v.into() ));
+    concrete.cant_fail = cant_fail;
+    concrete.tmp_dirs = collect_tmp_dirs(tmp_dirs);
+    let kind = ExternalCommand(inherit_stdio(cmd), output_arg.map(|v| From::from(v) ),
+                               None);
+    self.push(kind, concrete)
   }
 
   pub fn enqueue_simple_external<U>(&mut self,
                                     name: Option<U>,
-                                    mut cmd: process::Command,
+                                    cmd: process::Command,
                                     output_arg: Option<Cow<'static, str>>)
     -> &mut ConcreteCommand
     where U: Into<Cow<'static, str>>,
   {
-    use std::process::{Stdio};
-
-    cmd.stdout(Stdio::inherit())
-      .stderr(Stdio::inherit())
-      .stdin(Stdio::inherit());
-
-    let kind = ExternalCommand(cmd, output_arg, None);
-    let concrete = ConcreteCommand {
-      name: name.map(|v| v.into() ),
-      cant_fail: false,
-      tmp_dirs: Default::default(),
-      intermediate_name: None,
-      prev_outputs: true,
-      output_override: true,
-      copy_output_to: None,
-    };
-    let command = Command {
-      cmd: kind,
-      concrete,
-    };
-    let command = box command;
-
-    self.queue.push(command);
-    self.queue.last_mut().unwrap().concrete()
+    let concrete = ConcreteCommand::new(name.map(|v| v.into() ));
+    let kind = ExternalCommand(inherit_stdio(cmd), output_arg, None);
+    self.push(kind, concrete)
   }
 
   pub fn enqueue_external_with_input_transform<F, U, V>(&mut self,
                                                         name: Option<U>,
-                                                        mut cmd: process::Command,
+                                                        cmd: process::Command,
                                                         output_arg: Option<V>,
                                                         f: F)
     -> &mut ConcreteCommand
@@ -461,32 +1120,12 @@ impl<T> CommandQueue<T>
           U: Into<Cow<'static, str>>,
           V: Into<Cow<'static, str>>,
   {
-    use std::process::{Stdio};
-
-    cmd.stdout(Stdio::inherit())
-      .stderr(Stdio::inherit())
-      .stdin(Stdio::inherit());
-
     let f = box f as Box<_>;
 
-    let kind = ExternalCommand(cmd, output_arg.map(|v| v.into() ), Some(f));
-    let concrete = ConcreteCommand {
-      name: name.map(|v| v.into() ),
-      cant_fail: false,
-      tmp_dirs: Default::default(),
-      intermediate_name: None,
-      prev_outputs: true,
-      output_override: true,
-      copy_output_to: None,
-    };
-    let command = Command {
-      cmd: kind,
-      concrete,
-    };
-    let command = box command;
-
-    self.queue.push(command);
-    self.queue.last_mut().unwrap().concrete()
+    let concrete = ConcreteCommand::new(name.map(|v| v.into() ));
+    let kind = ExternalCommand(inherit_stdio(cmd), output_arg.map(|v| v.into() ),
+                               Some(f));
+    self.push(kind, concrete)
   }
 
   pub fn enqueue_tool<U, V>(&mut self,
@@ -500,30 +1139,34 @@ impl<T> CommandQueue<T>
   {
     process_invocation_args(&mut invocation, args, true)?;
 
-    let concrete = ConcreteCommand {
-      name: name.map(|v| v.into() ),
-      cant_fail,
-      tmp_dirs: tmp_dirs
-        .map(|dirs| {
-          dirs.into_iter()
-            .map(|dir| dir.into() )
-            .collect::<Vec<_>>()
-        })
-        .unwrap_or_default(),
-      intermediate_name: None,
-      prev_outputs: true,
-      output_override: true,
-      copy_output_to: None,
-    };
-    let command = Command {
-      cmd: CommandTool(invocation),
-      concrete,
-    };
-    let command = box command;
-
-    self.queue.push(command);
-
-    Ok(self.queue.last_mut().unwrap().concrete())
+    Ok(self.push_tool(name, CommandTool(invocation, None), cant_fail, tmp_dirs))
+  }
+  /// `enqueue_tool`, but `args` are processed (and the tool's state
+  /// checked) when the step runs, after the previous outputs are added to
+  /// its inputs; so errors come from the step, and the inputs check covers
+  /// the previous outputs.
+  pub fn enqueue_tool_deferred<U, V>(&mut self,
+                                     name: Option<&'static str>,
+                                     invocation: U, args: Vec<String>,
+                                     cant_fail: bool,
+                                     tmp_dirs: Option<Vec<V>>)
+    -> &mut ConcreteCommand
+    where U: ToolInvocation + 'static,
+          V: Into<Rc<TempDir>>,
+  {
+    self.push_tool(name, CommandTool(invocation, Some(args)), cant_fail, tmp_dirs)
+  }
+  fn push_tool<U, V>(&mut self, name: Option<&'static str>,
+                     tool: CommandTool<U>, cant_fail: bool,
+                     tmp_dirs: Option<Vec<V>>)
+    -> &mut ConcreteCommand
+    where U: ToolInvocation + 'static,
+          V: Into<Rc<TempDir>>,
+  {
+    let mut concrete = ConcreteCommand::new(name.map(|v| v.into() ));
+    concrete.cant_fail = cant_fail;
+    concrete.tmp_dirs = collect_tmp_dirs(tmp_dirs);
+    self.push(tool, concrete)
   }
   pub fn enqueue_simple_tool<U>(&mut self,
                                 name: Option<&'static str>,
@@ -531,24 +1174,8 @@ impl<T> CommandQueue<T>
     -> &mut ConcreteCommand
     where U: ToolInvocation + 'static,
   {
-    let concrete = ConcreteCommand {
-      name: name.map(|v| v.into() ),
-      cant_fail: false,
-      tmp_dirs: vec![],
-      intermediate_name: None,
-      prev_outputs: true,
-      output_override: true,
-      copy_output_to: None,
-    };
-    let command = Command {
-      cmd: CommandTool(invoc),
-      concrete,
-    };
-    let command = box command;
-
-    self.queue.push(command);
-
-    self.queue.last_mut().unwrap().concrete()
+    let concrete = ConcreteCommand::new(name.map(|v| v.into() ));
+    self.push(CommandTool(invoc, None), concrete)
   }
   pub fn enqueue_function<U, F>(&mut self,
                                 name: Option<U>,
@@ -558,24 +1185,8 @@ impl<T> CommandQueue<T>
           F: FnOnce(&mut &mut T) -> Result<(), CommandQueueError> + 'static,
   {
     let f_box = box f as Box<_>;
-    let kind = FunctionCommand(Some(f_box));
-    let concrete = ConcreteCommand {
-      name: name.map(|v| v.into() ),
-      cant_fail: false,
-      tmp_dirs: Default::default(),
-      intermediate_name: None,
-      prev_outputs: false,
-      output_override: false,
-      copy_output_to: None,
-    };
-    let command = Command {
-      cmd: kind,
-      concrete,
-    };
-    let command = box command;
-
-    self.queue.push(command);
-    self.queue.last_mut().unwrap().concrete()
+    let concrete = ConcreteCommand::in_process(name.map(|v| v.into() ));
+    self.push(FunctionCommand(Some(f_box)), concrete)
   }
   pub fn enqueue_state_function<U, F>(&mut self,
                                       name: Option<U>,
@@ -585,22 +1196,36 @@ impl<T> CommandQueue<T>
           F: FnOnce(&mut &mut T, &mut RunState) -> Result<(), CommandQueueError> + 'static,
   {
     let f_box = box f as Box<_>;
-    let kind = FunctionCommandWithState(Some(f_box));
-    let concrete = ConcreteCommand {
-      name: name.map(|v| v.into() ),
-      cant_fail: false,
-      tmp_dirs: Default::default(),
-      intermediate_name: None,
-      prev_outputs: false,
-      output_override: false,
-      copy_output_to: None,
+    let concrete = ConcreteCommand::in_process(name.map(|v| v.into() ));
+    self.push(FunctionCommandWithState(Some(f_box)), concrete)
+  }
+
+  /// Disassemble `input` (or the last previous output) into `output`. See
+  /// `DisassembleCommand`.
+  pub fn enqueue_disassemble<U>(&mut self, name: Option<U>,
+                                wasm_dis: PathBuf,
+                                input: Option<PathBuf>,
+                                output: PathBuf)
+    -> &mut ConcreteCommand
+    where U: Into<Cow<'static, str>>,
+  {
+    let kind = DisassembleCommand {
+      wasm_dis,
+      input,
+      output,
     };
-    let command = Command {
-      cmd: kind,
+    let concrete = ConcreteCommand::in_process(name.map(|v| v.into() ));
+    self.push(kind, concrete)
+  }
+
+  fn push<C>(&mut self, cmd: C, concrete: ConcreteCommand) -> &mut ConcreteCommand
+    where C: Debug + 'static,
+          Command<C>: ICommand<T>,
+  {
+    let command = box Command {
+      cmd,
       concrete,
     };
-    let command = box command;
-
     self.queue.push(command);
     self.queue.last_mut().unwrap().concrete()
   }
@@ -614,7 +1239,33 @@ impl<T> CommandQueue<T>
       .concrete()
   }
 
+  /// Describe the commands in the queue, one per line, without running
+  /// them; the commands of tools run as a step follow, indented. Command
+  /// lines are as they were enqueued: `<inputs>` and `<output>` mark where
+  /// previous outputs and intermediates would be added. This consumes the
+  /// queue, as planning tools enqueues their commands.
+  pub fn plan(mut self) -> Result<Vec<String>, CommandQueueError> {
+    let mut out = Vec::new();
+    self.plan_into(0, &mut out)?;
+    Ok(out)
+  }
+  fn plan_into(&mut self, depth: usize, out: &mut Vec<String>)
+    -> Result<(), CommandQueueError>
+  {
+    for cmd in self.queue.iter_mut() {
+      cmd.plan(depth, out)?;
+    }
+    Ok(())
+  }
+
   pub fn run_all(&mut self, mut invoc: &mut T) -> Result<(), CommandQueueError> {
+    let stem = self.source_stem.clone()
+      .or_else(|| {
+        self.final_output.as_ref()
+          .and_then(|out| out.file_stem() )
+          .map(|stem| stem.to_string_lossy().into_owned() )
+      })
+      .unwrap_or_else(|| "out".into() );
     let cmd_len = self.queue.len();
     let iter =
       self.queue
@@ -624,19 +1275,115 @@ impl<T> CommandQueue<T>
           (idx == cmd_len - 1, idx, v)
         });
 
+    let checkpoint = self.checkpoint && !self.dry_run;
     let mut state =
-      RunState::new(self.final_output.as_ref())?;
+      RunState::new(self.final_output.as_ref(), stem, checkpoint,
+                    self.flags.clone())?;
+    state.observers = self.observers.clone();
+    state.depth = self.depth;
+    let skip = if checkpoint && self.flags.resume {
+      state.load_checkpoint()?
+    } else {
+      0
+    };
+    if skip > 0 {
+      super::diag::note(&format!("resuming after {} completed step(s)", skip));
+    }
+
     for (is_last, idx, mut cmd) in iter {
-      if STOP_BEFORE_NEXT_JOB.load(Ordering::SeqCst) {
+      if super::interrupt::interrupted() {
+        if checkpoint {
+          super::diag::note("interrupted; rerun with `--resume` to continue");
+        }
         return Err(CommandQueueError::ProcessError(Some(1)));
       }
       state.dry_run = self.dry_run;
       state.is_last = is_last;
       state.idx = idx;
+      {
+        let concrete = cmd.concrete();
+        state.stdout_ok = concrete.stdout_output;
+        state.name_next(concrete.name.as_ref().map(|n| n.as_ref() ),
+                        concrete.output_ext.as_ref().map(|e| e.as_ref() ));
+      }
+      if idx < skip {
+        // ie sysroot's `switch-target`, so the remaining steps see the
+        // invocation as it was when they were enqueued.
+        if cmd.rerun_on_resume() {
+          cmd.run(&mut invoc, &mut state)?;
+        }
+        continue;
+      }
+
+      let name = cmd.concrete().name.clone();
+      {
+        let name = name.as_ref()
+          .map(|n| n.as_ref() )
+          .unwrap_or("<unnamed>");
+        super::report::enter_step(format!("{} (step {})", name, idx));
+        if let Some(ref pipeline) = self.flags.pipeline {
+          pipeline.record_step(name.to_string());
+        }
+      }
+      let mut info = StepInfo {
+        idx,
+        depth: self.depth,
+        name: name.as_ref().map(|n| n.as_ref() ),
+        command: format!("{:?}", cmd),
+      };
+      self.observers.each(|o| o.before_step(&info) );
+      let prev_outputs = state.prev_outputs.clone();
+      let start = Instant::now();
+
+      let slot = if cmd.concrete().heavy && !self.dry_run {
+        Some(super::jobs::acquire()?)
+      } else {
+        None
+      };
+      let result = cmd.run(&mut invoc, &mut state);
+      drop(slot);
+      info.command = format!("{:?}", cmd);
+      if let Err(err) = result {
+        self.observers.each(|o| {
+          o.after_step(&info, start.elapsed(), &[], Err(&err));
+        });
+        if checkpoint {
+          super::diag::note(&format!("step {} failed; rerun with `--resume` to \
+                                      continue from it", idx));
+        }
+        return Err(err);
+      }
+
+      let mut step_outputs: Vec<PathBuf> = state.prev_outputs.iter()
+        .filter(|out| !prev_outputs.contains(out) )
+        .cloned()
+        .collect();
+      let outputs = cmd.concrete().outputs.clone();
+      for (role, path) in outputs.into_iter() {
+        let path = if path.is_relative() {
+          state.intermediate.as_ref().unwrap().path().join(path)
+        } else {
+          path
+        };
+        step_outputs.push(path.clone());
+        state.add_output(role, path);
+      }
+      self.observers.each(|o| {
+        o.after_step(&info, start.elapsed(), &step_outputs[..], Ok(()));
+      });
+
+      if checkpoint {
+        state.save_checkpoint()?;
+      }
+    }
 
-      cmd.run(&mut invoc, &mut state)?;
+    // the last command wrote what's meant for stdout to a file.
+    let stdout_intermediate = state.stdout_intermediate();
+    if !self.dry_run && stdout_intermediate.exists() {
+      write_to_stdout(&stdout_intermediate)?;
     }
 
+    state.completed = true;
     Ok(())
   }
 }
\ No newline at end of file
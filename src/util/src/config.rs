@@ -0,0 +1,65 @@
+
+//! The toolchain config file, for defaults which would otherwise have to be
+//! given on every command line. It's `$WASM_TOOLCHAIN_CONFIG`, or
+//! `~/.wasm-toolchain/config`, and is made of `key = value` lines; `#`
+//! starts a comment. Explicit options always win over it.
+
+use std::collections::HashMap;
+use std::env::var_os;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+
+pub const CONFIG_ENV: &'static str = "WASM_TOOLCHAIN_CONFIG";
+
+lazy_static! {
+  static ref CONFIG: HashMap<String, String> = load();
+}
+
+pub fn config_path() -> Option<PathBuf> {
+  use dirs::home_dir;
+
+  var_os(CONFIG_ENV)
+    .map(PathBuf::from)
+    .or_else(|| {
+      home_dir()
+        .map(|home| home.join(".wasm-toolchain").join("config") )
+    })
+}
+
+fn load() -> HashMap<String, String> {
+  let mut out = HashMap::new();
+  let path = match config_path() {
+    Some(path) => path,
+    None => return out,
+  };
+  let mut contents = String::new();
+  match File::open(&path).and_then(|mut f| f.read_to_string(&mut contents) ) {
+    Ok(_) => {},
+    Err(_) => return out,
+  }
+
+  for (lineno, line) in contents.lines().enumerate() {
+    let line = line.splitn(2, '#').next().unwrap().trim();
+    if line.len() == 0 { continue; }
+
+    let mut split = line.splitn(2, '=');
+    match (split.next(), split.next()) {
+      (Some(key), Some(value)) => {
+        out.insert(key.trim().to_string(), value.trim().to_string());
+      },
+      _ => {
+        let location = format!("{}:{}", path.display(), lineno + 1);
+        super::diag::emit(super::diag::Severity::Warning, Some(&location),
+                          "expected `key = value`");
+      },
+    }
+  }
+
+  out
+}
+
+/// The value of `key`, if set.
+pub fn get(key: &str) -> Option<&'static str> {
+  CONFIG.get(key).map(|v| &v[..] )
+}
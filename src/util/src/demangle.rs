@@ -0,0 +1,27 @@
+
+//! Demangling of the symbols in text output, ie wasm-dis's; in place of
+//! piping through `c++filt`.
+
+use std::borrow::Cow;
+
+use cpp_demangle::{DemangleOptions, Symbol, };
+use regex::{Captures, Regex, };
+
+lazy_static! {
+  static ref MANGLED: Regex = Regex::new(r"\b_Z[0-9A-Za-z_.$]+").unwrap();
+}
+
+/// Demangle one symbol, if it is one.
+pub fn demangle(symbol: &str) -> Option<String> {
+  let symbol = Symbol::new(symbol.as_bytes()).ok()?;
+  symbol.demangle(&DemangleOptions::default()).ok()
+}
+
+/// Replace every mangled symbol in `text`. Anything which doesn't demangle
+/// is left as is.
+pub fn demangle_text(text: &str) -> Cow<str> {
+  MANGLED.replace_all(text, |cap: &Captures| {
+    let symbol = cap.get(0).unwrap().as_str();
+    demangle(symbol).unwrap_or_else(|| symbol.to_string() )
+  })
+}
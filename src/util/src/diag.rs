@@ -0,0 +1,174 @@
+//! The drivers' own diagnostics: `error:`, `warning:` and `note:` prefixed
+//! lines on stderr, like clang's (so IDE problem matchers pick them up),
+//! colored when stderr is a terminal.
+//!
+//! `$WASM_DRIVER_COLOR` (`always`, `never` or `auto`; `--no-color` sets
+//! `never`) overrides the terminal check; so does `$NO_COLOR`, if it's
+//! unset.
+//!
+//! In process callers can `capture` them instead.
+
+use std::cell::RefCell;
+use std::env;
+use std::io::{self, Write};
+use std::mem::replace;
+use std::sync::{Arc, Mutex};
+
+use atty;
+
+pub const COLOR_ENV: &'static str = "WASM_DRIVER_COLOR";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ColorChoice {
+  Always,
+  Never,
+  Auto,
+}
+
+/// What `--no-color` or `$WASM_DRIVER_COLOR` asks for.
+pub fn color_choice() -> ColorChoice {
+  if super::GlobalFlags::with_current(|flags| flags.no_color ) {
+    return ColorChoice::Never;
+  }
+  match env::var(COLOR_ENV) {
+    Ok(ref v) if v == "always" => ColorChoice::Always,
+    Ok(ref v) if v == "never" => ColorChoice::Never,
+    Ok(ref v) if v == "auto" => ColorChoice::Auto,
+    _ if env::var_os("NO_COLOR").is_some() => ColorChoice::Never,
+    _ => ColorChoice::Auto,
+  }
+}
+/// Should what's written to stderr be colored?
+pub fn color_enabled() -> bool {
+  match color_choice() {
+    ColorChoice::Always => true,
+    ColorChoice::Never => false,
+    ColorChoice::Auto => atty::is(atty::Stream::Stderr),
+  }
+}
+
+/// `s` in the SGR color/style `code`, if `color`.
+pub fn paint(code: &str, s: &str, color: bool) -> String {
+  if color {
+    format!("\x1b[{}m{}\x1b[0m", code, s)
+  } else {
+    s.to_string()
+  }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Severity {
+  Error,
+  Warning,
+  Note,
+}
+impl Severity {
+  pub fn name(&self) -> &'static str {
+    match self {
+      &Severity::Error => "error",
+      &Severity::Warning => "warning",
+      &Severity::Note => "note",
+    }
+  }
+  fn prefix(&self) -> &'static str {
+    match self {
+      &Severity::Error => "error:",
+      &Severity::Warning => "warning:",
+      &Severity::Note => "note:",
+    }
+  }
+  fn style(&self) -> &'static str {
+    match self {
+      &Severity::Error => "1;31",
+      &Severity::Warning => "1;35",
+      &Severity::Note => "1;36",
+    }
+  }
+}
+
+/// The diagnostic line(s) for `msg`, at `location` (ie `file:line`) if
+/// there is one.
+pub fn format(severity: Severity, location: Option<&str>, msg: &str,
+              color: bool) -> String {
+  let mut out = String::new();
+  if let Some(location) = location {
+    out.push_str(&paint("1", &format!("{}:", location), color));
+    out.push(' ');
+  }
+  out.push_str(&paint(severity.style(), severity.prefix(), color));
+  out.push(' ');
+  out.push_str(msg);
+  out.push('\n');
+  out
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Diagnostic {
+  pub severity: Severity,
+  pub location: Option<String>,
+  pub message: String,
+}
+
+/// Where a `capture` collects diagnostics.
+pub type Sink = Arc<Mutex<Vec<Diagnostic>>>;
+
+thread_local! {
+  static SINK: RefCell<Option<Sink>> = RefCell::new(None);
+}
+
+/// Makes the sink it replaced current again when dropped, so a panic in
+/// `capture` doesn't leave diagnostics captured; see `enter_sink`.
+#[must_use]
+pub struct SinkGuard(Option<Sink>);
+impl Drop for SinkGuard {
+  fn drop(&mut self) {
+    let prev = self.0.take();
+    SINK.with(|sink| *sink.borrow_mut() = prev );
+  }
+}
+
+/// The sink diagnostics emitted on this thread go to, if any. Threads
+/// started while capturing have to `enter_sink` it themselves.
+pub fn current_sink() -> Option<Sink> {
+  SINK.with(|sink| sink.borrow().clone() )
+}
+/// Send this thread's diagnostics to `sink` (stderr if `None`) until the
+/// guard is dropped.
+pub fn enter_sink(sink: Option<Sink>) -> SinkGuard {
+  let prev = SINK.with(|current| replace(&mut *current.borrow_mut(), sink) );
+  SinkGuard(prev)
+}
+
+/// Run `f`, collecting the diagnostics emitted on this thread (and on
+/// those which `enter_sink` its sink) while it runs, rather than writing
+/// them to stderr.
+pub fn capture<F, R>(f: F) -> (R, Vec<Diagnostic>)
+  where F: FnOnce() -> R,
+{
+  let sink = Sink::default();
+  let r = {
+    let _sink = enter_sink(Some(sink.clone()));
+    f()
+  };
+  let diags = replace(&mut *sink.lock().unwrap(), vec![]);
+  (r, diags)
+}
+
+pub fn emit(severity: Severity, location: Option<&str>, msg: &str) {
+  if let Some(sink) = current_sink() {
+    if let Ok(mut captured) = sink.lock() {
+      captured.push(Diagnostic {
+        severity,
+        location: location.map(|l| l.to_string() ),
+        message: msg.to_string(),
+      });
+      return;
+    }
+  }
+  let out = format(severity, location, msg, color_enabled());
+  let _ = io::stderr().write_all(out.as_bytes());
+}
+
+pub fn error(msg: &str) { emit(Severity::Error, None, msg) }
+pub fn warning(msg: &str) { emit(Severity::Warning, None, msg) }
+pub fn note(msg: &str) { emit(Severity::Note, None, msg) }
@@ -0,0 +1,284 @@
+
+//! `--explain-changes`: shows how this run's pipeline (each step and the
+//! command it ran) differs from the one recorded by the last
+//! `--explain-changes` run for the same output, so it's clear why an
+//! incremental build reran a step. Only those runs record their pipeline,
+//! in the `Pipeline` on their `GlobalFlags` (and they don't save it under
+//! `--hermetic`); the `MAX_RECORDS` most recent are kept.
+
+use std::env;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use regex::Regex;
+
+const MAX_RECORDS: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+  pub name: String,
+  /// Program, then args; empty for steps which run in process.
+  pub command: Vec<String>,
+}
+
+lazy_static! {
+  /// Intermediate dirs are random (or hashed from the invocation), so
+  /// they're masked before comparing.
+  static ref INTERMEDIATES_RE: Regex =
+    Regex::new(r"/[^/]*wasm-driver-cmd-queue-intermediates[^/]*").unwrap();
+}
+
+/// The steps of one run, shared by its nested queues.
+#[derive(Clone, Debug, Default)]
+pub struct Pipeline(Arc<Mutex<Vec<Step>>>);
+
+impl Pipeline {
+  /// Called by the command queue as each step starts.
+  pub fn record_step(&self, name: String) {
+    if let Ok(mut pipeline) = self.0.lock() {
+      pipeline.push(Step {
+        name,
+        command: Vec::new(),
+      });
+    }
+  }
+  /// Called just before the current step spawns `cmd`.
+  pub fn record_command(&self, cmd: &::std::process::Command) {
+    let mut command = vec![format!("{}", Path::new(cmd.get_program()).display())];
+    command.extend(cmd.get_args().map(|arg| arg.to_string_lossy().into_owned() ));
+    let command = command.into_iter()
+      .map(|arg| INTERMEDIATES_RE.replace_all(&arg, "/<intermediates>").into_owned() )
+      .collect();
+    if let Ok(mut pipeline) = self.0.lock() {
+      if let Some(step) = pipeline.last_mut() {
+        step.command = command;
+      }
+    }
+  }
+  pub fn steps(&self) -> Result<Vec<Step>, Box<Error>> {
+    let steps = self.0.lock()
+      .map_err(|_| "pipeline record poisoned" )?;
+    Ok(steps.clone())
+  }
+}
+
+fn record_path(output: &Path) -> Option<PathBuf> {
+  use dirs::{cache_dir, home_dir};
+
+  let output = env::current_dir().ok()?.join(output);
+  let dir = cache_dir()
+    .or_else(|| home_dir().map(|home| home.join(".cache")) )?
+    .join("wasm-driver")
+    .join("pipelines");
+  Some(dir.join(super::stable_hash(&[output.to_string_lossy().as_bytes()])))
+}
+
+fn escape(s: &str) -> String {
+  s.replace('\\', "\\\\").replace('\n', "\\n")
+}
+fn unescape(s: &str) -> String {
+  let mut out = String::with_capacity(s.len());
+  let mut chars = s.chars();
+  while let Some(c) = chars.next() {
+    if c != '\\' {
+      out.push(c);
+      continue;
+    }
+    match chars.next() {
+      Some('n') => out.push('\n'),
+      Some(c) => out.push(c),
+      None => {},
+    }
+  }
+  out
+}
+
+/// One `step <name>` line per step, followed by a `\t<arg>` line per arg.
+pub fn serialize(pipeline: &[Step]) -> String {
+  let mut out = String::new();
+  for step in pipeline.iter() {
+    out.push_str(&format!("step {}\n", escape(&step.name)));
+    for arg in step.command.iter() {
+      out.push_str(&format!("\t{}\n", escape(arg)));
+    }
+  }
+  out
+}
+pub fn deserialize(s: &str) -> Vec<Step> {
+  let mut out: Vec<Step> = Vec::new();
+  for line in s.lines() {
+    if line.starts_with("step ") {
+      out.push(Step {
+        name: unescape(&line["step ".len()..]),
+        command: Vec::new(),
+      });
+    } else if line.starts_with('\t') {
+      if let Some(step) = out.last_mut() {
+        step.command.push(unescape(&line[1..]));
+      }
+    }
+  }
+  out
+}
+
+struct Colors {
+  enabled: bool,
+}
+impl Colors {
+  fn paint(&self, code: &str, s: &str) -> String {
+    super::diag::paint(code, s, self.enabled)
+  }
+  fn added(&self, s: &str) -> String { self.paint("32", s) }
+  fn removed(&self, s: &str) -> String { self.paint("31", s) }
+  fn changed(&self, s: &str) -> String { self.paint("33", s) }
+}
+
+/// Args in `a` which aren't in `b`, counting repeats.
+fn missing_from<'a>(a: &'a [String], b: &[String]) -> Vec<&'a String> {
+  let mut b: Vec<&String> = b.iter().collect();
+  a.iter()
+    .filter(|arg| {
+      match b.iter().position(|b| b == arg ) {
+        Some(idx) => {
+          b.remove(idx);
+          false
+        },
+        None => true,
+      }
+    })
+    .collect()
+}
+
+/// Steps are matched up by name (and how many times the name was seen
+/// before), so an added or removed step doesn't make all the following
+/// ones look changed.
+pub fn explain(prev: &[Step], next: &[Step], color: bool) -> String {
+  let colors = Colors {
+    enabled: color,
+  };
+  fn keyed(pipeline: &[Step]) -> Vec<(&str, usize)> {
+    let mut out = Vec::new();
+    for (idx, step) in pipeline.iter().enumerate() {
+      let nth = pipeline[..idx].iter()
+        .filter(|s| s.name == step.name )
+        .count();
+      out.push((&step.name[..], nth));
+    }
+    out
+  }
+  let prev_keys = keyed(prev);
+  let next_keys = keyed(next);
+
+  let mut out = String::new();
+  let mut unchanged = 0;
+  for (idx, key) in prev_keys.iter().enumerate() {
+    if !next_keys.contains(key) {
+      out.push_str(&colors.removed(&format!("- {} (removed)", prev[idx].name)));
+      out.push('\n');
+    }
+  }
+  for (idx, key) in next_keys.iter().enumerate() {
+    let step = &next[idx];
+    let prev = match prev_keys.iter().position(|k| k == key ) {
+      Some(prev_idx) => &prev[prev_idx],
+      None => {
+        out.push_str(&colors.added(&format!("+ {} (new)", step.name)));
+        out.push('\n');
+        continue;
+      },
+    };
+    if prev.command == step.command {
+      unchanged += 1;
+      continue;
+    }
+
+    out.push_str(&colors.changed(&format!("~ {}", step.name)));
+    out.push('\n');
+    match (prev.command.first(), step.command.first()) {
+      (Some(a), Some(b)) if a != b => {
+        out.push_str(&colors.removed(&format!("    - program {}", a)));
+        out.push('\n');
+        out.push_str(&colors.added(&format!("    + program {}", b)));
+        out.push('\n');
+      },
+      _ => {},
+    }
+    let prev_args = prev.command.get(1..).unwrap_or(&[]);
+    let next_args = step.command.get(1..).unwrap_or(&[]);
+    let removed = missing_from(prev_args, next_args);
+    let added = missing_from(next_args, prev_args);
+    for arg in removed.iter() {
+      out.push_str(&colors.removed(&format!("    - {}", arg)));
+      out.push('\n');
+    }
+    for arg in added.iter() {
+      out.push_str(&colors.added(&format!("    + {}", arg)));
+      out.push('\n');
+    }
+    if removed.len() == 0 && added.len() == 0 {
+      out.push_str("    (same args, reordered)\n");
+    }
+  }
+  out.push_str(&format!("{} step(s) unchanged\n", unchanged));
+  out
+}
+
+/// Note how this run's pipeline differs from the last one recorded for
+/// `output`.
+pub fn explain_changes(output: &Path, pipeline: &Pipeline) -> Result<(), Box<Error>> {
+  use super::diag;
+
+  let next = pipeline.steps()?;
+  let path = match record_path(output) {
+    Some(path) => path,
+    None => return Ok(()),
+  };
+  if !path.exists() {
+    diag::note(&format!("no previous run of `{}` recorded", output.display()));
+    return Ok(());
+  }
+  let mut prev = String::new();
+  File::open(&path)?.read_to_string(&mut prev)?;
+  // captured diagnostics are plain text.
+  let color = diag::color_enabled() && diag::current_sink().is_none();
+  let changes = explain(&deserialize(&prev), &next, color);
+  diag::note(&format!("changes since the last run of `{}`:\n{}",
+                      output.display(), changes.trim_end()));
+  Ok(())
+}
+
+/// Record this run's pipeline for the next `--explain-changes`.
+pub fn save_pipeline(output: &Path, pipeline: &Pipeline) -> Result<(), Box<Error>> {
+  let path = match record_path(output) {
+    Some(path) => path,
+    None => return Ok(()),
+  };
+  let pipeline = pipeline.steps()?;
+  let dir = path.parent().unwrap();
+  fs::create_dir_all(dir)?;
+  // concurrent runs for the same output each write their own.
+  let tmp = path.with_extension(format!("{}.tmp", ::std::process::id()));
+  File::create(&tmp)?.write_all(serialize(&pipeline[..]).as_bytes())?;
+  fs::rename(tmp, &path)?;
+  prune(dir, MAX_RECORDS)?;
+  Ok(())
+}
+
+/// Remove all but the `keep` most recently written records in `dir`.
+fn prune(dir: &Path, keep: usize) -> Result<(), Box<Error>> {
+  let mut records = Vec::new();
+  for entry in fs::read_dir(dir)? {
+    let entry = entry?;
+    let modified = entry.metadata()?.modified()?;
+    records.push((modified, entry.path()));
+  }
+  if records.len() <= keep { return Ok(()); }
+  records.sort();
+  for &(_, ref path) in records[..records.len() - keep].iter() {
+    let _ = fs::remove_file(path);
+  }
+  Ok(())
+}
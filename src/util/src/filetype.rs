@@ -82,6 +82,7 @@ fn get_file_cache() -> Arc<Mutex<HashMap<PathBuf, &'static [u8]>>> {
 
 pub fn override_file_contents<T: AsRef<Path>>(p: T, contents: &'static [u8]) {
   let cache = get_file_cache();
+  SNIFFED.lock().unwrap().remove(p.as_ref());
 
   cache
     .lock()
@@ -90,6 +91,7 @@ pub fn override_file_contents<T: AsRef<Path>>(p: T, contents: &'static [u8]) {
 }
 pub fn clear_file_contents_cache<T: AsRef<Path>>(p: T) {
   let cache = get_file_cache();
+  SNIFFED.lock().unwrap().remove(p.as_ref());
 
   cache
     .lock()
@@ -163,10 +165,21 @@ const WASM_MAGIC: &'static [u8] = &[
   's' as u8,
   'm' as u8,
 ];
+const ELF_MAGIC: &'static [u8] = &[
+  0x7f,
+  'E' as u8,
+  'L' as u8,
+  'F' as u8,
+];
+/// Linker scripts bigger than this are assumed to be something else.
+const MAX_LINKER_SCRIPT_SIZE: u64 = 64 * 1024;
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Subtype {
   Bitcode,
+  Wasm,
+  /// Native code; never linkable.
+  Elf,
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -175,6 +188,8 @@ pub enum Type {
   Object(Subtype),
   Pexe,
   Wasm,
+  /// A text linker script, ie `INPUT(...)`/`GROUP(...)`.
+  LinkerScript,
 }
 
 macro_rules! test_magic (
@@ -231,10 +246,22 @@ test_magic!(is_file_pnacl_bitcode is_stream_pnacl_bitcode 4 =>
             [PNACL_BITCODE_MAGIC] -> Type::Pexe);
 test_magic!(is_file_wasm_module is_stream_wasm_module 4 =>
             [WASM_MAGIC] -> Type::Wasm);
+test_magic!(is_file_elf is_stream_elf 4 =>
+            [ELF_MAGIC] -> Type::Object(Subtype::Elf));
 
 test_magic!(is_file_llvm_bitcode is_stream_llvm_bitcode 4 =>
             [LLVM_BITCODE_MAGIC, LLVM_WRAPPER_MAGIC] -> Type::Object(Subtype::Bitcode));
 
+lazy_static! {
+  /// What `file_type` found, so each file is only read once. Separate from
+  /// the overrides, so clearing those doesn't forget real files, and the
+  /// files which aren't anything are remembered too.
+  static ref SNIFFED: Mutex<HashMap<PathBuf, Option<Type>>> = Default::default();
+}
+
+/// Sniff the type of `path` from its contents; the extension is never
+/// consulted, so misnamed inputs (objects named `.bc`, archives without
+/// `.a`, etc) are still recognized.
 pub fn file_type<T>(path: T) -> io::Result<Option<Type>>
   where T: AsRef<Path>,
 {
@@ -242,8 +269,11 @@ pub fn file_type<T>(path: T) -> io::Result<Option<Type>>
     Some(v) => { return Ok(Some(v)); },
     _ => {},
   }
+  if let Some(&t) = SNIFFED.lock().unwrap().get(path.as_ref()) {
+    return Ok(t);
+  }
 
-  let t = get_file_contents(&path, |_, mut file| {
+  let t = get_file_contents(&path, |path, mut file| {
     if is_stream_llvm_bitcode(file) {
       return Some(Type::Object(Subtype::Bitcode));
     }
@@ -253,44 +283,78 @@ pub fn file_type<T>(path: T) -> io::Result<Option<Type>>
     if is_stream_wasm_module(file) {
       return Some(Type::Wasm);
     }
+    if is_stream_elf(file) {
+      return Some(Type::Object(Subtype::Elf));
+    }
 
-    if let Ok(Some(ar_type)) = ar::stream_archive_type(&mut file) {
-      return Some(Type::Archive(ar_type));
+    if ar::is_buffer_an_archive(file) {
+      if let Ok(Some(members)) = ldtools::thin_archive_members(path) {
+        return ar::thin_archive_type(&members[..])
+          .map(Type::Archive);
+      }
+      if let Ok(Some(ar_type)) = ar::stream_archive_type(&mut file) {
+        return Some(Type::Archive(ar_type));
+      }
+      return None;
+    }
+
+    if is_stream_linker_script(path, file) {
+      return Some(Type::LinkerScript);
     }
 
     None
   })?;
 
+  SNIFFED.lock().unwrap().insert(path.as_ref().to_path_buf(), t);
+
   Ok(t)
 }
 
-pub fn could_be_linker_script<T: AsRef<Path>>(path: T) -> bool {
-  let exts: ::std::collections::HashSet<Option<::std::ffi::OsString>> = hashset!{
-        Some(From::from("o")), Some(From::from("so")),
-        Some(From::from("a")), Some(From::from("po")),
-        Some(From::from("pa")), Some(From::from("x")),
-    };
+fn is_stream_linker_script<T, U>(path: T, io: &mut U) -> bool
+  where T: AsRef<Path>,
+        U: Read + Seek + ?Sized,
+{
+  use std::io::SeekFrom;
+
+  let pos = match io.seek(SeekFrom::Current(0)) {
+    Ok(pos) => pos,
+    Err(_) => { return false; },
+  };
+  let mut buffer = String::new();
+  let read = io.take(MAX_LINKER_SCRIPT_SIZE + 1)
+    .read_to_string(&mut buffer);
+  let _ = io.seek(SeekFrom::Start(pos));
+  match read {
+    Ok(n) if n as u64 <= MAX_LINKER_SCRIPT_SIZE => {},
+    _ => { return false; },
+  }
 
-  exts.contains(&path.as_ref().extension().map(|v| From::from(v) )) &&
-    ar::archive_type(&path).is_none() &&
-    !is_file_raw_llvm_bitcode(&path) &&
-    !is_file_wrapped_llvm_bitcode(&path)
+  let dir = path.as_ref().parent().unwrap_or_else(|| ".".as_ref() );
+  ldtools::parse_linker_script(buffer, dir).is_some()
+}
+
+/// Is `path` text which parses as a linker script? Other text (and
+/// unknown binary formats) isn't.
+pub fn could_be_linker_script<T: AsRef<Path>>(path: T) -> bool {
+  is_linker_script(path)
 }
 pub fn is_linker_script<T: AsRef<Path>>(path: T) -> bool {
-  could_be_linker_script(path.as_ref()) &&
-    ldtools::parse_linker_script_file(&path).is_some()
+  file_type(&path)
+    .map(|t| t == Some(Type::LinkerScript) )
+    .unwrap_or(false)
 }
 
 pub mod ar {
   use std::fs::File;
   use std::io::{self, Error, ErrorKind, Read, Seek, SeekFrom, Cursor};
   use std::mem;
-  use std::path::Path;
+  use std::path::{Path, PathBuf};
   use std::str::FromStr;
 
   extern crate ar;
 
-  use super::{is_stream_llvm_bitcode, get_cached_filetype,
+  use super::{is_stream_llvm_bitcode, is_stream_wasm_module, is_stream_elf,
+              get_cached_filetype,
               get_file_contents, override_filetype};
 
   pub use super::Subtype as Type;
@@ -340,6 +404,11 @@ pub mod ar {
         _ => None,
       })
       .or_else(|| {
+        match ::ldtools::thin_archive_members(&path) {
+          Ok(Some(members)) => return thin_archive_type(&members[..]),
+          _ => {},
+        }
+
         // XXX(rdiamond): This ignores our cache.
         let file = File::open(path.as_ref())
           .unwrap_or_else(|err| {
@@ -373,6 +442,12 @@ pub mod ar {
         if is_stream_llvm_bitcode(&mut stream) {
           break 'block Ok(Some(Type::Bitcode));
         }
+        if is_stream_wasm_module(&mut stream) {
+          break 'block Ok(Some(Type::Wasm));
+        }
+        if is_stream_elf(&mut stream) {
+          break 'block Ok(Some(Type::Elf));
+        }
       }
 
       break 'block Ok(None);
@@ -382,6 +457,17 @@ pub mod ar {
     result
   }
 
+  /// The type of a thin archive, from the objects it references; the first
+  /// member of a known type decides, like `stream_archive_type`.
+  pub fn thin_archive_type(members: &[PathBuf]) -> Option<Type> {
+    members.iter()
+      .filter_map(|member| match super::file_type(member) {
+        Ok(Some(super::Type::Object(subtype))) => Some(subtype),
+        Ok(Some(super::Type::Wasm)) => Some(Type::Wasm),
+        _ => None,
+      })
+      .next()
+  }
 
   pub struct MemberHeader {
     pub start: u64,
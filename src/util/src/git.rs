@@ -3,48 +3,68 @@ use super::{run_unlogged_cmd};
 
 use git2::{Repository, };
 
-// XXX technically, no function here even returns an error.
 use std::error::Error;
 use std::fs::remove_dir_all;
 use std::path::{Path, PathBuf};
 use std::process::{Command};
 
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CheckoutOptions {
+  /// `--depth` for clones and fetches; `None` means the full history.
+  pub depth: Option<u32>,
+  /// Checkout submodules of submodules too.
+  pub recursive_submodules: bool,
+}
+
+fn update_submodules(task: &str, dest: &Path, opts: CheckoutOptions)
+  -> Result<(), Box<dyn Error>>
+{
+  let mut cmd = Command::new("git");
+  cmd.current_dir(dest)
+    .arg("submodule")
+    .arg("update")
+    .arg("--init");
+  if opts.recursive_submodules {
+    cmd.arg("--recursive");
+  }
+  if let Some(depth) = opts.depth {
+    cmd.arg("--depth").arg(depth.to_string());
+  }
+  run_unlogged_cmd(task, cmd)
+}
+
 pub fn checkout_repo(task: &str, dest: &Path,
                      repo_url: &str, branch: &str,
-                     thin: bool) {
+                     opts: CheckoutOptions)
+  -> Result<(), Box<dyn Error>>
+{
   let mut cmd = Command::new("git");
   let mut clone = false;
   if !dest.exists() {
     clone = true
   } else {
-    loop {
-      let repo = Repository::open(dest);
-      if repo.is_err() {
-        remove_dir_all(dest).unwrap();
-        clone = true;
-      }
-      let repo = repo.unwrap();
-
-      let _ = if let Ok(remote) = repo.find_remote("origin") {
-        if remote.url() != Some(repo_url) {
-          repo.remote_set_url("origin", repo_url).unwrap();
-          repo.find_remote("origin").unwrap()
+    match Repository::open(dest) {
+      Ok(repo) => {
+        if let Ok(remote) = repo.find_remote("origin") {
+          if remote.url() != Some(repo_url) {
+            repo.remote_set_url("origin", repo_url)?;
+          }
         } else {
-          remote
+          repo.remote("origin", repo_url)?;
         }
-      } else {
-        repo.remote("origin", repo_url).unwrap()
-      };
-
-      break;
+      },
+      Err(_) => {
+        remove_dir_all(dest)?;
+        clone = true;
+      },
     }
   };
 
   if clone {
     cmd.arg("clone")
       .arg(repo_url);
-    if thin {
-      cmd.arg("--depth").arg("1");
+    if let Some(depth) = opts.depth {
+      cmd.arg("--depth").arg(depth.to_string());
     }
     cmd
       .arg("--single-branch")
@@ -56,26 +76,26 @@ pub fn checkout_repo(task: &str, dest: &Path,
       let mut cmd = Command::new("git");
       cmd.current_dir(dest)
         .arg("stash");
-      run_unlogged_cmd(task, cmd);
+      run_unlogged_cmd(task, cmd)?;
     }
     cmd.current_dir(dest)
       .arg("fetch")
       .arg("--no-tags");
-    if thin {
-      cmd.arg("--depth").arg("1");
+    if let Some(depth) = opts.depth {
+      cmd.arg("--depth").arg(depth.to_string());
     }
     cmd
       .arg("origin")
       .arg(branch);
 
-    run_unlogged_cmd(task, cmd);
+    run_unlogged_cmd(task, cmd)?;
 
     cmd = Command::new("git");
     cmd.current_dir(dest)
       .arg("checkout")
       .arg("-B")
       .arg(branch);
-    run_unlogged_cmd(task, cmd);
+    run_unlogged_cmd(task, cmd)?;
 
     cmd = Command::new("git");
     cmd.current_dir(dest)
@@ -83,14 +103,9 @@ pub fn checkout_repo(task: &str, dest: &Path,
       .arg("--hard")
       .arg("FETCH_HEAD");
   }
-  run_unlogged_cmd(task, cmd);
+  run_unlogged_cmd(task, cmd)?;
 
-  let mut cmd = Command::new("git");
-  cmd.current_dir(dest)
-    .arg("submodule")
-    .arg("update")
-    .arg("--init");
-  run_unlogged_cmd(task, cmd);
+  update_submodules(task, dest, opts)
 }
 
 pub fn checkout_or_override(name: &str,
@@ -98,41 +113,42 @@ pub fn checkout_or_override(name: &str,
                             over: Option<&PathBuf>,
                             repo_url: &str,
                             repo_branch: &str,
-                            thin: bool)
+                            opts: CheckoutOptions)
   -> Result<(), Box<dyn Error>>
 {
   checkout_or_override_raw(name, dest_path,
                            over, repo_url, repo_branch,
-                           thin, checkout_repo)
+                           opts, checkout_repo)
 }
 pub fn checkout_or_override_commit(name: &str,
                                    dest_path: &Path,
                                    over: Option<&PathBuf>,
                                    repo_url: &str,
                                    repo_commit: &str,
-                                   thin: bool)
+                                   opts: CheckoutOptions)
   -> Result<(), Box<dyn Error>>
 {
   checkout_or_override_raw(name, dest_path,
                            over, repo_url, repo_commit,
-                           thin, checkout_repo_commit)
+                           opts, checkout_repo_commit)
 }
 pub fn checkout_or_override_raw<F>(name: &str,
                                    dest_path: &Path,
                                    over: Option<&PathBuf>,
                                    repo_url: &str,
                                    repo_branch: &str,
-                                   thin: bool,
+                                   opts: CheckoutOptions,
                                    checkout_repo: F)
   -> Result<(), Box<dyn Error>>
-  where F: FnOnce(&str, &Path, &str, &str, bool)
+  where F: FnOnce(&str, &Path, &str, &str, CheckoutOptions)
+  -> Result<(), Box<dyn Error>>,
 {
   let repo_url = if let Some(dir) = over {
     if dir == dest_path {
       return Ok(());
     } else {
-      println!("{} != {}", dir.display(),
-               dest_path.display());
+      super::diag::note(&format!("checking `{}` out from `{}`", dest_path.display(),
+                                 dir.display()));
     }
     dir.to_str().expect("non-utf8 in path")
   } else {
@@ -141,72 +157,101 @@ pub fn checkout_or_override_raw<F>(name: &str,
 
   let task = format!("checkout-{}", name);
   checkout_repo(&task[..], &dest_path,
-                repo_url, repo_branch, thin);
-
-  Ok(())
+                repo_url, repo_branch, opts)
 }
 
+/// With a `depth`, only `commit` (and that much of its history) is fetched.
 pub fn checkout_repo_commit(task: &str, dest: &Path,
                             repo_url: &str, commit: &str,
-                            _thin: bool) {
+                            opts: CheckoutOptions)
+  -> Result<(), Box<dyn Error>>
+{
   let mut cmd = Command::new("git");
   let mut clone = false;
   if !dest.exists() {
     clone = true
   } else {
-    loop {
-      let repo = Repository::open(dest);
-      if repo.is_err() {
-        remove_dir_all(dest).unwrap();
-        clone = true;
-      }
-      let repo = repo.unwrap();
-
-      let _ = if let Ok(remote) = repo.find_remote("origin") {
-        if remote.url() != Some(repo_url) {
-          repo.remote_set_url("origin", repo_url).unwrap();
-          repo.find_remote("origin").unwrap()
+    match Repository::open(dest) {
+      Ok(repo) => {
+        if let Ok(remote) = repo.find_remote("origin") {
+          if remote.url() != Some(repo_url) {
+            repo.remote_set_url("origin", repo_url)?;
+          }
         } else {
-          remote
+          repo.remote("origin", repo_url)?;
         }
-      } else {
-        repo.remote("origin", repo_url).unwrap()
-      };
-
-      break;
+      },
+      Err(_) => {
+        remove_dir_all(dest)?;
+        clone = true;
+      },
     }
   };
 
-  if clone {
+  if clone && opts.depth.is_none() {
     cmd.arg("clone")
       .arg(repo_url)
       .arg(dest);
 
-    run_unlogged_cmd(task, cmd);
+    run_unlogged_cmd(task, cmd)?;
     cmd = Command::new("git");
     cmd.current_dir(dest)
       .arg("fetch")
       .arg("--all");
-    run_unlogged_cmd(task, cmd);
+    run_unlogged_cmd(task, cmd)?;
 
     cmd = Command::new("git");
     cmd.current_dir(dest)
       .arg("reset")
       .arg("--hard")
       .arg(commit);
+  } else if let Some(depth) = opts.depth {
+    if clone {
+      cmd.arg("init")
+        .arg(dest);
+      run_unlogged_cmd(task, cmd)?;
+
+      cmd = Command::new("git");
+      cmd.current_dir(dest)
+        .arg("remote")
+        .arg("add")
+        .arg("origin")
+        .arg(repo_url);
+      run_unlogged_cmd(task, cmd)?;
+    } else {
+      // save changes so we don't overwrite on accident.
+      cmd.current_dir(dest)
+        .arg("stash");
+      run_unlogged_cmd(task, cmd)?;
+    }
+
+    cmd = Command::new("git");
+    cmd.current_dir(dest)
+      .arg("fetch")
+      .arg("--no-tags")
+      .arg("--depth").arg(depth.to_string())
+      .arg("origin")
+      .arg(commit);
+    run_unlogged_cmd(task, cmd)?;
+
+    cmd = Command::new("git");
+    cmd.current_dir(dest)
+      .arg("reset")
+      .arg("--hard")
+      .arg("FETCH_HEAD");
   } else {
     {
       // save changes so we don't overwrite on accident.
       let mut cmd = Command::new("git");
       cmd.current_dir(dest)
         .arg("stash");
-      run_unlogged_cmd(task, cmd);
+      run_unlogged_cmd(task, cmd)?;
     }
     cmd.current_dir(dest)
       .arg("fetch")
       .arg("--no-tags")
       .arg("origin");
-    run_unlogged_cmd(task, cmd);
+    run_unlogged_cmd(task, cmd)?;
 
     cmd = Command::new("git");
     cmd.current_dir(dest)
@@ -214,12 +259,72 @@ pub fn checkout_repo_commit(task: &str, dest: &Path,
       .arg("--hard")
       .arg(commit);
   }
-  run_unlogged_cmd(task, cmd);
+  run_unlogged_cmd(task, cmd)?;
 
+  update_submodules(task, dest, opts)
+}
+
+/// Checkout just `paths` of `repo_url` at `cref` (a branch, tag or
+/// commit), fetching only that commit and only the blobs under `paths`.
+pub fn checkout_sparse(task: &str, dest: &Path,
+                       repo_url: &str, cref: &str,
+                       paths: &[&str], opts: CheckoutOptions)
+  -> Result<(), Box<dyn Error>>
+{
+  // not `Repository::open`: libgit2 refuses the `worktreeConfig` extension
+  // `git sparse-checkout` turns on.
   let mut cmd = Command::new("git");
+  if !dest.join(".git").exists() {
+    if dest.exists() {
+      remove_dir_all(dest)?;
+    }
+    cmd.arg("init")
+      .arg(dest);
+    run_unlogged_cmd(task, cmd)?;
+
+    cmd = Command::new("git");
+    cmd.current_dir(dest)
+      .arg("remote")
+      .arg("add");
+  } else {
+    {
+      // save changes so we don't overwrite on accident.
+      let mut cmd = Command::new("git");
+      cmd.current_dir(dest)
+        .arg("stash");
+      run_unlogged_cmd(task, cmd)?;
+    }
+    cmd.current_dir(dest)
+      .arg("remote")
+      .arg("set-url");
+  }
+  cmd.arg("origin")
+    .arg(repo_url);
+  run_unlogged_cmd(task, cmd)?;
+
+  cmd = Command::new("git");
   cmd.current_dir(dest)
-    .arg("submodule")
-    .arg("update")
-    .arg("--init");
-  run_unlogged_cmd(task, cmd);
+    .arg("sparse-checkout")
+    .arg("set")
+    .args(paths);
+  run_unlogged_cmd(task, cmd)?;
+
+  cmd = Command::new("git");
+  cmd.current_dir(dest)
+    .arg("fetch")
+    .arg("--no-tags")
+    .arg("--depth").arg(opts.depth.unwrap_or(1).to_string())
+    .arg("--filter=blob:none")
+    .arg("origin")
+    .arg(cref);
+  run_unlogged_cmd(task, cmd)?;
+
+  cmd = Command::new("git");
+  cmd.current_dir(dest)
+    .arg("reset")
+    .arg("--hard")
+    .arg("FETCH_HEAD");
+  run_unlogged_cmd(task, cmd)?;
+
+  update_submodules(task, dest, opts)
 }
@@ -0,0 +1,256 @@
+//! Ctrl-C handling. The first Ctrl-C is forwarded to the commands we're
+//! running, and the queue stops once they've exited. A second one
+//! terminates them, waits (briefly) for them to go, removes the queues'
+//! intermediate dirs and exits; otherwise those would be left behind, as
+//! `exit` doesn't run destructors.
+//!
+//! A command with a timeout is started in a process group of its own, so
+//! whatever it starts (ie clang's `-cc1`) is killed with it. Its group isn't
+//! the terminal's, so Ctrl-C only reaches it through our handler.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command, ExitStatus, Output, Stdio};
+use std::sync::{Mutex, Once};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+
+/// How long to wait for terminated children before exiting anyway.
+const TERMINATE_TIMEOUT: Duration = Duration::from_secs(2);
+/// How often a child with a timeout is checked on.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static HANDLER: Once = Once::new();
+static EMBEDDED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+  /// `(pid, leads its own process group)`.
+  static ref CHILDREN: Mutex<Vec<(u32, bool)>> = Mutex::new(Vec::new());
+  static ref DIRS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+}
+
+/// Set the Ctrl-C handler, if it isn't already (and we aren't embedded).
+pub fn install() {
+  if EMBEDDED.load(Ordering::SeqCst) {
+    return;
+  }
+  HANDLER.call_once(|| {
+    if ::ctrlc::set_handler(on_ctrl_c).is_err() {
+      warn!("Couldn't set ctrl-c handler");
+    }
+  });
+}
+
+/// For drivers run in process: Ctrl-C is the host's to handle, so the
+/// handler, which exits the process on the second, is never set.
+pub fn set_embedded() {
+  EMBEDDED.store(true, Ordering::SeqCst);
+}
+/// Forget an earlier Ctrl-C, so the next queue runs.
+pub fn reset() {
+  INTERRUPTED.store(false, Ordering::SeqCst);
+}
+
+/// Whether Ctrl-C has been pressed; no new commands should be started.
+pub fn interrupted() -> bool {
+  INTERRUPTED.load(Ordering::SeqCst)
+}
+
+fn on_ctrl_c() {
+  if !INTERRUPTED.swap(true, Ordering::SeqCst) {
+    signal_children(false);
+    return;
+  }
+
+  signal_children(true);
+  let start = Instant::now();
+  while CHILDREN.lock().unwrap().len() != 0 && start.elapsed() < TERMINATE_TIMEOUT {
+    sleep(Duration::from_millis(20));
+  }
+  if !super::boolean_env("WASM_TOOLCHAIN_SAVE_TMPS") {
+    for dir in DIRS.lock().unwrap().iter() {
+      let _ = fs::remove_dir_all(dir);
+    }
+  }
+  process::exit(1);
+}
+
+fn signal_children(terminate: bool) {
+  for &(pid, group) in CHILDREN.lock().unwrap().iter() {
+    signal(pid, group, terminate);
+  }
+}
+
+#[cfg(unix)]
+fn signal(pid: u32, group: bool, terminate: bool) {
+  let sig = if terminate { ::libc::SIGTERM } else { ::libc::SIGINT };
+  let pid = pid as ::libc::pid_t;
+  unsafe {
+    ::libc::kill(if group { -pid } else { pid }, sig);
+  }
+}
+#[cfg(not(unix))]
+fn signal(pid: u32, _group: bool, terminate: bool) {
+  // the console already gives Ctrl-C to every process attached to it.
+  if terminate {
+    let _ = Command::new("taskkill")
+      .args(&["/F", "/T", "/PID"])
+      .arg(pid.to_string())
+      .output();
+  }
+}
+
+#[cfg(unix)]
+fn new_group(cmd: &mut Command) {
+  use std::os::unix::process::CommandExt;
+  cmd.process_group(0);
+}
+#[cfg(not(unix))]
+fn new_group(_cmd: &mut Command) {
+  // `taskkill /T` finds the whole tree anyway.
+}
+
+/// Kill `child`, and its process group if it leads one.
+#[cfg(unix)]
+fn kill(child: &mut Child, group: bool) {
+  if group {
+    unsafe {
+      ::libc::kill(-(child.id() as ::libc::pid_t), ::libc::SIGKILL);
+    }
+  }
+  let _ = child.kill();
+}
+#[cfg(not(unix))]
+fn kill(child: &mut Child, _group: bool) {
+  let _ = Command::new("taskkill")
+    .args(&["/F", "/T", "/PID"])
+    .arg(child.id().to_string())
+    .output();
+  let _ = child.kill();
+}
+
+/// Removes the child from `CHILDREN` once it's been waited on.
+struct Tracked {
+  pid: u32,
+  group: bool,
+}
+impl Drop for Tracked {
+  fn drop(&mut self) {
+    let pid = self.pid;
+    CHILDREN.lock().unwrap().retain(|&(p, _)| p != pid );
+  }
+}
+
+/// Spawn `cmd`, in a process group of its own if it has a `timeout`.
+fn spawn(cmd: &mut Command, timeout: Option<Duration>) -> io::Result<(Child, Tracked)> {
+  let group = timeout.is_some();
+  if group {
+    new_group(cmd);
+  }
+  let child = cmd.spawn()?;
+  CHILDREN.lock().unwrap().push((child.id(), group));
+  let tracked = Tracked { pid: child.id(), group, };
+  Ok((child, tracked))
+}
+
+/// `cmd.status()`, but Ctrl-C reaches the child.
+pub fn status(cmd: &mut Command) -> io::Result<ExitStatus> {
+  let (mut child, _tracked) = spawn(cmd, None)?;
+  child.wait()
+}
+
+/// `cmd.output()`, but Ctrl-C reaches the child. Unlike `Command::output`,
+/// only what's been set to `Stdio::piped()` is captured.
+pub fn output(cmd: &mut Command) -> io::Result<Output> {
+  let (child, _tracked) = spawn(cmd, None)?;
+  child.wait_with_output()
+}
+
+/// Wait for `child`, killing it (and its group) if it's still running after
+/// `timeout`; `true` if it was killed.
+fn wait(child: &mut Child, tracked: &Tracked, timeout: Option<Duration>)
+  -> io::Result<(ExitStatus, bool)>
+{
+  let timeout = match timeout {
+    Some(timeout) => timeout,
+    None => return Ok((child.wait()?, false)),
+  };
+  let start = Instant::now();
+  loop {
+    if let Some(status) = child.try_wait()? {
+      return Ok((status, false));
+    }
+    if start.elapsed() >= timeout {
+      // it could have exited since.
+      kill(child, tracked.group);
+      return Ok((child.wait()?, true));
+    }
+    sleep(POLL_INTERVAL);
+  }
+}
+
+/// `status`, but the child is killed if it runs for longer than `timeout`;
+/// `true` if it was.
+pub fn status_timeout(cmd: &mut Command, timeout: Option<Duration>)
+  -> io::Result<(ExitStatus, bool)>
+{
+  let (mut child, tracked) = spawn(cmd, timeout)?;
+  wait(&mut child, &tracked, timeout)
+}
+
+/// `status_timeout`, but the child's stdout and stderr are piped to `out`
+/// and `err` as it writes them, a line at a time.
+pub fn stream_timeout<O, E>(cmd: &mut Command, timeout: Option<Duration>,
+                            out: O, err: E)
+  -> io::Result<(ExitStatus, bool)>
+  where O: FnMut(&[u8]) + Send + 'static,
+        E: FnMut(&[u8]) + Send + 'static,
+{
+  fn pump<R, F>(pipe: Option<R>, mut f: F) -> mpsc::Receiver<()>
+    where R: Read + Send + 'static,
+          F: FnMut(&[u8]) + Send + 'static,
+  {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+      if let Some(pipe) = pipe {
+        let mut pipe = BufReader::new(pipe);
+        let mut line = Vec::new();
+        while let Ok(n) = pipe.read_until(b'\n', &mut line) {
+          if n == 0 { break; }
+          f(&line);
+          line.clear();
+        }
+      }
+      let _ = tx.send(());
+    });
+    rx
+  }
+
+  cmd.stdout(Stdio::piped());
+  cmd.stderr(Stdio::piped());
+  let (mut child, tracked) = spawn(cmd, timeout)?;
+  let pumps = vec![pump(child.stdout.take(), out), pump(child.stderr.take(), err)];
+  let (status, killed) = wait(&mut child, &tracked, timeout)?;
+  for done in pumps.into_iter() {
+    // a killed child's children can keep the pipes open.
+    if killed {
+      let _ = done.recv_timeout(TERMINATE_TIMEOUT);
+    } else {
+      let _ = done.recv();
+    }
+  }
+  Ok((status, killed))
+}
+
+/// Remove `dir` if we're forced to exit.
+pub fn remove_on_exit(dir: &Path) {
+  DIRS.lock().unwrap().push(dir.to_path_buf());
+}
+/// Undo `remove_on_exit`.
+pub fn keep_on_exit(dir: &Path) {
+  DIRS.lock().unwrap().retain(|d| d != dir );
+}
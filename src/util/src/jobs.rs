@@ -0,0 +1,96 @@
+//! Limits how many heavy (ie memory hungry) steps, like links and LTO
+//! codegen, run at once. A build running several drivers in parallel (ie
+//! `make -j` in a sysroot build) can otherwise run out of memory when a few
+//! links happen to line up.
+//!
+//! The limit is `$WASM_DRIVER_MAX_HEAVY_JOBS`, shared by every driver with
+//! the same `$WASM_DRIVER_HEAVY_JOBS_DIR` (by default, one per user; see
+//! `user_temp_dir`). A heavy step first takes one of
+//! the slots in that dir, which are file locks (see `lock`), so a slot is
+//! freed even if its holder is killed. Only unix is supported; elsewhere there's
+//! no limit.
+
+use std::env;
+use std::fs::File;
+use std::io;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub const MAX_HEAVY_JOBS_ENV: &'static str = "WASM_DRIVER_MAX_HEAVY_JOBS";
+pub const HEAVY_JOBS_DIR_ENV: &'static str = "WASM_DRIVER_HEAVY_JOBS_DIR";
+
+/// Slots held by this process. Nested queues (ie a link run by the clang
+/// driver) already have the slot of the step which started them.
+static HELD: AtomicUsize = AtomicUsize::new(0);
+
+/// `None` if there's no limit.
+pub fn max_heavy_jobs() -> Option<usize> {
+  env::var(MAX_HEAVY_JOBS_ENV).ok()
+    .and_then(|n| n.parse().ok() )
+    .and_then(|n| if n == 0 { None } else { Some(n) } )
+}
+#[cfg(unix)]
+fn slot_dir() -> io::Result<PathBuf> {
+  match env::var_os(HEAVY_JOBS_DIR_ENV) {
+    Some(dir) => Ok(PathBuf::from(dir)),
+    None => Ok(super::user_temp_dir()?.join("heavy-jobs")),
+  }
+}
+
+/// A held slot, released on drop.
+pub struct Slot(Option<File>);
+impl Drop for Slot {
+  fn drop(&mut self) {
+    if self.0.take().is_some() {
+      HELD.fetch_sub(1, Ordering::SeqCst);
+    }
+  }
+}
+
+/// Wait for a heavy job slot.
+pub fn acquire() -> io::Result<Slot> {
+  let max = match max_heavy_jobs() {
+    Some(max) if HELD.load(Ordering::SeqCst) == 0 => max,
+    _ => { return Ok(Slot(None)); },
+  };
+  let file = wait_for_slot(max)?;
+  if file.is_some() {
+    HELD.fetch_add(1, Ordering::SeqCst);
+  }
+  Ok(Slot(file))
+}
+
+#[cfg(unix)]
+fn wait_for_slot(max: usize) -> io::Result<Option<File>> {
+  use std::fs::{self, OpenOptions};
+  use std::thread::sleep;
+  use std::time::Duration;
+
+  let dir = slot_dir()?;
+  fs::create_dir_all(&dir)?;
+  let mut waiting = false;
+  loop {
+    for slot in 0..max {
+      let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .open(dir.join(format!("slot-{}", slot)))?;
+      if super::lock::try_lock(&file)? {
+        return Ok(Some(file));
+      }
+    }
+    if super::interrupt::interrupted() {
+      return Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"));
+    }
+    if !waiting {
+      info!("waiting for one of the {} heavy job slots", max);
+      waiting = true;
+    }
+    sleep(Duration::from_millis(50));
+  }
+}
+#[cfg(not(unix))]
+fn wait_for_slot(_max: usize) -> io::Result<Option<File>> {
+  Ok(None)
+}
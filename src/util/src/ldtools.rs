@@ -1,5 +1,6 @@
 
 use std::fmt;
+use std::io;
 use std::path::{Path, PathBuf};
 
 use filetype;
@@ -198,8 +199,63 @@ pub fn parse_linker_script<T, U>(input: T, dir: U)
   }
 }
 
+/// Linker flags which take the next arg as their value, so the value can
+/// come from its own `-Wl,`/`-Xlinker` (which is never split on commas).
+pub const LINKER_VALUE_FLAGS: &'static [&'static str] = &[
+  "-z",
+  "-o", "--output",
+  "-e", "--entry",
+  "-u", "--undefined",
+  "-y", "--trace-symbol",
+  "-L", "--library-path",
+  "-l", "--library",
+  "-soname", "--soname",
+  "-rpath", "-rpath-link",
+  "-defsym", "--defsym",
+  "--export",
+  "--version-script",
+];
+
+/// Keeps `-Wl,`/`-Xlinker` flags with their values, as in
+/// `-Wl,-z,stack-size=1` or `-Xlinker -z -Xlinker stack-size=1`.
+#[derive(Clone, Debug, Default)]
+pub struct LinkerFlagValues {
+  /// The last linker arg was this flag, and its value is the next.
+  value_of: Option<String>,
+}
+impl LinkerFlagValues {
+  /// Is `arg` the value of the flag before it? If so it's passed on as
+  /// is, even if it looks like a flag.
+  pub fn is_value(&mut self, arg: &str) -> bool {
+    if self.value_of.take().is_some() {
+      return true;
+    }
+    if LINKER_VALUE_FLAGS.contains(&arg) {
+      self.value_of = Some(arg.into());
+    }
+    false
+  }
+  /// Error if the last flag never got its value.
+  pub fn check(&mut self) -> Result<(), String> {
+    match self.value_of.take() {
+      Some(flag) => Err(format!("linker flag `{}` is missing its value (ie `-Wl,{},<value>` \
+                                 or `-Xlinker {} -Xlinker <value>`)", flag, flag, flag)),
+      None => Ok(()),
+    }
+  }
+}
+
 pub fn expand_input(input: Input, search: &[PathBuf],
                     static_only: bool) -> Result<Vec<Input>, String> {
+  expand_input_reading(input, search, static_only, &mut vec![])
+}
+
+/// `expand_input`, also listing the files found along the way in `read`
+/// (the libraries and linker scripts), so the expansion can be cached.
+pub fn expand_input_reading(input: Input, search: &[PathBuf],
+                            static_only: bool, read: &mut Vec<PathBuf>)
+  -> Result<Vec<Input>, String>
+{
 
   fn find_file<T: AsRef<Path>>(name: T, search: &[PathBuf]) -> Option<PathBuf> {
     for dir in search.iter() {
@@ -232,11 +288,12 @@ pub fn expand_input(input: Input, search: &[PathBuf],
 
       match chain {
         Some(p) => {
+          read.push(p.clone());
           if filetype::could_be_linker_script(&p) {
             if let Some(expanded) = parse_linker_script_file(&p) {
               for arg in expanded.into_iter() {
-                ret.extend(expand_input(arg, search,
-                                        static_only)?);
+                ret.extend(expand_input_reading(arg, search,
+                                                static_only, read)?);
               }
               return Ok(ret);
             }
@@ -251,11 +308,12 @@ pub fn expand_input(input: Input, search: &[PathBuf],
       }
     },
     Input::File(path) => {
+      read.push(path.clone());
       if filetype::could_be_linker_script(&path) {
         if let Some(expanded) = parse_linker_script_file(&path) {
           for arg in expanded.into_iter() {
-            ret.extend(expand_input(arg, search,
-                                    static_only)?);
+            ret.extend(expand_input_reading(arg, search,
+                                            static_only, read)?);
           }
           return Ok(ret);
         } else {
@@ -271,3 +329,82 @@ pub fn expand_input(input: Input, search: &[PathBuf],
 
   Ok(ret)
 }
+
+const THIN_MAGIC: &'static [u8] = b"!<thin>\n";
+
+/// The members of a thin archive (`!<thin>`), which only reference their
+/// objects by path, relative to the archive. `None` if `path` isn't a thin
+/// archive.
+pub fn thin_archive_members<T: AsRef<Path>>(path: T)
+  -> io::Result<Option<Vec<PathBuf>>>
+{
+  use std::fs::File;
+  use std::io::{Error, ErrorKind, Read};
+  use std::str::from_utf8;
+
+  let path = path.as_ref();
+  let mut file = File::open(path)?;
+  // only thin archives are read in full; regular ones can be big.
+  let mut bytes = vec![0u8; THIN_MAGIC.len()];
+  match file.read_exact(&mut bytes) {
+    Ok(()) if bytes == THIN_MAGIC => {},
+    Ok(()) => return Ok(None),
+    Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+    Err(e) => return Err(e),
+  }
+  file.read_to_end(&mut bytes)?;
+
+  let invalid = |msg: &str| {
+    Error::new(ErrorKind::InvalidData,
+               format!("thin archive `{}`: {}", path.display(), msg))
+  };
+  let dir = path.parent().unwrap_or_else(|| ".".as_ref() );
+
+  let mut members = Vec::new();
+  let mut strtab: &[u8] = &[];
+  let mut pos = THIN_MAGIC.len();
+  while pos < bytes.len() {
+    let header = bytes.get(pos..pos + 60)
+      .ok_or_else(|| invalid("truncated member header") )?;
+    pos += 60;
+    if &header[58..] != b"`\n" {
+      return Err(invalid("bad member header"));
+    }
+    let name = from_utf8(&header[..16])
+      .map_err(|_| invalid("non-UTF-8 member name") )?
+      .trim_end();
+    let size: usize = from_utf8(&header[48..58]).ok()
+      .and_then(|size| size.trim_end().parse().ok() )
+      .ok_or_else(|| invalid("bad member size") )?;
+
+    // Only the symbol and name tables are stored in the archive itself.
+    let is_table = name == "/" || name == "//" || name == "/SYM64/" ||
+      name.starts_with("__.SYMDEF");
+    if is_table {
+      let data = bytes.get(pos..pos + size)
+        .ok_or_else(|| invalid("truncated member") )?;
+      if name == "//" {
+        strtab = data;
+      }
+      pos += size + (size & 1);
+      continue;
+    }
+
+    let member = if name.starts_with('/') {
+      let offset: usize = name[1..].parse()
+        .map_err(|_| invalid("bad long member name") )?;
+      let rest = strtab.get(offset..)
+        .ok_or_else(|| invalid("long member name out of range") )?;
+      let end = rest.windows(2)
+        .position(|w| w == b"/\n" )
+        .unwrap_or(rest.len());
+      from_utf8(&rest[..end])
+        .map_err(|_| invalid("non-UTF-8 member name") )?
+    } else {
+      name.trim_end_matches('/')
+    };
+    members.push(dir.join(member));
+  }
+
+  Ok(Some(members))
+}
@@ -12,21 +12,26 @@ use std::path::{Path, PathBuf};
 use std::process;
 
 pub use command_queue::{CommandQueueError, CommandQueue,
-                        Command};
+                        Command, OutputRole, QueueObserver, StepInfo};
 
 pub extern crate regex;
 extern crate tempdir;
+extern crate atty;
+extern crate cpp_demangle;
 extern crate ctrlc;
 extern crate dirs;
 extern crate git2;
+#[cfg(unix)]
+extern crate libc;
+extern crate sha2;
 #[macro_use]
 extern crate log;
 
 #[macro_use]
 extern crate lazy_static;
+#[cfg(feature = "arg-plugins")]
+extern crate toml;
 
-#[macro_use]
-extern crate maplit;
 
 #[macro_export] macro_rules! tool_arguments {
   ($ty:ty => [ $( $arg:expr, )* ]) => ({
@@ -425,8 +430,20 @@ pub mod filetype;
 pub mod ldtools;
 pub mod toolchain;
 pub mod command_queue;
+pub mod config;
+pub mod demangle;
+pub mod diag;
+pub mod explain;
 pub mod git;
+pub mod interrupt;
+pub mod jobs;
+pub mod lock;
+pub mod manifest;
 pub mod repo;
+pub mod report;
+pub mod suggest;
+#[cfg(feature = "arg-plugins")]
+pub mod plugins;
 
 pub trait CreateIfNotExists: Sized + AsRef<Path> {
   fn create_if_not_exists(self) -> std::io::Result<Self> {
@@ -444,7 +461,6 @@ impl<'a> CreateIfNotExists for &'a PathBuf { }
 #[cfg(feature = "nacl")]
 pub const SDK_VERSION: &'static str = include_str!(concat!(env!("OUT_DIR"),
                                                            "/REV"));
-pub const CLANG_VERSION: &'static str = "5.0.0";
 
 #[cfg(not(any(feature = "sdk", target_os = "nacl")))]
 pub fn need_nacl_toolchain() -> PathBuf {
@@ -531,12 +547,11 @@ pub fn add_gold_args(cmd: &mut process::Command) {
     cmd.arg("-plugin-opt=emit-llvm");
 }
 
-pub fn expect_next<'a, T>(args: &mut T) -> <T as Iterator>::Item
+pub fn expect_next<'a, T>(args: &mut T) -> Result<<T as Iterator>::Item, Box<dyn Error>>
     where T: Iterator, <T as Iterator>::Item: AsRef<str> + PartialEq<&'a str>
 {
-    let arg = args.next();
-    if arg.is_none() { panic!("expected another argument"); }
-    arg.unwrap()
+    args.next()
+      .ok_or_else(|| "expected another argument".into() )
 }
 
 pub fn get_crate_root() -> PathBuf {
@@ -582,6 +597,41 @@ impl Default for Arch {
     }
 }
 
+/// Target triples (and aliases) accepted by the drivers. Aliases are built
+/// as `wasm32-unknown-unknown-wasm`; the rest are passed to clang as is, but
+/// still use our sysroot.
+pub const WASM_TARGETS: &'static [(&'static str, &'static str)] = &[
+  ("wasm32-unknown-unknown-wasm", "the canonical target"),
+  ("wasm32-unknown-unknown", "alias for wasm32-unknown-unknown-wasm"),
+  ("wasm32", "alias for wasm32-unknown-unknown-wasm"),
+  ("wasm32-wasi", "accepted for compatibility; uses this toolchain's sysroot"),
+  ("wasm32-unknown-wasi", "accepted for compatibility; uses this toolchain's sysroot"),
+  ("wasm32-unknown-emscripten", "accepted for compatibility; uses this toolchain's sysroot"),
+];
+
+/// `triple`, if the toolchain can build it, with aliases resolved.
+pub fn canonical_target(triple: &str) -> Result<&'static str, String> {
+  let &(target, desc) = WASM_TARGETS.iter()
+    .find(|&&(target, _)| target == triple )
+    .ok_or_else(|| {
+      format!("`{}` isn't a supported target; see `--print-targets` \
+               for the accepted targets", triple)
+    })?;
+  if desc.starts_with("alias for ") {
+    Ok(WASM_TARGETS[0].0)
+  } else {
+    Ok(target)
+  }
+}
+
+/// For `--print-targets`.
+pub fn print_targets() {
+  println!("Accepted targets:");
+  for &(target, desc) in WASM_TARGETS.iter() {
+    println!("  {:<28} {}", target, desc);
+  }
+}
+
 lazy_static! {
   static ref ARCHS: Vec<(Arch, regex::Regex)> =
     vec![
@@ -626,12 +676,24 @@ impl Arch {
 
     let arch = match arch {
       None => {
-        return Err(format!("`{}` is an unknown target arch",
-                           arch_str));
+        return Err(format!("`{}` is an unknown target arch; see `--print-targets` \
+                            for the accepted targets", arch_str));
       },
       Some(arch) => arch,
     };
 
+    if arch.is_wasm() {
+      // the triple minus the arch, ie `-unknown-unknown-wasm`
+      let rest = &triple[arch_str.len()..];
+      let known = WASM_TARGETS.iter()
+        .any(|&(target, _)| &target["wasm32".len()..] == rest );
+      if known {
+        return Ok(arch);
+      }
+      return Err(format!("`{}` isn't a supported target; see `--print-targets` \
+                          for the accepted targets", triple));
+    }
+
     macro_rules! unsupported_os(
             ($os:ident) => {
                 return Err(format!("OS `{}` is not supported",
@@ -738,30 +800,39 @@ impl fmt::Display for OptimizationGoal {
 }
 
 impl OptimizationGoal {
-  pub fn parse(str: &str) -> Option<OptimizationGoal> {
+  /// `str` is what follows `-O`. `-Og` is `-O1`, and `-Ofast` is `-O3` (the
+  /// fast-math part is up to the caller).
+  pub fn parse(str: &str) -> Result<OptimizationGoal, String> {
     let o = match str {
-      "" | "2" => OptimizationGoal::Speed(2),
-      "0" => OptimizationGoal::Speed(0),
-      "1" => OptimizationGoal::Speed(1),
-      "3" => OptimizationGoal::Speed(3),
-      "4" => OptimizationGoal::Speed(4),
+      "" => OptimizationGoal::Speed(2),
+      "g" => OptimizationGoal::Speed(1),
+      "fast" => OptimizationGoal::Speed(3),
       "s" => OptimizationGoal::Balanced,
       "z" => OptimizationGoal::Size,
-
-      _ => { return None; },
+      _ => {
+        let o = str.parse()
+          .map(OptimizationGoal::Speed)
+          .map_err(|_| invalid_optimization_level(&format!("-O{}", str)) )?;
+        o.check()?;
+        o
+      },
     };
-    Some(o)
+    Ok(o)
   }
 
-  pub fn check(&self) {
+  pub fn check(&self) -> Result<(), String> {
     match self {
       &OptimizationGoal::Speed(n) if n > 4 => {
-        panic!("invalid optimization level");
+        Err(invalid_optimization_level(&format!("{}", self)))
       },
-      _ => {},
+      _ => Ok(()),
     }
   }
 }
+fn invalid_optimization_level(arg: &str) -> String {
+  format!("invalid optimization level `{}`; expected one of `-O0` to `-O4`, \
+           `-Os`, `-Oz`, `-Og` or `-Ofast`", arg)
+}
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum StripMode {
@@ -790,7 +861,9 @@ impl fmt::Display for StripMode {
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum EhMode {
   None,
+  /// Emscripten style: invokes go through JS, which catches the throw.
   SjLj,
+  /// The wasm exception handling proposal (`--eh=wasm`).
   Zerocost,
 }
 
@@ -801,6 +874,32 @@ impl Default for EhMode {
 }
 
 impl EhMode {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      &EhMode::None => "none",
+      &EhMode::SjLj => "sjlj",
+      &EhMode::Zerocost => "wasm",
+    }
+  }
+  /// What clang needs to compile code which throws. Objects and libraries
+  /// built for one mode can't be mixed with another's.
+  pub fn clang_flags(&self) -> &'static [&'static str] {
+    match self {
+      &EhMode::None => &[],
+      &EhMode::SjLj => &["-fexceptions", "-mllvm", "-enable-emscripten-cxx-exceptions"],
+      &EhMode::Zerocost => &["-fwasm-exceptions"],
+    }
+  }
+  /// Where the sysroot has the C++ runtime built for this mode, relative
+  /// to its `lib` dir. `None` is the plain `lib`.
+  pub fn lib_subdir(&self) -> Option<&'static str> {
+    match self {
+      &EhMode::None => None,
+      &EhMode::SjLj => Some("eh-sjlj"),
+      &EhMode::Zerocost => Some("eh-wasm"),
+    }
+  }
+
   pub fn parse_arg(arg: &str) -> Option<Result<EhMode, String>> {
     const PNACL_EH: &'static str = "--pnacl-exceptions=";
     if arg.starts_with(PNACL_EH) {
@@ -828,6 +927,24 @@ impl EhMode {
   }
 }
 
+impl fmt::Display for EhMode {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.pad(self.as_str())
+  }
+}
+impl ::std::str::FromStr for EhMode {
+  type Err = String;
+  fn from_str(s: &str) -> Result<EhMode, String> {
+    match s {
+      "none" => Ok(EhMode::None),
+      "sjlj" => Ok(EhMode::SjLj),
+      "wasm" | "zerocost" => Ok(EhMode::Zerocost),
+      _ => Err(format!("unknown exception handling mode `{}`; expected `none`, `wasm` or `sjlj`",
+                       s)),
+    }
+  }
+}
+
 #[test]
 fn eh_mode_test() {
   assert_eq!(EhMode::parse_arg("--something"), None);
@@ -850,6 +967,411 @@ fn eh_mode_test() {
              Some(Ok(EhMode::Zerocost)));
 }
 
+const DETERMINISTIC_ENV: &'static str = "WASM_DRIVER_DETERMINISTIC";
+const HERMETIC_ENV: &'static str = "WASM_DRIVER_HERMETIC";
+const NO_CAPTURE_ENV: &'static str = "WASM_DRIVER_NO_CAPTURE";
+const RELAXED_FLAGS_ENV: &'static str = "WASM_DRIVER_RELAXED_FLAGS";
+const FORWARD_MACHINE_FLAGS_ENV: &'static str = "WASM_DRIVER_FORWARD_UNKNOWN_MACHINE_FLAGS";
+const ALLOW_UNUSED_ARGS_ENV: &'static str = "WASM_DRIVER_ALLOW_UNUSED_ARGS";
+const IGNORE_HOST_ARGS_ENV: &'static str = "WASM_DRIVER_IGNORE_HOST_ARGS";
+/// Seconds; see `step_timeout`.
+const STEP_TIMEOUT_ENV: &'static str = "WASM_DRIVER_STEP_TIMEOUT";
+/// `:` separated list of extra vars to pass through in hermetic mode.
+const PASSTHROUGH_ENV: &'static str = "WASM_DRIVER_ENV_PASSTHROUGH";
+/// Newline separated `NAME=VALUE`s from `--tool-env`, set on every tool we
+/// run (and so inherited by nested drivers).
+const TOOL_ENV: &'static str = "WASM_DRIVER_TOOL_ENV";
+/// Always passed through in hermetic mode, in addition to the toolchain
+/// vars and our own `WASM_DRIVER_*` vars.
+const HERMETIC_ALLOWLIST: &'static [&'static str] = &[
+  "PATH",
+  "HOME",
+  "TMPDIR",
+];
+
+/// The global flags (`--deterministic`, `--hermetic`, etc), given to us or
+/// inherited from the driver which ran us through our `WASM_DRIVER_*`
+/// vars. `run_main` makes them current for the thread running the driver
+/// (see `enter`); the process's environment is left alone, and the vars
+/// are only set on the commands we spawn (see `hermetic_env`), so nested
+/// drivers get the same flags.
+#[derive(Clone, Debug, Default)]
+pub struct GlobalFlags {
+  pub deterministic: bool,
+  pub hermetic: bool,
+  pub no_capture: bool,
+  pub relaxed_flags: bool,
+  pub forward_unknown_machine_flags: bool,
+  pub allow_unused_args: bool,
+  pub ignore_host_args: bool,
+  pub no_color: bool,
+  pub report_bug: bool,
+  pub step_timeout: Option<::std::time::Duration>,
+  /// Unlike the others, `--resume` isn't passed on to the tools we run;
+  /// they're always started fresh.
+  pub resume: bool,
+  /// `--tool-env NAME=VALUE`s.
+  pub tool_env: Vec<(String, String)>,
+  /// `--env PASSTHROUGH=VAR`s.
+  pub passthrough: Vec<String>,
+  /// Where the steps are recorded under `--explain-changes`; like
+  /// `--resume`, it isn't passed on.
+  pub pipeline: Option<explain::Pipeline>,
+}
+
+thread_local! {
+  static CURRENT_FLAGS: std::cell::RefCell<Option<GlobalFlags>> =
+    std::cell::RefCell::new(None);
+}
+
+/// Makes the flags it replaced current again when dropped; see
+/// `GlobalFlags::enter`.
+#[must_use]
+pub struct GlobalFlagsGuard(Option<GlobalFlags>);
+impl Drop for GlobalFlagsGuard {
+  fn drop(&mut self) {
+    let prev = self.0.take();
+    CURRENT_FLAGS.with(|current| *current.borrow_mut() = prev );
+  }
+}
+
+impl GlobalFlags {
+  /// The flags the driver which ran us passed on, if any.
+  pub fn from_env() -> GlobalFlags {
+    use std::env::var;
+
+    GlobalFlags {
+      deterministic: boolean_env(DETERMINISTIC_ENV),
+      hermetic: boolean_env(HERMETIC_ENV),
+      no_capture: boolean_env(NO_CAPTURE_ENV),
+      relaxed_flags: boolean_env(RELAXED_FLAGS_ENV),
+      forward_unknown_machine_flags: boolean_env(FORWARD_MACHINE_FLAGS_ENV),
+      allow_unused_args: boolean_env(ALLOW_UNUSED_ARGS_ENV),
+      ignore_host_args: boolean_env(IGNORE_HOST_ARGS_ENV),
+      // `$WASM_DRIVER_COLOR` is read by `diag` itself.
+      no_color: false,
+      report_bug: boolean_env(report::REPORT_BUG_ENV),
+      step_timeout: var(STEP_TIMEOUT_ENV).ok()
+        .and_then(|secs| secs.parse().ok() )
+        .filter(|&secs| secs != 0 )
+        .map(::std::time::Duration::from_secs),
+      resume: false,
+      tool_env: var(TOOL_ENV).unwrap_or_default()
+        .lines()
+        .filter_map(|kv| {
+          let mut kv = kv.splitn(2, '=');
+          Some((kv.next()?.to_string(), kv.next()?.to_string()))
+        })
+        .collect(),
+      passthrough: var(PASSTHROUGH_ENV).unwrap_or_default()
+        .split(':')
+        .filter(|v| v.len() != 0 )
+        .map(|v| v.to_string() )
+        .collect(),
+      pipeline: None,
+    }
+  }
+  /// The flags of the driver running on this thread; outside of a driver,
+  /// those in our environment.
+  pub fn current() -> GlobalFlags {
+    Self::with_current(|flags| flags.clone() )
+  }
+  fn with_current<F, R>(f: F) -> R
+    where F: FnOnce(&GlobalFlags) -> R,
+  {
+    CURRENT_FLAGS.with(|current| {
+      match *current.borrow() {
+        Some(ref flags) => f(flags),
+        None => f(&GlobalFlags::from_env()),
+      }
+    })
+  }
+  /// Change the current flags, ie for `--env`s from the site flags.
+  pub fn modify_current<F>(f: F)
+    where F: FnOnce(&mut GlobalFlags),
+  {
+    CURRENT_FLAGS.with(|current| {
+      let mut current = current.borrow_mut();
+      if current.is_none() {
+        *current = Some(GlobalFlags::from_env());
+      }
+      f(current.as_mut().unwrap())
+    })
+  }
+  /// Make `self` current on this thread until the guard is dropped. Threads
+  /// started by the driver have to enter the flags themselves.
+  pub fn enter(self) -> GlobalFlagsGuard {
+    let prev = CURRENT_FLAGS.with(|current| current.borrow_mut().replace(self) );
+    GlobalFlagsGuard(prev)
+  }
+
+  /// Should spawned commands have their output captured and prefixed with
+  /// the step name? The default when stdout isn't a TTY.
+  pub fn capture_output(&self) -> bool {
+    !self.no_capture && !atty::is(atty::Stream::Stdout)
+  }
+
+  /// Handle `--env PASSTHROUGH=VAR`.
+  fn env_flag(&mut self, arg: &str) {
+    const PREFIX: &'static str = "PASSTHROUGH=";
+    if !arg.starts_with(PREFIX) {
+      diag::warning(&format!("ignoring unknown `--env {}`", arg));
+      return;
+    }
+    self.add_passthrough(&arg[PREFIX.len()..]);
+  }
+  /// Pass `var` through to everything we run in hermetic mode.
+  pub fn add_passthrough(&mut self, var: &str) {
+    if !self.passthrough.iter().any(|v| v == var ) {
+      self.passthrough.push(var.to_string());
+    }
+  }
+  /// Handle `--tool-env NAME=VALUE`.
+  fn add_tool_env(&mut self, arg: &str) -> Result<(), String> {
+    let mut kv = arg.splitn(2, '=');
+    match (kv.next(), kv.next()) {
+      (Some(name), Some(value)) if name.len() != 0 && !arg.contains('\n') => {
+        self.set_tool_env(name, value);
+        Ok(())
+      },
+      _ => Err(format!("`--tool-env {}`: expected `NAME=VALUE`", arg)),
+    }
+  }
+
+  /// Set `var` on every tool we run, replacing an earlier value.
+  pub fn set_tool_env(&mut self, var: &str, value: &str) {
+    self.tool_env.retain(|&(ref k, _)| k != var );
+    self.tool_env.push((var.to_string(), value.to_string()));
+  }
+
+  /// Set our vars on `cmd`, so a driver it runs, even through `make`,
+  /// etc, gets the same flags.
+  fn export(&self, cmd: &mut process::Command) {
+    let flags = [
+      (DETERMINISTIC_ENV, self.deterministic),
+      (HERMETIC_ENV, self.hermetic),
+      (NO_CAPTURE_ENV, self.no_capture),
+      (RELAXED_FLAGS_ENV, self.relaxed_flags),
+      (FORWARD_MACHINE_FLAGS_ENV, self.forward_unknown_machine_flags),
+      (ALLOW_UNUSED_ARGS_ENV, self.allow_unused_args),
+      (IGNORE_HOST_ARGS_ENV, self.ignore_host_args),
+      (report::REPORT_BUG_ENV, self.report_bug),
+    ];
+    for &(k, set) in flags.iter() {
+      if set {
+        cmd.env(k, "1");
+      }
+    }
+    if self.no_color {
+      cmd.env(diag::COLOR_ENV, "never");
+    }
+    if let Some(timeout) = self.step_timeout {
+      cmd.env(STEP_TIMEOUT_ENV, timeout.as_secs().to_string());
+    }
+    if self.tool_env.len() != 0 {
+      let vars: Vec<String> = self.tool_env.iter()
+        .map(|&(ref k, ref v)| format!("{}={}", k, v) )
+        .collect();
+      cmd.env(TOOL_ENV, vars.join("\n"));
+    }
+    if self.passthrough.len() != 0 {
+      cmd.env(PASSTHROUGH_ENV, self.passthrough.join(":"));
+    }
+  }
+  /// `hermetic_env`, for these flags.
+  pub fn apply_env(&self, cmd: &mut process::Command) {
+    if self.hermetic {
+      self.scrub_env(cmd);
+    }
+    self.export(cmd);
+    for &(ref k, ref v) in self.tool_env.iter() {
+      cmd.env(k, v);
+    }
+  }
+  fn scrub_env(&self, cmd: &mut process::Command) {
+    use std::env::{var_os, vars_os};
+    use std::ffi::OsString;
+
+    let explicit: Vec<(OsString, Option<OsString>)> = cmd.get_envs()
+      .map(|(k, v)| (k.to_os_string(), v.map(|v| v.to_os_string() )) )
+      .collect();
+
+    cmd.env_clear();
+
+    let allowed = HERMETIC_ALLOWLIST.iter()
+      .chain(toolchain::TOOLCHAIN_ENVS.iter())
+      .map(|&k| k )
+      .chain(self.passthrough.iter().map(|k| &k[..] ));
+    for k in allowed {
+      if let Some(v) = var_os(k) {
+        cmd.env(k, v);
+      }
+    }
+    for (k, v) in vars_os() {
+      if is_driver_var(&k) {
+        cmd.env(k, v);
+      }
+    }
+
+    for (k, v) in explicit.into_iter() {
+      match v {
+        Some(v) => { cmd.env(k, v); },
+        None => { cmd.env_remove(k); },
+      }
+    }
+  }
+}
+fn is_driver_var(k: &std::ffi::OsStr) -> bool {
+  k.to_str().map(|k| k.starts_with("WASM_DRIVER_") ).unwrap_or(false)
+}
+
+/// Are we in `--deterministic` mode?
+pub fn deterministic() -> bool {
+  GlobalFlags::with_current(|flags| flags.deterministic )
+}
+
+/// Are spawned commands given a scrubbed environment (`--hermetic`)?
+pub fn hermetic() -> bool {
+  GlobalFlags::with_current(|flags| flags.hermetic )
+}
+
+/// See `GlobalFlags::capture_output`; `--no-capture` disables.
+pub fn capture_output() -> bool {
+  GlobalFlags::with_current(|flags| flags.capture_output() )
+}
+
+/// Are unknown `-f`/`-m` flags warned about and ignored (`--relaxed-flags`),
+/// instead of being errors?
+pub fn relaxed_flags() -> bool {
+  GlobalFlags::with_current(|flags| flags.relaxed_flags )
+}
+
+/// Are unknown `-m` flags passed on to clang
+/// (`--forward-unknown-machine-flags`), instead of being errors? Build
+/// systems add x86 flags, ie `-msse4.2`, which clang ignores for wasm.
+pub fn forward_unknown_machine_flags() -> bool {
+  GlobalFlags::with_current(|flags| flags.forward_unknown_machine_flags )
+}
+
+/// Are arguments no tool arg matched warned about (`--allow-unused-args`),
+/// instead of being errors?
+pub fn allow_unused_args() -> bool {
+  GlobalFlags::with_current(|flags| flags.allow_unused_args )
+}
+
+/// Are macOS specific args, like `-framework`, warned about and ignored
+/// (`--ignore-host-args`), instead of being errors?
+pub fn ignore_host_args() -> bool {
+  GlobalFlags::with_current(|flags| flags.ignore_host_args )
+}
+
+/// How long a command the queue runs can take before it's killed
+/// (`--step-timeout=<secs>`). Steps can override it; see
+/// `ConcreteCommand::timeout`.
+pub fn step_timeout() -> Option<::std::time::Duration> {
+  GlobalFlags::with_current(|flags| flags.step_timeout )
+}
+
+/// Should a checkpointed queue continue from where an earlier run of the
+/// same invocation stopped (`--resume`)?
+pub fn resume() -> bool {
+  GlobalFlags::with_current(|flags| flags.resume )
+}
+
+/// The `--tool-env` vars.
+pub fn tool_env() -> Vec<(String, String)> {
+  GlobalFlags::with_current(|flags| flags.tool_env.clone() )
+}
+
+/// In hermetic mode, scrub `cmd`'s environment down to the allowlist (plus
+/// `--env PASSTHROUGH=VAR` vars). Vars explicitly set on `cmd` are kept.
+/// Either way, the global flags are set for nested drivers, then the
+/// `--tool-env` vars, overriding everything else.
+pub fn hermetic_env(cmd: &mut process::Command) {
+  GlobalFlags::with_current(|flags| flags.apply_env(cmd) )
+}
+
+/// A short hash of `parts` for naming things on disk. Unlike
+/// `DefaultHasher`'s, it's the same from one build of the drivers to the
+/// next.
+pub fn stable_hash<I, T>(parts: I) -> String
+  where I: IntoIterator<Item = T>,
+        T: AsRef<[u8]>,
+{
+  use sha2::{Digest, Sha256};
+
+  let mut hasher = Sha256::new();
+  for part in parts {
+    let part = part.as_ref();
+    // length prefixed, so `["ab", "c"]` and `["a", "bc"]` differ.
+    hasher.update(&(part.len() as u64).to_le_bytes());
+    hasher.update(part);
+  }
+  hasher.finalize()[..8].iter()
+    .map(|b| format!("{:02x}", b) )
+    .collect()
+}
+
+/// `s` as a JSON string literal, quotes included.
+pub fn json_string(s: &str) -> String {
+  let mut out = String::with_capacity(s.len() + 2);
+  out.push('"');
+  for c in s.chars() {
+    match c {
+      '"' => out.push_str("\\\""),
+      '\\' => out.push_str("\\\\"),
+      '\n' => out.push_str("\\n"),
+      c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+      c => out.push(c),
+    }
+  }
+  out.push('"');
+  out
+}
+
+/// `$TMPDIR/wasm-driver-<uid>`, only usable by this user, for what would
+/// otherwise be at a predictable path in the shared temp dir, where another
+/// user could get in the way (or pre-create it, or symlink it elsewhere).
+pub fn user_temp_dir() -> std::io::Result<PathBuf> {
+  use std::fs::DirBuilder;
+  use std::io::ErrorKind;
+
+  #[cfg(unix)]
+  fn user() -> String { unsafe { libc::getuid() }.to_string() }
+  #[cfg(not(unix))]
+  fn user() -> String { std::env::var("USERNAME").unwrap_or_default() }
+
+  let dir = std::env::temp_dir().join(format!("wasm-driver-{}", user()));
+  let mut builder = DirBuilder::new();
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::DirBuilderExt;
+    builder.mode(0o700);
+  }
+  match builder.create(&dir) {
+    Ok(()) => {},
+    Err(ref e) if e.kind() == ErrorKind::AlreadyExists => {},
+    Err(e) => return Err(e),
+  }
+
+  #[cfg(unix)]
+  {
+    use std::io::Error;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let meta = std::fs::symlink_metadata(&dir)?;
+    if !meta.is_dir() || meta.uid() != unsafe { libc::getuid() } {
+      return Err(Error::new(ErrorKind::PermissionDenied,
+                            format!("`{}` isn't a directory owned by this user",
+                                    dir.display())));
+    }
+    if meta.mode() & 0o077 != 0 {
+      std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))?;
+    }
+  }
+  Ok(dir)
+}
+
 pub fn boolean_env<K>(k: K) -> bool
   where K: AsRef<std::ffi::OsStr>,
 {
@@ -858,10 +1380,20 @@ pub fn boolean_env<K>(k: K) -> bool
     _ => false,
   }
 }
-fn run_unlogged_cmd(task: &str, mut cmd: process::Command) {
-  println!("({}): Running: {:?}", task, cmd);
-  let mut child = cmd.spawn().unwrap();
-  assert!(child.wait().unwrap().success(), "{:?}", cmd);
+fn run_unlogged_cmd(task: &str, mut cmd: process::Command)
+  -> Result<(), Box<dyn Error>>
+{
+  hermetic_env(&mut cmd);
+  info!("({}): running: {:?}", task, cmd);
+  let timeout = step_timeout();
+  let (status, killed) = interrupt::status_timeout(&mut cmd, timeout)?;
+  if killed {
+    Err(format!("timed out after {}s: {:?}", timeout.unwrap().as_secs(), cmd))?;
+  }
+  if !status.success() {
+    Err(format!("failed ({}): {:?}", status, cmd))?;
+  }
+  Ok(())
 }
 
 /// A function to call if the associated regex was a match. Return `Err` if
@@ -951,77 +1483,62 @@ impl<This> Clone for ToolArg<This>
 impl<This> InitedToolArg<This>
   where This: ?Sized,
 {
+  /// Try to match this arg against the front of `args`, running its action
+  /// if it does. `count` is set to the number of args consumed, even if an
+  /// error is returned.
   pub fn check<'a, T>(&self,
                       this: &mut This,
                       args: &mut Peekable<T>,
                       count: &mut usize) -> Option<Result<(), Box<dyn Error>>>
-  // Some(Ok(<number of args used>))
     where T: Iterator,
           <T as Iterator>::Item: AsRef<str> + PartialEq<&'a str>
   {
+    // This is so we can capture the next arg, whatever it is (including
+    // empty).
+    lazy_static! {
+      static ref SECOND_ARG: regex::Regex = regex::Regex::new("(?s)^(.*)$").unwrap();
+    }
+
     *count = 0;
-    let res = {
-      let first_arg = match args.peek() {
-        Some(arg) => arg.as_ref().to_string(),
-        None => { return None; },
-      };
-      if self.action.is_none() {
-        if self.single.is_some() &&
-          self.single.as_ref().unwrap().is_match(first_arg.as_ref()) {
-          Some(Ok(()))
-        } else if self.split.as_ref().map(|r| r.is_match(first_arg.as_ref()) ).unwrap_or(false) {
-          assert!(args.next().is_some());
-          if args.peek().is_none() {
-            let msg = format!("`{}` expects another argument",
-                              self.split.as_ref().unwrap());
-            Some(Err(From::from(msg)))
-          } else {
-            *count += 1;
-            Some(Ok(()))
-          }
-        } else {
-          None
-        }
-      } else {
-        let action = self.action.unwrap();
-        let match_ = self.single
-          .as_ref()
-          .and_then(|s| s.captures(first_arg.as_ref()) )
-          .map(|capture| {
-            action(this, true, capture)
-          });
-        if match_.is_some() {
-          match_
-        } else if self.split.as_ref().map(|r| r.is_match(first_arg.as_ref()) ).unwrap_or(false) {
-          // This is so we can capture the next arg:
-          lazy_static! {
-              static ref SECOND_ARG: regex::Regex = regex::Regex::new("(.+)").unwrap();
-            };
-          assert!(args.next().is_some());
-
-          if args.peek().is_none() {
-            let msg = format!("`{}` expects another argument",
-                              self.split.as_ref().unwrap());
-            Some(Err(From::from(msg)))
-          } else {
-            let cap = SECOND_ARG.captures(args.peek().unwrap().as_ref())
-              .unwrap();
-            let action_result = action(this, false, cap);
-            *count += 1;
-            Some(action_result)
-          }
-        } else {
-          None
-        }
-      }
+    let first_arg = match args.peek() {
+      Some(arg) => arg.as_ref().to_string(),
+      None => { return None; },
     };
 
-    if res.is_some() {
-      assert!(args.next().is_some());
-      *count += 1;
+    if let Some(cap) = self.single.as_ref().and_then(|s| s.captures(&first_arg) ) {
+      args.next();
+      *count = 1;
+      return Some(match self.action {
+        Some(action) => action(this, true, cap),
+        None => Ok(()),
+      });
     }
 
-    res
+    let is_split = self.split.as_ref()
+      .map(|r| r.is_match(&first_arg) )
+      .unwrap_or(false);
+    if !is_split { return None; }
+
+    args.next();
+    *count = 1;
+    let next_arg = match args.peek() {
+      Some(arg) => arg.as_ref().to_string(),
+      None => {
+        let msg = format!("`{}` expects another argument", first_arg);
+        return Some(Err(msg.into()));
+      },
+    };
+    args.next();
+    *count = 2;
+
+    Some(match self.action {
+      Some(action) => {
+        let cap = SECOND_ARG.captures(&next_arg)
+          .expect("SECOND_ARG matches anything");
+        action(this, false, cap)
+      },
+      None => Ok(()),
+    })
   }
 }
 
@@ -1048,13 +1565,83 @@ pub trait ToolInvocation: Tool + Default {
   /// Called until `None` is returned. Put args that override errors before
   /// the the args that can have those errors
   fn args(&self, iteration: usize) -> Option<ToolArgs<Self>>;
+
+  /// Defaults which are derived from the rest of the invocation when the
+  /// commands are enqueued, for `--print-effective-commandline`.
+  fn derived_state(&self) -> Vec<(&'static str, String)> { Vec::new() }
+
+  /// Contradictory flags, checked once every argument has been processed
+  /// (so they're all reported together, before anything runs).
+  fn conflicts(&self) -> Vec<String> { Vec::new() }
+
+  /// Pass `args` through to the compiler this tool runs; for the
+  /// `arg-plugins` feature.
+  fn forward_compiler_args(&mut self, _args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    Err(format!("`{}` doesn't run a compiler", self.get_name()).into())
+  }
+  /// Pass `args` through to the linker this tool runs.
+  fn forward_linker_args(&mut self, _args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    Err(format!("`{}` doesn't run a linker", self.get_name()).into())
+  }
 }
 
+/// Print the invocation as it is after argument processing, as YAML.
+/// Every field is listed (via `Debug`), followed by the derived defaults.
+pub fn print_effective_commandline<T>(invocation: &T)
+  where T: ToolInvocation,
+{
+  fn quote(s: &str) -> String {
+    format!("{:?}", s)
+  }
+
+  println!("tool: {}", quote(&invocation.get_name()));
+  match invocation.get_output() {
+    Some(out) => println!("output: {}", quote(&format!("{}", out.display()))),
+    None => println!("output: null"),
+  }
+  println!("invocation: |");
+  for line in format!("{:#?}", invocation).lines() {
+    println!("  {}", line);
+  }
+  let derived = invocation.derived_state();
+  if derived.len() == 0 {
+    println!("derived: {{}}");
+  } else {
+    println!("derived:");
+    for (key, value) in derived.into_iter() {
+      println!("  {}: {}", key, quote(&value));
+    }
+  }
+}
+
+#[cfg(feature = "arg-plugins")]
+pub fn process_invocation_args<T>(invocation: &mut T,
+                                  args: Vec<String>,
+                                  skip_inputs_check: bool)
+  -> Result<(), Box<dyn Error>>
+  where T: ToolInvocation + 'static,
+{
+  let flags = plugins::configured()?;
+  plugins::process_invocation_args(flags, invocation, args, skip_inputs_check)
+}
+#[cfg(not(feature = "arg-plugins"))]
 pub fn process_invocation_args<T>(invocation: &mut T,
                                   args: Vec<String>,
                                   skip_inputs_check: bool)
   -> Result<(), Box<dyn Error>>
   where T: ToolInvocation + 'static,
+{
+  parse_args(invocation, args, skip_inputs_check, &mut |_, _| None )
+}
+
+/// `site` gets the first go at each argument of the last set (see
+/// `plugins`).
+fn parse_args<T>(invocation: &mut T,
+                 args: Vec<String>,
+                 skip_inputs_check: bool,
+                 site: &mut dyn FnMut(&mut T, &str) -> Option<Result<(), Box<dyn Error>>>)
+  -> Result<(), Box<dyn Error>>
+  where T: ToolInvocation + 'static,
 {
   use std::collections::BTreeMap;
   use std::io::{Cursor, };
@@ -1074,6 +1661,8 @@ pub fn process_invocation_args<T>(invocation: &mut T,
 
     if next_args.is_none() { break; }
     let next_args = next_args.unwrap();
+    // the catch-alls (ie `UNSUPPORTED` and the inputs) are in the last set.
+    let last = invocation.args(iteration + 1).is_none();
     let next_args: Vec<InitedToolArg<_>> = next_args
       .into_iter()
       .map(|v| v.into() )
@@ -1104,6 +1693,19 @@ pub fn process_invocation_args<T>(invocation: &mut T,
           .unwrap()
           .to_string();
         //println!("current_arg: {}", current_arg);
+        // site flags go after the other options have taken their values,
+        // so they only match whole arguments, but before the catch-alls.
+        if last {
+          if let Some(res) = site(invocation, &current_arg) {
+            used.push(program_arg_id);
+            program_args_iter.next();
+            program_arg_id += 1;
+            if let Err(msg) = res {
+              errors.push((current_arg, msg));
+            }
+            continue 'outer;
+          }
+        }
         'inner: for accepted_arg in next_args.iter() {
 
           let mut args_used = 0;
@@ -1127,8 +1729,8 @@ pub fn process_invocation_args<T>(invocation: &mut T,
               }
 
               if let Err(msg) = res {
+                // keep going, so every bad arg is reported at once.
                 errors.push((current_arg, msg));
-                break;
               }
 
               continue 'outer;
@@ -1166,21 +1768,98 @@ pub fn process_invocation_args<T>(invocation: &mut T,
     iteration += 1;
   }
 
+  if program_args.len() != 0 {
+    let unused: Vec<String> = program_args.values().cloned().collect();
+    suggest::unused_args(invocation, &unused[..])?;
+  }
+
+  let conflicts = invocation.conflicts();
+  if conflicts.len() != 0 {
+    let conflicts: Vec<String> = conflicts.into_iter()
+      .map(|conflict| format!("conflicting arguments: {}", conflict) )
+      .collect();
+    Err(conflicts.join("\n"))?;
+  }
+
   Ok(())
 }
 
 pub fn main_inner<T>(invocation: Option<T>) -> Result<T, CommandQueueError>
     where T: ToolInvocation + 'static,
 {
-  use std::env;
-
+  main_inner_flags(invocation, &mut GlobalFlags::from_env())
+}
+/// `main_inner`, leaving the global flags the args gave in `flags`, even
+/// if the driver fails.
+fn main_inner_flags<T>(invocation: Option<T>, flags: &mut GlobalFlags)
+  -> Result<T, CommandQueueError>
+    where T: ToolInvocation + 'static,
+{
+  let args = std::env::args_os()
+    .skip(1)
+    .map(|arg| {
+      arg.into_string()
+        .map_err(|arg| format!("argument `{}` isn't valid UTF-8",
+                               arg.to_string_lossy()))
+    })
+    .collect::<Result<Vec<String>, String>>()?;
+  let process_args = invocation.is_none();
+  run_main(invocation, args, process_args, flags)
+}
+/// `main_inner` for `invocation`, with `args` (less the program name)
+/// instead of the process's; for running a driver in process (see the
+/// `capi` crate). The global flags are only current for the call.
+pub fn main_inner_with_args<T>(invocation: T, args: Vec<String>)
+  -> Result<T, CommandQueueError>
+    where T: ToolInvocation + 'static,
+{
+  run_main(Some(invocation), args, true, &mut GlobalFlags::from_env())
+}
+fn run_main<T>(invocation: Option<T>, args: Vec<String>, process_args: bool,
+               flags: &mut GlobalFlags)
+  -> Result<T, CommandQueueError>
+    where T: ToolInvocation + 'static,
+{
   let mut verbose = false;
   let mut no_op   = false;
+  let mut print_effective = false;
+  let mut print_manifest = false;
+  let mut tool_env = vec![];
+  let mut step_timeout = None;
 
   let args: Vec<String> = {
-    let mut i = env::args();
-    i.next();
+    let i = args.into_iter();
+    let mut env_arg = false;
+    let mut tool_env_arg = false;
+    let mut step_timeout_arg = false;
     i.filter(|arg| {
+      if env_arg {
+        env_arg = false;
+        flags.env_flag(arg);
+        return false;
+      }
+      if tool_env_arg {
+        tool_env_arg = false;
+        tool_env.push(arg.clone());
+        return false;
+      }
+      if step_timeout_arg {
+        step_timeout_arg = false;
+        step_timeout = Some(arg.clone());
+        return false;
+      }
+      if arg.starts_with("--env=") {
+        flags.env_flag(&arg["--env=".len()..]);
+        return false;
+      }
+      if arg.starts_with("--tool-env=") {
+        tool_env.push(arg["--tool-env=".len()..].to_string());
+        return false;
+      }
+      if arg.starts_with("--step-timeout=") {
+        step_timeout = Some(arg["--step-timeout=".len()..].to_string());
+        return false;
+      }
       match &arg[..] {
         "--pnacl-driver-verbose" |
         "--wasm-driver-verbose" => {
@@ -1191,29 +1870,122 @@ pub fn main_inner<T>(invocation: Option<T>) -> Result<T, CommandQueueError>
           no_op = true;
           false
         },
+        "--print-effective-commandline" => {
+          print_effective = true;
+          false
+        },
+        "--deterministic" => {
+          flags.deterministic = true;
+          false
+        },
+        "--hermetic" => {
+          flags.hermetic = true;
+          false
+        },
+        "--no-capture" => {
+          flags.no_capture = true;
+          false
+        },
+        "--relaxed-flags" => {
+          flags.relaxed_flags = true;
+          false
+        },
+        "--forward-unknown-machine-flags" => {
+          flags.forward_unknown_machine_flags = true;
+          false
+        },
+        "--allow-unused-args" => {
+          flags.allow_unused_args = true;
+          false
+        },
+        "--ignore-host-args" => {
+          flags.ignore_host_args = true;
+          false
+        },
+        "--no-color" => {
+          flags.no_color = true;
+          false
+        },
+        "--resume" => {
+          flags.resume = true;
+          false
+        },
+        "--explain-changes" => {
+          flags.pipeline = Some(Default::default());
+          false
+        },
+        "--print-toolchain-manifest" => {
+          print_manifest = true;
+          false
+        },
+        "--report-bug" => {
+          flags.report_bug = true;
+          false
+        },
+        "--env" => {
+          env_arg = true;
+          false
+        },
+        "--tool-env" => {
+          tool_env_arg = true;
+          false
+        },
+        "--step-timeout" => {
+          step_timeout_arg = true;
+          false
+        },
         _ => true,
       }
     })
       .collect()
   };
+  for arg in tool_env.iter() {
+    flags.add_tool_env(arg)?;
+  }
+  if let Some(secs) = step_timeout {
+    match secs.parse::<u64>() {
+      Ok(n) if n != 0 => flags.step_timeout = Some(::std::time::Duration::from_secs(n)),
+      _ => Err(format!("invalid `--step-timeout={}`; expected a number of seconds",
+                       secs))?,
+    }
+  }
+  let _flags = flags.clone().enter();
 
-  let process_args = invocation.is_none();
   let mut invocation: T = invocation.unwrap_or_default();
+  if print_manifest {
+    manifest::print_toolchain_manifest();
+    return Ok(invocation);
+  }
   if process_args {
     process_invocation_args(&mut invocation, args, false)?;
   }
+  if print_effective {
+    print_effective_commandline(&invocation);
+    return Ok(invocation);
+  }
 
   let output = invocation.get_output()
     .map(|out| out.clone() );
-  let mut commands = CommandQueue::new(output);
+  let mut commands = CommandQueue::new(output.clone());
   commands.set_verbose(verbose);
   commands.set_dry_run(no_op);
+  commands.set_checkpoint(flags.resume);
+  commands.set_flags(flags.clone());
   invocation.enqueue_commands(&mut commands)?;
 
-  commands.run_all(&mut invocation)
-    .map(move |_| {
-      invocation
-    })
+  let result = commands.run_all(&mut invocation);
+  let output = output.filter(|out| !command_queue::is_stdout(out) );
+  if let (Some(output), Some(pipeline)) = (output, flags.pipeline.as_ref()) {
+    if let Err(e) = explain::explain_changes(&output, pipeline) {
+      diag::warning(&format!("couldn't explain changes: {}", e));
+    }
+    if result.is_ok() && !no_op && !flags.hermetic {
+      if let Err(e) = explain::save_pipeline(&output, pipeline) {
+        diag::warning(&format!("couldn't record the pipeline for `--explain-changes`: {}", e));
+      }
+    }
+  }
+  result.map(move |_| invocation )
 }
 
 pub fn main<T>(outs: Option<(&mut dyn Write, &mut dyn Write)>)
@@ -1221,7 +1993,7 @@ pub fn main<T>(outs: Option<(&mut dyn Write, &mut dyn Write)>)
   where T: ToolInvocation + 'static,
 {
   use std::io::{stdout, stderr};
-  use std::panic::catch_unwind;
+  use std::panic::{catch_unwind, AssertUnwindSafe};
 
   #[cfg(test)]
   fn test_safe_exit(code: i32) -> Result<(), i32> {
@@ -1232,22 +2004,47 @@ pub fn main<T>(outs: Option<(&mut dyn Write, &mut dyn Write)>)
     ::std::process::exit(code);
   }
 
+  fn write_reproducer(err: &mut dyn Write, reason: &str) {
+    match report::write_reproducer(reason) {
+      Ok(path) => {
+        writeln!(err, "A reproducer was written to `{}`; please attach it.",
+                 path.display())
+      },
+      Err(e) => writeln!(err, "Couldn't write a reproducer: {}", e),
+    }.unwrap();
+  }
+
   let mut stdout = stdout();
   let mut stderr = stderr();
 
+  // only the real stderr could be a terminal.
+  let real_stderr = outs.is_none();
   let (_, err) = outs.unwrap_or((&mut stdout, &mut stderr));
 
-  match catch_unwind(move || {
-    main_inner(None::<T>)?;
+  let mut flags = GlobalFlags::from_env();
+  let result = catch_unwind(AssertUnwindSafe(|| {
+    main_inner_flags(None::<T>, &mut flags)?;
     Ok(())
-  }) {
+  }));
+  // so `--no-color` and `--report-bug` apply to what's reported below.
+  let _flags = flags.enter();
+  match result {
     Ok(Err(CommandQueueError::Error(msg))) => {
-      write!(err, "{}\n", msg)
+      // after `--no-color` has been seen.
+      let color = real_stderr && diag::color_enabled();
+      let msg = msg.to_string();
+      write!(err, "{}", diag::format(diag::Severity::Error, None, msg.trim_end(), color))
         .unwrap();
+      if report::report_bug() {
+        write_reproducer(err, &format!("error: {}", msg));
+      }
 
       test_safe_exit(1)
     },
     Ok(Err(CommandQueueError::ProcessError(code))) => {
+      if report::report_bug() {
+        write_reproducer(err, &format!("command failed ({:?})", code));
+      }
       if let Some(code) = code {
         test_safe_exit(code)
       } else {
@@ -1255,11 +2052,21 @@ pub fn main<T>(outs: Option<(&mut dyn Write, &mut dyn Write)>)
       }
     }
     Ok(Ok(ok)) => Ok(ok),
-    Err(..) => {
+    Err(payload) => {
       writeln!(err, "Woa! It looks like something bad happened! :(")
         .unwrap();
       writeln!(err, "Please let us know by filling a bug at https://github.com/DiamondLovesYou/pnacl-clang-driver")
         .unwrap();
+      let msg = payload.downcast_ref::<&str>()
+        .map(|msg| msg.to_string() )
+        .or_else(|| payload.downcast_ref::<String>().cloned() )
+        .unwrap_or_else(|| "<unknown>".into() );
+      if report::report_bug() {
+        write_reproducer(err, &format!("panic: {}", msg));
+      } else {
+        writeln!(err, "Rerun with `--report-bug` to write a reproducer to attach.")
+          .unwrap();
+      }
 
       test_safe_exit(127)
     },
@@ -0,0 +1,95 @@
+//! Advisory file locks, for when concurrent drivers could write to the same
+//! place. The locks are `flock`s, so they go away with their holder, and
+//! the lock file has the holder's pid for the diagnostics. Only unix is
+//! supported; elsewhere locking always succeeds.
+
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// A held lock, released on drop.
+#[derive(Debug)]
+pub struct FileLock {
+  path: PathBuf,
+  _file: File,
+}
+impl FileLock {
+  pub fn path(&self) -> &Path { &self.path }
+}
+
+/// Try to take the lock on `file` without blocking.
+#[cfg(unix)]
+pub fn try_lock(file: &File) -> io::Result<bool> {
+  use std::os::unix::io::AsRawFd;
+
+  let r = unsafe {
+    ::libc::flock(file.as_raw_fd(), ::libc::LOCK_EX | ::libc::LOCK_NB)
+  };
+  if r == 0 { return Ok(true); }
+  let err = io::Error::last_os_error();
+  if err.kind() == io::ErrorKind::WouldBlock {
+    Ok(false)
+  } else {
+    Err(err)
+  }
+}
+#[cfg(not(unix))]
+pub fn try_lock(_file: &File) -> io::Result<bool> {
+  Ok(true)
+}
+
+fn holder(file: &mut File) -> Option<String> {
+  let mut pid = String::new();
+  file.seek(SeekFrom::Start(0)).ok()?;
+  file.read_to_string(&mut pid).ok()?;
+  let pid = pid.trim();
+  if pid.len() == 0 { None } else { Some(pid.to_string()) }
+}
+
+/// Lock `path`, which is `what` in the diagnostics, waiting at most
+/// `timeout` (forever if `None`) for whoever has it.
+pub fn lock(path: &Path, what: &str, timeout: Option<Duration>)
+  -> Result<FileLock, Box<Error>>
+{
+  let mut file = OpenOptions::new()
+    .create(true)
+    .read(true)
+    .write(true)
+    .open(path)
+    .map_err(|e| format!("opening {}'s lock `{}`: {}", what, path.display(), e) )?;
+
+  let start = Instant::now();
+  let mut waiting = false;
+  while !try_lock(&file)? {
+    let held_by = holder(&mut file)
+      .map(|pid| format!(" (held by pid {})", pid) )
+      .unwrap_or_default();
+    if super::interrupt::interrupted() {
+      return Err(format!("interrupted waiting for {}'s lock", what).into());
+    }
+    if let Some(timeout) = timeout {
+      if start.elapsed() >= timeout {
+        return Err(format!("timed out after {}s waiting for {}'s lock `{}`{}; \
+                            is another build using it?",
+                           timeout.as_secs(), what, path.display(), held_by).into());
+      }
+    }
+    if !waiting {
+      super::diag::note(&format!("waiting for {}'s lock `{}`{}", what,
+                                 path.display(), held_by));
+      waiting = true;
+    }
+    sleep(Duration::from_millis(100));
+  }
+
+  file.set_len(0)?;
+  file.seek(SeekFrom::Start(0))?;
+  write!(file, "{}", ::std::process::id())?;
+  Ok(FileLock {
+    path: path.to_path_buf(),
+    _file: file,
+  })
+}
@@ -0,0 +1,116 @@
+//! `--print-toolchain-manifest`: exactly which toolchain components a build
+//! used, by path, `--version` and sha256, so a build can be pinned to (or
+//! compared against) another. `wasm-ld --embed-toolchain-manifest` puts the
+//! same JSON in the output's `driver.toolchain` custom section.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use sha2::{Digest, Sha256};
+
+use json_string;
+use toolchain::WasmToolchain;
+
+/// Everything the drivers run, found in `$LLVM_ROOT/bin`.
+const LLVM_TOOLS: &'static [&'static str] = &[
+  "clang",
+  "wasm-ld",
+  "llvm-link",
+  "opt",
+  "llc",
+  "llvm-ar",
+  "llvm-ranlib",
+  "llvm-nm",
+  "llvm-dis",
+  "llvm-split",
+  "llvm-strip",
+  "llvm-objdump",
+  "llvm-dwarfdump",
+];
+/// ... and in `$BINARYEN/bin`.
+const BINARYEN_TOOLS: &'static [&'static str] = &[
+  "wasm-opt",
+  "wasm-dis",
+  "wasm-shell",
+];
+
+#[derive(Clone, Debug)]
+pub struct Component {
+  pub name: &'static str,
+  pub path: PathBuf,
+  /// The first line of `--version`.
+  pub version: Option<String>,
+  pub sha256: Option<String>,
+}
+
+pub fn sha256(path: &Path) -> io::Result<String> {
+  let mut hasher = Sha256::new();
+  let mut file = File::open(path)?;
+  let mut buf = [0u8; 64 * 1024];
+  loop {
+    let len = file.read(&mut buf)?;
+    if len == 0 { break; }
+    hasher.update(&buf[..len]);
+  }
+  let hash: Vec<String> = hasher.finalize().iter()
+    .map(|b| format!("{:02x}", b) )
+    .collect();
+  Ok(hash.concat())
+}
+
+fn version(path: &Path) -> Option<String> {
+  let mut cmd = Command::new(path);
+  cmd.arg("--version")
+    .stdin(Stdio::null());
+  super::hermetic_env(&mut cmd);
+  let output = cmd.output().ok()?;
+  if !output.status.success() { return None; }
+  String::from_utf8_lossy(&output.stdout)
+    .lines()
+    .map(|line| line.trim() )
+    .find(|line| line.len() != 0 )
+    .map(|line| line.to_string() )
+}
+
+/// The components of `tc` which are there.
+pub fn components(tc: &WasmToolchain) -> Vec<Component> {
+  let llvm = LLVM_TOOLS.iter()
+    .map(|&name| (name, tc.llvm_tool(name)) );
+  let binaryen = BINARYEN_TOOLS.iter()
+    .map(|&name| (name, tc.binaryen_tool(name)) );
+  llvm.chain(binaryen)
+    .filter(|&(_, ref path)| path.is_file() )
+    .map(|(name, path)| Component {
+      name,
+      version: version(&path),
+      sha256: sha256(&path).ok(),
+      path,
+    })
+    .collect()
+}
+
+
+/// `components` as JSON.
+pub fn manifest(components: &[Component]) -> String {
+  fn opt(s: &Option<String>) -> String {
+    s.as_ref().map(|s| json_string(s) ).unwrap_or_else(|| "null".into() )
+  }
+
+  let mut out = String::from("{\n  \"components\": [");
+  for (idx, c) in components.iter().enumerate() {
+    if idx != 0 { out.push(','); }
+    out.push_str(&format!("\n    {{\"name\": {}, \"path\": {}, \"version\": {}, \"sha256\": {}}}",
+                          json_string(c.name), json_string(&format!("{}", c.path.display())),
+                          opt(&c.version), opt(&c.sha256)));
+  }
+  if components.len() != 0 { out.push_str("\n  "); }
+  out.push_str("]\n}\n");
+  out
+}
+
+pub fn print_toolchain_manifest() {
+  let tc = WasmToolchain::new();
+  print!("{}", manifest(&components(&tc)[..]));
+}
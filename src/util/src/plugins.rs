@@ -0,0 +1,202 @@
+
+//! Site specific flags, without forking the drivers (the `arg-plugins`
+//! feature). The `arg-plugins` config key is a `:` separated list of TOML
+//! files, each with any number of:
+//!
+//! ```toml
+//! [[flag]]
+//! # matched against each whole argument (never an option's value), ahead
+//! # of the tool's catch-alls.
+//! match = "^-mmy-cpu=(.+)$"
+//! # `compiler`, `linker` or `env`.
+//! action = "compiler"
+//! # what's forwarded; `$1`, etc, are `match`'s groups. Defaults to the
+//! # argument itself.
+//! args = ["-DMY_CPU=$1"]
+//! # only for these tools (ie `wasm-clang`); every tool by default.
+//! tools = ["wasm-clang"]
+//!
+//! [[flag]]
+//! match = "^--my-cache=(.+)$"
+//! action = "env"
+//! var = "MY_CACHE"
+//! value = "$1"
+//! ```
+//!
+//! `env` flags set the var for everything we run, like `--tool-env` (so
+//! it's set in hermetic mode too).
+
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use regex::Regex;
+use toml::Value;
+
+use super::ToolInvocation;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+  /// Passed to the compiler (ie clang's driver args).
+  Compiler(Vec<String>),
+  /// Passed to the linker (wasm-ld).
+  Linker(Vec<String>),
+  Env {
+    var: String,
+    value: String,
+  },
+}
+
+#[derive(Debug, Clone)]
+pub struct PluginFlag {
+  pub pattern: Regex,
+  pub action: Action,
+  /// Empty for every tool.
+  pub tools: Vec<String>,
+}
+
+lazy_static! {
+  static ref CONFIGURED: Result<Vec<PluginFlag>, String> = load_configured();
+}
+
+fn load_configured() -> Result<Vec<PluginFlag>, String> {
+  let mut out = Vec::new();
+  let paths = match super::config::get("arg-plugins") {
+    Some(paths) => paths,
+    None => return Ok(out),
+  };
+  for path in paths.split(':').filter(|path| path.len() != 0 ) {
+    let flags = load(Path::new(path))
+      .map_err(|e| format!("arg plugin `{}`: {}", path, e) )?;
+    out.extend(flags);
+  }
+  Ok(out)
+}
+
+fn str_field<'a>(table: &'a Value, key: &str) -> Result<Option<&'a str>, Box<dyn Error>> {
+  match table.get(key) {
+    None => Ok(None),
+    Some(&Value::String(ref s)) => Ok(Some(&s[..])),
+    Some(_) => Err(format!("`{}` should be a string", key).into()),
+  }
+}
+fn strs_field(table: &Value, key: &str) -> Result<Option<Vec<String>>, Box<dyn Error>> {
+  let array = match table.get(key) {
+    None => return Ok(None),
+    Some(&Value::Array(ref array)) => array,
+    Some(_) => return Err(format!("`{}` should be an array of strings", key).into()),
+  };
+  array.iter()
+    .map(|v| {
+      v.as_str()
+        .map(|s| s.to_string() )
+        .ok_or_else(|| format!("`{}` should be an array of strings", key).into() )
+    })
+    .collect::<Result<Vec<String>, Box<dyn Error>>>()
+    .map(Some)
+}
+
+/// Parse the `[[flag]]`s in `contents`.
+pub fn parse(contents: &str) -> Result<Vec<PluginFlag>, Box<dyn Error>> {
+  let root: Value = contents.parse()?;
+  let flags = match root.get("flag") {
+    None => return Ok(Vec::new()),
+    Some(&Value::Array(ref flags)) => flags,
+    Some(_) => return Err("`flag` should be an array of tables (`[[flag]]`)".into()),
+  };
+
+  let mut out = Vec::new();
+  for (idx, flag) in flags.iter().enumerate() {
+    let parsed = (|| -> Result<PluginFlag, Box<dyn Error>> {
+      let pattern = str_field(flag, "match")?
+        .ok_or("missing `match`")?;
+      let pattern = Regex::new(pattern)?;
+      let args = strs_field(flag, "args")?
+        .unwrap_or_else(|| vec!["$0".to_string()] );
+      let action = match str_field(flag, "action")?.ok_or("missing `action`")? {
+        "compiler" => Action::Compiler(args),
+        "linker" => Action::Linker(args),
+        "env" => Action::Env {
+          var: str_field(flag, "var")?.ok_or("missing `var`")?.to_string(),
+          value: str_field(flag, "value")?.unwrap_or("$0").to_string(),
+        },
+        action => {
+          return Err(format!("unknown action `{}`; expected `compiler`, `linker` or `env`",
+                             action).into());
+        },
+      };
+      let tools = strs_field(flag, "tools")?.unwrap_or_default();
+      Ok(PluginFlag { pattern, action, tools, })
+    })();
+    out.push(parsed.map_err(|e| format!("flag {}: {}", idx + 1, e) )?);
+  }
+  Ok(out)
+}
+
+pub fn load(path: &Path) -> Result<Vec<PluginFlag>, Box<dyn Error>> {
+  let mut contents = String::new();
+  File::open(path)?.read_to_string(&mut contents)?;
+  parse(&contents)
+}
+
+impl PluginFlag {
+  /// `self.action`, with `arg`'s groups substituted, if it's for us.
+  pub fn matches(&self, tool: &str, arg: &str) -> Option<Action> {
+    if self.tools.len() != 0 && !self.tools.iter().any(|t| t == tool ) {
+      return None;
+    }
+    let cap = self.pattern.captures(arg)?;
+    let expand = |template: &String| {
+      let mut out = String::new();
+      cap.expand(template, &mut out);
+      out
+    };
+    Some(match self.action {
+      Action::Compiler(ref args) => Action::Compiler(args.iter().map(&expand).collect()),
+      Action::Linker(ref args) => Action::Linker(args.iter().map(&expand).collect()),
+      Action::Env { ref var, ref value } => Action::Env {
+        var: var.clone(),
+        value: expand(value),
+      },
+    })
+  }
+}
+
+/// Do the action of the first of `flags` which matches `arg`, if any.
+pub fn apply<T>(flags: &[PluginFlag], invocation: &mut T, arg: &str)
+  -> Option<Result<(), Box<dyn Error>>>
+  where T: ToolInvocation,
+{
+  let tool = invocation.get_name();
+  let action = flags.iter()
+    .filter_map(|flag| flag.matches(&tool, arg) )
+    .next()?;
+  Some(match action {
+    Action::Compiler(args) => invocation.forward_compiler_args(args),
+    Action::Linker(args) => invocation.forward_linker_args(args),
+    Action::Env { var, value } => {
+      // set on the tools we run, not on us.
+      super::GlobalFlags::modify_current(|flags| flags.set_tool_env(&var, &value) );
+      Ok(())
+    },
+  })
+}
+
+/// The flags from the config.
+pub fn configured() -> Result<&'static [PluginFlag], Box<dyn Error>> {
+  match *CONFIGURED {
+    Ok(ref flags) => Ok(&flags[..]),
+    Err(ref e) => Err(e.clone().into()),
+  }
+}
+
+/// `process_invocation_args`, with `flags` as the site flags.
+pub fn process_invocation_args<T>(flags: &[PluginFlag], invocation: &mut T,
+                                  args: Vec<String>, skip_inputs_check: bool)
+  -> Result<(), Box<dyn Error>>
+  where T: ToolInvocation + 'static,
+{
+  super::parse_args(invocation, args, skip_inputs_check,
+                    &mut |invocation, arg| apply(flags, invocation, arg) )
+}
@@ -1,6 +1,8 @@
 
 use std::borrow::Cow;
+use std::env;
 use std::error::Error;
+use std::fs;
 use std::path::{Path, PathBuf};
 use std::process::{Command};
 
@@ -9,6 +11,64 @@ use super::{ToolArgs, ToolArg, ToolArgAccessor, CommandQueue,
 
 use super::git;
 use super::git2;
+use super::lock::{lock, FileLock};
+
+/// Where sources are checked out by default, so that they can be shared
+/// between build trees, ie `~/.cache/wasm-driver/src`. `None` without a
+/// `$HOME`.
+pub fn default_src_cache() -> Option<PathBuf> {
+  use dirs::{cache_dir, home_dir};
+
+  let cache = cache_dir()
+    .or_else(|| home_dir().map(|home| home.join(".cache")) )?;
+  Some(cache.join("wasm-driver").join("src"))
+}
+
+/// `--repo-mirror`s and `--repo-depth`, applied to each repo before it's
+/// checked out; see `apply`.
+#[derive(Clone, Debug, Default)]
+pub struct CheckoutConfig {
+  /// `(NAME, PATH_OR_URL)`s. The last mirror given for a name wins.
+  pub mirrors: Vec<(String, String)>,
+  /// The clone depth for repos which don't set their own.
+  pub depth: Option<u32>,
+}
+impl CheckoutConfig {
+  /// Handle `--repo-mirror NAME=PATH_OR_URL`.
+  pub fn add_mirror(&mut self, spec: &str) -> Result<(), String> {
+    let mut kv = spec.splitn(2, '=');
+    match (kv.next(), kv.next()) {
+      (Some(name), Some(mirror)) if name.len() != 0 && mirror.len() != 0 => {
+        self.mirrors.push((name.to_string(), mirror.to_string()));
+        Ok(())
+      },
+      _ => Err(format!("`--repo-mirror {}`: expected `NAME=PATH_OR_URL`", spec)),
+    }
+  }
+  /// Where the repo `name` should be fetched from instead, if anywhere.
+  /// Local paths are made into `file://` URLs, so clone depths still apply.
+  pub fn mirror(&self, name: &str) -> Option<String> {
+    let mirror = self.mirrors.iter()
+      .filter(|&&(ref n, _)| n == name )
+      .map(|&(_, ref mirror)| mirror )
+      .last()?;
+    let path = Path::new(mirror);
+    if path.exists() {
+      let path = env::current_dir().ok()?.join(path);
+      Some(format!("file://{}", path.display()))
+    } else {
+      Some(mirror.clone())
+    }
+  }
+  /// `repo`, with its mirror and default depth.
+  pub fn apply(&self, mut repo: Repo) -> Repo {
+    if let Some(mirror) = self.mirror(&repo.name) {
+      repo.mirror = Some(mirror.into());
+    }
+    repo.depth = repo.depth.or(self.depth);
+    repo
+  }
+}
 
 #[derive(Clone, Debug)]
 pub enum RepoRoot {
@@ -49,6 +109,15 @@ pub struct Repo {
   /// ignored if root is local.
   pub commit: Option<Cow<'static, str>>,
   pub clobber: bool,
+  /// Only these paths are checked out, and only their blobs fetched (ie
+  /// for a few projects in a monorepo). Empty means everything. Ignored if
+  /// root is local.
+  pub sparse: Vec<Cow<'static, str>>,
+  /// Clone depth; see `Repo::depth`.
+  pub depth: Option<u32>,
+  /// Fetched from instead of the root's URL; see `CheckoutConfig`.
+  pub mirror: Option<Cow<'static, str>>,
+  pub recursive_submodules: bool,
 }
 
 impl Repo {
@@ -65,6 +134,10 @@ impl Repo {
       },
       commit: None,
       clobber: true,
+      sparse: vec![],
+      depth: None,
+      mirror: None,
+      recursive_submodules: false,
     }
   }
   pub fn new_git_commit<T, U, V, W>(name: T, url: U, branch: V,
@@ -83,39 +156,142 @@ impl Repo {
       },
       commit: Some(commit.into()),
       clobber: true,
+      sparse: vec![],
+      depth: None,
+      mirror: None,
+      recursive_submodules: false,
     }
   }
+  pub fn sparse<I, T>(mut self, paths: I) -> Self
+    where I: IntoIterator<Item = T>,
+          T: Into<Cow<'static, str>>,
+  {
+    self.sparse = paths.into_iter().map(Into::into).collect();
+    self
+  }
+  pub fn recursive_submodules(mut self) -> Self {
+    self.recursive_submodules = true;
+    self
+  }
+
+  /// How much history to fetch: `depth`, else just the tip, unless this
+  /// is a pinned commit (which might not be a branch tip) or `fat`.
+  fn depth(&self, fat: bool) -> Option<u32> {
+    if fat { return None; }
+    self.depth
+      .or_else(|| match (&self.root, &self.commit) {
+        (&RepoRoot::Git { .. }, &Some(_)) => None,
+        _ => Some(1),
+      })
+  }
+  /// `url`, or its `mirror`.
+  fn url(&self, url: &Cow<'static, str>) -> Cow<'static, str> {
+    self.mirror.clone()
+      .unwrap_or_else(|| url.clone() )
+  }
 
   pub fn checkout<T>(&self, dest: T, fat: bool)
     -> Result<(), Box<dyn Error>>
     where T: AsRef<Path>,
   {
     let dest = dest.as_ref();
+    // checkouts can be shared between builds (ie in a source cache), so
+    // concurrent ones take turns.
+    let _lock = self.lock_checkout(dest)?;
+    if self.is_pinned_checkout(dest) {
+      return Ok(());
+    }
     if self.clobber || !dest.exists() {
+      let opts = git::CheckoutOptions {
+        depth: self.depth(fat),
+        recursive_submodules: self.recursive_submodules,
+      };
+      if let RepoRoot::Git { ref url, ref branch, } = self.root {
+        if self.sparse.len() != 0 {
+          let cref = self.commit.as_ref().unwrap_or(branch);
+          let paths: Vec<&str> = self.sparse.iter()
+            .map(|path| path.as_ref() )
+            .collect();
+          let task = format!("checkout-{}", self.name);
+          return git::checkout_sparse(&task, dest, &self.url(url), cref,
+                                      &paths[..], opts);
+        }
+      }
       let (over, url, branch) = match self.root {
         RepoRoot::Local { ref path, ref branch, } => {
-          (Some(path), "", branch.as_ref())
+          (Some(path), Cow::Borrowed(""), branch.as_ref())
         },
         RepoRoot::Git { ref url, ref branch, } => {
-          (None, url.as_ref(), branch.as_ref())
+          (None, self.url(url), branch.as_ref())
         },
       };
-      let (cref, checkout) = if let RepoRoot::Local { .. } = self.root {
-        (branch, git::checkout_repo as fn(&str, &Path, &str, &str, bool))
+      type Checkout = fn(&str, &Path, &str, &str, git::CheckoutOptions)
+        -> Result<(), Box<dyn Error>>;
+      let (cref, checkout): (&str, Checkout) = if let RepoRoot::Local { .. } = self.root {
+        (branch, git::checkout_repo)
       } else if let Some(commit) = self.commit.as_ref() {
-        (commit.as_ref(), git::checkout_repo_commit as _)
+        (commit.as_ref(), git::checkout_repo_commit)
       } else {
-        (branch, git::checkout_repo as _)
+        (branch, git::checkout_repo)
       };
       git::checkout_or_override_raw(self.name.as_ref(),
                                     &dest,
-                                    over, url,
-                                    cref, !fat,
+                                    over, &url,
+                                    cref, opts,
                                     checkout)?;
     }
 
     Ok(())
   }
+  /// Lock `dest`, with a lock file next to it.
+  fn lock_checkout(&self, dest: &Path) -> Result<FileLock, Box<dyn Error>> {
+    let name = dest.file_name()
+      .ok_or_else(|| format!("bad checkout path `{}`", dest.display()) )?;
+    let parent = dest.parent().unwrap_or(Path::new("."));
+    fs::create_dir_all(parent)?;
+    let path = parent.join(format!("{}.lock", name.to_string_lossy()));
+    let what = format!("the `{}` checkout", self.name);
+    lock(&path, &what, None)
+  }
+  /// Is `dest` already a checkout of this repo's pinned `commit`? Those
+  /// are reused as is, even if clobbering, so a shared source cache isn't
+  /// fetched again for every build tree which pins the same commit.
+  pub fn is_pinned_checkout<T>(&self, dest: T) -> bool
+    where T: AsRef<Path>,
+  {
+    let commit = match (&self.root, &self.commit) {
+      (&RepoRoot::Git { .. }, &Some(ref commit)) => commit,
+      _ => return false,
+    };
+    let repo = match git2::Repository::open(dest.as_ref()) {
+      Ok(repo) => repo,
+      Err(_) => return false,
+    };
+    // `commit` can be a tag.
+    let pinned = repo.revparse_single(commit.as_ref()).ok()
+      .and_then(|obj| obj.peel_to_commit().ok() )
+      .map(|commit| commit.id() );
+    let head = repo.head().ok()
+      .and_then(|head| head.target() );
+    head.is_some() && head == pinned
+  }
+  /// Would `checkout` need to touch the network for `dest`?
+  pub fn needs_network<T>(&self, dest: T) -> bool
+    where T: AsRef<Path>,
+  {
+    match self.root {
+      RepoRoot::Local { .. } => false,
+      RepoRoot::Git { .. } if self.url_is_local() => false,
+      RepoRoot::Git { .. } if self.is_pinned_checkout(&dest) => false,
+      RepoRoot::Git { .. } => self.clobber || !dest.as_ref().exists(),
+    }
+  }
+  fn url_is_local(&self) -> bool {
+    self.mirror.as_ref()
+      .map(|url| url.starts_with("file://") )
+      .unwrap_or(false)
+  }
+
   pub fn checkout_thin<T>(&self, dest: T)
     -> Result<(), Box<dyn Error>>
     where T: AsRef<Path>,
@@ -229,6 +405,10 @@ impl Repo {
       },
       clobber: false,
       commit: None,
+      sparse: vec![],
+      depth: None,
+      mirror: None,
+      recursive_submodules: false,
     })
   }
   pub fn merge_branch<T>(&self, checkout: &PathBuf,
@@ -0,0 +1,190 @@
+
+//! Reproducers for bug reports. With `--report-bug`, when a driver crashes
+//! or fails, everything needed to rerun it is written to a file the user
+//! can attach to the issue: the exact argv, the environment the toolchain
+//! is configured from and the step which was running.
+
+use std::env;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use toolchain::TOOLCHAIN_ENVS;
+
+pub const REPORT_BUG_ENV: &'static str = "WASM_DRIVER_REPORT_BUG";
+
+/// Vars which commonly change what a build does, in addition to the
+/// toolchain's and our own `WASM_DRIVER_*` vars.
+const BUILD_ENVS: &'static [&'static str] = &[
+  "PATH",
+  "CC",
+  "CXX",
+  "CFLAGS",
+  "CXXFLAGS",
+  "CPPFLAGS",
+  "LDFLAGS",
+];
+
+#[derive(Debug, Default)]
+struct Step {
+  name: String,
+  command: Option<String>,
+}
+
+lazy_static! {
+  static ref CURRENT_STEP: Mutex<Option<Step>> = Mutex::new(None);
+}
+
+/// Should crashes and failures write a reproducer?
+pub fn report_bug() -> bool {
+  super::GlobalFlags::with_current(|flags| flags.report_bug )
+}
+
+/// Called by the command queue as each step starts.
+pub fn enter_step(name: String) {
+  if let Ok(mut step) = CURRENT_STEP.lock() {
+    *step = Some(Step {
+      name,
+      command: None,
+    });
+  }
+}
+/// Called just before the current step spawns `command`.
+pub fn step_command(command: String) {
+  if let Ok(mut step) = CURRENT_STEP.lock() {
+    if let Some(ref mut step) = *step {
+      step.command = Some(command);
+    }
+  }
+}
+
+/// POSIX shell quoting, so the rerun line can be pasted as is.
+pub fn shell_quote(arg: &str) -> String {
+  let plain = arg.len() != 0 && arg.chars().all(|c| {
+    c.is_ascii_alphanumeric() || "-_=+./,:@%".contains(c)
+  });
+  if plain {
+    arg.to_string()
+  } else {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+  }
+}
+
+/// Split `line` into words like a POSIX shell would (quotes and
+/// backslashes, but no expansions); the inverse of `shell_quote`.
+pub fn shell_split(line: &str) -> Result<Vec<String>, String> {
+  let mut words = Vec::new();
+  let mut word: Option<String> = None;
+  let mut chars = line.chars();
+  while let Some(c) = chars.next() {
+    match c {
+      c if c.is_whitespace() => {
+        words.extend(word.take());
+      },
+      '\'' => {
+        let word = word.get_or_insert_with(String::new);
+        loop {
+          match chars.next() {
+            Some('\'') => break,
+            Some(c) => word.push(c),
+            None => return Err(format!("unterminated `'` in `{}`", line)),
+          }
+        }
+      },
+      '"' => {
+        let word = word.get_or_insert_with(String::new);
+        loop {
+          match chars.next() {
+            Some('"') => break,
+            Some('\\') => match chars.next() {
+              Some(c) if "$`\"\\\n".contains(c) => word.push(c),
+              Some(c) => {
+                word.push('\\');
+                word.push(c);
+              },
+              None => return Err(format!("unterminated `\"` in `{}`", line)),
+            },
+            Some(c) => word.push(c),
+            None => return Err(format!("unterminated `\"` in `{}`", line)),
+          }
+        }
+      },
+      '\\' => match chars.next() {
+        Some(c) => word.get_or_insert_with(String::new).push(c),
+        None => return Err(format!("trailing `\\` in `{}`", line)),
+      },
+      c => word.get_or_insert_with(String::new).push(c),
+    }
+  }
+  words.extend(word.take());
+  Ok(words)
+}
+
+fn reproducer(reason: &str) -> String {
+  let args: Vec<_> = env::args_os().collect();
+  let tool = args.first()
+    .map(|arg| arg.to_string_lossy().into_owned() )
+    .unwrap_or_default();
+
+  let mut out = String::new();
+  out.push_str(&format!("tool: {}\n", tool));
+  out.push_str(&format!("version: {} (wasm-driver-utils)\n",
+                        env!("CARGO_PKG_VERSION")));
+  out.push_str(&format!("reason: {}\n", reason));
+  match env::current_dir() {
+    Ok(dir) => out.push_str(&format!("cwd: {}\n", dir.display())),
+    Err(e) => out.push_str(&format!("cwd: <{}>\n", e)),
+  }
+
+  // `Debug` escapes anything which isn't valid UTF-8 instead of losing it.
+  out.push_str("\nargv:\n");
+  for arg in args.iter() {
+    out.push_str(&format!("  {:?}\n", arg));
+  }
+
+  out.push_str("\ntoolchain:\n");
+  for &var in TOOLCHAIN_ENVS.iter() {
+    match env::var_os(var) {
+      Some(v) => out.push_str(&format!("  {}={:?}\n", var, v)),
+      None => out.push_str(&format!("  {} (unset)\n", var)),
+    }
+  }
+
+  out.push_str("\nenvironment:\n");
+  let mut vars: Vec<_> = env::vars_os()
+    .filter(|&(ref k, _)| {
+      let k = k.to_string_lossy();
+      k.starts_with("WASM_DRIVER_") || BUILD_ENVS.contains(&&k[..])
+    })
+    .collect();
+  vars.sort();
+  for (k, v) in vars.into_iter() {
+    out.push_str(&format!("  {}={:?}\n", k.to_string_lossy(), v));
+  }
+
+  out.push_str("\nlast step:\n");
+  match CURRENT_STEP.lock().ok().as_ref().and_then(|step| step.as_ref() ) {
+    Some(step) => {
+      out.push_str(&format!("  {}\n", step.name));
+      if let Some(ref command) = step.command {
+        out.push_str(&format!("  {}\n", command));
+      }
+    },
+    None => out.push_str("  (none started)\n"),
+  }
+
+  let rerun: Vec<String> = args.iter()
+    .map(|arg| shell_quote(&arg.to_string_lossy()) )
+    .collect();
+  out.push_str(&format!("\nrerun:\n  {}\n", rerun.join(" ")));
+  out
+}
+
+/// Write a reproducer to the temp dir, returning its path.
+pub fn write_reproducer(reason: &str) -> io::Result<PathBuf> {
+  let path = env::temp_dir()
+    .join(format!("wasm-driver-bug-report.{}.txt", ::std::process::id()));
+  File::create(&path)?.write_all(reproducer(reason).as_bytes())?;
+  Ok(path)
+}
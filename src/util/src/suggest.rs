@@ -0,0 +1,174 @@
+
+//! Reporting unsupported flags: suggest the closest flags the tool does
+//! accept, or, with `--relaxed-flags`, ignore unknown `-f`/`-m` flags like
+//! GCC does (which configure scripts probing for features depend on).
+
+use std::error::Error;
+
+use super::{ToolInvocation, relaxed_flags, allow_unused_args, ignore_host_args, };
+use super::diag;
+
+const META: &'static [char] = &['(', ')', '[', '.', '*', '+', '?', '$',
+                                '|', '{'];
+
+/// The literal flag names `regex` accepts, ie `^(-y|--trace-symbol=)(.+)$`
+/// gives `-y` and `--trace-symbol`. Optional groups are dropped, and we stop
+/// at anything too dynamic to tell.
+fn flag_names(regex: &str) -> Vec<String> {
+  expand(regex.trim_start_matches('^'))
+    .into_iter()
+    .map(|name| name.trim_end_matches('=').to_string() )
+    .filter(|name| name.starts_with('-') && name.len() > 1 )
+    .collect()
+}
+fn expand(regex: &str) -> Vec<String> {
+  let mut prefix = String::new();
+  let mut chars = regex.char_indices();
+  while let Some((i, c)) = chars.next() {
+    match c {
+      '\\' => {
+        match chars.next() {
+          Some((_, c)) => prefix.push(c),
+          None => break,
+        }
+      },
+      // `--?foo`: take the optional char.
+      '?' => {},
+      '(' => {
+        let end = match regex[i..].find(')') {
+          Some(end) => i + end,
+          None => break,
+        };
+        let group = &regex[i + 1..end];
+        let rest = &regex[end + 1..];
+        if rest.starts_with('?') {
+          return expand(&rest[1..]).into_iter()
+            .map(|r| format!("{}{}", prefix, r) )
+            .collect();
+        }
+        if group.contains(|c| c != '|' && META.contains(&c) ) {
+          break;
+        }
+
+        let rest = expand(rest);
+        let mut out = Vec::new();
+        for alt in group.split('|') {
+          for r in rest.iter() {
+            out.push(format!("{}{}{}", prefix, alt, r));
+          }
+        }
+        return out;
+      },
+      c if META.contains(&c) => break,
+      c => prefix.push(c),
+    }
+  }
+  vec![prefix]
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+  let b: Vec<char> = b.chars().collect();
+  let mut prev: Vec<usize> = (0..b.len() + 1).collect();
+  for (i, ca) in a.chars().enumerate() {
+    let mut cur = vec![i + 1];
+    for (j, &cb) in b.iter().enumerate() {
+      let cost = if ca == cb { 0 } else { 1 };
+      let v = (prev[j] + cost)
+        .min(prev[j + 1] + 1)
+        .min(cur[j] + 1);
+      cur.push(v);
+    }
+    prev = cur;
+  }
+  prev[b.len()]
+}
+
+/// The (up to three) flags `this` accepts which are closest to `arg`.
+pub fn suggestions<T>(this: &T, arg: &str) -> Vec<String>
+  where T: ToolInvocation + 'static,
+{
+  let arg = arg.split('=').next().unwrap();
+  let max = ::std::cmp::max(2, arg.len() / 3);
+
+  let mut known = Vec::new();
+  let mut iteration = 0;
+  while let Some(args) = this.args(iteration) {
+    for tool_arg in args.iter() {
+      for regex in tool_arg.single.iter().chain(tool_arg.split.iter()) {
+        known.extend(flag_names(regex.as_ref()));
+      }
+    }
+    iteration += 1;
+  }
+  known.sort();
+  known.dedup();
+
+  let mut close: Vec<(usize, String)> = known.into_iter()
+    .map(|name| (edit_distance(arg, &name), name) )
+    .filter(|&(d, _)| d <= max )
+    .collect();
+  close.sort();
+  close.into_iter()
+    .take(3)
+    .map(|(_, name)| name )
+    .collect()
+}
+
+/// For the catch-all `UNSUPPORTED` args.
+pub fn unsupported_arg<T>(this: &T, arg: &str) -> Result<(), Box<Error>>
+  where T: ToolInvocation + 'static,
+{
+  if relaxed_flags() && (arg.starts_with("-f") || arg.starts_with("-m")) {
+    diag::warning(&format!("ignoring unsupported argument `{}`", arg));
+    return Ok(());
+  }
+
+  let suggestions = suggestions(this, arg);
+  let mut msg = format!("unsupported argument `{}`", arg);
+  if suggestions.len() != 0 {
+    let suggestions: Vec<String> = suggestions.into_iter()
+      .map(|s| format!("`{}`", s) )
+      .collect();
+    msg.push_str(&format!("; did you mean {}?", suggestions.join(" or ")));
+  }
+  Err(msg.into())
+}
+
+/// For args which only mean something when targeting macOS, ie
+/// `-framework`, which configure scripts on macOS probe for. Saying so
+/// plainly (and failing) keeps the probe from concluding something odd.
+pub fn host_only_arg(arg: &str) -> Result<(), Box<Error>> {
+  if ignore_host_args() {
+    diag::warning(&format!("ignoring macOS specific argument `{}`", arg));
+    return Ok(());
+  }
+  Err(format!("`{}` is macOS specific, and not supported on wasm targets \
+               (`--ignore-host-args` ignores it)", arg).into())
+}
+
+/// For the args left over once every iteration of `this.args()` has had a
+/// go; dropping them silently could change what gets built.
+pub fn unused_args<T>(this: &T, args: &[String]) -> Result<(), Box<Error>>
+  where T: ToolInvocation + 'static,
+{
+  if allow_unused_args() {
+    let args: Vec<String> = args.iter()
+      .map(|arg| format!("`{}`", arg) )
+      .collect();
+    diag::warning(&format!("ignoring unused arguments: {}", args.join(", ")));
+    return Ok(());
+  }
+
+  let mut msg = "unused arguments (`--allow-unused-args` ignores them):".to_string();
+  for arg in args.iter() {
+    msg.push_str(&format!("\n  `{}`", arg));
+    let suggestions = suggestions(this, arg);
+    if suggestions.len() != 0 {
+      let suggestions: Vec<String> = suggestions.into_iter()
+        .map(|s| format!("`{}`", s) )
+        .collect();
+      msg.push_str(&format!("; did you mean {}?", suggestions.join(" or ")));
+    }
+  }
+  Err(msg.into())
+}
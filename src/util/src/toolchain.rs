@@ -1,7 +1,9 @@
 
 use std::env::{var_os};
+use std::fmt;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::str::FromStr;
 
 use {CreateIfNotExists, ToolArgs, ToolArg, };
 
@@ -9,6 +11,59 @@ const BINARYEN_ROOT_ENV: &'static str = "BINARYEN";
 const EMSCRIPTEN_ROOT_ENV: &'static str = "EMSCRIPTEN";
 const LLVM_ROOT_ENV: &'static str = "LLVM_ROOT";
 const SYSROOT_ENV: &'static str = "WASM_SYSROOT";
+const THREADS_ENV: &'static str = "WASM_THREADS";
+/// Vars the toolchain is configured from; these are always passed through
+/// in hermetic mode.
+pub const TOOLCHAIN_ENVS: &'static [&'static str] = &[
+  BINARYEN_ROOT_ENV,
+  EMSCRIPTEN_ROOT_ENV,
+  LLVM_ROOT_ENV,
+  SYSROOT_ENV,
+  THREADS_ENV,
+  ::config::CONFIG_ENV,
+];
+
+/// How `pthread`s are provided. This has to agree across clang, ld and the
+/// sysroot, so it lives in the toolchain (and in `$WASM_THREADS`).
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ThreadModel {
+  /// Single threaded; `pthread_create` fails.
+  None,
+  /// Threads run to completion when created, from a stub library
+  /// (`libgreen-threads`).
+  Emulated,
+  /// Real threads, using wasm atomics and a shared memory.
+  Atomics,
+}
+impl ThreadModel {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      &ThreadModel::None => "none",
+      &ThreadModel::Emulated => "emulated",
+      &ThreadModel::Atomics => "atomics",
+    }
+  }
+}
+impl Default for ThreadModel {
+  fn default() -> ThreadModel { ThreadModel::None }
+}
+impl fmt::Display for ThreadModel {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.pad(self.as_str())
+  }
+}
+impl FromStr for ThreadModel {
+  type Err = String;
+  fn from_str(s: &str) -> Result<ThreadModel, String> {
+    match s {
+      "none" => Ok(ThreadModel::None),
+      "emulated" => Ok(ThreadModel::Emulated),
+      "atomics" => Ok(ThreadModel::Atomics),
+      _ => Err(format!("unknown thread model `{}`; expected `none`, `emulated` or `atomics`",
+                       s)),
+    }
+  }
+}
 
 #[derive(Clone, Debug)]
 pub struct WasmToolchain {
@@ -17,6 +72,12 @@ pub struct WasmToolchain {
   pub llvm: PathBuf,
 
   pub sysroot: PathBuf,
+
+  pub threads: ThreadModel,
+
+  /// clang's resource dir, which has its builtin headers (`stddef.h`,
+  /// etc). `-nostdinc` drops them, so they're added back explicitly.
+  pub clang_resource_dir: Option<PathBuf>,
 }
 impl WasmToolchain {
   pub fn new() -> WasmToolchain {
@@ -31,12 +92,72 @@ impl WasmToolchain {
     let binaryen = get_var(BINARYEN_ROOT_ENV);
     let emscripten = get_var(EMSCRIPTEN_ROOT_ENV);
     let llvm = get_var(LLVM_ROOT_ENV);
+    let threads = match var_os(THREADS_ENV) {
+      Some(ref v) if v.len() != 0 => {
+        v.to_str()
+          .ok_or_else(|| format!("`{}` isn't utf8", THREADS_ENV) )
+          .and_then(FromStr::from_str)
+          .unwrap_or_else(|e| {
+            ::diag::warning(&format!("ignoring `{}`: {}", THREADS_ENV, e));
+            Default::default()
+          })
+      },
+      _ => Default::default(),
+    };
+
+    let clang_resource_dir = Self::detect_clang_resource_dir(&llvm);
 
     WasmToolchain {
       binaryen: binaryen,
       emscripten: emscripten,
       llvm,
       sysroot: Self::default_sysroot(),
+      threads,
+      clang_resource_dir,
+    }
+  }
+
+  /// The `clang-resource-dir` config key, else the newest version in
+  /// `<llvm>/lib/clang`, else whatever clang says.
+  pub fn detect_clang_resource_dir(llvm: &Path) -> Option<PathBuf> {
+    use std::fs::read_dir;
+
+    if let Some(dir) = ::config::get("clang-resource-dir") {
+      return Some(dir.into());
+    }
+
+    // `5.0.0`, or just the major version since LLVM 16.
+    fn version(dir: &Path) -> Option<Vec<u32>> {
+      dir.file_name()?
+        .to_str()?
+        .split('.')
+        .map(|v| v.parse().ok() )
+        .collect()
+    }
+    let newest = read_dir(llvm.join("lib").join("clang"))
+      .into_iter()
+      .flat_map(|dirs| dirs )
+      .filter_map(|entry| entry.ok() )
+      .map(|entry| entry.path() )
+      .filter_map(|dir| version(&dir).map(|v| (v, dir) ) )
+      .max();
+    if let Some((_, dir)) = newest {
+      return Some(dir);
+    }
+
+    let clang = llvm.join("bin").join("clang");
+    if !clang.exists() { return None; }
+    let mut cmd = Command::new(clang);
+    cmd.arg("-print-resource-dir");
+    super::hermetic_env(&mut cmd);
+    let output = cmd.output().ok()?;
+    if !output.status.success() { return None; }
+    let dir = String::from_utf8(output.stdout).ok()?;
+    let dir = dir.trim();
+    if dir.len() == 0 {
+      None
+    } else {
+      Some(dir.into())
     }
   }
 
@@ -82,12 +203,20 @@ impl WasmToolchain {
   pub fn sysroot(&self) -> &PathBuf { &self.sysroot }
   pub fn sysroot_cache(&self) -> &PathBuf { &self.sysroot }
   pub fn sysroot_lib(&self) -> PathBuf { self.sysroot.join("lib") }
+  /// The libraries built for `eh`, ie the C++ runtime with exceptions.
+  pub fn sysroot_eh_lib(&self, eh: ::EhMode) -> PathBuf {
+    match eh.lib_subdir() {
+      Some(dir) => self.sysroot_lib().join(dir),
+      None => self.sysroot_lib(),
+    }
+  }
 
   pub fn set_envs(&self, cmd: &mut Command) {
     cmd.env(BINARYEN_ROOT_ENV, &self.binaryen)
       .env(EMSCRIPTEN_ROOT_ENV, &self.emscripten)
       .env(LLVM_ROOT_ENV, &self.llvm)
-      .env(SYSROOT_ENV, &self.sysroot);
+      .env(SYSROOT_ENV, &self.sysroot)
+      .env(THREADS_ENV, self.threads.as_str());
   }
 
   pub fn args<T>(into: &mut ToolArgs<T>)
@@ -106,6 +235,21 @@ impl WasmToolchain {
       }),
     };
     into.to_mut().push(o);
+
+    // only the thread models are matched, so tools are free to have their
+    // own `--threads=<n>`.
+    let o = ToolArg {
+      name: "threads".into(),
+      single: Some(r"^--threads=(none|emulated|atomics)$".into()),
+      split: None,
+      help: None,
+      action: Some(|this: &mut T, _single, cap| {
+        let tc = this.wasm_toolchain_mut();
+        tc.threads = FromStr::from_str(cap.get(1).unwrap().as_str())?;
+        Ok(())
+      }),
+    };
+    into.to_mut().push(o);
   }
 }
 impl Default for WasmToolchain {
@@ -0,0 +1,14 @@
+/* Entry shim which runs global constructors before handing off to musl's
+ * `_start_c`. wasm-ld collects constructors into `__wasm_call_ctors`, but
+ * nothing calls it for us. Destructors (and `__attribute__((destructor))`
+ * functions) are registered with `atexit` by the constructors themselves,
+ * so they run when `main` returns or `exit` is called.
+ */
+
+extern void __wasm_call_ctors(void);
+extern void _start_c(long *p);
+
+void _start_ctors(long *p) {
+  __wasm_call_ctors();
+  _start_c(p);
+}
@@ -0,0 +1,42 @@
+/* pthreads for `--threads=emulated`.
+ *
+ * wasm can't switch stacks, so a "thread" is run to completion inside
+ * `pthread_create`. This is enough for code which spawns workers and later
+ * joins them; code which needs threads to actually run concurrently (ie
+ * waiting on a condvar which another thread will signal) will deadlock.
+ * `pthread_exit` from a green thread exits the whole program.
+ */
+
+#include <errno.h>
+#include <pthread.h>
+#include <stdlib.h>
+
+struct green_thread {
+  void *result;
+};
+
+int pthread_create(pthread_t *restrict thread,
+                   const pthread_attr_t *restrict attr,
+                   void *(*start)(void *), void *restrict arg) {
+  struct green_thread *t;
+  (void)attr;
+
+  t = calloc(1, sizeof(*t));
+  if (!t) return EAGAIN;
+
+  t->result = start(arg);
+  *thread = (pthread_t)t;
+  return 0;
+}
+
+int pthread_join(pthread_t thread, void **result) {
+  struct green_thread *t = (struct green_thread *)thread;
+  if (result) *result = t->result;
+  free(t);
+  return 0;
+}
+
+int pthread_detach(pthread_t thread) {
+  free((struct green_thread *)thread);
+  return 0;
+}